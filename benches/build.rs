@@ -30,7 +30,7 @@ fn build_fst_set(b: &mut Bencher) {
         for word in &words {
             bfst.add(word).unwrap();
         }
-        Fst::new(bfst.into_inner().unwrap()).unwrap();
+        tokio_test::block_on(Fst::new(bfst.into_inner().unwrap())).unwrap();
     });
 }
 
@@ -43,7 +43,7 @@ fn build_fst_map(b: &mut Bencher) {
         for &(ref word, len) in &words {
             bfst.insert(word, len).unwrap();
         }
-        Fst::new(bfst.into_inner().unwrap()).unwrap();
+        tokio_test::block_on(Fst::new(bfst.into_inner().unwrap())).unwrap();
     });
 }
 
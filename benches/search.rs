@@ -37,7 +37,7 @@ macro_rules! search {
                             bfst.add(word).unwrap();
                         }
                         let bytes = bfst.into_inner().unwrap();
-                        Fst::new(bytes).unwrap()
+                        tokio_test::block_on(Fst::new(bytes)).unwrap()
                     };
                 }
                 let mut i = 0;
@@ -47,6 +47,26 @@ macro_rules! search {
                 })
             }
 
+            #[bench]
+            fn fst_get_contiguous(b: &mut Bencher) {
+                lazy_static! {
+                    static ref FST: Fst = {
+                        let mut bfst = Builder::memory();
+                        for word in $keys.iter() {
+                            bfst.add(word).unwrap();
+                        }
+                        let bytes = bfst.into_inner().unwrap();
+                        tokio_test::block_on(Fst::new(bytes)).unwrap()
+                    };
+                }
+                let fst = FST.as_contiguous().unwrap();
+                let mut i = 0;
+                b.iter(|| {
+                    i = (i + 1) % $keys.len();
+                    assert!(fst.get(&$keys[i]).is_some());
+                })
+            }
+
             #[bench]
             fn fst_streams(b: &mut Bencher) {
                 use fst::{IntoStreamer, Streamer};
@@ -57,7 +77,7 @@ macro_rules! search {
                             bfst.add(word).unwrap();
                         }
                         let bytes = bfst.into_inner().unwrap();
-                        Fst::new(bytes).unwrap()
+                        tokio_test::block_on(Fst::new(bytes)).unwrap()
                     };
                 }
                 b.iter(|| {
@@ -0,0 +1,236 @@
+//! Python bindings for `fst`.
+//!
+//! This exposes `Map` and `MapBuilder` to Python, along with range queries
+//! and regex searches, so that maps can be built in Python pipelines and
+//! queried from Rust services, or the other way around. Keys may be given as
+//! `bytes` or as `str`, in which case they are encoded as UTF-8. Keys are
+//! always returned as `bytes`.
+//!
+//! Build the extension module with the `extension-module` feature, for
+//! example with `maturin build --features extension-module`.
+
+use fst::raw::Fst;
+use fst::{FakeArr, IntoStreamer, Regex, Streamer};
+use futures::executor::block_on;
+use pyo3::exceptions::{PyKeyError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyString};
+
+/// An immutable map from byte strings to unsigned 64 bit integers.
+#[pyclass(name = "Map", module = "fst_python", frozen)]
+struct Map(fst::Map<Vec<u8>>);
+
+#[pymethods]
+impl Map {
+    /// Opens a map from the bytes written by `MapBuilder.finish`.
+    #[new]
+    fn new(data: Vec<u8>) -> PyResult<Map> {
+        let fst = block_on(Fst::new(data)).map_err(to_py_err)?;
+        Ok(Map(fst::Map::from(fst)))
+    }
+
+    fn __len__(&self) -> usize {
+        self.0.len() as usize
+    }
+
+    fn __contains__(&self, key: &Bound<'_, PyAny>) -> PyResult<bool> {
+        Ok(self.0.contains_key(key_bytes(key)?))
+    }
+
+    fn __getitem__(&self, key: &Bound<'_, PyAny>) -> PyResult<u64> {
+        self.0
+            .get(key_bytes(key)?)
+            .ok_or_else(|| PyKeyError::new_err(key.clone().unbind()))
+    }
+
+    /// Returns the value of `key`, or `default` if it does not exist.
+    #[pyo3(signature = (key, default=None))]
+    fn get(&self, key: &Bound<'_, PyAny>, default: Option<u64>) -> PyResult<Option<u64>> {
+        Ok(self.0.get(key_bytes(key)?).or(default))
+    }
+
+    /// Returns every key, in lexicographic order.
+    fn keys<'py>(&self, py: Python<'py>) -> Vec<Bound<'py, PyBytes>> {
+        let mut keys = vec![];
+        let mut stream = self.0.keys();
+        while let Some(key) = stream.next() {
+            keys.push(PyBytes::new(py, &key.to_vec()));
+        }
+        keys
+    }
+
+    /// Returns every key-value pair, in lexicographic order of keys.
+    fn items<'py>(&self, py: Python<'py>) -> Vec<(Bound<'py, PyBytes>, u64)> {
+        collect(py, self.0.stream())
+    }
+
+    /// Returns the key-value pairs whose keys are within the given bounds,
+    /// in lexicographic order of keys. Missing bounds are unbounded.
+    #[pyo3(signature = (ge=None, gt=None, le=None, lt=None))]
+    fn range<'py>(
+        &self,
+        py: Python<'py>,
+        ge: Option<&Bound<'py, PyAny>>,
+        gt: Option<&Bound<'py, PyAny>>,
+        le: Option<&Bound<'py, PyAny>>,
+        lt: Option<&Bound<'py, PyAny>>,
+    ) -> PyResult<Vec<(Bound<'py, PyBytes>, u64)>> {
+        let mut range = self.0.range();
+        if let Some(ge) = ge {
+            range = range.ge(key_bytes(ge)?);
+        }
+        if let Some(gt) = gt {
+            range = range.gt(key_bytes(gt)?);
+        }
+        if let Some(le) = le {
+            range = range.le(key_bytes(le)?);
+        }
+        if let Some(lt) = lt {
+            range = range.lt(key_bytes(lt)?);
+        }
+        Ok(collect(py, range.into_stream()))
+    }
+
+    /// Returns the key-value pairs whose keys match the regular expression
+    /// `pattern`, in lexicographic order of keys.
+    fn search<'py>(
+        &self,
+        py: Python<'py>,
+        pattern: &str,
+    ) -> PyResult<Vec<(Bound<'py, PyBytes>, u64)>> {
+        let re = Regex::new(pattern).map_err(|err| PyValueError::new_err(err.to_string()))?;
+        Ok(collect(py, self.0.search(re).into_stream()))
+    }
+}
+
+/// Builds a map in memory from keys inserted in lexicographic order.
+#[pyclass(name = "MapBuilder", module = "fst_python")]
+struct MapBuilder(Option<fst::MapBuilder<Vec<u8>>>);
+
+#[pymethods]
+impl MapBuilder {
+    #[new]
+    fn new() -> MapBuilder {
+        MapBuilder(Some(fst::MapBuilder::memory()))
+    }
+
+    /// Inserts a key-value pair. Keys must be inserted in lexicographic
+    /// order, and each key at most once.
+    fn insert(&mut self, key: &Bound<'_, PyAny>, value: u64) -> PyResult<()> {
+        let key = key_bytes(key)?;
+        self.builder()?.insert(key, value).map_err(to_py_err)
+    }
+
+    /// Finishes the map and returns its bytes, which can be opened with
+    /// `Map`. The builder cannot be used afterwards.
+    fn finish<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        let builder = self.0.take().ok_or_else(finished)?;
+        let bytes = builder.into_inner().map_err(to_py_err)?;
+        Ok(PyBytes::new(py, &bytes))
+    }
+}
+
+impl MapBuilder {
+    fn builder(&mut self) -> PyResult<&mut fst::MapBuilder<Vec<u8>>> {
+        self.0.as_mut().ok_or_else(finished)
+    }
+}
+
+fn finished() -> PyErr {
+    PyValueError::new_err("builder was already finished")
+}
+
+fn collect<'py, S>(py: Python<'py>, mut stream: S) -> Vec<(Bound<'py, PyBytes>, u64)>
+where
+    S: for<'a> Streamer<'a, Item = (fst::FakeArrSlice<'a>, u64)>,
+{
+    let mut items = vec![];
+    while let Some((key, value)) = stream.next() {
+        items.push((PyBytes::new(py, &key.to_vec()), value));
+    }
+    items
+}
+
+fn key_bytes(key: &Bound<'_, PyAny>) -> PyResult<Vec<u8>> {
+    if let Ok(key) = key.downcast::<PyBytes>() {
+        Ok(key.as_bytes().to_vec())
+    } else if let Ok(key) = key.downcast::<PyString>() {
+        Ok(key.to_str()?.as_bytes().to_vec())
+    } else {
+        Err(PyValueError::new_err("keys must be bytes or str"))
+    }
+}
+
+fn to_py_err(err: fst::Error) -> PyErr {
+    PyValueError::new_err(err.to_string())
+}
+
+/// Finite state transducer backed maps.
+#[pymodule]
+fn fst_python(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Map>()?;
+    m.add_class::<MapBuilder>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build<'py>(py: Python<'py>, items: &[(&str, u64)]) -> Map {
+        let mut builder = MapBuilder::new();
+        for &(key, value) in items {
+            let key = PyString::new(py, key);
+            builder.insert(key.as_any(), value).unwrap();
+        }
+        let bytes = builder.finish(py).unwrap();
+        assert!(builder.insert(PyString::new(py, "zz").as_any(), 0).is_err());
+        Map::new(bytes.as_bytes().to_vec()).unwrap()
+    }
+
+    fn pairs(items: Vec<(Bound<'_, PyBytes>, u64)>) -> Vec<(Vec<u8>, u64)> {
+        items
+            .into_iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value))
+            .collect()
+    }
+
+    #[test]
+    fn build_and_query() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let map = build(py, &[("a", 1), ("ab", 2), ("b", 3), ("bc", 4)]);
+            assert_eq!(map.__len__(), 4);
+
+            let key = PyBytes::new(py, b"ab");
+            assert_eq!(map.__getitem__(key.as_any()).unwrap(), 2);
+            assert!(map.__contains__(key.as_any()).unwrap());
+            let key = PyString::new(py, "bc");
+            assert_eq!(map.__getitem__(key.as_any()).unwrap(), 4);
+
+            let missing = PyString::new(py, "c");
+            let err = map.__getitem__(missing.as_any()).unwrap_err();
+            assert!(err.is_instance_of::<PyKeyError>(py));
+            assert_eq!(map.get(missing.as_any(), Some(7)).unwrap(), Some(7));
+            let err = map.__getitem__(1u64.into_pyobject(py).unwrap().as_any()).unwrap_err();
+            assert!(err.is_instance_of::<PyValueError>(py));
+
+            let ge = PyString::new(py, "ab");
+            let lt = PyBytes::new(py, b"bc");
+            let range = map.range(py, Some(ge.as_any()), None, None, Some(lt.as_any()));
+            assert_eq!(
+                pairs(range.unwrap()),
+                vec![(b"ab".to_vec(), 2), (b"b".to_vec(), 3)]
+            );
+            let all = map.range(py, None, None, None, None).unwrap();
+            assert_eq!(pairs(all).len(), 4);
+
+            assert_eq!(
+                pairs(map.search(py, "b.*").unwrap()),
+                vec![(b"b".to_vec(), 3), (b"bc".to_vec(), 4)]
+            );
+            let err = map.search(py, "(").unwrap_err();
+            assert!(err.is_instance_of::<PyValueError>(py));
+        });
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::future::Future;
+use std::io;
+
+use futures::future::BoxFuture;
+
+use crate::fake_arr::{FakeArr, Ulen};
+
+/// The async counterpart of `FakeArr`, for data that is read over the
+/// network, such as from object storage.
+///
+/// The length is known up front, as it is for `FakeArr`, while reads return
+/// futures. An `AsyncFakeArr` is wrapped in a `BlockingFakeArr` to open an
+/// fst, whose async methods, such as `Map::get_async`, then await its reads.
+///
+/// This is only available with the `async` feature.
+pub trait AsyncFakeArr: Debug + Send + Sync {
+    /// Returns the number of bytes of the data.
+    fn len(&self) -> Ulen;
+
+    /// Reads `buf.len()` bytes starting at `offset` into `buf`.
+    fn read_into<'a>(&'a self, offset: Ulen, buf: &'a mut [u8]) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Reads `len` bytes starting at `offset`.
+    fn read_vec(&self, offset: Ulen, len: usize) -> BoxFuture<'_, io::Result<Vec<u8>>> {
+        Box::pin(async move {
+            let mut buf = vec![0; len];
+            self.read_into(offset, &mut buf).await?;
+            Ok(buf)
+        })
+    }
+
+    /// Returns true if the data has no bytes.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Runs futures to completion on behalf of a `BlockingFakeArr`.
+///
+/// This is implemented by `FuturesBlockOn`, which runs them on the current
+/// thread. A service running on tokio would implement it for a wrapper of a
+/// `tokio::runtime::Handle`, so that reads are driven by the runtime that
+/// owns their connections.
+pub trait BlockOn: Debug + Send + Sync {
+    /// Blocks the current thread until `future` completes.
+    fn block_on<F: Future>(&self, future: F) -> F::Output;
+}
+
+/// A `BlockOn` that runs futures on the current thread, with the executor
+/// of the `futures` crate.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FuturesBlockOn;
+
+impl BlockOn for FuturesBlockOn {
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        futures::executor::block_on(future)
+    }
+}
+
+/// Bridges an `AsyncFakeArr` to `FakeArr`, by blocking on each read with a
+/// `BlockOn` runtime.
+///
+/// Since every read through `FakeArr` blocks the thread that makes it, the
+/// synchronous methods of fsts backed by this should be called from threads
+/// that may block, such as those of `tokio::task::spawn_blocking`. Async
+/// tasks use `Map::get_async` and `StreamBuilder::into_async_stream`
+/// instead, which await the reads of the `AsyncFakeArr` and never block.
+#[derive(Debug)]
+pub struct BlockingFakeArr<A, R = FuturesBlockOn> {
+    arr: A,
+    runtime: R,
+}
+
+impl<A: AsyncFakeArr, R: BlockOn> BlockingFakeArr<A, R> {
+    /// Reads `arr` by blocking on `runtime`.
+    pub fn new(arr: A, runtime: R) -> BlockingFakeArr<A, R> {
+        BlockingFakeArr { arr, runtime }
+    }
+
+    /// Returns the async data this reads from.
+    pub fn get_ref(&self) -> &A {
+        &self.arr
+    }
+
+    /// Returns the async data this reads from.
+    pub fn into_inner(self) -> A {
+        self.arr
+    }
+}
+
+impl<A: AsyncFakeArr, R: BlockOn> FakeArr for BlockingFakeArr<A, R> {
+    fn len(&self) -> Ulen {
+        self.arr.len()
+    }
+
+    fn read_into(&self, offset: Ulen, buf: &mut [u8]) -> io::Result<()> {
+        self.runtime.block_on(self.arr.read_into(offset, buf))
+    }
+
+    fn as_async(&self) -> Option<&dyn AsyncFakeArr> {
+        Some(&self.arr)
+    }
+
+    fn as_dyn(&self) -> &dyn FakeArr {
+        self
+    }
+}
+
+/// The number of bytes fetched from an `AsyncFakeArr` at once by async
+/// searches.
+const BLOCK_SIZE: Ulen = 4096;
+
+/// The number of blocks an async stream keeps between pages. Past this, it
+/// drops them all and fetches the ones it needs again.
+const MAX_BLOCKS: usize = 256;
+
+/// A read of a block by `Fetched::fetch`, with the index of the block.
+pub(crate) type Fetch<'a> = BoxFuture<'a, io::Result<(Ulen, Vec<u8>)>>;
+
+/// The blocks of an `AsyncFakeArr` that an async search fetched so far.
+///
+/// Async searches run the synchronous search code over this. A read of a
+/// block that was not fetched yet fails with an error naming the block,
+/// which the search fetches before running again. Blocks are only dropped
+/// between the pages of a stream, so every run gets further than the last.
+pub(crate) struct Fetched<'a> {
+    arr: &'a dyn AsyncFakeArr,
+    offset: Ulen,
+    len: Ulen,
+    blocks: HashMap<Ulen, Vec<u8>>,
+}
+
+/// The error of a read of a block that was not fetched yet.
+#[derive(Debug)]
+struct Missing(Ulen);
+
+impl fmt::Display for Missing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "block {} was not fetched yet", self.0)
+    }
+}
+
+impl std::error::Error for Missing {}
+
+/// Returns the block that a read failed to find, if `err` is the error of a
+/// read of `Fetched`.
+pub(crate) fn missing_block(err: &io::Error) -> Option<Ulen> {
+    err.get_ref()?
+        .downcast_ref::<Missing>()
+        .map(|missing| missing.0)
+}
+
+impl<'a> Fetched<'a> {
+    /// Reads the `len` bytes of `arr` starting at `offset`.
+    pub(crate) fn new(arr: &'a dyn AsyncFakeArr, offset: Ulen, len: Ulen) -> Fetched<'a> {
+        Fetched {
+            arr,
+            offset,
+            len,
+            blocks: HashMap::new(),
+        }
+    }
+
+    /// Returns a future that reads the block at `index`, to be passed to
+    /// `insert`.
+    ///
+    /// The future does not borrow `self`, so that a stream can hold it.
+    pub(crate) fn fetch(&self, index: Ulen) -> Fetch<'a> {
+        let arr = self.arr;
+        let start = index * BLOCK_SIZE;
+        let offset = self.offset + start;
+        let len = BLOCK_SIZE.min(self.len - start) as usize;
+        Box::pin(async move { Ok((index, arr.read_vec(offset, len).await?)) })
+    }
+
+    /// Adds a block read by a future returned by `fetch`.
+    pub(crate) fn insert(&mut self, (index, block): (Ulen, Vec<u8>)) {
+        self.blocks.insert(index, block);
+    }
+
+    /// Drops all blocks if there are too many of them.
+    pub(crate) fn trim(&mut self) {
+        if self.blocks.len() > MAX_BLOCKS {
+            self.blocks.clear();
+        }
+    }
+}
+
+impl<'a> Debug for Fetched<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Fetched")
+            .field("arr", &self.arr)
+            .field("offset", &self.offset)
+            .field("len", &self.len)
+            .field("blocks", &self.blocks.len())
+            .finish()
+    }
+}
+
+impl<'a> FakeArr for Fetched<'a> {
+    fn len(&self) -> Ulen {
+        self.len
+    }
+
+    fn read_into(&self, offset: Ulen, buf: &mut [u8]) -> io::Result<()> {
+        let end = offset + buf.len() as Ulen;
+        if end > self.len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "read past the end of the array",
+            ));
+        }
+        let mut pos = offset;
+        while pos < end {
+            let index = pos / BLOCK_SIZE;
+            let from = (pos - index * BLOCK_SIZE) as usize;
+            let block = self
+                .blocks
+                .get(&index)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::WouldBlock, Missing(index)))?;
+            let n = (block.len() - from).min((end - pos) as usize);
+            buf[(pos - offset) as usize..][..n].copy_from_slice(&block[from..from + n]);
+            pos += n as Ulen;
+        }
+        Ok(())
+    }
+
+    fn as_dyn(&self) -> &dyn FakeArr {
+        self
+    }
+}
@@ -0,0 +1,125 @@
+use std::ops::Bound;
+
+use super::Automaton;
+use crate::bytes_util::prefix_upper_bound;
+
+/// The lexicographically smallest and largest strings accepted by an
+/// automaton, as computed by `bounds`.
+///
+/// Strings longer than the length limit given to `bounds` are cut off. When
+/// that happens, the cut off string is reported along with a flag saying
+/// that it is only a prefix of what the automaton may accept. Either way,
+/// `lower` and `upper` are bounds on *every* string the automaton accepts,
+/// so they can be used as a range pre-filter for a search.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Bounds {
+    /// The smallest accepted string, or a prefix of it.
+    pub min: Vec<u8>,
+    /// Whether `min` is only a prefix of the smallest accepted string.
+    pub min_is_prefix: bool,
+    /// The largest accepted string, or a prefix of it.
+    pub max: Vec<u8>,
+    /// Whether `max` is only a prefix, in which case the automaton may
+    /// accept longer strings that start with `max`.
+    pub max_is_prefix: bool,
+}
+
+impl Bounds {
+    /// Returns an inclusive lower bound on the strings the automaton
+    /// accepts.
+    pub fn lower(&self) -> Bound<&[u8]> {
+        Bound::Included(&self.min)
+    }
+
+    /// Returns an upper bound on the strings the automaton accepts.
+    pub fn upper(&self) -> Bound<Vec<u8>> {
+        if !self.max_is_prefix {
+            Bound::Included(self.max.clone())
+        } else {
+            match prefix_upper_bound(&self.max) {
+                Some(end) => Bound::Excluded(end),
+                None => Bound::Unbounded,
+            }
+        }
+    }
+}
+
+/// Computes the lexicographically smallest and largest strings of at most
+/// `max_len` bytes that `aut` accepts, or `None` if it accepts no string.
+///
+/// The search is guided by `Automaton::can_match`, so it is fast when
+/// `can_match` is precise. An automaton that claims it can match from states
+/// where it cannot may make the search explore many dead ends. Searching
+/// stops at `max_len` bytes, where the string found so far is reported as a
+/// prefix. See `Bounds` for details.
+pub fn bounds<A: Automaton>(aut: &A, max_len: usize) -> Option<Bounds> {
+    let start = aut.start();
+    if !aut.can_match(&start) {
+        return None;
+    }
+    let mut min = vec![];
+    let min_is_prefix = smallest(aut, &start, max_len, &mut min)?;
+    let mut max = vec![];
+    let max_is_prefix = largest(aut, &start, max_len, &mut max)?;
+    Some(Bounds {
+        min,
+        min_is_prefix,
+        max,
+        max_is_prefix,
+    })
+}
+
+/// Appends the smallest string accepted from `state` to `out` and returns
+/// whether it was cut off.
+fn smallest<A: Automaton>(
+    aut: &A,
+    state: &A::State,
+    left: usize,
+    out: &mut Vec<u8>,
+) -> Option<bool> {
+    if aut.is_match(state) {
+        return Some(false);
+    }
+    if left == 0 {
+        return Some(true);
+    }
+    for b in 0..=255 {
+        let next = aut.accept(state, b);
+        if aut.can_match(&next) {
+            out.push(b);
+            if let Some(cut) = smallest(aut, &next, left - 1, out) {
+                return Some(cut);
+            }
+            out.pop();
+        }
+    }
+    None
+}
+
+/// Appends the largest string accepted from `state` to `out` and returns
+/// whether it was cut off.
+fn largest<A: Automaton>(
+    aut: &A,
+    state: &A::State,
+    left: usize,
+    out: &mut Vec<u8>,
+) -> Option<bool> {
+    if left == 0 {
+        return Some(true);
+    }
+    for b in (0..=255).rev() {
+        let next = aut.accept(state, b);
+        if aut.can_match(&next) {
+            out.push(b);
+            if let Some(cut) = largest(aut, &next, left - 1, out) {
+                return Some(cut);
+            }
+            out.pop();
+        }
+    }
+    if aut.is_match(state) {
+        Some(false)
+    } else {
+        None
+    }
+}
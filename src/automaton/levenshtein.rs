@@ -0,0 +1,141 @@
+/*!
+Fuzzy matching of keys by edit distance.
+
+`Levenshtein` is an automaton, so it can be passed to `Map::search` or
+`Set::search` like any other, and combined with the other automata of this
+crate, e.g. with `starts_with` for fuzzy prefix queries. `Map::fuzzy_search`
+also reports the distance of each key.
+*/
+use super::Automaton;
+
+/// An automaton that matches the strings within a bounded edit distance of a
+/// query.
+///
+/// Distances count insertions, deletions and substitutions of Unicode
+/// scalar values, so keys are decoded as UTF-8 while they are searched.
+/// Bytes that are not valid UTF-8 count as one character each, which never
+/// equals a character of the query.
+#[derive(Clone, Debug)]
+pub struct Levenshtein {
+    query: Vec<char>,
+    distance: u32,
+}
+
+/// The state of a `Levenshtein` automaton. `None` is the dead state.
+pub type LevenshteinState = Option<Row>;
+
+/// One row of the edit distance table, along with the bytes of a character
+/// that was only partially read.
+#[derive(Clone, Debug)]
+pub struct Row {
+    dists: Vec<u32>,
+    pending: [u8; 4],
+    pending_len: u8,
+}
+
+impl Row {
+    /// Returns the edit distance between the query and the string read so
+    /// far, or `None` if it ends in the middle of a character.
+    pub fn edit_distance(&self) -> Option<u32> {
+        if self.pending_len == 0 {
+            self.dists.last().cloned()
+        } else {
+            None
+        }
+    }
+}
+
+impl Levenshtein {
+    /// Creates an automaton matching the strings within `distance` edits of
+    /// `query`.
+    pub fn new(query: &str, distance: u32) -> Levenshtein {
+        Levenshtein {
+            query: query.chars().collect(),
+            distance,
+        }
+    }
+
+    /// Returns the number of bytes this automaton holds on the heap.
+    ///
+    /// This does not count its states, each of which holds a row of edit
+    /// distances as long as the query.
+    pub fn heap_usage(&self) -> usize {
+        self.query.capacity() * std::mem::size_of::<char>()
+    }
+
+    fn step(&self, dists: &[u32], c: Option<char>) -> Vec<u32> {
+        let mut next = Vec::with_capacity(dists.len());
+        next.push(dists[0] + 1);
+        for (i, &q) in self.query.iter().enumerate() {
+            let cost = if Some(q) == c { 0 } else { 1 };
+            let dist = (dists[i] + cost).min(dists[i + 1] + 1).min(next[i] + 1);
+            next.push(dist);
+        }
+        next
+    }
+}
+
+impl Automaton for Levenshtein {
+    type State = LevenshteinState;
+
+    fn start(&self) -> LevenshteinState {
+        Some(Row {
+            dists: (0..=self.query.len() as u32).collect(),
+            pending: [0; 4],
+            pending_len: 0,
+        })
+    }
+
+    fn is_match(&self, state: &LevenshteinState) -> bool {
+        state
+            .as_ref()
+            .and_then(Row::edit_distance)
+            .is_some_and(|dist| dist <= self.distance)
+    }
+
+    fn can_match(&self, state: &LevenshteinState) -> bool {
+        state.is_some()
+    }
+
+    fn accept(&self, state: &LevenshteinState, byte: u8) -> LevenshteinState {
+        let row = state.as_ref()?;
+        let mut pending = row.pending;
+        let mut pending_len = row.pending_len as usize;
+        pending[pending_len] = byte;
+        pending_len += 1;
+        let c = match utf8_len(pending[0]) {
+            Some(len) if pending_len < len => {
+                return Some(Row {
+                    dists: row.dists.clone(),
+                    pending,
+                    pending_len: pending_len as u8,
+                });
+            }
+            Some(len) => std::str::from_utf8(&pending[..len])
+                .ok()
+                .and_then(|s| s.chars().next()),
+            None => None,
+        };
+        let dists = self.step(&row.dists, c);
+        if dists.iter().all(|&d| d > self.distance) {
+            return None;
+        }
+        Some(Row {
+            dists,
+            pending: [0; 4],
+            pending_len: 0,
+        })
+    }
+}
+
+/// Returns the length of the UTF-8 sequence started by `b`, or `None` if `b`
+/// cannot start a sequence.
+fn utf8_len(b: u8) -> Option<usize> {
+    match b {
+        0x00..=0x7F => Some(1),
+        0xC2..=0xDF => Some(2),
+        0xE0..=0xEF => Some(3),
+        0xF0..=0xF4 => Some(4),
+        _ => None,
+    }
+}
@@ -1,7 +1,21 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
 use crate::fake_arr::Ulen;
 
+pub use self::bounds::{bounds, Bounds};
+pub use self::levenshtein::Levenshtein;
+pub use self::table::TableDfa;
 use self::StartsWithStateInternal::*;
 
+mod bounds;
+pub mod levenshtein;
+#[cfg(feature = "regex-automata")]
+mod regex_dfa;
+mod table;
+
 /// Automaton describes types that behave as a finite automaton.
 ///
 /// All implementors of this trait are represented by *byte based* automata.
@@ -23,6 +37,16 @@ use self::StartsWithStateInternal::*;
 /// some definition of "character"). Therefore, the automaton must have UTF-8
 /// decoding built into it. This can be tricky to implement, so you may find
 /// the [`utf8-ranges`](https://crates.io/crates/utf8-ranges) crate useful.
+///
+/// When the `regex-automata` feature is enabled, this trait is also
+/// implemented for `regex_automata::dfa::dense::DFA` and
+/// `regex_automata::dfa::sparse::DFA`. Searches are anchored at the start of
+/// each key and a key matches only if the DFA accepts it in its entirety.
+///
+/// Searching only reads an automaton through `&self`, and every automaton in
+/// this crate is `Send + Sync`, so one compiled automaton, such as a
+/// `Regex` behind an `Arc`, can be shared by any number of concurrent
+/// searches.
 pub trait Automaton {
     /// The type of the state used in the automaton.
     type State;
@@ -66,6 +90,17 @@ pub trait Automaton {
     /// Return the next state given `state` and an input.
     fn accept(&self, state: &Self::State, byte: u8) -> Self::State;
 
+    /// Returns a prefix that every string matched by this automaton starts
+    /// with.
+    ///
+    /// Searches use this to skip the parts of an fst that cannot contain a
+    /// match. If some matched string does not start with the returned prefix,
+    /// then those matches may never be reached. Returning an empty prefix is
+    /// always correct.
+    fn literal_prefix(&self) -> &[u8] {
+        &[]
+    }
+
     /// Returns an automaton that matches the strings that start with something
     /// this automaton matches.
     fn starts_with(self) -> StartsWith<Self>
@@ -99,7 +134,10 @@ pub trait Automaton {
     where
         Self: Sized,
     {
-        Complement(self)
+        Complement {
+            aut: self,
+            max_len: None,
+        }
     }
 }
 
@@ -125,6 +163,10 @@ impl<'a, T: Automaton> Automaton for &'a T {
     fn accept(&self, state: &Self::State, byte: u8) -> Self::State {
         (*self).accept(state, byte)
     }
+
+    fn literal_prefix(&self) -> &[u8] {
+        (*self).literal_prefix()
+    }
 }
 
 /// An automaton that matches if the input contains a specific subsequence.
@@ -265,6 +307,9 @@ impl<A: Automaton> Automaton for StartsWith<A> {
             }
         })
     }
+    fn literal_prefix(&self) -> &[u8] {
+        self.0.literal_prefix()
+    }
 }
 
 /// An automaton that matches when one of its component automata match.
@@ -327,35 +372,368 @@ impl<A: Automaton, B: Automaton> Automaton for Intersection<A, B> {
     fn accept(&self, state: &IntersectionState<A, B>, byte: u8) -> IntersectionState<A, B> {
         IntersectionState(self.0.accept(&state.0, byte), self.1.accept(&state.1, byte))
     }
+
+    fn literal_prefix(&self) -> &[u8] {
+        // Matches start with both prefixes, so the longer one is implied.
+        let (a, b) = (self.0.literal_prefix(), self.1.literal_prefix());
+        if a.len() >= b.len() {
+            a
+        } else {
+            b
+        }
+    }
 }
 
 /// An automaton that matches exactly when the automaton it wraps does not.
+///
+/// The complement of an automaton matches infinitely many strings, and it
+/// can only rule out a branch of a search when the wrapped automaton reports
+/// that every string in it matches through `will_always_match`. Most
+/// automata, including `Regex`, never report that, so searching with a
+/// complement visits every key that is not a match.
+///
+/// `max_len` restricts the complement to strings of at most a given length,
+/// in bytes. A search then never goes deeper than that length, which makes
+/// scans for "all keys *not* matching a pattern" cheap when the interesting
+/// keys are short, without a separate difference pass over two streams.
 #[derive(Clone, Debug)]
-pub struct Complement<A>(A);
+pub struct Complement<A> {
+    aut: A,
+    max_len: Option<usize>,
+}
+
+impl<A: Automaton> Complement<A> {
+    /// Restricts this automaton to strings of at most `max_len` bytes.
+    pub fn max_len(mut self, max_len: usize) -> Complement<A> {
+        self.max_len = Some(max_len);
+        self
+    }
+}
 
 /// The `Automaton` state for `Complement<A>`.
-pub struct ComplementState<A: Automaton>(A::State);
+pub struct ComplementState<A: Automaton> {
+    state: A::State,
+    len: usize,
+}
 
 impl<A: Automaton> Automaton for Complement<A> {
     type State = ComplementState<A>;
 
     fn start(&self) -> ComplementState<A> {
-        ComplementState(self.0.start())
+        ComplementState {
+            state: self.aut.start(),
+            len: 0,
+        }
     }
 
     fn is_match(&self, state: &ComplementState<A>) -> bool {
-        !self.0.is_match(&state.0)
+        self.max_len.is_none_or(|max| state.len <= max) && !self.aut.is_match(&state.state)
     }
 
     fn can_match(&self, state: &ComplementState<A>) -> bool {
-        !self.0.will_always_match(&state.0)
+        match self.max_len {
+            Some(max) if state.len >= max => self.is_match(state),
+            _ => !self.aut.will_always_match(&state.state),
+        }
     }
 
     fn will_always_match(&self, state: &ComplementState<A>) -> bool {
-        !self.0.can_match(&state.0)
+        // Longer strings never match when the length is capped.
+        self.max_len.is_none() && !self.aut.can_match(&state.state)
     }
 
     fn accept(&self, state: &ComplementState<A>, byte: u8) -> ComplementState<A> {
-        ComplementState(self.0.accept(&state.0, byte))
+        ComplementState {
+            state: self.aut.accept(&state.state, byte),
+            len: state.len.saturating_add(1),
+        }
+    }
+}
+
+/// An automaton that gives up after a fixed number of steps.
+///
+/// Every call to `accept` counts as a step, across all the states of a
+/// search. Once `max_steps` steps were taken, every new state is a dead
+/// state that neither matches nor can lead to a match, so a search ends
+/// soon after. This bounds the work any untrusted automaton can cause
+/// without allocating.
+///
+/// Since the count is shared by all states, a `Limited` automaton should be
+/// used for a single search. `is_exhausted` tells whether the search may
+/// have missed matches because the budget ran out. The count is atomic, so
+/// that `Limited` stays `Sync`, but searches sharing one from several
+/// threads share its budget too.
+#[derive(Debug)]
+pub struct Limited<A> {
+    aut: A,
+    max_steps: u64,
+    steps: AtomicU64,
+    exhausted: AtomicBool,
+}
+
+/// The `Automaton` state for `Limited<A>`.
+///
+/// This is `None` once the step budget is exhausted.
+pub struct LimitedState<A: Automaton>(Option<A::State>);
+
+impl<A: Automaton> Limited<A> {
+    /// Wraps `aut` so that at most `max_steps` steps are taken.
+    pub fn new(aut: A, max_steps: u64) -> Limited<A> {
+        Limited {
+            aut,
+            max_steps,
+            steps: AtomicU64::new(0),
+            exhausted: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the number of steps taken so far.
+    pub fn steps(&self) -> u64 {
+        self.steps.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if a step was refused because the budget ran out.
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted.load(Ordering::Relaxed)
+    }
+}
+
+impl<A: Clone> Clone for Limited<A> {
+    fn clone(&self) -> Limited<A> {
+        Limited {
+            aut: self.aut.clone(),
+            max_steps: self.max_steps,
+            steps: AtomicU64::new(self.steps.load(Ordering::Relaxed)),
+            exhausted: AtomicBool::new(self.exhausted.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl<A: Automaton> Automaton for Limited<A> {
+    type State = LimitedState<A>;
+
+    fn start(&self) -> LimitedState<A> {
+        LimitedState(Some(self.aut.start()))
+    }
+
+    fn is_match(&self, state: &LimitedState<A>) -> bool {
+        state.0.as_ref().is_some_and(|s| self.aut.is_match(s))
+    }
+
+    fn can_match(&self, state: &LimitedState<A>) -> bool {
+        state.0.as_ref().is_some_and(|s| self.aut.can_match(s))
+    }
+
+    fn will_always_match(&self, _state: &LimitedState<A>) -> bool {
+        // More steps may be refused, so nothing is certain to match.
+        false
+    }
+
+    fn accept(&self, state: &LimitedState<A>, byte: u8) -> LimitedState<A> {
+        let max_steps = self.max_steps;
+        let step = self
+            .steps
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |steps| {
+                if steps < max_steps {
+                    Some(steps + 1)
+                } else {
+                    None
+                }
+            });
+        if step.is_err() {
+            self.exhausted.store(true, Ordering::Relaxed);
+            return LimitedState(None);
+        }
+        LimitedState(state.0.as_ref().map(|s| self.aut.accept(s, byte)))
+    }
+
+    fn literal_prefix(&self) -> &[u8] {
+        self.aut.literal_prefix()
+    }
+}
+
+/// An automaton that records which bytes of a key moved the wrapped
+/// automaton forward, for highlighting matches in search results.
+///
+/// A byte is recorded when it changes the state of the wrapped automaton.
+/// For automata that track progress through a query, such as `Subsequence`,
+/// these are exactly the bytes that matched the query. Consecutive bytes are
+/// merged into a single span.
+///
+/// The spans are part of the state, so they are surfaced by streams that
+/// return states, such as `StreamBuilder::with_state`. Since every step
+/// copies the spans of the previous state, this is meant for showing a page
+/// of results rather than for large scans.
+#[derive(Clone, Debug)]
+pub struct Highlight<A>(A);
+
+/// The `Automaton` state for `Highlight<A>`.
+pub struct HighlightState<A: Automaton> {
+    state: A::State,
+    len: usize,
+    spans: Vec<Range<usize>>,
+}
+
+impl<A: Automaton> HighlightState<A> {
+    /// Returns the byte ranges of the key that moved the wrapped automaton
+    /// forward, in increasing order.
+    pub fn spans(&self) -> &[Range<usize>] {
+        &self.spans
+    }
+
+    /// Returns the state of the wrapped automaton.
+    pub fn inner(&self) -> &A::State {
+        &self.state
+    }
+}
+
+impl<A: Automaton> Clone for HighlightState<A>
+where
+    A::State: Clone,
+{
+    fn clone(&self) -> HighlightState<A> {
+        HighlightState {
+            state: self.state.clone(),
+            len: self.len,
+            spans: self.spans.clone(),
+        }
+    }
+}
+
+impl<A: Automaton> Highlight<A> {
+    /// Wraps `aut` so that the bytes that move it forward are recorded.
+    pub fn new(aut: A) -> Highlight<A> {
+        Highlight(aut)
+    }
+}
+
+impl<A: Automaton> Automaton for Highlight<A>
+where
+    A::State: PartialEq,
+{
+    type State = HighlightState<A>;
+
+    fn start(&self) -> HighlightState<A> {
+        HighlightState {
+            state: self.0.start(),
+            len: 0,
+            spans: vec![],
+        }
+    }
+
+    fn is_match(&self, state: &HighlightState<A>) -> bool {
+        self.0.is_match(&state.state)
+    }
+
+    fn can_match(&self, state: &HighlightState<A>) -> bool {
+        self.0.can_match(&state.state)
+    }
+
+    fn will_always_match(&self, state: &HighlightState<A>) -> bool {
+        self.0.will_always_match(&state.state)
+    }
+
+    fn accept(&self, state: &HighlightState<A>, byte: u8) -> HighlightState<A> {
+        let next = self.0.accept(&state.state, byte);
+        let mut spans = state.spans.clone();
+        if next != state.state {
+            match spans.last_mut() {
+                Some(last) if last.end == state.len => last.end += 1,
+                _ => spans.push(state.len..state.len + 1),
+            }
+        }
+        HighlightState {
+            state: next,
+            len: state.len + 1,
+            spans,
+        }
+    }
+
+    fn literal_prefix(&self) -> &[u8] {
+        self.0.literal_prefix()
+    }
+}
+
+/// Builds an automaton from closures, for prototyping matching logic before
+/// writing a type for it.
+///
+/// `start` returns the state a search begins with, `accept` returns the
+/// state after a byte, and `is_match` tells whether a state matches. Since
+/// nothing tells the automaton which states are dead ends, a search visits
+/// every key in its range. `FromFn::can_match` adds a closure that prunes
+/// them.
+pub fn from_fn<S, St, Ac, Im>(start: St, accept: Ac, is_match: Im) -> FromFn<S, St, Ac, Im>
+where
+    S: Clone,
+    St: Fn() -> S,
+    Ac: Fn(&S, u8) -> S,
+    Im: Fn(&S) -> bool,
+{
+    FromFn {
+        start,
+        accept,
+        is_match,
+        can_match: |_| true,
+        state: PhantomData,
+    }
+}
+
+/// An automaton made of closures over states of type `S`. See `from_fn`.
+#[derive(Clone)]
+pub struct FromFn<S, St, Ac, Im, Cm = fn(&S) -> bool> {
+    start: St,
+    accept: Ac,
+    is_match: Im,
+    can_match: Cm,
+    state: PhantomData<fn() -> S>,
+}
+
+impl<S, St, Ac, Im, Cm> FromFn<S, St, Ac, Im, Cm> {
+    /// Prunes the states for which `can_match` returns false, which must
+    /// be states from which no key can match.
+    pub fn can_match<F>(self, can_match: F) -> FromFn<S, St, Ac, Im, F>
+    where
+        F: Fn(&S) -> bool,
+    {
+        FromFn {
+            start: self.start,
+            accept: self.accept,
+            is_match: self.is_match,
+            can_match,
+            state: PhantomData,
+        }
+    }
+}
+
+impl<S, St, Ac, Im, Cm> fmt::Debug for FromFn<S, St, Ac, Im, Cm> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FromFn").finish_non_exhaustive()
+    }
+}
+
+impl<S, St, Ac, Im, Cm> Automaton for FromFn<S, St, Ac, Im, Cm>
+where
+    S: Clone,
+    St: Fn() -> S,
+    Ac: Fn(&S, u8) -> S,
+    Im: Fn(&S) -> bool,
+    Cm: Fn(&S) -> bool,
+{
+    type State = S;
+
+    fn start(&self) -> S {
+        (self.start)()
+    }
+
+    fn is_match(&self, state: &S) -> bool {
+        (self.is_match)(state)
+    }
+
+    fn can_match(&self, state: &S) -> bool {
+        (self.can_match)(state)
+    }
+
+    fn accept(&self, state: &S, byte: u8) -> S {
+        (self.accept)(state, byte)
     }
 }
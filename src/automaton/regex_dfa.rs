@@ -0,0 +1,79 @@
+use regex_automata::dfa::{dense, sparse, Automaton as DfaAutomaton};
+use regex_automata::util::primitives::StateID;
+use regex_automata::util::start;
+use regex_automata::Anchored;
+
+use super::Automaton;
+
+// regex-automata reports matches with a delay of one byte, so whether a key
+// matches in its entirety is decided by feeding the end-of-input transition.
+//
+// The search is always anchored at the start of the key. To get whole-key
+// semantics for patterns with alternations, build the DFA with
+// `MatchKind::All`; with leftmost-first semantics, the DFA may stop as soon
+// as it finds the preferred (possibly shorter) match.
+
+fn dfa_start<D: DfaAutomaton>(dfa: &D) -> Option<StateID> {
+    dfa.start_state(&start::Config::new().anchored(Anchored::Yes))
+        .ok()
+}
+
+fn dfa_is_match<D: DfaAutomaton>(dfa: &D, state: &Option<StateID>) -> bool {
+    state.is_some_and(|id| dfa.is_match_state(dfa.next_eoi_state(id)))
+}
+
+fn dfa_can_match<D: DfaAutomaton>(dfa: &D, state: &Option<StateID>) -> bool {
+    state.is_some_and(|id| !dfa.is_dead_state(id) && !dfa.is_quit_state(id))
+}
+
+fn dfa_accept<D: DfaAutomaton>(dfa: &D, state: &Option<StateID>, byte: u8) -> Option<StateID> {
+    state.map(|id| dfa.next_state(id, byte))
+}
+
+impl<T: AsRef<[u32]>> Automaton for dense::DFA<T> {
+    type State = Option<StateID>;
+
+    #[inline]
+    fn start(&self) -> Option<StateID> {
+        dfa_start(self)
+    }
+
+    #[inline]
+    fn is_match(&self, state: &Option<StateID>) -> bool {
+        dfa_is_match(self, state)
+    }
+
+    #[inline]
+    fn can_match(&self, state: &Option<StateID>) -> bool {
+        dfa_can_match(self, state)
+    }
+
+    #[inline]
+    fn accept(&self, state: &Option<StateID>, byte: u8) -> Option<StateID> {
+        dfa_accept(self, state, byte)
+    }
+}
+
+impl<T: AsRef<[u8]>> Automaton for sparse::DFA<T> {
+    type State = Option<StateID>;
+
+    #[inline]
+    fn start(&self) -> Option<StateID> {
+        dfa_start(self)
+    }
+
+    #[inline]
+    fn is_match(&self, state: &Option<StateID>) -> bool {
+        dfa_is_match(self, state)
+    }
+
+    #[inline]
+    fn can_match(&self, state: &Option<StateID>) -> bool {
+        dfa_can_match(self, state)
+    }
+
+    #[inline]
+    fn accept(&self, state: &Option<StateID>, byte: u8) -> Option<StateID> {
+        dfa_accept(self, state, byte)
+    }
+}
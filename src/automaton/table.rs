@@ -0,0 +1,128 @@
+use super::Automaton;
+
+/// An automaton backed by an explicit transition table.
+///
+/// This makes it possible to plug in a DFA that was compiled elsewhere (for
+/// example by `regex-automata` or by a custom compiler) without implementing
+/// `Automaton` and its state plumbing by hand.
+///
+/// State `0` is the start state. A missing transition (`None`) leads to a dead
+/// state from which no match is possible.
+#[derive(Clone, Debug)]
+pub struct TableDfa {
+    transitions: Vec<[Option<u32>; 256]>,
+    is_match: Vec<bool>,
+    can_match: Vec<bool>,
+    will_always_match: Vec<bool>,
+}
+
+impl TableDfa {
+    /// Creates a new automaton from a transition table and the set of match
+    /// states.
+    ///
+    /// `transitions[s][b]` is the state reached from state `s` on input byte
+    /// `b`.
+    ///
+    /// # Panics
+    ///
+    /// This panics if a transition or a match state refers to a state that
+    /// is not in `transitions`.
+    pub fn new<I>(transitions: Vec<[Option<u32>; 256]>, match_states: I) -> TableDfa
+    where
+        I: IntoIterator<Item = u32>,
+    {
+        let len = transitions.len();
+        let mut is_match = vec![false; len];
+        for s in match_states {
+            assert!((s as usize) < len, "match state {} does not exist", s);
+            is_match[s as usize] = true;
+        }
+        for (s, row) in transitions.iter().enumerate() {
+            for &next in row.iter().flatten() {
+                assert!(
+                    (next as usize) < len,
+                    "transition from state {} to nonexistent state {}",
+                    s,
+                    next
+                );
+            }
+        }
+        // A state can match if it is a match state or if it can reach one.
+        // A state will always match if it is a match state and every byte
+        // leads to a state that will always match. Both are fixed points that
+        // we find by iterating until nothing changes.
+        let mut can_match = is_match.clone();
+        let mut will_always_match = is_match.clone();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (s, row) in transitions.iter().enumerate() {
+                if !can_match[s] && row.iter().flatten().any(|&t| can_match[t as usize]) {
+                    can_match[s] = true;
+                    changed = true;
+                }
+                if will_always_match[s]
+                    && !row
+                        .iter()
+                        .all(|t| t.is_some_and(|t| will_always_match[t as usize]))
+                {
+                    will_always_match[s] = false;
+                    changed = true;
+                }
+            }
+        }
+        TableDfa {
+            transitions,
+            is_match,
+            can_match,
+            will_always_match,
+        }
+    }
+
+    /// Returns the number of states in this automaton.
+    pub fn num_states(&self) -> usize {
+        self.transitions.len()
+    }
+
+    /// Returns the number of bytes this automaton holds on the heap, which
+    /// is about two kilobytes per state.
+    pub fn heap_usage(&self) -> usize {
+        self.transitions.capacity() * std::mem::size_of::<[Option<u32>; 256]>()
+            + self.is_match.capacity()
+            + self.can_match.capacity()
+            + self.will_always_match.capacity()
+    }
+}
+
+impl Automaton for TableDfa {
+    type State = Option<u32>;
+
+    #[inline]
+    fn start(&self) -> Option<u32> {
+        if self.transitions.is_empty() {
+            None
+        } else {
+            Some(0)
+        }
+    }
+
+    #[inline]
+    fn is_match(&self, state: &Option<u32>) -> bool {
+        state.is_some_and(|s| self.is_match[s as usize])
+    }
+
+    #[inline]
+    fn can_match(&self, state: &Option<u32>) -> bool {
+        state.is_some_and(|s| self.can_match[s as usize])
+    }
+
+    #[inline]
+    fn will_always_match(&self, state: &Option<u32>) -> bool {
+        state.is_some_and(|s| self.will_always_match[s as usize])
+    }
+
+    #[inline]
+    fn accept(&self, state: &Option<u32>, byte: u8) -> Option<u32> {
+        state.and_then(|s| self.transitions[s as usize][byte as usize])
+    }
+}
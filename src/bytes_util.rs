@@ -0,0 +1,38 @@
+/*!
+Helpers for computing the keys right after a key or a prefix, in the byte
+order of fst keys.
+
+These turn inclusive bounds into exclusive ones and prefixes into ranges,
+which is what `StreamBuilder::ge`, `lt` and friends take. Both are easy to
+get wrong around keys ending in `0xFF` bytes and around the empty key.
+*/
+
+/// Returns the smallest key that is greater than `key`.
+///
+/// That is `key` followed by a `0x00` byte, so it always exists. The
+/// successor of the empty key is `[0x00]`.
+///
+/// This turns an inclusive bound into an exclusive one and back:
+/// `ge(key_successor(k))` is the same as `gt(k)`, and
+/// `lt(key_successor(k))` is the same as `le(k)`.
+pub fn key_successor(key: &[u8]) -> Vec<u8> {
+    let mut successor = Vec::with_capacity(key.len() + 1);
+    successor.extend_from_slice(key);
+    successor.push(0x00);
+    successor
+}
+
+/// Returns the smallest key greater than every key that starts with
+/// `prefix`, or `None` if there is no such key.
+///
+/// The keys that start with `prefix` are then exactly those that are at
+/// least `prefix` and less than the returned key. Trailing `0xFF` bytes are
+/// dropped before the last byte is incremented, so the upper bound of
+/// `b"a\xFF"` is `b"b"`. There is none when `prefix` is empty or made only
+/// of `0xFF` bytes, since every key after `prefix` starts with it.
+pub fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let i = prefix.iter().rposition(|&b| b != 0xFF)?;
+    let mut end = prefix[..=i].to_vec();
+    end[i] += 1;
+    Some(end)
+}
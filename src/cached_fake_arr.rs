@@ -0,0 +1,157 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::Mutex;
+
+use crate::fake_arr::{FakeArr, Ulen};
+
+/// A `FakeArr` that keeps the most recently used blocks of another one in
+/// memory.
+///
+/// Reads are split into fixed-size blocks, and only the blocks that are not
+/// cached are read from the inner array, one whole block at a time. Every
+/// search starts from the root node, so queries against a remote backend
+/// read the same region over and over; caching it saves most requests.
+///
+/// By default, the cache holds 256 blocks of 4 KiB.
+#[derive(Debug)]
+pub struct CachedFakeArr<A> {
+    inner: A,
+    block_size: Ulen,
+    capacity: usize,
+    cache: Mutex<Lru>,
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    // The cached blocks by index, each with the time it was last used.
+    blocks: HashMap<Ulen, (Vec<u8>, u64)>,
+    // The indexes of the cached blocks by the time they were last used, so
+    // the least recently used one comes first.
+    order: BTreeMap<u64, Ulen>,
+    clock: u64,
+}
+
+impl Lru {
+    fn get(&mut self, index: Ulen) -> Option<&[u8]> {
+        let clock = &mut self.clock;
+        let order = &mut self.order;
+        self.blocks.get_mut(&index).map(|(block, used)| {
+            order.remove(used);
+            *clock += 1;
+            *used = *clock;
+            order.insert(*used, index);
+            &block[..]
+        })
+    }
+
+    fn insert(&mut self, index: Ulen, block: Vec<u8>, capacity: usize) {
+        if capacity == 0 {
+            return;
+        }
+        self.clock += 1;
+        if let Some((_, used)) = self.blocks.insert(index, (block, self.clock)) {
+            self.order.remove(&used);
+        }
+        self.order.insert(self.clock, index);
+        while self.blocks.len() > capacity {
+            let (&used, &oldest) = self.order.iter().next().unwrap();
+            self.order.remove(&used);
+            self.blocks.remove(&oldest);
+        }
+    }
+}
+
+impl<A: FakeArr> CachedFakeArr<A> {
+    /// Caches reads of `inner` with the default block size and capacity.
+    pub fn new(inner: A) -> CachedFakeArr<A> {
+        CachedFakeArr {
+            inner,
+            block_size: 4096,
+            capacity: 256,
+            cache: Mutex::new(Lru::default()),
+        }
+    }
+
+    /// Sets the number of bytes in a block, which is also the least number
+    /// of bytes read from the inner array at once.
+    ///
+    /// This empties the cache.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is zero.
+    pub fn block_size(mut self, bytes: Ulen) -> CachedFakeArr<A> {
+        assert!(bytes > 0, "the block size must not be zero");
+        self.block_size = bytes;
+        self.clear();
+        self
+    }
+
+    /// Sets the number of blocks the cache holds. With a capacity of zero,
+    /// nothing is cached.
+    pub fn capacity(mut self, blocks: usize) -> CachedFakeArr<A> {
+        self.capacity = blocks;
+        self.clear();
+        self
+    }
+
+    /// Drops all cached blocks.
+    pub fn clear(&self) {
+        *self.lock() = Lru::default();
+    }
+
+    /// Returns the array this reads from.
+    pub fn get_ref(&self) -> &A {
+        &self.inner
+    }
+
+    /// Returns the array this reads from.
+    pub fn into_inner(self) -> A {
+        self.inner
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Lru> {
+        self.cache.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl<A: FakeArr> FakeArr for CachedFakeArr<A> {
+    fn len(&self) -> Ulen {
+        self.inner.len()
+    }
+
+    fn read_into(&self, offset: Ulen, buf: &mut [u8]) -> io::Result<()> {
+        let end = offset + buf.len() as Ulen;
+        if end > self.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "read past the end of the array",
+            ));
+        }
+        let mut pos = offset;
+        while pos < end {
+            let index = pos / self.block_size;
+            let start = index * self.block_size;
+            let from = (pos - start) as usize;
+            let n = (self.block_size - (pos - start)).min(end - pos) as usize;
+            let to = &mut buf[(pos - offset) as usize..][..n];
+            if let Some(block) = self.lock().get(index) {
+                to.copy_from_slice(&block[from..from + n]);
+                pos += n as Ulen;
+                continue;
+            }
+            // The lock is not held while reading, so that a slow read does
+            // not hold up reads of cached blocks.
+            let mut block = vec![0; self.block_size.min(self.len() - start) as usize];
+            self.inner.read_into(start, &mut block)?;
+            to.copy_from_slice(&block[from..from + n]);
+            self.lock().insert(index, block, self.capacity);
+            pos += n as Ulen;
+        }
+        Ok(())
+    }
+
+    fn as_dyn(&self) -> &dyn FakeArr {
+        self
+    }
+}
@@ -0,0 +1,153 @@
+/*!
+Deterministic corpora of fst bytes for fuzzing and testing.
+
+Random keys rarely produce the shapes where decoding goes wrong: chains as
+long as a key, nodes with a transition for every byte, outputs that need all
+eight bytes, or transitions that point far enough back that their addresses
+need more bytes. `generate` builds fsts that have each of these shapes, plus
+a few random ones, and returns their bytes. The same seed always yields the
+same bytes, so a corpus can be regenerated instead of checked in.
+
+The entries are meant to seed fuzzers of code that reads fsts, and to test
+custom `FakeArr` backends, for example by serving each entry through the
+backend and checking that streaming it yields `keys` with their values.
+*/
+use std::collections::BTreeMap;
+
+use crate::raw::Builder;
+
+/// An fst built by `generate`, along with what it holds.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CorpusEntry {
+    /// A short name for the shape of the fst, such as `"deep_chain"`.
+    pub name: &'static str,
+    /// The key-value pairs in the fst, in lexicographic order.
+    pub keys: Vec<(Vec<u8>, u64)>,
+    /// The bytes of the fst.
+    pub bytes: Vec<u8>,
+}
+
+/// Builds a corpus of structurally interesting fsts from `seed`.
+///
+/// Every entry has a distinct name. The shapes are the same for every seed,
+/// while the keys and values of the random parts vary with it.
+pub fn generate(seed: u64) -> Vec<CorpusEntry> {
+    let mut rng = XorShift::new(seed);
+    let mut corpus = vec![];
+    let mut add = |name, keys: BTreeMap<Vec<u8>, u64>, new: fn() -> Builder<Vec<u8>>| {
+        let mut builder = new();
+        for (k, &v) in &keys {
+            // Keys come out of a map, so they are sorted and unique.
+            builder.insert(k, v).unwrap();
+        }
+        corpus.push(CorpusEntry {
+            name,
+            keys: keys.into_iter().collect(),
+            bytes: builder.into_inner().unwrap(),
+        });
+    };
+    let plain: fn() -> Builder<Vec<u8>> = Builder::memory;
+
+    add("empty", BTreeMap::new(), plain);
+    add("empty_key", keys(vec![(vec![], rng.next())]), plain);
+
+    // A single key compiles to one chain of nodes, as deep as it is long.
+    let chain: Vec<u8> = (0..4096).map(|_| rng.byte()).collect();
+    add("deep_chain", keys(vec![(chain.clone(), rng.next())]), plain);
+    let forks = (0..=255u8).map(|b| {
+        let mut k = chain.clone();
+        k.push(b);
+        (k, rng.next())
+    });
+    add("deep_chain_wide_end", forks.collect(), plain);
+
+    // Nodes with a transition for every byte, at the root and below it.
+    let wide = (0..=255u8).map(|b| (vec![b], rng.next()));
+    add("wide_root", wide.collect(), plain);
+    let wide = (0..=255u8).flat_map(|a| (0..=255u8).map(move |b| vec![a, b]));
+    add("wide_two_levels", wide.map(|k| (k, 0)).collect(), plain);
+
+    // Outputs that need every byte, on finals and on transitions.
+    let max = vec![
+        (b"a".to_vec(), u64::MAX),
+        (b"ab".to_vec(), u64::MAX),
+        (b"abc".to_vec(), u64::MAX - 1),
+        (b"b".to_vec(), 0),
+        (b"ba".to_vec(), u64::MAX),
+    ];
+    add("max_outputs", keys(max), plain);
+
+    // A suffix that is compiled first and shared by the last key, with
+    // enough filler in between that the transition to it needs addresses
+    // of two and then three bytes. The filler is made of a few wide nodes
+    // with large outputs, so that the builder still remembers the suffix
+    // when it reaches the last key.
+    for &(name, wide) in &[("far_address_2", 1), ("far_address_3", 64)] {
+        let mut ks = BTreeMap::new();
+        ks.insert(b"a\xFFshared-suffix".to_vec(), 1);
+        for a in 0..wide {
+            for b in 0..=255u8 {
+                ks.insert(vec![b'b', a, b], rng.next());
+            }
+        }
+        ks.insert(b"c\xFFshared-suffix".to_vec(), 1);
+        add(name, ks, plain);
+    }
+
+    // Random keys, written with each of the optional footer entries.
+    let random = random_keys(&mut rng, 1000);
+    add("random", random.clone(), plain);
+    add("random_dictionary", random.clone(), || {
+        Builder::new_dictionary_encoded(vec![]).unwrap()
+    });
+    add("random_counts", random, || {
+        let mut builder = Builder::memory();
+        builder.with_counts(true);
+        builder
+    });
+    corpus
+}
+
+fn keys(pairs: Vec<(Vec<u8>, u64)>) -> BTreeMap<Vec<u8>, u64> {
+    pairs.into_iter().collect()
+}
+
+fn random_keys(rng: &mut XorShift, n: usize) -> BTreeMap<Vec<u8>, u64> {
+    let mut ks = BTreeMap::new();
+    while ks.len() < n {
+        let len = rng.below(16) as usize;
+        // A small alphabet makes keys share prefixes and suffixes.
+        let k = (0..len).map(|_| b'a' + rng.below(4) as u8).collect();
+        ks.insert(k, rng.below(16));
+    }
+    ks
+}
+
+/// A xorshift64* generator, so the corpus does not depend on the
+/// algorithm of some version of a random number crate.
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> XorShift {
+        // The state must never be zero.
+        match seed ^ 0x9E37_79B9_7F4A_7C15 {
+            0 => XorShift(0x9E37_79B9_7F4A_7C15),
+            state => XorShift(state),
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    fn byte(&mut self) -> u8 {
+        self.next() as u8
+    }
+}
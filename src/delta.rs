@@ -0,0 +1,382 @@
+/*!
+Append-only delta files for small updates to a map.
+
+Rebuilding an fst for every handful of changes is wasteful. A delta file
+records a sorted batch of inserts and removals instead, and a `LayeredMap`
+reads a base map through any number of delta files, the newest one winning.
+Deltas are meant to be folded into a new base from time to time, by
+streaming the `LayeredMap` into a `MapBuilder`.
+
+A delta file is a sequence of entries followed by a footer:
+
+```text
+[entry]* [number of entries: u64] [length of the entries: u64] [magic: u64]
+```
+
+Each entry is an operation byte (`0` for an insert, `1` for a removal), the
+length of the key as a little endian `u32`, the key, and for inserts only the
+value as a little endian `u64`. Keys are strictly increasing. The footer is
+written last, so a delta file that was not finished is rejected when read.
+*/
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::io;
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use crate::error::Result;
+use crate::map::{self, Map};
+use crate::raw::Error;
+use crate::stream::Streamer;
+use crate::FakeArr;
+
+/// The last eight bytes of every delta file.
+const MAGIC: u64 = 0x6174_6c65_6474_7366;
+
+/// The number of bytes in the footer of a delta file.
+const FOOTER_LEN: usize = 24;
+
+const OP_INSERT: u8 = 0;
+const OP_REMOVE: u8 = 1;
+
+/// A change recorded for a key in a delta file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Change {
+    /// The key is inserted, or its value replaced, with the given value.
+    Insert(u64),
+    /// The key is removed.
+    Remove,
+}
+
+/// A builder for delta files.
+///
+/// Like `MapBuilder`, keys must be given in lexicographic order, each at
+/// most once, and the delta is streamed to any `io::Write` as it is built.
+pub struct DeltaBuilder<W> {
+    wtr: W,
+    last: Option<Vec<u8>>,
+    len: u64,
+    bytes_written: u64,
+}
+
+impl DeltaBuilder<Vec<u8>> {
+    /// Create a builder that builds a delta file in memory.
+    pub fn memory() -> Self {
+        DeltaBuilder::new(vec![])
+    }
+}
+
+impl<W: io::Write> DeltaBuilder<W> {
+    /// Create a builder that writes a delta file to `wtr`.
+    pub fn new(wtr: W) -> DeltaBuilder<W> {
+        DeltaBuilder {
+            wtr,
+            last: None,
+            len: 0,
+            bytes_written: 0,
+        }
+    }
+
+    /// Records that `key` is inserted with the value `val`.
+    ///
+    /// If `key` is not greater than the previous key, then an error is
+    /// returned. Keys longer than `u32::MAX` bytes are rejected with
+    /// `raw::Error::KeyTooLong`.
+    pub fn insert<K: AsRef<[u8]>>(&mut self, key: K, val: u64) -> Result<()> {
+        self.push(key.as_ref(), Change::Insert(val))
+    }
+
+    /// Records that `key` is removed.
+    ///
+    /// If `key` is not greater than the previous key, then an error is
+    /// returned. Keys longer than `u32::MAX` bytes are rejected with
+    /// `raw::Error::KeyTooLong`.
+    pub fn remove<K: AsRef<[u8]>>(&mut self, key: K) -> Result<()> {
+        self.push(key.as_ref(), Change::Remove)
+    }
+
+    /// Finishes the delta file by writing its footer, and flushes the
+    /// underlying writer.
+    pub fn finish(self) -> Result<()> {
+        self.into_inner()?;
+        Ok(())
+    }
+
+    /// Just like `finish`, except it returns the underlying writer.
+    pub fn into_inner(mut self) -> Result<W> {
+        self.wtr.write_u64::<LittleEndian>(self.len)?;
+        self.wtr.write_u64::<LittleEndian>(self.bytes_written)?;
+        self.wtr.write_u64::<LittleEndian>(MAGIC)?;
+        self.wtr.flush()?;
+        Ok(self.wtr)
+    }
+
+    /// Returns the number of entries written so far.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns true if and only if no entry has been written.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn push(&mut self, key: &[u8], change: Change) -> Result<()> {
+        if let Some(ref last) = self.last {
+            if key == &last[..] {
+                return Err(Error::DuplicateKey { got: key.to_vec() }.into());
+            }
+            if key < &last[..] {
+                return Err(Error::OutOfOrder {
+                    previous: last.clone(),
+                    got: key.to_vec(),
+                }
+                .into());
+            }
+        }
+        if key.len() > u32::MAX as usize {
+            return Err(Error::KeyTooLong {
+                len: key.len() as u64,
+            }
+            .into());
+        }
+        let op = match change {
+            Change::Insert(_) => OP_INSERT,
+            Change::Remove => OP_REMOVE,
+        };
+        self.wtr.write_u8(op)?;
+        self.wtr.write_u32::<LittleEndian>(key.len() as u32)?;
+        self.wtr.write_all(key)?;
+        self.bytes_written += 5 + key.len() as u64;
+        if let Change::Insert(val) = change {
+            self.wtr.write_u64::<LittleEndian>(val)?;
+            self.bytes_written += 8;
+        }
+        self.len += 1;
+        self.last = Some(key.to_vec());
+        Ok(())
+    }
+}
+
+/// A delta file read from memory.
+///
+/// The entries are parsed once, when the reader is created, so lookups are
+/// a binary search over them.
+#[derive(Clone, Debug)]
+pub struct DeltaReader<'a> {
+    entries: Vec<(&'a [u8], Change)>,
+}
+
+impl<'a> DeltaReader<'a> {
+    /// Reads the delta file in `bytes`.
+    ///
+    /// If `bytes` is not a complete delta file, then an error is returned.
+    pub fn new(bytes: &'a [u8]) -> Result<DeltaReader<'a>> {
+        if bytes.len() < FOOTER_LEN {
+            return Err(Error::Format.into());
+        }
+        let footer = &bytes[bytes.len() - FOOTER_LEN..];
+        let len = LittleEndian::read_u64(&footer[0..8]);
+        let entries_len = LittleEndian::read_u64(&footer[8..16]);
+        if LittleEndian::read_u64(&footer[16..24]) != MAGIC
+            || entries_len != (bytes.len() - FOOTER_LEN) as u64
+        {
+            return Err(Error::Format.into());
+        }
+        let mut rest = &bytes[..bytes.len() - FOOTER_LEN];
+        let mut entries: Vec<(&'a [u8], Change)> = vec![];
+        while !rest.is_empty() {
+            if rest.len() < 5 {
+                return Err(Error::Format.into());
+            }
+            let op = rest[0];
+            let key_len = LittleEndian::read_u32(&rest[1..5]) as usize;
+            rest = &rest[5..];
+            if rest.len() < key_len {
+                return Err(Error::Format.into());
+            }
+            let (key, after) = rest.split_at(key_len);
+            rest = after;
+            let change = match op {
+                OP_INSERT if rest.len() >= 8 => {
+                    let val = LittleEndian::read_u64(&rest[..8]);
+                    rest = &rest[8..];
+                    Change::Insert(val)
+                }
+                OP_REMOVE => Change::Remove,
+                _ => return Err(Error::Format.into()),
+            };
+            if entries.last().is_some_and(|&(last, _)| last >= key) {
+                return Err(Error::Format.into());
+            }
+            entries.push((key, change));
+        }
+        if entries.len() as u64 != len {
+            return Err(Error::Format.into());
+        }
+        Ok(DeltaReader { entries })
+    }
+
+    /// Returns the change recorded for `key`, if any.
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Option<Change> {
+        let key = key.as_ref();
+        self.entries
+            .binary_search_by(|&(k, _)| k.cmp(key))
+            .ok()
+            .map(|i| self.entries[i].1)
+    }
+
+    /// Returns the number of entries in the delta file.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if and only if the delta file has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the entries of the delta file, in lexicographic order of
+    /// their keys.
+    pub fn iter(&self) -> impl Iterator<Item = (&'a [u8], Change)> + '_ {
+        self.entries.iter().cloned()
+    }
+}
+
+/// A read only view of a map with delta files applied on top of it.
+///
+/// Deltas are added from oldest to newest. The change recorded for a key in
+/// the newest delta that has one wins, and keys that no delta mentions are
+/// read from the base map.
+pub struct LayeredMap<'m, Data: FakeArr> {
+    base: &'m Map<Data>,
+    deltas: Vec<DeltaReader<'m>>,
+}
+
+impl<'m, Data: FakeArr> LayeredMap<'m, Data> {
+    /// Creates a view of `base` with no deltas.
+    pub fn new(base: &'m Map<Data>) -> LayeredMap<'m, Data> {
+        LayeredMap {
+            base,
+            deltas: vec![],
+        }
+    }
+
+    /// Adds a delta on top of the ones already in this view.
+    pub fn push(&mut self, delta: DeltaReader<'m>) {
+        self.deltas.push(delta);
+    }
+
+    /// Like `push`, but consumes and returns the view.
+    pub fn with_delta(mut self, delta: DeltaReader<'m>) -> Self {
+        self.push(delta);
+        self
+    }
+
+    /// Returns the number of deltas in this view.
+    pub fn num_deltas(&self) -> usize {
+        self.deltas.len()
+    }
+
+    /// Retrieves the value associated with a key.
+    ///
+    /// If the key was removed by a delta, or is in neither a delta nor the
+    /// base map, then `None` is returned.
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Option<u64> {
+        let key = key.as_ref();
+        for delta in self.deltas.iter().rev() {
+            match delta.get(key) {
+                Some(Change::Insert(val)) => return Some(val),
+                Some(Change::Remove) => return None,
+                None => {}
+            }
+        }
+        self.base.get(key)
+    }
+
+    /// Tests the membership of a single key.
+    pub fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Returns a lexicographically ordered stream of every key in this view,
+    /// along with its value.
+    ///
+    /// The changes of all deltas are collapsed into memory when the stream
+    /// is created, while the base map is streamed.
+    pub fn stream(&self) -> LayeredStream<'m> {
+        let mut overlay = BTreeMap::new();
+        for delta in &self.deltas {
+            for (key, change) in delta.iter() {
+                overlay.insert(key, change);
+            }
+        }
+        LayeredStream {
+            base: self.base.stream(),
+            base_next: None,
+            base_done: false,
+            overlay: overlay
+                .into_iter()
+                .collect::<Vec<_>>()
+                .into_iter()
+                .peekable(),
+            key: vec![],
+        }
+    }
+}
+
+/// A lexicographically ordered stream of key-value pairs from a
+/// `LayeredMap`.
+///
+/// The `'m` lifetime parameter refers to the lifetime of the base map and of
+/// the deltas.
+pub struct LayeredStream<'m> {
+    base: map::Stream<'m>,
+    base_next: Option<(Vec<u8>, u64)>,
+    base_done: bool,
+    overlay: std::iter::Peekable<std::vec::IntoIter<(&'m [u8], Change)>>,
+    key: Vec<u8>,
+}
+
+impl<'m> LayeredStream<'m> {
+    fn fill_base(&mut self) {
+        if self.base_next.is_none() && !self.base_done {
+            match self.base.next() {
+                Some((key, val)) => self.base_next = Some((key.to_vec(), val)),
+                None => self.base_done = true,
+            }
+        }
+    }
+}
+
+impl<'a, 'm> Streamer<'a> for LayeredStream<'m> {
+    type Item = (&'a [u8], u64);
+
+    fn next(&'a mut self) -> Option<Self::Item> {
+        loop {
+            self.fill_base();
+            let order = match (&self.base_next, self.overlay.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((base_key, _)), Some(&(key, _))) => base_key[..].cmp(key),
+            };
+            if order == Ordering::Equal {
+                // The delta shadows the base map.
+                self.base_next = None;
+            }
+            let from_base = order == Ordering::Less;
+            if from_base {
+                let (key, val) = self.base_next.take().unwrap();
+                self.key = key;
+                return Some((&self.key, val));
+            }
+            let (key, change) = self.overlay.next().unwrap();
+            if let Change::Insert(val) = change {
+                self.key.clear();
+                self.key.extend_from_slice(key);
+                return Some((&self.key, val));
+            }
+        }
+    }
+}
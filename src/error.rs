@@ -2,11 +2,17 @@ use crate::raw;
 use std::error;
 use std::fmt;
 use std::io;
+use std::path::{Path, PathBuf};
 
 /// A `Result` type alias for this crate's `Error` type.
 pub type Result<T> = ::std::result::Result<T, Error>;
 
 /// An error that encapsulates all possible errors in this crate.
+///
+/// Every error is `Send`, `Sync` and `'static`, so it composes with error
+/// handling libraries like `anyhow`. The `Fst` and `Io` variants are
+/// transparent: they display as the error they wrap and report its source.
+/// `IoContext` displays the context and reports the IO error as its source.
 #[derive(Debug)]
 pub enum Error {
     /// An error that occurred while reading or writing a finite state
@@ -14,6 +20,95 @@ pub enum Error {
     Fst(raw::Error),
     /// An IO error that occurred while writing a finite state transducer.
     Io(io::Error),
+    /// An IO error along with what was being done when it occurred.
+    IoContext(IoContext),
+}
+
+/// An IO error along with the operation, and optionally the path and offset,
+/// that it occurred at.
+#[derive(Debug)]
+pub struct IoContext {
+    operation: String,
+    path: Option<PathBuf>,
+    offset: Option<u64>,
+    err: io::Error,
+}
+
+impl IoContext {
+    /// Wraps an IO error that occurred while performing `operation`, like
+    /// `"writing footer"`.
+    pub fn new<S: Into<String>>(operation: S, err: io::Error) -> IoContext {
+        IoContext {
+            operation: operation.into(),
+            path: None,
+            offset: None,
+            err,
+        }
+    }
+
+    /// Records the path of the file that was being read or written.
+    pub fn path<P: Into<PathBuf>>(mut self, path: P) -> IoContext {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Records the byte offset at which the error occurred.
+    pub fn offset(mut self, offset: u64) -> IoContext {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Returns the operation that failed.
+    pub fn operation(&self) -> &str {
+        &self.operation
+    }
+
+    /// Returns the path of the file that was being read or written, if
+    /// known.
+    pub fn file_path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Returns the byte offset at which the error occurred, if known.
+    pub fn byte_offset(&self) -> Option<u64> {
+        self.offset
+    }
+
+    /// Returns the underlying IO error.
+    pub fn io_error(&self) -> &io::Error {
+        &self.err
+    }
+
+    /// Unwraps the underlying IO error.
+    pub fn into_io_error(self) -> io::Error {
+        self.err
+    }
+}
+
+impl fmt::Display for IoContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "IO error while {}", self.operation)?;
+        if let Some(ref path) = self.path {
+            write!(f, " {}", path.display())?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {}", offset)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for IoContext {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.err)
+    }
+}
+
+impl From<IoContext> for Error {
+    #[inline]
+    fn from(err: IoContext) -> Error {
+        Error::IoContext(err)
+    }
 }
 
 impl From<io::Error> for Error {
@@ -36,16 +131,18 @@ impl fmt::Display for Error {
         match *self {
             Fst(ref err) => err.fmt(f),
             Io(ref err) => err.fmt(f),
+            IoContext(ref err) => err.fmt(f),
         }
     }
 }
 
 impl error::Error for Error {
-    fn cause(&self) -> Option<&dyn error::Error> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         use self::Error::*;
         match *self {
-            Fst(ref err) => Some(err),
-            Io(ref err) => Some(err),
+            Fst(ref err) => err.source(),
+            Io(ref err) => err.source(),
+            IoContext(ref err) => err.source(),
         }
     }
 }
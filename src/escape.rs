@@ -0,0 +1,117 @@
+/*!
+Canonical escaping for keys made of several components.
+
+Composite keys are commonly built by joining components with a reserved
+separator byte, usually `0x00`. That breaks as soon as a component contains
+the separator itself. This module escapes components so that they may hold
+any byte:
+
+* A `0x00` byte inside a component is written as `0x00 0xFF`.
+* Components are separated by `0x00 0x01`.
+
+Escaping preserves order: joined keys sort exactly like the lists of their
+components, compared component by component. So range queries over the
+leading components of composite keys work as expected, and `prefix` builds
+the bound for them.
+
+`MapBuilder::insert_components` and `Map::get_components` apply this scheme
+when building and reading maps.
+*/
+use crate::error::Result;
+use crate::raw::Error;
+
+/// The reserved byte that separates components.
+pub const SEPARATOR: u8 = 0x00;
+
+/// The byte that follows `SEPARATOR` to separate components.
+pub(crate) const NEXT: u8 = 0x01;
+
+/// The byte that follows `SEPARATOR` to stand for a literal `SEPARATOR`.
+pub(crate) const LITERAL: u8 = 0xFF;
+
+/// Appends the escaped form of `component` to `out`.
+pub fn escape_into(component: &[u8], out: &mut Vec<u8>) {
+    for &b in component {
+        out.push(b);
+        if b == SEPARATOR {
+            out.push(LITERAL);
+        }
+    }
+}
+
+/// Returns the escaped form of `component`.
+pub fn escape(component: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(component.len());
+    escape_into(component, &mut out);
+    out
+}
+
+/// Reverses `escape`.
+///
+/// If `escaped` contains a separator or an invalid escape, then an error is
+/// returned.
+pub fn unescape(escaped: &[u8]) -> Result<Vec<u8>> {
+    let mut components = split(escaped)?;
+    if components.len() != 1 {
+        return Err(Error::BadEscape {
+            got: escaped.to_vec(),
+        }
+        .into());
+    }
+    Ok(components.pop().unwrap())
+}
+
+/// Escapes every component and joins them into a single key.
+pub fn join<I, C>(components: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = C>,
+    C: AsRef<[u8]>,
+{
+    let mut key = vec![];
+    for (i, component) in components.into_iter().enumerate() {
+        if i > 0 {
+            key.push(SEPARATOR);
+            key.push(NEXT);
+        }
+        escape_into(component.as_ref(), &mut key);
+    }
+    key
+}
+
+/// Returns the key that every key starting with `components` starts with.
+///
+/// For example, `prefix(&["a"])` is a prefix of `join(&["a", "b"])` but not
+/// of `join(&["ab"])`, so it can be given to a range query or to
+/// `automaton::StartsWith` to find the keys whose first component is `a`.
+pub fn prefix<I, C>(components: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = C>,
+    C: AsRef<[u8]>,
+{
+    let mut key = join(components);
+    key.push(SEPARATOR);
+    key.push(NEXT);
+    key
+}
+
+/// Splits a key made by `join` into its unescaped components.
+///
+/// If the key contains an invalid escape, then an error is returned.
+pub fn split(key: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut components = vec![];
+    let mut component = vec![];
+    let mut bytes = key.iter();
+    while let Some(&b) = bytes.next() {
+        if b != SEPARATOR {
+            component.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(&LITERAL) => component.push(SEPARATOR),
+            Some(&NEXT) => components.push(std::mem::take(&mut component)),
+            _ => return Err(Error::BadEscape { got: key.to_vec() }.into()),
+        }
+    }
+    components.push(component);
+    Ok(components)
+}
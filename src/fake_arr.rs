@@ -1,10 +1,13 @@
+use std::ops::{Index, Range, RangeFrom, RangeFull, RangeToInclusive};
 use std::{
     fmt::Debug,
     ops::{Bound, RangeBounds},
 };
 use std::{
-    io::Read,
-    ops::{Index, Range, RangeFrom, RangeFull, RangeToInclusive},
+    fs::File,
+    io::{BufReader, Read, Seek},
+    path::Path,
+    sync::Mutex,
 };
 
 pub type Ulen = u64; // maybe changeable? shouldn't be Ulen since then we couldn't use an index > 2GB in webassembly
@@ -50,11 +53,17 @@ pub trait FakeArr: Debug {
         self.slice((..).into())
     }
     fn get_byte(&self, offset: Ulen) -> u8 {
-        self.slice((offset..offset + 1).into()).actually_read_it()[0]
+        expect_read(self.try_get_byte(offset))
+    }
+    /// Reads the byte at `offset`, returning the error of a read that fails.
+    fn try_get_byte(&self, offset: Ulen) -> std::io::Result<u8> {
+        let mut buf = [0];
+        self.read_into(offset, &mut buf)?;
+        Ok(buf[0])
     }
     fn actually_read_it(&self) -> Vec<u8> {
         let mut v = vec![0; self.len() as usize];
-        self.read_into(0, &mut v).unwrap();
+        expect_read(self.read_into(0, &mut v));
         v
     }
     fn to_vec(&self) -> Vec<u8> {
@@ -63,8 +72,40 @@ pub trait FakeArr: Debug {
     fn is_empty(&self) -> bool {
         self.len() == 0
     }
+    /// Returns the bytes of this array if they are all in memory, so that
+    /// hot paths can read them without going through `read_into`.
+    fn as_contiguous(&self) -> Option<&[u8]> {
+        None
+    }
+    /// Returns the `AsyncFakeArr` these bytes are read from, at the same
+    /// offsets, so that async searches can await reads instead of blocking.
+    ///
+    /// This is only available with the `async` feature.
+    #[cfg(feature = "async")]
+    fn as_async(&self) -> Option<&dyn crate::AsyncFakeArr> {
+        None
+    }
     fn as_dyn(&self) -> &dyn FakeArr;
 }
+/// Returns the result of a read on a path that cannot return its error, such
+/// as `Streamer::next` or `Fst::get`, and panics if it failed.
+///
+/// Those paths have `try_` counterparts, such as `TryStreamer::try_next` and
+/// `Fst::try_get`, that return the error instead.
+#[inline(always)]
+pub(crate) fn expect_read<T>(result: std::io::Result<T>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(err) => read_failed(err),
+    }
+}
+
+#[cold]
+#[inline(never)]
+fn read_failed(err: std::io::Error) -> ! {
+    panic!("failed to read the fst: {}", err)
+}
+
 impl<'a> PartialEq for dyn FakeArr + 'a {
     fn eq(&self, other: &Self) -> bool {
         return &self.to_vec()[..] == &other.to_vec()[..];
@@ -152,6 +193,16 @@ impl<'a> FakeArrSlice<'a> {
         };
     }
 
+    /// Returns the `AsyncFakeArr` the array this slices is read from, and
+    /// the offset of this slice in it.
+    #[cfg(feature = "async")]
+    pub(crate) fn as_async_at(&self) -> Option<(&'a dyn crate::AsyncFakeArr, Ulen)> {
+        match self.real {
+            Wtfisthis::Dyn(e) => Some((e.as_async()?, self.offset)),
+            Wtfisthis::Slic(_) => None,
+        }
+    }
+
     pub async fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let read_len = std::cmp::min(buf.len() as Ulen, self.len);
         let res = (*self).read_into(0, buf).map(|()| read_len as usize);
@@ -174,6 +225,14 @@ impl<'a> FakeArr for FakeArrSlice<'a> {
         self.slice2(b)
     }
 
+    fn as_contiguous(&self) -> Option<&[u8]> {
+        let bytes = match self.real {
+            Wtfisthis::Dyn(e) => e.as_contiguous()?,
+            Wtfisthis::Slic(e) => e,
+        };
+        Some(&bytes[self.offset as usize..(self.offset + self.len) as usize])
+    }
+
     fn as_dyn(&self) -> &dyn FakeArr {
         todo!()
     }
@@ -190,6 +249,10 @@ impl FakeArr for Vec<u8> {
         <&[u8] as FakeArr>::read_into(&&self[..], offset, buf)
     }
 
+    fn as_contiguous(&self) -> Option<&[u8]> {
+        Some(self)
+    }
+
     fn as_dyn(&self) -> &dyn FakeArr {
         self
     }
@@ -205,6 +268,104 @@ impl FakeArr for &[u8] {
         buf.copy_from_slice(&self[offset as usize..end]);
         Ok(())
     }
+
+    fn as_contiguous(&self) -> Option<&[u8]> {
+        Some(self)
+    }
+    fn as_dyn(&self) -> &dyn FakeArr {
+        self
+    }
+}
+
+macro_rules! impl_fake_arr_for_box {
+    ($ty:ty) => {
+        impl FakeArr for $ty {
+            fn len(&self) -> Ulen {
+                (**self).len()
+            }
+
+            fn read_into(&self, offset: Ulen, buf: &mut [u8]) -> std::io::Result<()> {
+                (**self).read_into(offset, buf)
+            }
+
+            fn as_contiguous(&self) -> Option<&[u8]> {
+                (**self).as_contiguous()
+            }
+
+            #[cfg(feature = "async")]
+            fn as_async(&self) -> Option<&dyn crate::AsyncFakeArr> {
+                (**self).as_async()
+            }
+
+            fn as_dyn(&self) -> &dyn FakeArr {
+                (**self).as_dyn()
+            }
+        }
+    };
+}
+
+impl_fake_arr_for_box!(Box<dyn FakeArr>);
+impl_fake_arr_for_box!(Box<dyn FakeArr + Send + Sync>);
+
+/// A `FakeArr` that reads a file on demand.
+///
+/// Reads go through a buffer, so reading nodes that are close together in
+/// the file, as lookups and streams mostly do, takes few system calls.
+#[derive(Debug)]
+pub struct FileArr {
+    reader: Mutex<BufReader<File>>,
+    len: Ulen,
+}
+
+impl FileArr {
+    /// Opens the file at `path` for reading on demand.
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<FileArr> {
+        FileArr::new(File::open(path)?)
+    }
+
+    /// Reads `file` on demand, from its start to its current end.
+    pub fn new(file: File) -> std::io::Result<FileArr> {
+        let len = file.metadata()?.len();
+        Ok(FileArr {
+            reader: Mutex::new(BufReader::new(file)),
+            len,
+        })
+    }
+}
+
+impl FakeArr for FileArr {
+    fn len(&self) -> Ulen {
+        self.len
+    }
+
+    fn read_into(&self, offset: Ulen, buf: &mut [u8]) -> std::io::Result<()> {
+        let mut reader = self.reader.lock().unwrap_or_else(|err| err.into_inner());
+        // Seeking relative to the current position keeps the buffer when
+        // `offset` is in it.
+        let pos = reader.stream_position()?;
+        reader.seek_relative(offset as i64 - pos as i64)?;
+        reader.read_exact(buf)
+    }
+
+    fn as_dyn(&self) -> &dyn FakeArr {
+        self
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl FakeArr for memmap2::Mmap {
+    fn len(&self) -> Ulen {
+        (self as &[u8]).len() as Ulen
+    }
+
+    fn read_into(&self, offset: Ulen, buf: &mut [u8]) -> std::io::Result<()> {
+        <&[u8] as FakeArr>::read_into(&&self[..], offset, buf)
+    }
+
+    fn as_contiguous(&self) -> Option<&[u8]> {
+        Some(self)
+    }
+
     fn as_dyn(&self) -> &dyn FakeArr {
         self
     }
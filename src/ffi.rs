@@ -0,0 +1,245 @@
+//! A C ABI for reading maps.
+//!
+//! This lets services written in other languages use the same FST files
+//! without reimplementing the format. Every function takes and returns plain
+//! pointers and integers:
+//!
+//! * `fst_map_open` copies the bytes of a serialized map and returns an
+//!   opaque handle, which must be released with `fst_map_free`.
+//! * `fst_map_len` and `fst_map_get` answer point queries.
+//! * `fst_map_range` and `fst_map_search_regex` call back into the caller for
+//!   every matching key, in lexicographic order.
+//!
+//! Keys are passed as a pointer and a length and are never NUL terminated.
+//! Key pointers handed to a callback are only valid for the duration of that
+//! call.
+//!
+//! This module is only available with the `ffi` feature.
+
+use std::os::raw::c_void;
+use std::ptr;
+use std::slice;
+use std::str;
+
+use futures::executor::block_on;
+
+use crate::automaton::Automaton;
+use crate::raw::Fst;
+use crate::stream::{IntoStreamer, Streamer};
+use crate::{FakeArr, Map, Regex};
+
+/// An opaque handle to a map opened with `fst_map_open`.
+pub struct FstMap(Map<Vec<u8>>);
+
+/// Called for every key visited by `fst_map_range` and
+/// `fst_map_search_regex`, with the key, its value and the caller's
+/// `user_data`. Iteration stops early if it returns `false`.
+pub type FstMapCallback =
+    extern "C" fn(key: *const u8, key_len: usize, value: u64, user_data: *mut c_void) -> bool;
+
+/// Opens a map from a copy of the `len` bytes at `data`.
+///
+/// Returns a null pointer if the bytes are not a valid map.
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes, unless `len` is zero.
+#[no_mangle]
+pub unsafe extern "C" fn fst_map_open(data: *const u8, len: usize) -> *mut FstMap {
+    let bytes = bytes_from_raw(data, len).to_vec();
+    match block_on(Fst::new(bytes)) {
+        Ok(fst) => Box::into_raw(Box::new(FstMap(Map::from(fst)))),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Releases a map opened with `fst_map_open`. Null pointers are ignored.
+///
+/// # Safety
+///
+/// `map` must be null or a handle returned by `fst_map_open` that was not
+/// released yet.
+#[no_mangle]
+pub unsafe extern "C" fn fst_map_free(map: *mut FstMap) {
+    if !map.is_null() {
+        drop(Box::from_raw(map));
+    }
+}
+
+/// Returns the number of keys in `map`.
+///
+/// # Safety
+///
+/// `map` must be a live handle returned by `fst_map_open`.
+#[no_mangle]
+pub unsafe extern "C" fn fst_map_len(map: *const FstMap) -> u64 {
+    (*map).0.len()
+}
+
+/// Looks up a key, writing its value to `value` and returning `true` if it
+/// exists. `value` may be null if only membership is needed.
+///
+/// # Safety
+///
+/// `map` must be a live handle returned by `fst_map_open`, `key` must point
+/// to `key_len` readable bytes, and `value` must be null or valid for
+/// writes.
+#[no_mangle]
+pub unsafe extern "C" fn fst_map_get(
+    map: *const FstMap,
+    key: *const u8,
+    key_len: usize,
+    value: *mut u64,
+) -> bool {
+    match (*map).0.get(bytes_from_raw(key, key_len)) {
+        None => false,
+        Some(v) => {
+            if !value.is_null() {
+                *value = v;
+            }
+            true
+        }
+    }
+}
+
+/// Visits every key `k` with `ge <= k < lt`, in order. A null bound is
+/// unbounded.
+///
+/// Returns the number of keys passed to `callback`.
+///
+/// # Safety
+///
+/// `map` must be a live handle returned by `fst_map_open`, and `ge` and `lt`
+/// must be null or point to `ge_len` and `lt_len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn fst_map_range(
+    map: *const FstMap,
+    ge: *const u8,
+    ge_len: usize,
+    lt: *const u8,
+    lt_len: usize,
+    callback: FstMapCallback,
+    user_data: *mut c_void,
+) -> u64 {
+    let mut range = (*map).0.range();
+    if !ge.is_null() {
+        range = range.ge(bytes_from_raw(ge, ge_len));
+    }
+    if !lt.is_null() {
+        range = range.lt(bytes_from_raw(lt, lt_len));
+    }
+    visit(range.into_stream(), callback, user_data)
+}
+
+/// Visits every key matched by the regular expression `pattern`, which must
+/// be `pattern_len` bytes of UTF-8. See `Regex` for the supported syntax.
+///
+/// Returns the number of keys passed to `callback`, or `-1` if the pattern
+/// is invalid.
+///
+/// # Safety
+///
+/// `map` must be a live handle returned by `fst_map_open`, and `pattern`
+/// must point to `pattern_len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn fst_map_search_regex(
+    map: *const FstMap,
+    pattern: *const u8,
+    pattern_len: usize,
+    callback: FstMapCallback,
+    user_data: *mut c_void,
+) -> i64 {
+    let re = match str::from_utf8(bytes_from_raw(pattern, pattern_len))
+        .ok()
+        .and_then(|pattern| Regex::new(pattern).ok())
+    {
+        None => return -1,
+        Some(re) => re,
+    };
+    visit((*map).0.search(re).into_stream(), callback, user_data) as i64
+}
+
+fn visit<A: Automaton>(
+    mut stream: crate::map::Stream<'_, A>,
+    callback: FstMapCallback,
+    user_data: *mut c_void,
+) -> u64 {
+    let mut count = 0;
+    let mut key = vec![];
+    while let Some((k, v)) = stream.next() {
+        key.clear();
+        key.resize(k.len() as usize, 0);
+        k.read_into(0, &mut key).unwrap();
+        count += 1;
+        if !callback(key.as_ptr(), key.len(), v, user_data) {
+            break;
+        }
+    }
+    count
+}
+
+unsafe fn bytes_from_raw<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(data, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MapBuilder;
+
+    extern "C" fn collect(
+        key: *const u8,
+        key_len: usize,
+        value: u64,
+        user_data: *mut c_void,
+    ) -> bool {
+        let out = unsafe { &mut *(user_data as *mut Vec<(Vec<u8>, u64)>) };
+        out.push((unsafe { bytes_from_raw(key, key_len) }.to_vec(), value));
+        out.len() < 2
+    }
+
+    #[test]
+    fn open_get_and_iterate() {
+        let mut builder = MapBuilder::memory();
+        builder.insert("a", 1).unwrap();
+        builder.insert("bc", 2).unwrap();
+        builder.insert("bd", 3).unwrap();
+        let bytes = builder.into_inner().unwrap();
+
+        unsafe {
+            assert!(fst_map_open(bytes.as_ptr(), 3).is_null());
+            let map = fst_map_open(bytes.as_ptr(), bytes.len());
+            assert!(!map.is_null());
+            assert_eq!(fst_map_len(map), 3);
+
+            let mut value = 0;
+            assert!(fst_map_get(map, b"bc".as_ptr(), 2, &mut value));
+            assert_eq!(value, 2);
+            assert!(!fst_map_get(map, b"z".as_ptr(), 1, ptr::null_mut()));
+
+            let mut got: Vec<(Vec<u8>, u64)> = vec![];
+            let data = &mut got as *mut _ as *mut c_void;
+            let n = fst_map_range(map, b"b".as_ptr(), 1, ptr::null(), 0, collect, data);
+            assert_eq!(n, 2);
+            assert_eq!(got, vec![(b"bc".to_vec(), 2), (b"bd".to_vec(), 3)]);
+
+            got.clear();
+            let data = &mut got as *mut _ as *mut c_void;
+            assert_eq!(
+                fst_map_search_regex(map, b"a|bd".as_ptr(), 4, collect, data),
+                2
+            );
+            assert_eq!(got, vec![(b"a".to_vec(), 1), (b"bd".to_vec(), 3)]);
+            assert_eq!(
+                fst_map_search_regex(map, b"(".as_ptr(), 1, collect, data),
+                -1
+            );
+
+            fst_map_free(map);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+/*!
+Order-preserving encoding of keys made of several typed parts.
+
+`KeyEncoder` appends strings, byte strings, integers and floats to a key such
+that keys sort exactly like the tuples they encode, compared part by part.
+`KeyDecoder` reads the parts back, given their types in the same order.
+
+Strings and byte strings are escaped with the scheme of the `escape` module
+and end with a terminator, while numbers take eight bytes:
+
+* `u64` is written big-endian.
+* `i64` is written big-endian with its sign bit flipped.
+* `f64` is written big-endian with its sign bit flipped, or with all bits
+  flipped if it is negative. `-0.0` sorts before `0.0` and `NaN`s sort at
+  the ends.
+
+Since every part is self delimiting, the encoding of the leading parts of a
+tuple is a prefix of the encoding of the whole tuple. That makes it a bound
+for range queries and `automaton::StartsWith`.
+*/
+use crate::error::Result;
+use crate::escape::{self, LITERAL, NEXT, SEPARATOR};
+use crate::raw::Error;
+
+const SIGN: u64 = 1 << 63;
+
+/// Builds a key from a sequence of typed parts.
+#[derive(Clone, Debug, Default)]
+pub struct KeyEncoder {
+    key: Vec<u8>,
+}
+
+impl KeyEncoder {
+    /// Creates an empty key.
+    pub fn new() -> KeyEncoder {
+        KeyEncoder::default()
+    }
+
+    /// Appends a byte string.
+    pub fn bytes<B: AsRef<[u8]>>(mut self, part: B) -> KeyEncoder {
+        escape::escape_into(part.as_ref(), &mut self.key);
+        self.key.push(SEPARATOR);
+        self.key.push(NEXT);
+        self
+    }
+
+    /// Appends a string.
+    pub fn str<S: AsRef<str>>(self, part: S) -> KeyEncoder {
+        self.bytes(part.as_ref())
+    }
+
+    /// Appends an unsigned integer.
+    pub fn u64(mut self, part: u64) -> KeyEncoder {
+        self.key.extend_from_slice(&part.to_be_bytes());
+        self
+    }
+
+    /// Appends a signed integer.
+    pub fn i64(self, part: i64) -> KeyEncoder {
+        self.u64(part as u64 ^ SIGN)
+    }
+
+    /// Appends a float.
+    pub fn f64(self, part: f64) -> KeyEncoder {
+        let bits = part.to_bits();
+        if bits & SIGN == 0 {
+            self.u64(bits ^ SIGN)
+        } else {
+            self.u64(!bits)
+        }
+    }
+
+    /// Returns the key encoded so far.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Returns the encoded key.
+    pub fn finish(self) -> Vec<u8> {
+        self.key
+    }
+}
+
+/// Reads the parts of a key built by `KeyEncoder`.
+///
+/// Parts must be read with the types they were written with. If a part does
+/// not fit the remaining bytes, then an error is returned.
+#[derive(Clone, Debug)]
+pub struct KeyDecoder<'a> {
+    key: &'a [u8],
+    rest: &'a [u8],
+}
+
+impl<'a> KeyDecoder<'a> {
+    /// Starts reading `key` from its first part.
+    pub fn new(key: &'a [u8]) -> KeyDecoder<'a> {
+        KeyDecoder { key, rest: key }
+    }
+
+    /// Returns true if and only if every part has been read.
+    pub fn is_empty(&self) -> bool {
+        self.rest.is_empty()
+    }
+
+    /// Returns the bytes that have not been read yet.
+    pub fn remaining(&self) -> &'a [u8] {
+        self.rest
+    }
+
+    /// Reads a byte string.
+    pub fn bytes(&mut self) -> Result<Vec<u8>> {
+        let mut part = vec![];
+        let mut i = 0;
+        while i < self.rest.len() {
+            let b = self.rest[i];
+            if b != SEPARATOR {
+                part.push(b);
+                i += 1;
+                continue;
+            }
+            match self.rest.get(i + 1) {
+                Some(&LITERAL) => part.push(SEPARATOR),
+                Some(&NEXT) => {
+                    self.rest = &self.rest[i + 2..];
+                    return Ok(part);
+                }
+                _ => break,
+            }
+            i += 2;
+        }
+        Err(self.error())
+    }
+
+    /// Reads a string.
+    pub fn str(&mut self) -> Result<String> {
+        let part = self.bytes()?;
+        String::from_utf8(part).map_err(|err| Error::FromUtf8(err).into())
+    }
+
+    /// Reads an unsigned integer.
+    pub fn u64(&mut self) -> Result<u64> {
+        if self.rest.len() < 8 {
+            return Err(self.error());
+        }
+        let (part, rest) = self.rest.split_at(8);
+        self.rest = rest;
+        let mut buf = [0; 8];
+        buf.copy_from_slice(part);
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    /// Reads a signed integer.
+    pub fn i64(&mut self) -> Result<i64> {
+        Ok((self.u64()? ^ SIGN) as i64)
+    }
+
+    /// Reads a float.
+    pub fn f64(&mut self) -> Result<f64> {
+        let bits = self.u64()?;
+        if bits & SIGN != 0 {
+            Ok(f64::from_bits(bits ^ SIGN))
+        } else {
+            Ok(f64::from_bits(!bits))
+        }
+    }
+
+    fn error(&self) -> crate::Error {
+        Error::InvalidKey {
+            got: self.key.to_vec(),
+        }
+        .into()
+    }
+}
@@ -9,23 +9,47 @@
 #![allow(clippy::should_implement_trait)]
 
 pub use crate::automaton::Automaton;
-pub use crate::error::{Error, Result};
+pub use crate::error::{Error, IoContext, Result};
 pub use crate::map::{Map, MapBuilder};
-pub use crate::stream::{IntoStreamer, Streamer};
+pub use crate::open::{open, open_with, Backend, OpenPolicy};
+pub use crate::set::{Set, SetBuilder};
+pub use crate::stream::{IntoStreamer, Streamer, TryStreamer};
+#[cfg(feature = "async")]
+pub use crate::stream::AsyncStreamer;
 
 mod regex;
 mod fake_arr;
 
-pub use self::regex::Regex;
-pub use fake_arr::{FakeArr, ShRange, FakeArrSlice, Ulen};
+pub use self::regex::{Regex, RegexBuilder, RegexFlags};
+pub use fake_arr::{FakeArr, FileArr, ShRange, FakeArrSlice, Ulen};
+pub use cached_fake_arr::CachedFakeArr;
+#[cfg(feature = "async")]
+pub use async_fake_arr::{AsyncFakeArr, BlockOn, BlockingFakeArr, FuturesBlockOn};
+#[cfg(feature = "mmap")]
+pub use memmap2::Mmap;
 
+#[cfg(feature = "async")]
+mod async_fake_arr;
+mod cached_fake_arr;
 mod error;
+pub mod bytes_util;
+pub mod corpus;
+pub mod delta;
+pub mod escape;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 #[path = "automaton/mod.rs"]
 mod inner_automaton;
 #[path = "map.rs"]
 mod inner_map;
+#[path = "set.rs"]
+mod inner_set;
+pub mod keys;
+mod open;
 pub mod raw;
-mod stream;
+pub mod results;
+pub mod snapshot;
+pub mod stream;
 
 /// Automaton implementations for finite state transducers.
 ///
@@ -49,7 +73,24 @@ pub mod automaton {
 /// `StreamBuilder` builds range queries. `OpBuilder` collects a set of streams
 /// and executes set operations like `union` or `intersection` on them with the
 /// option of specifying a merge strategy for a map's values. The rest of the
-/// types are streams for set operations.
+/// types are streams for set operations. `DynMap` erases the type of the data
+/// of a map, so that maps with different backends can be stored together.
 pub mod map {
     pub use crate::inner_map::*;
 }
+
+/// Set operations implemented by finite state transducers.
+///
+/// This API provided by this sub-module is close in spirit to the API
+/// provided by
+/// [`std::collections::BTreeSet`](https://doc.rust-lang.org/stable/std/collections/struct.BTreeSet.html).
+/// The principal difference with the `map` module is that sets only hold
+/// keys: every output of the underlying finite state transducer is `0`.
+///
+/// `Set` is a read only interface to pre-constructed sets and `SetBuilder`
+/// is used to create new sets. `StreamBuilder` builds range queries and
+/// `OpBuilder` executes set operations like `union` or `intersection` on a
+/// collection of streams of keys.
+pub mod set {
+    pub use crate::inner_set::*;
+}
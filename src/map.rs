@@ -1,15 +1,22 @@
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::fmt;
 use std::io;
 use std::iter::FromIterator;
+use std::sync::Arc;
+use std::task::Poll;
 
+use crate::automaton::levenshtein::Levenshtein;
+use crate::escape;
 use crate::raw;
 pub use crate::raw::IndexedValue;
-use crate::stream::{IntoStreamer, Streamer};
-use crate::Result;
+use crate::stream::{IntoStreamer, Streamer, TryStreamer};
 use crate::{
     automaton::{AlwaysMatch, Automaton},
     fake_arr::{FakeArr, FakeArrRef, Ulen},
 };
+use crate::{IoContext, Result};
 use std::ops::Deref;
 
 /// Map is a lexicographically ordered map from byte strings to integers.
@@ -58,7 +65,79 @@ use std::ops::Deref;
 /// although it isn't clear where exactly this should live).
 pub struct Map<Data: FakeArr>(raw::Fst<Data>);
 
+impl Map<Vec<u8>> {
+    /// Reads a map from `rdr` until EOF and opens it.
+    ///
+    /// `size_hint` is the expected size of the map in bytes, such as the
+    /// length of an archive entry or a `Content-Length` header. The buffer is
+    /// allocated up front to hold that many bytes, but the map may be smaller
+    /// or larger. Use `0` if the size is not known.
+    pub async fn from_reader<R: io::Read>(mut rdr: R, size_hint: usize) -> Result<Map<Vec<u8>>> {
+        let mut data = Vec::with_capacity(size_hint);
+        rdr.read_to_end(&mut data)
+            .map_err(|err| IoContext::new("reading map", err))?;
+        Ok(Map(raw::Fst::new(data).await?))
+    }
+
+    /// Reads a map that was compressed with `Map::write_compressed` from
+    /// `rdr` and opens it.
+    ///
+    /// The map is fully decompressed into memory, and its footer is checked
+    /// just like `Fst::new` does, so a stream that was cut short or mangled
+    /// in transit is reported as an error.
+    ///
+    /// This is only available with the `zstd` feature.
+    #[cfg(feature = "zstd")]
+    pub async fn from_compressed_reader<R: io::Read>(rdr: R) -> Result<Map<Vec<u8>>> {
+        let mut data = vec![];
+        zstd::stream::read::Decoder::new(rdr)
+            .and_then(|mut dec| io::Read::read_to_end(&mut dec, &mut data))
+            .map_err(|err| IoContext::new("decompressing map", err))?;
+        Ok(Map(raw::Fst::new(data).await?))
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl Map<memmap2::Mmap> {
+    /// Memory maps the file at `path` and opens the map in it.
+    ///
+    /// Lookups and streams read straight from the page cache, so only the
+    /// parts of the map that are used are ever loaded.
+    ///
+    /// This is only available with the `mmap` feature.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated, by this process or any
+    /// other, while the map is open. The map reads the file as memory, so
+    /// a change to it is undefined behavior, as with any memory map.
+    pub async unsafe fn from_path<P: AsRef<std::path::Path>>(
+        path: P,
+    ) -> Result<Map<memmap2::Mmap>> {
+        let path = path.as_ref();
+        let context = |op: &str, err| IoContext::new(op, err).path(path);
+        let file = std::fs::File::open(path).map_err(|err| context("opening map", err))?;
+        // SAFETY: the caller guarantees that the file is not modified while
+        // it is mapped.
+        let mmap =
+            unsafe { memmap2::Mmap::map(&file) }.map_err(|err| context("memory mapping map", err))?;
+        Ok(Map(raw::Fst::new(mmap).await?))
+    }
+}
+
 impl<Data: FakeArr> Map<Data> {
+    /// Salvages what it can from a map whose bytes may have been truncated,
+    /// for example by a crash while the map was being written.
+    ///
+    /// This never fails on truncated data, as long as the header survived.
+    /// Instead, the returned `Recovered` reports whether the map was intact
+    /// and exposes the recoverable part of the key space, which is partial
+    /// unless `Recovered::is_complete` returns true. See `Recovered` for
+    /// details.
+    pub fn open_truncated(data: Data) -> Result<Recovered<Data>> {
+        raw::Recovered::new(data).map(Recovered)
+    }
+
     /// Tests the membership of a single key.
     ///
     /// # Example
@@ -75,6 +154,92 @@ impl<Data: FakeArr> Map<Data> {
         self.0.contains_key(key)
     }
 
+    /// Just like `contains_key`, except a read that fails returns an error
+    /// instead of panicking. See `raw::Fst::try_get`.
+    pub fn try_contains_key<K: AsRef<[u8]>>(&self, key: K) -> Result<bool> {
+        self.0.try_contains_key(key)
+    }
+
+    /// Returns true if and only if some key in this map starts with
+    /// `prefix`, without streaming. See `raw::Fst::contains_prefix`.
+    pub fn contains_prefix<K: AsRef<[u8]>>(&self, prefix: K) -> bool {
+        self.0.contains_prefix(prefix)
+    }
+
+    /// Returns the greatest key that is less than or equal to `key`, along
+    /// with its value. See `raw::Fst::get_floor`.
+    pub fn get_floor<K: AsRef<[u8]>>(&self, key: K) -> Option<(Vec<u8>, u64)> {
+        self.0.get_floor(key).map(|(key, out)| (key, out.value()))
+    }
+
+    /// Returns the smallest key that is greater than or equal to `key`,
+    /// along with its value. See `raw::Fst::get_ceiling`.
+    pub fn get_ceiling<K: AsRef<[u8]>>(&self, key: K) -> Option<(Vec<u8>, u64)> {
+        self.0.get_ceiling(key).map(|(key, out)| (key, out.value()))
+    }
+
+    /// Returns the smallest key in this map along with its value, or `None`
+    /// if it is empty, without building a stream.
+    pub fn min_key(&self) -> Option<(Vec<u8>, u64)> {
+        self.0.min_key().map(|(key, out)| (key, out.value()))
+    }
+
+    /// Returns the greatest key in this map along with its value, or `None`
+    /// if it is empty, without building a stream.
+    pub fn max_key(&self) -> Option<(Vec<u8>, u64)> {
+        self.0.max_key().map(|(key, out)| (key, out.value()))
+    }
+
+    /// Tests the membership of many keys at once, returning whether each of
+    /// them is in this map.
+    ///
+    /// Keys should be sorted, so that the prefix each key shares with the
+    /// previous one is only walked once. See `raw::Fst::contains_all`.
+    pub fn contains_all<K: AsRef<[u8]>>(&self, keys: &[K]) -> Vec<bool> {
+        self.0.contains_all(keys)
+    }
+
+    /// Returns true if any of the keys is in this map. Like `contains_all`,
+    /// this is fastest when the keys are sorted.
+    pub fn contains_any<K: AsRef<[u8]>>(&self, keys: &[K]) -> bool {
+        self.0.contains_any(keys)
+    }
+
+    /// Returns true if and only if this map has no key `k` with
+    /// `lo <= k < hi`. See `raw::Fst::is_disjoint_range`.
+    pub fn is_disjoint_range<K1: AsRef<[u8]>, K2: AsRef<[u8]>>(&self, lo: K1, hi: K2) -> bool {
+        self.0.is_disjoint_range(lo, hi)
+    }
+
+    /// Returns the ordinal of each of the given keys, or `None` for keys
+    /// that are not in this map. See `raw::Fst::ordinals_for`.
+    ///
+    /// `sorted_keys` must be in lexicographic order.
+    pub fn ordinals_for<K: AsRef<[u8]>>(&self, sorted_keys: &[K]) -> Result<Vec<Option<u64>>> {
+        self.0.ordinals_for(sorted_keys)
+    }
+
+    /// Returns the ordinal of a key, which is its zero-based position in
+    /// lexicographic order, or `None` if it is not in this map. See
+    /// `raw::Fst::get_index`.
+    pub fn get_index<K: AsRef<[u8]>>(&self, key: K) -> Option<u64> {
+        self.0.get_index(key)
+    }
+
+    /// Returns the key whose ordinal is `index`, along with its value, or
+    /// `None` if `index` is not less than the length of this map. See
+    /// `raw::Fst::get_key`.
+    pub fn get_key(&self, index: u64) -> Option<(Vec<u8>, u64)> {
+        self.0.get_key(index).map(|(key, out)| (key, out.value()))
+    }
+
+    /// Returns up to `n - 1` keys that split this map into `n` ranges with
+    /// nearly the same number of keys each, so that each range can be
+    /// streamed by a separate worker. See `raw::Fst::partition_points`.
+    pub fn partition_points(&self, n: usize) -> Vec<Vec<u8>> {
+        self.0.partition_points(n)
+    }
+
     /// Retrieves the value associated with a key.
     ///
     /// If the key does not exist, then `None` is returned.
@@ -93,6 +258,117 @@ impl<Data: FakeArr> Map<Data> {
         self.0.get(key).map(|output| output.value())
     }
 
+    /// Just like `get`, except a read that fails returns an error instead of
+    /// panicking, so that lookups on maps backed by disk or network IO can
+    /// be retried. See `raw::Fst::try_get`.
+    pub fn try_get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<u64>> {
+        Ok(self.0.try_get(key)?.map(|output| output.value()))
+    }
+
+    /// Retrieves the value associated with a key from async code.
+    ///
+    /// Maps read from an `AsyncFakeArr`, through a `BlockingFakeArr`, await
+    /// their reads rather than blocking on them. See `raw::Fst::get_async`.
+    ///
+    /// This is only available with the `async` feature.
+    #[cfg(feature = "async")]
+    pub async fn get_async<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<u64>> {
+        Ok(self.0.get_async(key).await?.map(|output| output.value()))
+    }
+
+    /// Retrieves the value associated with the composite key made of
+    /// `components`, as joined by `escape::join`.
+    pub fn get_components<I, C>(&self, components: I) -> Option<u64>
+    where
+        I: IntoIterator<Item = C>,
+        C: AsRef<[u8]>,
+    {
+        self.get(escape::join(components))
+    }
+
+    /// Walks `prefix` once and returns a cursor that `get_suffix` can
+    /// resume lookups from, or `None` if no key starts with `prefix`.
+    ///
+    /// This saves walking a long common prefix again for every lookup.
+    pub fn get_with_prefix_state<K: AsRef<[u8]>>(&self, prefix: K) -> Option<raw::PrefixCursor> {
+        self.0.get_with_prefix_state(prefix)
+    }
+
+    /// Retrieves the value of the key made of the prefix of `cursor`
+    /// followed by `rest`.
+    ///
+    /// `cursor` must have been returned by `get_with_prefix_state` on this
+    /// map.
+    pub fn get_suffix<K: AsRef<[u8]>>(&self, cursor: &raw::PrefixCursor, rest: K) -> Option<u64> {
+        self.0.get_suffix(cursor, rest).map(|output| output.value())
+    }
+
+    /// Returns the `k` key-value pairs with the greatest values among the
+    /// keys that start with `prefix`, in descending order of value. Ties are
+    /// broken by key, in lexicographic order.
+    ///
+    /// This is useful for weighted autocompletion. Unlike streaming every
+    /// key with the prefix and keeping the best ones, this runs a best-first
+    /// search that only explores the parts of the map that can contain one
+    /// of the top `k` keys.
+    /// Dictionary encoded maps stream every key with the prefix instead. See
+    /// `raw::Fst::top_k_completions`.
+    pub fn top_k_completions<K: AsRef<[u8]>>(&self, prefix: K, k: usize) -> Vec<(Vec<u8>, u64)> {
+        self.0
+            .top_k_completions(prefix, k)
+            .into_iter()
+            .map(|(key, out)| (key, out.value()))
+            .collect()
+    }
+
+    /// Returns the `k` key-value pairs with the greatest values, in
+    /// descending order of value. Ties are broken by key, in lexicographic
+    /// order.
+    ///
+    /// This streams the map once and only holds `k` keys at a time, which
+    /// makes it suited to reports of the heaviest keys of large maps. Use
+    /// `StreamBuilder::top_values` to restrict it to a range or to the keys
+    /// matched by an automaton. See `raw::Fst::top_values`.
+    pub fn top_values(&self, k: usize) -> Vec<(Vec<u8>, u64)> {
+        self.range().top_values(k)
+    }
+
+    /// Just like `top_k_completions`, except the greatest value reachable
+    /// from each node is read from a precomputed sidecar instead of being
+    /// computed during the search.
+    ///
+    /// The sidecar must store maximums and must have been built from this
+    /// map with `raw::OutputSidecar::new(map.as_fst(), SidecarKind::Max)`.
+    pub fn top_k_completions_with<K: AsRef<[u8]>>(
+        &self,
+        prefix: K,
+        k: usize,
+        sidecar: &raw::OutputSidecar,
+    ) -> Vec<(Vec<u8>, u64)> {
+        self.0
+            .top_k_completions_with(prefix, k, sidecar)
+            .into_iter()
+            .map(|(key, out)| (key, out.value()))
+            .collect()
+    }
+
+    /// Returns the offset of the record of the given key, for a map built
+    /// with `MapBuilder::insert_length`.
+    ///
+    /// This is the sum of the lengths of all keys before it. If the key does
+    /// not exist, then `None` is returned.
+    pub fn offset_of<K: AsRef<[u8]>>(&self, key: K) -> Option<u64> {
+        self.0.offset_of(key)
+    }
+
+    /// Returns the length of the record of the given key, for a map built
+    /// with `MapBuilder::insert_length`.
+    ///
+    /// If the key does not exist, then `None` is returned.
+    pub fn length_of<K: AsRef<[u8]>>(&self, key: K) -> Option<u64> {
+        self.0.length_of(key)
+    }
+
     /// Return a lexicographically ordered stream of all key-value pairs in
     /// this map.
     ///
@@ -130,6 +406,58 @@ impl<Data: FakeArr> Map<Data> {
         Stream(self.0.stream())
     }
 
+    /// Return a stream of all key-value pairs in this map, in reverse
+    /// lexicographic order. See `raw::Fst::stream_rev`.
+    pub fn stream_rev(&self) -> Stream<'_> {
+        Stream(self.0.stream_rev())
+    }
+
+    /// Returns a lexicographically ordered stream of the key-value pairs in
+    /// this map whose keys start with `prefix`. See
+    /// `raw::Fst::stream_prefix`.
+    pub fn stream_prefix<K: AsRef<[u8]>>(&self, prefix: K) -> Stream<'_> {
+        Stream(self.0.stream_prefix(prefix))
+    }
+
+    /// Return a stream of all key-value pairs in this map, with every key
+    /// transformed by `map` as it is emitted.
+    ///
+    /// `map` may borrow from the key it is given, for instance to strip a
+    /// field prefix, or return an owned key, for instance to decode an
+    /// escape scheme. This avoids collecting the keys and transforming them
+    /// in a second pass.
+    pub fn stream_mapped<F>(&self, map: F) -> MappedStream<'_, F>
+    where
+        F: for<'k> FnMut(&'k [u8]) -> Cow<'k, [u8]>,
+    {
+        self.stream().into_mapped(map)
+    }
+
+    /// Returns up to `limit` key-value pairs matched by `aut` that come
+    /// right before `key`, in lexicographic order.
+    ///
+    /// This is reverse pagination for dictionary browsers: given the first
+    /// key of a page, it returns the previous page. The keys are found by
+    /// streaming backward from `key`, so only the returned keys and the
+    /// nodes around them are visited.
+    pub fn preceding<A: Automaton, K: AsRef<[u8]>>(
+        &self,
+        aut: A,
+        key: K,
+        limit: usize,
+    ) -> Vec<(Vec<u8>, u64)> {
+        let mut stream = self.search(aut).before(key).into_stream();
+        let mut page = Vec::with_capacity(limit);
+        while page.len() < limit {
+            match stream.next() {
+                Some((k, v)) => page.push((k.to_vec(), v)),
+                None => break,
+            }
+        }
+        page.reverse();
+        page
+    }
+
     /// Return a lexicographically ordered stream of all keys in this map.
     ///
     /// Memory requirements are the same as described on `Map::stream`.
@@ -153,6 +481,12 @@ impl<Data: FakeArr> Map<Data> {
         Keys(self.0.stream())
     }
 
+    /// Return a stream of all keys in this map, in reverse lexicographic
+    /// order.
+    pub fn keys_rev(&self) -> Keys<'_> {
+        Keys(self.0.stream_rev())
+    }
+
     /// Return a stream of all values in this map ordered lexicographically
     /// by each value's corresponding key.
     ///
@@ -177,6 +511,12 @@ impl<Data: FakeArr> Map<Data> {
         Values(self.0.stream())
     }
 
+    /// Return a stream of all values in this map, ordered by their keys in
+    /// reverse lexicographic order.
+    pub fn values_rev(&self) -> Values<'_> {
+        Values(self.0.stream_rev())
+    }
+
     /// Return a builder for range queries.
     ///
     /// A range query returns a subset of key-value pairs in this map in a
@@ -213,6 +553,13 @@ impl<Data: FakeArr> Map<Data> {
         StreamBuilder(self.0.range())
     }
 
+    /// Return a builder for the keys of this map in `range`, which is any
+    /// standard range of byte strings, such as `"a".."m"`. See
+    /// `raw::StreamBuilder::range_bounds`.
+    pub fn range_bounds<R: raw::KeyRange>(&self, range: R) -> StreamBuilder<'_> {
+        StreamBuilder(self.0.range_bounds(range))
+    }
+
     /// Executes an automaton on the keys of this map.
     ///
     /// Note that this returns a `StreamBuilder`, which can be used to
@@ -259,6 +606,59 @@ impl<Data: FakeArr> Map<Data> {
         StreamBuilder(self.0.search(aut))
     }
 
+    /// Executes an automaton on the keys of this map, streaming the matches
+    /// in reverse lexicographic order.
+    ///
+    /// This is a shorthand for `search(aut).backward()`. Bounds can still
+    /// be added to the returned builder, and its `keys` and `values`
+    /// streams are reversed too.
+    pub fn search_rev<A: Automaton>(&self, aut: A) -> StreamBuilder<'_, A> {
+        self.search(aut).backward()
+    }
+
+    /// Returns a stream of the keys within `max_dist` edits of `term`, in
+    /// lexicographic order, along with their values and their distance from
+    /// `term`.
+    ///
+    /// Distances count insertions, deletions and substitutions of Unicode
+    /// characters.
+    pub fn fuzzy_search(&self, term: &str, max_dist: u8) -> FuzzyStream<'_> {
+        let aut = Levenshtein::new(term, u32::from(max_dist));
+        FuzzyStream(self.0.search(aut).with_state().into_stream())
+    }
+
+    /// Returns up to `k` keys within `max_dist` edits of `term`, best first.
+    ///
+    /// Candidates are ranked by distance, then by decreasing value, so that
+    /// maps from words to frequencies give the most likely corrections
+    /// first. Ties are broken by key. Only the best `k` candidates are kept
+    /// while searching.
+    pub fn suggest(&self, term: &str, max_dist: u8, k: usize) -> Vec<Suggestion> {
+        let mut heap = BinaryHeap::with_capacity(k + 1);
+        let mut stream = self.fuzzy_search(term, max_dist);
+        while let Some((key, value, distance)) = stream.next() {
+            let rank = (distance, Reverse(value));
+            if heap.len() == k {
+                match heap.peek() {
+                    Some(&(worst, _)) if rank < worst => {}
+                    _ => continue,
+                }
+            }
+            heap.push((rank, key.to_vec()));
+            if heap.len() > k {
+                heap.pop();
+            }
+        }
+        heap.into_sorted_vec()
+            .into_iter()
+            .map(|((distance, Reverse(value)), key)| Suggestion {
+                key,
+                value,
+                distance,
+            })
+            .collect()
+    }
+
     /// Returns the number of elements in this map.
     #[inline]
     pub fn len(&self) -> Ulen {
@@ -271,6 +671,48 @@ impl<Data: FakeArr> Map<Data> {
         self.0.is_empty()
     }
 
+    /// Returns the length of the longest key in this map, if it is known.
+    ///
+    /// This is known for every map built by this version of the crate, and
+    /// can be used to size buffers that hold keys from this map up front.
+    #[inline]
+    pub fn max_key_len(&self) -> Option<usize> {
+        self.0.max_key_len()
+    }
+
+    /// Returns the distinct values of a map built with
+    /// `MapBuilder::new_dictionary_encoded`, in the order they were
+    /// first inserted, or `None` if its values are stored directly.
+    pub fn distinct_values(&self) -> Option<&[u64]> {
+        self.0.distinct_values()
+    }
+
+    /// Streams every key-value pair in this map and reports the throughput
+    /// in keys and key bytes per second.
+    ///
+    /// This is meant for comparing backing stores for the same map, like
+    /// memory maps, caches or remote storage.
+    pub fn scan_benchmark(&self) -> raw::ScanReport {
+        self.0.scan_benchmark()
+    }
+
+    /// Just like `scan_benchmark`, except only the keys matched by `aut` are
+    /// visited.
+    pub fn scan_benchmark_with<A: Automaton>(&self, aut: A) -> raw::ScanReport {
+        self.0.scan_benchmark_with(aut)
+    }
+
+    /// Looks up a key and returns the trace of the lookup: every node it
+    /// visits, the transitions it takes with their outputs, and the final
+    /// output it ends with.
+    ///
+    /// Printing the trace shows how the value of a key is put together,
+    /// which helps when a key has an unexpected value, such as after a
+    /// merge.
+    pub fn explain<K: AsRef<[u8]>>(&self, key: K) -> raw::Explanation {
+        self.0.explain(key)
+    }
+
     /// Creates a new map operation with this map added to it.
     ///
     /// The `OpBuilder` type can be used to add additional map streams
@@ -324,6 +766,99 @@ impl<Data: FakeArr> Map<Data> {
     pub fn as_fst(&self) -> &raw::Fst<Data> {
         &self.0
     }
+
+    /// Returns the number of bytes this map holds on the heap, not counting
+    /// its data. See `raw::Fst::heap_usage`.
+    pub fn heap_usage(&self) -> usize {
+        self.0.heap_usage()
+    }
+
+    /// Returns a copy of this map that is backed by a `Vec<u8>`.
+    ///
+    /// This reads the whole map once, which is useful to keep a lazily
+    /// loaded map in memory for serving. The format is not validated again.
+    pub fn to_vec_backed(&self) -> Map<Vec<u8>> {
+        Map(self.0.to_vec_backed())
+    }
+
+    /// Writes an fst holding only the keys of this map to `wtr`, with every
+    /// output set to zero, and returns `wtr`.
+    ///
+    /// Without outputs, the keys are usually stored in a much smaller fst.
+    /// This is useful for replicas that only answer membership queries. The
+    /// fst type of this map is kept.
+    pub fn keys_into_fst<W: io::Write>(&self, wtr: W) -> Result<W> {
+        let mut builder = raw::Builder::new_type(wtr, self.0.fst_type())?;
+        let mut keys = self.keys();
+        while let Some(key) = keys.next() {
+            builder.add(key.actually_read_it())?;
+        }
+        builder.into_inner()
+    }
+
+    /// Writes the bytes of this map to `wtr`, compressed with zstd.
+    ///
+    /// This is meant for shipping whole maps over the wire. The result is a
+    /// plain zstd stream that can be opened with
+    /// `Map::from_compressed_reader`, or decompressed with any zstd tool.
+    ///
+    /// This is only available with the `zstd` feature.
+    #[cfg(feature = "zstd")]
+    pub fn write_compressed<W: io::Write>(&self, wtr: W) -> Result<()> {
+        const CHUNK: Ulen = 1 << 16;
+
+        let compress = || -> io::Result<()> {
+            let mut enc = zstd::stream::write::Encoder::new(wtr, 0)?;
+            let data = self.0.as_inner();
+            let mut buf = vec![];
+            let mut offset = 0;
+            while offset < data.len() {
+                let len = CHUNK.min(data.len() - offset);
+                buf.resize(len as usize, 0);
+                data.read_into(offset, &mut buf)?;
+                io::Write::write_all(&mut enc, &buf)?;
+                offset += len;
+            }
+            enc.finish()?.flush()
+        };
+        compress().map_err(|err| IoContext::new("compressing map", err).into())
+    }
+
+    /// Returns the data this map was opened from.
+    #[inline]
+    pub fn as_inner(&self) -> &Data {
+        self.0.as_inner()
+    }
+
+    /// Consumes this map and returns the data it was opened from.
+    #[inline]
+    pub fn into_inner(self) -> Data {
+        self.0.into_inner()
+    }
+
+    /// Erases the type of the data of this map. See `DynMap`.
+    pub fn into_dyn(self) -> DynMap
+    where
+        Data: Send + Sync + 'static,
+    {
+        DynMap(Map(self.0.into_dyn()))
+    }
+}
+
+impl<Data: FakeArr + AsRef<[u8]>> Map<Data> {
+    /// Returns the binary contents of this map without copying them.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+
+    /// Returns a view of this map that borrows its bytes.
+    ///
+    /// This is useful to pass an owned map to APIs that are generic over
+    /// slice backed maps. The format is not validated again.
+    pub fn as_slice_view(&self) -> Map<&[u8]> {
+        Map(self.0.as_slice_view())
+    }
 }
 
 impl<Data: FakeArr> fmt::Debug for Map<Data> {
@@ -355,6 +890,249 @@ impl<Data: FakeArr> From<raw::Fst<Data>> for Map<Data> {
     }
 }
 
+/// A map whose data has its type erased.
+///
+/// Maps backed by a memory map, by bytes in memory or by a remote store all
+/// become the same type, so they can be kept in one collection without
+/// making the code that holds them generic. A `DynMap` derefs to a `Map`,
+/// which provides the whole read API, and is `Send` and `Sync`.
+#[derive(Debug)]
+pub struct DynMap(Map<Box<dyn FakeArr + Send + Sync>>);
+
+impl DynMap {
+    /// Erases the type of the data of `map`.
+    pub fn new<Data: FakeArr + Send + Sync + 'static>(map: Map<Data>) -> DynMap {
+        map.into_dyn()
+    }
+
+    /// Returns the map with its data boxed.
+    pub fn into_inner(self) -> Map<Box<dyn FakeArr + Send + Sync>> {
+        self.0
+    }
+}
+
+impl Deref for DynMap {
+    type Target = Map<Box<dyn FakeArr + Send + Sync>>;
+
+    fn deref(&self) -> &Map<Box<dyn FakeArr + Send + Sync>> {
+        &self.0
+    }
+}
+
+impl<Data: FakeArr + Send + Sync + 'static> From<Map<Data>> for DynMap {
+    fn from(map: Map<Data>) -> DynMap {
+        map.into_dyn()
+    }
+}
+
+/// The key-value pairs that could be salvaged from a possibly truncated map.
+///
+/// This is returned by `Map::open_truncated`. The salvaged pairs are split
+/// into fragments, each of which can be streamed on its own. Unless the map
+/// is complete, the keys in a fragment are suffixes of the original keys and
+/// their values are partial, so they are mostly useful for salvage tooling.
+pub struct Recovered<Data: FakeArr = Vec<u8>>(raw::Recovered<Data>);
+
+impl<Data: FakeArr> Recovered<Data> {
+    /// Returns true if the map was intact, in which case the only fragment
+    /// is the whole map.
+    pub fn is_complete(&self) -> bool {
+        self.0.is_complete()
+    }
+
+    /// Returns the number of leading bytes that were recovered.
+    pub fn recovered_len(&self) -> Ulen {
+        self.0.recovered_len()
+    }
+
+    /// Returns the number of fragments that were recovered.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns true if nothing could be recovered.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the number of key-value pairs in the `i`th fragment.
+    pub fn fragment_len(&self, i: usize) -> Ulen {
+        self.0.fragment_len(i)
+    }
+
+    /// Returns a lexicographically ordered stream of the key-value pairs in
+    /// the `i`th fragment.
+    pub fn stream(&self, i: usize) -> Stream<'_> {
+        Stream(self.0.stream(i))
+    }
+
+    /// Returns the underlying recovered finite state transducer.
+    pub fn as_raw(&self) -> &raw::Recovered<Data> {
+        &self.0
+    }
+}
+
+/// How a `MultiMap` picks the value of a key that is in several maps.
+#[derive(Clone)]
+pub enum TieBreak {
+    /// The value from the map added last wins.
+    Newest,
+    /// The value from the map added first wins.
+    Oldest,
+    /// The smallest value wins.
+    SmallestValue,
+    /// The largest value wins.
+    LargestValue,
+    /// The value returned by the callback wins.
+    ///
+    /// The callback is given every occurrence of the key, with the index of
+    /// the map it is in, in increasing order of index.
+    Custom(TieBreakFn),
+}
+
+/// A callback that picks the value of a key among its occurrences. See
+/// `TieBreak::Custom`.
+pub type TieBreakFn = Arc<dyn Fn(&[IndexedValue]) -> u64 + Send + Sync>;
+
+impl TieBreak {
+    /// Picks a value among the occurrences of a key, which must not be
+    /// empty.
+    pub fn resolve(&self, values: &[IndexedValue]) -> u64 {
+        match *self {
+            TieBreak::Newest => values.iter().max_by_key(|v| v.index).unwrap().value,
+            TieBreak::Oldest => values.iter().min_by_key(|v| v.index).unwrap().value,
+            TieBreak::SmallestValue => values.iter().map(|v| v.value).min().unwrap(),
+            TieBreak::LargestValue => values.iter().map(|v| v.value).max().unwrap(),
+            TieBreak::Custom(ref f) => {
+                let mut values = values.to_vec();
+                values.sort_by_key(|v| v.index);
+                f(&values)
+            }
+        }
+    }
+}
+
+impl fmt::Debug for TieBreak {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TieBreak::Newest => write!(f, "Newest"),
+            TieBreak::Oldest => write!(f, "Oldest"),
+            TieBreak::SmallestValue => write!(f, "SmallestValue"),
+            TieBreak::LargestValue => write!(f, "LargestValue"),
+            TieBreak::Custom(_) => write!(f, "Custom(..)"),
+        }
+    }
+}
+
+/// A unified, read only view of several maps, such as the segments of an
+/// index.
+///
+/// Maps are indexed in the order they are added, starting at `0`. When a key
+/// is in several maps, its value is picked by a `TieBreak` policy, which is
+/// `TieBreak::Newest` by default. The same policy is applied by `get`,
+/// `stream` and `search`.
+pub struct MultiMap<'m, Data: FakeArr> {
+    maps: Vec<&'m Map<Data>>,
+    tie_break: TieBreak,
+}
+
+impl<'m, Data: FakeArr> MultiMap<'m, Data> {
+    /// Creates a view of the given maps, from oldest to newest.
+    pub fn new<I: IntoIterator<Item = &'m Map<Data>>>(maps: I) -> MultiMap<'m, Data> {
+        MultiMap {
+            maps: maps.into_iter().collect(),
+            tie_break: TieBreak::Newest,
+        }
+    }
+
+    /// Sets the policy for keys that are in several maps.
+    pub fn tie_break(mut self, tie_break: TieBreak) -> Self {
+        self.tie_break = tie_break;
+        self
+    }
+
+    /// Returns the number of maps in this view.
+    pub fn len(&self) -> usize {
+        self.maps.len()
+    }
+
+    /// Returns true if there are no maps in this view.
+    pub fn is_empty(&self) -> bool {
+        self.maps.is_empty()
+    }
+
+    /// Tests the membership of a single key in any of the maps.
+    pub fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> bool {
+        let key = key.as_ref();
+        self.maps.iter().any(|map| map.contains_key(key))
+    }
+
+    /// Retrieves the value associated with a key, picked by the tie-break
+    /// policy if the key is in several maps.
+    ///
+    /// If the key is in none of the maps, then `None` is returned.
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Option<u64> {
+        let key = key.as_ref();
+        let values: Vec<IndexedValue> = self
+            .maps
+            .iter()
+            .enumerate()
+            .filter_map(|(i, map)| {
+                map.get(key).map(|value| IndexedValue {
+                    index: i as Ulen,
+                    value,
+                })
+            })
+            .collect();
+        if values.is_empty() {
+            None
+        } else {
+            Some(self.tie_break.resolve(&values))
+        }
+    }
+
+    /// Returns a lexicographically ordered stream of every key in any of the
+    /// maps, along with its value.
+    pub fn stream(&self) -> MultiStream<'m> {
+        self.search(AlwaysMatch)
+    }
+
+    /// Returns a lexicographically ordered stream of every key matched by
+    /// `aut` in any of the maps, along with its value.
+    pub fn search<A>(&self, aut: A) -> MultiStream<'m>
+    where
+        A: 'm + Automaton + Clone,
+    {
+        let mut op = OpBuilder::new();
+        for map in &self.maps {
+            op.push(map.search(aut.clone()));
+        }
+        MultiStream {
+            union: op.union(),
+            tie_break: self.tie_break.clone(),
+        }
+    }
+}
+
+/// A lexicographically ordered stream of key-value pairs from a `MultiMap`.
+///
+/// The `'m` lifetime parameter refers to the lifetime of the underlying maps.
+pub struct MultiStream<'m> {
+    union: Union<'m>,
+    tie_break: TieBreak,
+}
+
+impl<'a, 'm> Streamer<'a> for MultiStream<'m> {
+    type Item = (FakeArrRef<'a>, u64);
+
+    fn next(&'a mut self) -> Option<Self::Item> {
+        let tie_break = &self.tie_break;
+        self.union
+            .next()
+            .map(|(key, values)| (key, tie_break.resolve(values)))
+    }
+}
+
 /// Returns the underlying finite state transducer.
 impl<Data: FakeArr> AsRef<raw::Fst<Data>> for Map<Data> {
     #[inline]
@@ -449,6 +1227,20 @@ impl<W: io::Write> MapBuilder<W> {
         raw::Builder::new_type(wtr, 0).map(MapBuilder)
     }
 
+    /// Create a builder that writes runs of nodes with a single transition
+    /// as chains, which shrinks maps of keys like URLs and file paths. See
+    /// `raw::Builder::new_with_chains`.
+    pub fn new_with_chains(wtr: W) -> Result<MapBuilder<W>> {
+        raw::Builder::new_with_chains(wtr).map(MapBuilder)
+    }
+
+    /// Create a builder that stores every distinct value once and uses
+    /// indices into that table as outputs. See
+    /// `raw::Builder::new_dictionary_encoded`.
+    pub fn new_dictionary_encoded(wtr: W) -> Result<MapBuilder<W>> {
+        raw::Builder::new_dictionary_encoded(wtr).map(MapBuilder)
+    }
+
     /// Insert a new key-value pair into the map.
     ///
     /// Keys must be convertible to byte strings. Values must be a `u64`, which
@@ -462,6 +1254,66 @@ impl<W: io::Write> MapBuilder<W> {
         self.0.insert(key, val)
     }
 
+    /// Insert the composite key made of `components`, as joined by
+    /// `escape::join`.
+    ///
+    /// Components may contain `escape::SEPARATOR`. Composite keys must be
+    /// inserted in the order of their lists of components.
+    pub fn insert_components<I, C>(&mut self, components: I, val: u64) -> Result<()>
+    where
+        I: IntoIterator<Item = C>,
+        C: AsRef<[u8]>,
+    {
+        self.insert(escape::join(components), val)
+    }
+
+    /// Reports the bytes written by this builder to `observer`. See
+    /// `raw::Builder::set_observer`.
+    pub fn set_observer(&mut self, observer: Arc<dyn raw::ResourceObserver>) {
+        self.0.set_observer(observer)
+    }
+
+    /// Stores the number of keys below every node, which speeds up
+    /// `Map::get_index`, `Map::get_key` and `StreamBuilder::count`. See
+    /// `raw::Builder::with_counts`.
+    pub fn with_counts(&mut self, enabled: bool) {
+        self.0.with_counts(enabled)
+    }
+
+    /// Sets the number of bytes written since the last `flush` at which
+    /// `poll_insert` stops accepting keys until the builder is flushed.
+    pub fn set_high_water_mark(&mut self, bytes: u64) {
+        self.0.set_high_water_mark(bytes)
+    }
+
+    /// Returns the number of bytes written to the underlying writer since
+    /// the last call to `flush`.
+    pub fn bytes_since_flush(&self) -> u64 {
+        self.0.bytes_since_flush()
+    }
+
+    /// Inserts a key-value pair unless the high-water mark was reached, in
+    /// which case `Poll::Pending` is returned and the key must be inserted
+    /// again after a `flush`. See `raw::Builder::poll_insert`.
+    pub fn poll_insert<K: AsRef<[u8]>>(&mut self, key: K, val: u64) -> Result<Poll<()>> {
+        self.0.poll_insert(key, val)
+    }
+
+    /// Flushes the underlying writer and resets the count of bytes written
+    /// since the last flush.
+    pub fn flush(&mut self) -> Result<()> {
+        self.0.flush()
+    }
+
+    /// Insert a new key along with the length of its record, storing the
+    /// running sum of all lengths inserted so far as its value.
+    ///
+    /// Use `Map::offset_of` and `Map::length_of` to find records again. See
+    /// `raw::Builder::insert_length` for details.
+    pub fn insert_length<K: AsRef<[u8]>>(&mut self, key: K, len: u64) -> Result<()> {
+        self.0.insert_length(key, len)
+    }
+
     /// Calls insert on each item in the iterator.
     ///
     /// If an error occurred while adding an element, processing is stopped
@@ -508,15 +1360,99 @@ impl<W: io::Write> MapBuilder<W> {
         self.0.into_inner()
     }
 
+    /// Just like `into_inner`, except the footer is returned instead of
+    /// being written to `W`.
+    ///
+    /// The map cannot be read until the footer is appended to the data
+    /// written to `W`, so the footer can serve as a commit marker.
+    pub fn finish_into_parts(self) -> Result<(W, Vec<u8>)> {
+        self.0.finish_into_parts()
+    }
+
+    /// Switches this builder to diagnostic mode.
+    ///
+    /// In diagnostic mode, keys that are out of order or duplicates are
+    /// skipped and recorded instead of causing an error. At most `cap` such
+    /// keys are recorded. See `raw::Builder::record_invalid_keys`.
+    pub fn record_invalid_keys(&mut self, cap: usize) {
+        self.0.record_invalid_keys(cap)
+    }
+
+    /// Returns the report of rejected keys, if this builder is in diagnostic
+    /// mode.
+    pub fn key_report(&self) -> Option<&raw::KeyReport> {
+        self.0.key_report()
+    }
+
+    /// Just like `into_inner`, except it also returns the report of rejected
+    /// keys.
+    pub fn into_inner_with_report(self) -> Result<(W, raw::KeyReport)> {
+        self.0.into_inner_with_report()
+    }
+
     /// Gets a reference to the underlying writer.
     pub fn get_ref(&self) -> &W {
         self.0.get_ref()
     }
 
+    /// Gets a mutable reference to the underlying writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.0.get_mut()
+    }
+
     /// Returns the number of bytes written to the underlying writer
     pub fn bytes_written(&self) -> u64 {
         self.0.bytes_written()
     }
+
+    /// Returns the number of bytes this builder holds on the heap, not
+    /// counting the writer. See `raw::Builder::heap_usage`.
+    pub fn heap_usage(&self) -> usize {
+        self.0.heap_usage()
+    }
+
+    /// Returns a scope in which keys are inserted as suffixes of `prefix`.
+    ///
+    /// Keys inserted through the scope are checked like any other key, so
+    /// suffixes must be inserted in lexicographic order. See
+    /// `raw::Builder::scope`.
+    pub fn scope<K: AsRef<[u8]>>(&mut self, prefix: K) -> KeyScope<'_, W> {
+        KeyScope(self.0.scope(prefix))
+    }
+
+    /// Returns a checkpoint that `rollback` can return this builder to. See
+    /// `raw::Builder::checkpoint`.
+    pub fn checkpoint(&mut self) -> raw::Checkpoint {
+        self.0.checkpoint()
+    }
+}
+
+impl<W: raw::Truncate> MapBuilder<W> {
+    /// Returns this builder to `checkpoint`, undoing every key-value pair inserted
+    /// after it, and truncating the writer accordingly. See
+    /// `raw::Builder::rollback`.
+    pub fn rollback(&mut self, checkpoint: raw::Checkpoint) -> Result<()> {
+        self.0.rollback(checkpoint)
+    }
+}
+
+/// A scope of a `MapBuilder` that accepts only the suffix of each key, all
+/// keys sharing the same prefix.
+///
+/// This is created by `MapBuilder::scope`.
+pub struct KeyScope<'b, W>(raw::KeyScope<'b, W>);
+
+impl<'b, W: io::Write> KeyScope<'b, W> {
+    /// Returns the prefix shared by all keys in this scope.
+    pub fn prefix(&self) -> &[u8] {
+        self.0.prefix()
+    }
+
+    /// Inserts the key made of the prefix followed by `suffix`, with the
+    /// given value.
+    pub fn insert<K: AsRef<[u8]>>(&mut self, suffix: K, val: u64) -> Result<()> {
+        self.0.insert(suffix, val)
+    }
 }
 
 /// A lexicographically ordered stream of key-value pairs from a map.
@@ -537,7 +1473,29 @@ impl<'a, 'm, A: Automaton> Streamer<'a> for Stream<'m, A> {
     }
 }
 
+impl<'a, 'm, A: Automaton> TryStreamer<'a> for Stream<'m, A> {
+    type Item = (FakeArrRef<'a>, u64);
+
+    fn try_next(&'a mut self) -> Option<Result<Self::Item>> {
+        Some(self.0.try_next()?.map(|(key, out)| (key, out.value())))
+    }
+}
+
+impl<'m, A: Automaton + Clone> Stream<'m, A> {
+    /// Returns the next key and value from the other end of this stream.
+    /// See `raw::Stream::next_back`.
+    pub fn next_back(&mut self) -> Option<(FakeArrRef<'_>, u64)> {
+        self.0.next_back().map(|(key, out)| (key, out.value()))
+    }
+}
+
 impl<'m, A: Automaton> Stream<'m, A> {
+    /// Returns the number of bytes this stream holds on the heap. See
+    /// `raw::Stream::heap_usage`.
+    pub fn heap_usage(&self) -> usize {
+        self.0.heap_usage()
+    }
+
     /// Convert this stream into a vector of byte strings and outputs.
     ///
     /// Note that this creates a new allocation for every key in the stream.
@@ -555,6 +1513,24 @@ impl<'m, A: Automaton> Stream<'m, A> {
         self.0.into_str_vec()
     }
 
+    /// Convert this stream into a stream of Unicode strings and outputs.
+    ///
+    /// Unlike `into_str_vec`, this does not allocate for every key. If a key
+    /// is not valid UTF-8, then a UTF-8 decoding error is yielded in its
+    /// place.
+    pub fn into_str_stream(self) -> StrStream<'m, A> {
+        StrStream(self.0.into_str_stream())
+    }
+
+    /// Convert this stream into a stream of keys transformed by `map` and
+    /// values. See `Map::stream_mapped`.
+    pub fn into_mapped<F>(self, map: F) -> MappedStream<'m, F, A>
+    where
+        F: for<'k> FnMut(&'k [u8]) -> Cow<'k, [u8]>,
+    {
+        MappedStream(self.0.into_mapped(map))
+    }
+
     /// Convert this stream into a vector of byte strings.
     ///
     /// Note that this creates a new allocation for every key in the stream.
@@ -576,14 +1552,86 @@ impl<'m, A: Automaton> Stream<'m, A> {
     pub fn into_values(self) -> Vec<u64> {
         self.0.into_values()
     }
+
+    /// Appends up to `max_items` key-value pairs to flat, columnar buffers
+    /// and returns the number of pairs appended.
+    ///
+    /// Each key's bytes are appended to `keys_buf`, the offset just past
+    /// them to `offsets` and its value to `values`. See `raw::Stream::fill`.
+    pub fn fill(
+        &mut self,
+        keys_buf: &mut Vec<u8>,
+        offsets: &mut Vec<u32>,
+        values: &mut Vec<u64>,
+        max_items: usize,
+    ) -> usize {
+        self.0.fill(keys_buf, offsets, values, max_items)
+    }
+
+    /// Returns the current upper bound of this stream.
+    pub fn upper_bound(&self) -> raw::BoundCursor {
+        self.0.upper_bound()
+    }
+
+    /// Tightens the upper bound of this stream to the smaller of its current
+    /// upper bound and `bound`.
+    ///
+    /// This is useful for merge drivers that share a single, shrinking upper
+    /// bound between several streams.
+    pub fn clamp_upper(&mut self, bound: &raw::BoundCursor) {
+        self.0.clamp_upper(bound)
+    }
+}
+
+/// A lexicographically ordered stream of Unicode keys and values from a map.
+///
+/// This is created by `Stream::into_str_stream`.
+pub struct StrStream<'m, A = AlwaysMatch>(raw::StrStream<'m, A>)
+where
+    A: Automaton;
+
+impl<'a, 'm, A: Automaton> Streamer<'a> for StrStream<'m, A> {
+    type Item = Result<(&'a str, u64)>;
+
+    fn next(&'a mut self) -> Option<Self::Item> {
+        self.0
+            .next()
+            .map(|res| res.map(|(key, out)| (key, out.value())))
+    }
+}
+
+/// A stream of transformed keys and values from a map.
+///
+/// Keys are emitted in the order of the map, which may not be the order of
+/// the transformed keys. This is created by `Map::stream_mapped` or
+/// `Stream::into_mapped`.
+pub struct MappedStream<'m, F, A = AlwaysMatch>(raw::MappedStream<'m, F, A>)
+where
+    A: Automaton;
+
+impl<'a, 'm, F, A> Streamer<'a> for MappedStream<'m, F, A>
+where
+    F: for<'k> FnMut(&'k [u8]) -> Cow<'k, [u8]>,
+    A: Automaton,
+{
+    type Item = (Cow<'a, [u8]>, u64);
+
+    fn next(&'a mut self) -> Option<Self::Item> {
+        self.0.next().map(|(key, out)| (key, out.value()))
+    }
 }
 
 /// A lexicographically ordered stream of keys from a map.
 ///
+/// This is created by `Map::keys`, `Map::keys_rev` or `StreamBuilder::keys`,
+/// the last two of which may stream in reverse order.
+///
 /// The `'m` lifetime parameter refers to the lifetime of the underlying map.
-pub struct Keys<'m>(raw::Stream<'m>);
+pub struct Keys<'m, A = AlwaysMatch>(raw::Stream<'m, A>)
+where
+    A: Automaton;
 
-impl<'a, 'm> Streamer<'a> for Keys<'m> {
+impl<'a, 'm, A: Automaton> Streamer<'a> for Keys<'m, A> {
     type Item = FakeArrRef<'a>;
 
     #[inline]
@@ -592,13 +1640,27 @@ impl<'a, 'm> Streamer<'a> for Keys<'m> {
     }
 }
 
+impl<'a, 'm, A: Automaton> TryStreamer<'a> for Keys<'m, A> {
+    type Item = FakeArrRef<'a>;
+
+    fn try_next(&'a mut self) -> Option<Result<Self::Item>> {
+        Some(self.0.try_next()?.map(|(key, _)| key))
+    }
+}
+
 /// A stream of values from a map, lexicographically ordered by each value's
 /// corresponding key.
 ///
+/// This is created by `Map::values`, `Map::values_rev` or
+/// `StreamBuilder::values`, the last two of which may stream in reverse
+/// order.
+///
 /// The `'m` lifetime parameter refers to the lifetime of the underlying map.
-pub struct Values<'m>(raw::Stream<'m>);
+pub struct Values<'m, A = AlwaysMatch>(raw::Stream<'m, A>)
+where
+    A: Automaton;
 
-impl<'a, 'm> Streamer<'a> for Values<'m> {
+impl<'a, 'm, A: Automaton> Streamer<'a> for Values<'m, A> {
     type Item = u64;
 
     #[inline]
@@ -607,6 +1669,84 @@ impl<'a, 'm> Streamer<'a> for Values<'m> {
     }
 }
 
+impl<'a, 'm, A: Automaton> TryStreamer<'a> for Values<'m, A> {
+    type Item = u64;
+
+    fn try_next(&'a mut self) -> Option<Result<Self::Item>> {
+        Some(self.0.try_next()?.map(|(_, out)| out.value()))
+    }
+}
+
+/// A lexicographically ordered stream of keys from a map along with the
+/// states of the automaton.
+///
+/// This is created by `StreamBuilder::keys_with_state`.
+pub struct KeysWithState<'m, A = AlwaysMatch>(raw::StreamWithState<'m, A>)
+where
+    A: Automaton;
+
+impl<'a, 'm, A: 'a + Automaton> Streamer<'a> for KeysWithState<'m, A>
+where
+    A::State: Clone,
+{
+    type Item = (FakeArrRef<'a>, A::State);
+
+    #[inline]
+    fn next(&'a mut self) -> Option<Self::Item> {
+        self.0.next().map(|(key, _, state)| (key, state))
+    }
+}
+
+/// A stream of values from a map along with the states of the automaton,
+/// lexicographically ordered by each value's corresponding key.
+///
+/// This is created by `StreamBuilder::values_with_state`.
+pub struct ValuesWithState<'m, A = AlwaysMatch>(raw::StreamWithState<'m, A>)
+where
+    A: Automaton;
+
+impl<'a, 'm, A: 'a + Automaton> Streamer<'a> for ValuesWithState<'m, A>
+where
+    A::State: Clone,
+{
+    type Item = (u64, A::State);
+
+    #[inline]
+    fn next(&'a mut self) -> Option<Self::Item> {
+        self.0.next().map(|(_, out, state)| (out.value(), state))
+    }
+}
+
+/// A lexicographically ordered stream of keys that are close to a term,
+/// along with their values and their edit distance from the term.
+///
+/// This is created by `Map::fuzzy_search`.
+pub struct FuzzyStream<'m>(raw::StreamWithState<'m, Levenshtein>);
+
+impl<'a, 'm> Streamer<'a> for FuzzyStream<'m> {
+    type Item = (FakeArrRef<'a>, u64, u8);
+
+    fn next(&'a mut self) -> Option<Self::Item> {
+        // Every key is a match, so its state always holds a distance that
+        // is at most the maximum distance, which fits in a `u8`.
+        self.0.next().map(|(key, out, state)| {
+            let dist = state.and_then(|row| row.edit_distance()).unwrap();
+            (key, out.value(), dist as u8)
+        })
+    }
+}
+
+/// A key suggested by `Map::suggest`.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Suggestion {
+    /// The suggested key.
+    pub key: Vec<u8>,
+    /// The value of the key in the map.
+    pub value: u64,
+    /// The edit distance between the key and the term.
+    pub distance: u8,
+}
+
 /// A builder for constructing range queries on streams.
 ///
 /// Once all bounds are set, one should call `into_stream` to get a
@@ -642,16 +1782,152 @@ impl<'m, A: Automaton> StreamBuilder<'m, A> {
         StreamBuilder(self.0.lt(bound))
     }
 
+    /// Specify both bounds from a standard range. See
+    /// `raw::StreamBuilder::range_bounds`.
+    pub fn range_bounds<R: raw::KeyRange>(self, range: R) -> Self {
+        StreamBuilder(self.0.range_bounds(range))
+    }
+
     /// Make it iterate backwards.
     pub fn backward(self) -> Self {
         StreamBuilder(self.0.backward())
     }
 
+    /// Streams backward from just below `key`. See
+    /// `raw::StreamBuilder::before`.
+    pub fn before<T: AsRef<[u8]>>(self, key: T) -> Self {
+        StreamBuilder(self.0.before(key))
+    }
+
+    /// Specify an upper bound from a `BoundCursor`.
+    pub fn upper(self, bound: raw::BoundCursor) -> Self {
+        StreamBuilder(self.0.upper(bound))
+    }
+
+    /// Sets the number of key bytes the stream allocates room for up front.
+    ///
+    /// Pre-allocating for the longest key avoids reallocating during
+    /// iteration. The default is the length of the longest key in the map
+    /// when it is known, up to 64 KiB, and 128 otherwise. See
+    /// `raw::StreamBuilder::key_capacity`.
+    pub fn key_capacity(self, capacity: usize) -> Self {
+        StreamBuilder(self.0.key_capacity(capacity))
+    }
+
+    /// Reports every node the stream visits to `observer`.
+    pub fn observer(self, observer: Arc<dyn raw::ResourceObserver>) -> Self {
+        StreamBuilder(self.0.observer(observer))
+    }
+
+    /// Returns the lower and upper bounds set on this builder.
+    pub fn bounds(&self) -> (std::ops::Bound<&[u8]>, std::ops::Bound<&[u8]>) {
+        self.0.bounds()
+    }
+
+    /// Returns the automaton that filters the stream.
+    pub fn automaton(&self) -> &A {
+        self.0.automaton()
+    }
+
+    /// Returns true if the stream will be streamed backward.
+    pub fn is_backward(&self) -> bool {
+        self.0.is_backward()
+    }
+
+    /// Replaces the automaton with `f(automaton)`, keeping every other
+    /// setting of this builder. See `raw::StreamBuilder::map_automaton`.
+    pub fn map_automaton<B, F>(self, f: F) -> StreamBuilder<'m, B>
+    where
+        B: Automaton,
+        F: FnOnce(A) -> B,
+    {
+        StreamBuilder(self.0.map_automaton(f))
+    }
+
+    /// Returns the number of keys this builder would stream, without
+    /// streaming them. See `raw::StreamBuilder::count`.
+    pub fn count(self) -> u64 {
+        self.0.count()
+    }
+
+    /// Returns the `k` key-value pairs with the greatest values among the
+    /// keys this builder streams, in descending order of value. See
+    /// `raw::StreamBuilder::top_values`.
+    pub fn top_values(self, k: usize) -> Vec<(Vec<u8>, u64)> {
+        self.0
+            .top_values(k)
+            .into_iter()
+            .map(|(key, out)| (key, out.value()))
+            .collect()
+    }
+
+    /// Streams the matching key-value pairs until `budget` runs out, and
+    /// returns them with a builder that resumes after the last one when
+    /// there may be more. See `raw::StreamBuilder::into_stream_with_budget`.
+    pub fn into_stream_with_budget(
+        self,
+        budget: raw::Budget,
+    ) -> raw::Page<(Vec<u8>, u64), StreamBuilder<'m, A>> {
+        let page = self.0.into_stream_with_budget(budget);
+        raw::Page {
+            items: page.items,
+            rest: page.rest.map(StreamBuilder),
+        }
+    }
+
+    /// Returns a stream of the matching key-value pairs for async code.
+    /// See `raw::StreamBuilder::into_async_stream`.
+    ///
+    /// This is only available with the `async` feature.
+    #[cfg(feature = "async")]
+    pub fn into_async_stream(self) -> raw::AsyncStream<'m, A> {
+        self.0.into_async_stream()
+    }
+
+    /// Returns the underlying raw stream builder.
+    pub fn into_raw(self) -> raw::StreamBuilder<'m, A> {
+        self.0
+    }
+
     /// Return this builder and gives the automaton states
     /// along with the results.
     pub fn with_state(self) -> StreamWithStateBuilder<'m, A> {
         StreamWithStateBuilder(self.0.with_state())
     }
+
+    /// Return a stream of only the keys matched by this builder, in the
+    /// direction set by `backward`.
+    pub fn keys(self) -> Keys<'m, A> {
+        Keys(self.0.into_stream())
+    }
+
+    /// Return a stream of only the values matched by this builder, in the
+    /// order of their keys set by `backward`.
+    pub fn values(self) -> Values<'m, A> {
+        Values(self.0.into_stream())
+    }
+
+    /// Return a stream of the keys matched by this builder along with the
+    /// automaton states.
+    ///
+    /// This is a shorthand for `with_state().keys()`.
+    pub fn keys_with_state(self) -> KeysWithState<'m, A>
+    where
+        A::State: Clone,
+    {
+        self.with_state().keys()
+    }
+
+    /// Return a stream of the values matched by this builder along with the
+    /// automaton states.
+    ///
+    /// This is a shorthand for `with_state().values()`.
+    pub fn values_with_state(self) -> ValuesWithState<'m, A>
+    where
+        A::State: Clone,
+    {
+        self.with_state().values()
+    }
 }
 
 impl<'m, 'a, A: Automaton> IntoStreamer<'a> for StreamBuilder<'m, A> {
@@ -678,6 +1954,21 @@ impl<'m, 'a, A: Automaton> IntoStreamer<'a> for StreamBuilder<'m, A> {
 /// The `'m` lifetime parameter refers to the lifetime of the underlying map.
 pub struct StreamWithStateBuilder<'m, A = AlwaysMatch>(raw::StreamWithStateBuilder<'m, A>);
 
+impl<'m, A: Automaton> StreamWithStateBuilder<'m, A>
+where
+    A::State: Clone,
+{
+    /// Return a stream of only the keys along with the automaton states.
+    pub fn keys(self) -> KeysWithState<'m, A> {
+        KeysWithState(self.0.into_stream())
+    }
+
+    /// Return a stream of only the values along with the automaton states.
+    pub fn values(self) -> ValuesWithState<'m, A> {
+        ValuesWithState(self.0.into_stream())
+    }
+}
+
 impl<'m, 'a, A: 'a + Automaton> IntoStreamer<'a> for StreamWithStateBuilder<'m, A>
 where
     A::State: Clone,
@@ -955,6 +2246,14 @@ where
 /// The `'m` lifetime parameter refers to the lifetime of the underlying map.
 pub struct Union<'m>(raw::Union<'m>);
 
+impl<'m> Union<'m> {
+    /// Emits only the keys of this operation that `aut` matches, filtering
+    /// them after they are merged. See `raw::OpSearch`.
+    pub fn search<A: Automaton>(self, aut: A) -> OpSearch<'m, A> {
+        OpSearch(self.0.search(aut))
+    }
+}
+
 impl<'a, 'm> Streamer<'a> for Union<'m> {
     type Item = (FakeArrRef<'a>, &'a [IndexedValue]);
 
@@ -970,6 +2269,14 @@ impl<'a, 'm> Streamer<'a> for Union<'m> {
 /// The `'m` lifetime parameter refers to the lifetime of the underlying map.
 pub struct Intersection<'m>(raw::Intersection<'m>);
 
+impl<'m> Intersection<'m> {
+    /// Emits only the keys of this operation that `aut` matches, filtering
+    /// them after they are merged. See `raw::OpSearch`.
+    pub fn search<A: Automaton>(self, aut: A) -> OpSearch<'m, A> {
+        OpSearch(self.0.search(aut))
+    }
+}
+
 impl<'a, 'm> Streamer<'a> for Intersection<'m> {
     type Item = (FakeArrRef<'a>, &'a [IndexedValue]);
 
@@ -989,6 +2296,14 @@ impl<'a, 'm> Streamer<'a> for Intersection<'m> {
 /// The `'m` lifetime parameter refers to the lifetime of the underlying map.
 pub struct Difference<'m>(raw::Difference<'m>);
 
+impl<'m> Difference<'m> {
+    /// Emits only the keys of this operation that `aut` matches, filtering
+    /// them after they are merged. See `raw::OpSearch`.
+    pub fn search<A: Automaton>(self, aut: A) -> OpSearch<'m, A> {
+        OpSearch(self.0.search(aut))
+    }
+}
+
 impl<'a, 'm> Streamer<'a> for Difference<'m> {
     type Item = (&'a [u8], &'a [IndexedValue]);
 
@@ -1004,6 +2319,14 @@ impl<'a, 'm> Streamer<'a> for Difference<'m> {
 /// The `'m` lifetime parameter refers to the lifetime of the underlying map.
 pub struct SymmetricDifference<'m>(raw::SymmetricDifference<'m>);
 
+impl<'m> SymmetricDifference<'m> {
+    /// Emits only the keys of this operation that `aut` matches, filtering
+    /// them after they are merged. See `raw::OpSearch`.
+    pub fn search<A: Automaton>(self, aut: A) -> OpSearch<'m, A> {
+        OpSearch(self.0.search(aut))
+    }
+}
+
 impl<'a, 'm> Streamer<'a> for SymmetricDifference<'m> {
     type Item = (&'a [u8], &'a [IndexedValue]);
 
@@ -1013,6 +2336,21 @@ impl<'a, 'm> Streamer<'a> for SymmetricDifference<'m> {
     }
 }
 
+/// A set operation over map streams whose keys are filtered by an
+/// automaton.
+///
+/// The `'m` lifetime parameter refers to the lifetime of the underlying map.
+pub struct OpSearch<'m, A: Automaton>(raw::OpSearch<'m, A>);
+
+impl<'a, 'm, A: Automaton> Streamer<'a> for OpSearch<'m, A> {
+    type Item = (&'a [u8], &'a [IndexedValue]);
+
+    #[inline]
+    fn next(&'a mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
 /// A specialized stream for mapping map streams (`(&[u8], u64)`) to streams
 /// used by raw fsts (`(&[u8], Output)`).
 ///
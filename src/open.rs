@@ -0,0 +1,127 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use crate::fake_arr::{FakeArr, FileArr};
+use crate::{IoContext, Map, Result};
+
+/// A way for `open_with` to read a map from a file.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Backend {
+    /// Read the whole file into memory.
+    Memory,
+    /// Memory map the file. This needs the `mmap` feature, and is only
+    /// picked by policies that allow it with `OpenPolicy::mmap`.
+    Mmap,
+    /// Read the file on demand, through a `FileArr`.
+    File,
+}
+
+/// Decides how `open_with` reads a map, based on the size of its file.
+///
+/// By default, files of at most 1 MiB are read into memory, and larger
+/// files are read on demand. Memory mapping them instead must be allowed
+/// with `mmap`, which is unsafe.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OpenPolicy {
+    max_in_memory: u64,
+    mmap: bool,
+}
+
+impl Default for OpenPolicy {
+    fn default() -> OpenPolicy {
+        OpenPolicy {
+            max_in_memory: 1 << 20,
+            mmap: false,
+        }
+    }
+}
+
+impl OpenPolicy {
+    /// Creates the default policy.
+    pub fn new() -> OpenPolicy {
+        OpenPolicy::default()
+    }
+
+    /// Reads files of at most `bytes` bytes into memory.
+    pub fn max_in_memory(mut self, bytes: u64) -> OpenPolicy {
+        self.max_in_memory = bytes;
+        self
+    }
+
+    /// Sets whether larger files are memory mapped, rather than read on
+    /// demand. Without the `mmap` feature, they never are.
+    ///
+    /// # Safety
+    ///
+    /// If `yes` is true, the files opened with this policy must not be
+    /// modified or truncated while their maps are open. See
+    /// `Map::from_path`.
+    pub unsafe fn mmap(mut self, yes: bool) -> OpenPolicy {
+        self.mmap = yes && cfg!(feature = "mmap");
+        self
+    }
+
+    /// Returns the backend used for a file of `len` bytes.
+    pub fn backend(&self, len: u64) -> Backend {
+        if len <= self.max_in_memory {
+            Backend::Memory
+        } else if self.mmap {
+            Backend::Mmap
+        } else {
+            Backend::File
+        }
+    }
+}
+
+/// Opens the map in the file at `path`, with the default `OpenPolicy`.
+///
+/// The backend is picked from the size of the file, so applications do not
+/// have to hard-code one.
+pub async fn open<P: AsRef<Path>>(path: P) -> Result<Map<Box<dyn FakeArr + Send + Sync>>> {
+    open_with(path, OpenPolicy::default()).await
+}
+
+/// Opens the map in the file at `path`, with the backend that `policy`
+/// picks for its size.
+///
+/// Files are only memory mapped by policies built with the unsafe
+/// `OpenPolicy::mmap`, whose caller guarantees that they are not modified
+/// while their maps are open.
+pub async fn open_with<P: AsRef<Path>>(
+    path: P,
+    policy: OpenPolicy,
+) -> Result<Map<Box<dyn FakeArr + Send + Sync>>> {
+    let path = path.as_ref();
+    let context = |op: &str, err| IoContext::new(op, err).path(path);
+    let file = File::open(path).map_err(|err| context("opening map", err))?;
+    let len = file
+        .metadata()
+        .map_err(|err| context("reading map metadata", err))?
+        .len();
+    let data: Box<dyn FakeArr + Send + Sync> = match policy.backend(len) {
+        Backend::Memory => {
+            let mut data = Vec::with_capacity(len as usize);
+            (&file)
+                .read_to_end(&mut data)
+                .map_err(|err| context("reading map", err))?;
+            Box::new(data)
+        }
+        #[cfg(feature = "mmap")]
+        Backend::Mmap => {
+            // SAFETY: the policy only picks this backend if the caller of
+            // `OpenPolicy::mmap` guaranteed that the file is not modified
+            // while it is mapped.
+            let mmap = unsafe { memmap2::Mmap::map(&file) }
+                .map_err(|err| context("memory mapping map", err))?;
+            Box::new(mmap)
+        }
+        #[cfg(not(feature = "mmap"))]
+        Backend::Mmap => unreachable!("memory mapping needs the mmap feature"),
+        Backend::File => {
+            let arr = FileArr::new(file).map_err(|err| context("opening map", err))?;
+            Box::new(arr)
+        }
+    };
+    Ok(Map::from(crate::raw::Fst::new(data).await?))
+}
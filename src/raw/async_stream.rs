@@ -0,0 +1,166 @@
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::async_fake_arr::{missing_block, Fetch, Fetched};
+use crate::automaton::{AlwaysMatch, Automaton};
+use crate::error::{Error, Result};
+use crate::fake_arr::{full_slice, FakeArr};
+use crate::raw::{Budget, Fst, Output, StreamBuilder};
+use crate::stream::AsyncStreamer;
+
+/// The number of nodes a stream visits in one page. A page whose reads
+/// wait for a block is collected again once the block arrives, so this
+/// bounds the work that is done twice.
+const NODES_PER_PAGE: u64 = 1024;
+
+impl<Data: FakeArr> Fst<Data> {
+    /// Retrieves the value associated with a key from async code.
+    ///
+    /// When the fst is read from an `AsyncFakeArr`, through a
+    /// `BlockingFakeArr`, the lookup awaits its reads rather than blocking
+    /// on them. Other data is read synchronously. Like `try_get`, a read
+    /// that fails returns an error.
+    ///
+    /// This is only available with the `async` feature.
+    pub async fn get_async<B: AsRef<[u8]>>(&self, key: B) -> Result<Option<Output>> {
+        let data = self.data.full_slice();
+        let (arr, offset) = match data.as_async_at() {
+            None => return self.try_get(key),
+            Some(found) => found,
+        };
+        let mut fst = Fst {
+            meta: self.meta.clone(),
+            data: Fetched::new(arr, offset, data.len()),
+        };
+        loop {
+            let result = fst.try_get(key.as_ref());
+            let index = match result {
+                Err(Error::Io(ref err)) => missing_block(err),
+                _ => None,
+            };
+            match index {
+                None => return result,
+                Some(index) => {
+                    let block = fst.data.fetch(index).await?;
+                    fst.data.insert(block);
+                }
+            }
+        }
+    }
+}
+
+impl<'f, A: Automaton> StreamBuilder<'f, A> {
+    /// Returns a stream of the matching key-value pairs for async code.
+    ///
+    /// When the fst is read from an `AsyncFakeArr`, through a
+    /// `BlockingFakeArr`, the stream awaits its reads rather than blocking
+    /// on them. Other data is read synchronously, in which case a poll runs
+    /// until the next pair is found. A read that fails is emitted as an
+    /// error, after which the stream ends.
+    ///
+    /// This is only available with the `async` feature.
+    pub fn into_async_stream(self) -> AsyncStream<'f, A> {
+        let fetched = self
+            .data
+            .as_async_at()
+            .map(|(arr, offset)| Fetched::new(arr, offset, self.data.len()));
+        AsyncStream {
+            rest: Some(self),
+            items: VecDeque::new(),
+            nodes: NODES_PER_PAGE,
+            fetched,
+            fetch: None,
+        }
+    }
+}
+
+/// A stream of owned key-value pairs for async code. See
+/// `StreamBuilder::into_async_stream`.
+///
+/// This implements both `AsyncStreamer` and the `Stream` trait of the
+/// `futures` crate.
+pub struct AsyncStream<'f, A = AlwaysMatch> {
+    rest: Option<StreamBuilder<'f, A>>,
+    items: VecDeque<(Vec<u8>, u64)>,
+    // The budget of the next page. It grows while pages find nothing, so
+    // that a stream always makes progress.
+    nodes: u64,
+    // The blocks read so far, if the fst is read from an `AsyncFakeArr`.
+    fetched: Option<Fetched<'f>>,
+    // The read of the block the current page waits for.
+    fetch: Option<Fetch<'f>>,
+}
+
+// The stream is never pinned in place, since polling it only moves owned
+// items around, and the read it waits for is boxed.
+impl<'f, A> Unpin for AsyncStream<'f, A> {}
+
+impl<'f, A: Automaton> AsyncStreamer for AsyncStream<'f, A> {
+    type Item = Result<(Vec<u8>, u64)>;
+
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(item) = self.items.pop_front() {
+                return Poll::Ready(Some(Ok(item)));
+            }
+            if let Some(ref mut fetch) = self.fetch {
+                let block = match fetch.as_mut().poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(block) => block,
+                };
+                self.fetch = None;
+                match block {
+                    Ok(block) => {
+                        if let Some(ref mut fetched) = self.fetched {
+                            fetched.insert(block);
+                        }
+                    }
+                    Err(err) => {
+                        self.rest = None;
+                        return Poll::Ready(Some(Err(err.into())));
+                    }
+                }
+            }
+            let rest = match self.rest.take() {
+                None => return Poll::Ready(None),
+                Some(rest) => rest,
+            };
+            let budget = Budget::new().nodes(self.nodes);
+            let page = match self.fetched {
+                Some(ref fetched) => rest.collect_page(budget, full_slice(fetched)),
+                None => rest.collect_page(budget, rest.data),
+            };
+            match page {
+                Ok((items, more)) => {
+                    let page = rest.into_page(items, more);
+                    self.nodes = if page.items.is_empty() {
+                        self.nodes.saturating_mul(2)
+                    } else {
+                        NODES_PER_PAGE
+                    };
+                    self.items.extend(page.items);
+                    self.rest = page.rest;
+                    if let Some(ref mut fetched) = self.fetched {
+                        fetched.trim();
+                    }
+                }
+                Err(err) => match (&self.fetched, missing_block(&err)) {
+                    (Some(fetched), Some(index)) => {
+                        self.fetch = Some(fetched.fetch(index));
+                        self.rest = Some(rest);
+                    }
+                    _ => return Poll::Ready(Some(Err(err.into()))),
+                },
+            }
+        }
+    }
+}
+
+impl<'f, A: Automaton> futures::Stream for AsyncStream<'f, A> {
+    type Item = Result<(Vec<u8>, u64)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        AsyncStreamer::poll_next(self.get_mut(), cx)
+    }
+}
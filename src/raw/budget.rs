@@ -0,0 +1,251 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::automaton::Automaton;
+use crate::fake_arr::{expect_read, FakeArrRef};
+use crate::raw::{Bound, ResourceObserver, StreamBuilder};
+use crate::stream::IntoStreamer;
+
+/// The clock is read once every this many steps of the automaton, since
+/// reading it on every step would dominate the cost of a search.
+const CLOCK_INTERVAL: u64 = 64;
+
+/// Limits on the work done by `StreamBuilder::into_stream_with_budget`.
+///
+/// A budget has no limits until some are set. Whichever limit is reached
+/// first stops the stream.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Budget {
+    items: Option<usize>,
+    nodes: Option<u64>,
+    time: Option<Duration>,
+}
+
+impl Budget {
+    /// Creates a budget without limits.
+    pub fn new() -> Budget {
+        Budget::default()
+    }
+
+    /// Stops once `n` items were collected.
+    pub fn items(mut self, n: usize) -> Budget {
+        self.items = Some(n);
+        self
+    }
+
+    /// Stops once `n` nodes were visited.
+    pub fn nodes(mut self, n: u64) -> Budget {
+        self.nodes = Some(n);
+        self
+    }
+
+    /// Stops once `time` has passed since the stream started.
+    pub fn time(mut self, time: Duration) -> Budget {
+        self.time = Some(time);
+        self
+    }
+}
+
+/// The key-value pairs of a page.
+type Pairs = Vec<(Vec<u8>, u64)>;
+
+/// A page of key-value pairs, and the builder that resumes the stream.
+type StreamPage<'f, A> = Page<(Vec<u8>, u64), StreamBuilder<'f, A>>;
+
+/// The items streamed by `into_stream_with_budget` before the budget ran
+/// out, and the builder that resumes the stream after them.
+#[derive(Debug)]
+pub struct Page<T, B> {
+    /// The items collected, in the order of the stream.
+    pub items: Vec<T>,
+    /// A builder that streams the items after the last one collected, or
+    /// `None` if there are none.
+    pub rest: Option<B>,
+}
+
+impl<'f, A: Automaton> StreamBuilder<'f, A> {
+    /// Streams the matching key-value pairs until `budget` runs out.
+    ///
+    /// This returns the pairs collected so far, along with a builder that
+    /// resumes the stream right after the last of them when there may be
+    /// more. The builder keeps every other setting of this one, so it can
+    /// be run with a new budget, or streamed to the end. It is `None` only
+    /// when the stream was exhausted.
+    ///
+    /// This suits interactive queries such as typeahead, where showing
+    /// some results quickly beats showing all of them. Note that a budget
+    /// too small to reach the next key returns no pairs and a continuation
+    /// equal to this builder.
+    pub fn into_stream_with_budget(self, budget: Budget) -> StreamPage<'f, A> {
+        expect_read(self.try_into_stream_with_budget(budget))
+    }
+
+    /// Like `into_stream_with_budget`, but returns the error of a read that
+    /// fails.
+    pub(crate) fn try_into_stream_with_budget(
+        self,
+        budget: Budget,
+    ) -> io::Result<StreamPage<'f, A>> {
+        let (items, more) = self.collect_page(budget, self.data)?;
+        Ok(self.into_page(items, more))
+    }
+
+    /// Collects the pairs of the page that `budget` allows, reading the fst
+    /// from `data` rather than from the data of this builder, and returns
+    /// whether the stream may have more of them.
+    ///
+    /// This leaves the builder alone, so that a page whose reads failed can
+    /// be collected again.
+    pub(crate) fn collect_page(
+        &self,
+        budget: Budget,
+        data: FakeArrRef<'_>,
+    ) -> io::Result<(Pairs, bool)> {
+        let spent = Arc::new(Spent {
+            max_nodes: budget.nodes.unwrap_or(u64::MAX),
+            deadline: budget.time.map(|time| Instant::now() + time),
+            nodes: AtomicU64::new(0),
+            steps: AtomicU64::new(0),
+            exhausted: AtomicBool::new(false),
+            inner: self.observer.clone(),
+        });
+        let max_items = budget.items.unwrap_or(usize::MAX);
+        let mut items = vec![];
+        let more = {
+            let budgeted = StreamBuilder {
+                meta: self.meta,
+                data,
+                aut: Budgeted {
+                    aut: &self.aut,
+                    spent: spent.clone(),
+                },
+                min: self.min.clone(),
+                max: self.max.clone(),
+                min_derived: self.min_derived,
+                max_derived: self.max_derived,
+                backward: self.backward,
+                key_capacity: self.key_capacity,
+                observer: Some(spent.clone() as Arc<dyn ResourceObserver>),
+            };
+            let mut stream = budgeted.into_stream();
+            while items.len() < max_items {
+                match stream.advance_front()? {
+                    None => break,
+                    Some((len, out)) => {
+                        items.push((stream.0.inp.prefix(len).to_vec(), out.value()))
+                    }
+                }
+            }
+            // Look one key ahead, so that a stream that ends right at the
+            // limit on items does not return a continuation.
+            items.len() == max_items && stream.advance_front()?.is_some()
+        };
+        Ok((items, more || spent.exhausted.load(Ordering::Relaxed)))
+    }
+
+    /// Returns the page of `items`, collected by `collect_page`, with a
+    /// builder that resumes the stream after them if it may have more.
+    pub(crate) fn into_page(self, items: Pairs, more: bool) -> StreamPage<'f, A> {
+        if !more {
+            return Page { items, rest: None };
+        }
+        let mut rest = self;
+        if let Some((last, _)) = items.last() {
+            if rest.backward {
+                rest.set_max(Bound::Excluded(last.clone()));
+            } else {
+                rest.set_min(Bound::Excluded(last.clone()));
+            }
+        }
+        Page {
+            items,
+            rest: Some(rest),
+        }
+    }
+}
+
+/// The work done by a budgeted stream so far. This is both the observer of
+/// the stream, to count nodes, and part of its automaton, to stop it.
+struct Spent {
+    max_nodes: u64,
+    deadline: Option<Instant>,
+    nodes: AtomicU64,
+    steps: AtomicU64,
+    exhausted: AtomicBool,
+    inner: Option<Arc<dyn ResourceObserver>>,
+}
+
+impl Spent {
+    fn is_exhausted(&self) -> bool {
+        self.exhausted.load(Ordering::Relaxed)
+    }
+
+    /// Takes a step, and returns false if the budget ran out.
+    fn step(&self) -> bool {
+        if self.is_exhausted() {
+            return false;
+        }
+        let steps = self.steps.fetch_add(1, Ordering::Relaxed);
+        let late = steps % CLOCK_INTERVAL == 0
+            && self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline);
+        if late || self.nodes.load(Ordering::Relaxed) >= self.max_nodes {
+            self.exhausted.store(true, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+}
+
+impl ResourceObserver for Spent {
+    fn node_visited(&self, bytes_read: u64) {
+        self.nodes.fetch_add(1, Ordering::Relaxed);
+        if let Some(ref inner) = self.inner {
+            inner.node_visited(bytes_read);
+        }
+    }
+}
+
+/// Wraps the automaton of a budgeted stream. Once the budget runs out,
+/// nothing matches anymore, not even keys whose states were computed
+/// before, so that every key after the last one emitted is left to the
+/// continuation.
+struct Budgeted<'a, A> {
+    aut: &'a A,
+    spent: Arc<Spent>,
+}
+
+impl<'a, A: Automaton> Automaton for Budgeted<'a, A> {
+    type State = Option<A::State>;
+
+    fn start(&self) -> Option<A::State> {
+        Some(self.aut.start())
+    }
+
+    fn is_match(&self, state: &Option<A::State>) -> bool {
+        !self.spent.is_exhausted() && state.as_ref().is_some_and(|s| self.aut.is_match(s))
+    }
+
+    fn can_match(&self, state: &Option<A::State>) -> bool {
+        !self.spent.is_exhausted() && state.as_ref().is_some_and(|s| self.aut.can_match(s))
+    }
+
+    fn will_always_match(&self, _state: &Option<A::State>) -> bool {
+        // The budget may run out, so nothing is certain to match.
+        false
+    }
+
+    fn accept(&self, state: &Option<A::State>, byte: u8) -> Option<A::State> {
+        if !self.spent.step() {
+            return None;
+        }
+        state.as_ref().map(|s| self.aut.accept(s, byte))
+    }
+
+    fn literal_prefix(&self) -> &[u8] {
+        self.aut.literal_prefix()
+    }
+}
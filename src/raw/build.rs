@@ -1,16 +1,30 @@
+use std::cmp;
+use std::collections::HashMap;
 use std::io::{self, Write};
+use std::mem;
+use std::sync::Arc;
+use std::task::Poll;
 
 use byteorder::{LittleEndian, WriteBytesExt};
 
-use crate::{error::Result, fake_arr::{FakeArrRef, Ulen}};
+use crate::{error::{IoContext, Result}, fake_arr::{FakeArrRef, Ulen}};
 use crate::raw::counting_writer::CountingWriter;
 use crate::raw::error::Error;
+use crate::raw::footer::{
+    write_footer, TAG_MAX_KEY_LEN, TAG_SUBTREE_COUNTS, TAG_VALUE_TABLE, VALUE_TABLE_VERSION,
+};
+use crate::raw::node::{StateChain, CHAINS_VERSION, MAX_CHAIN_LEN};
 use crate::raw::registry::{Registry, RegistryEntry};
-use crate::raw::{CompiledAddr, FstType, Output, Transition, EMPTY_ADDRESS, NONE_ADDRESS, VERSION};
+use crate::raw::ResourceObserver;
+use crate::raw::{CompiledAddr, FstType, Output, Transition, EMPTY_ADDRESS, NONE_ADDRESS, DEFAULT_VERSION};
 // use raw::registry_minimal::{Registry, RegistryEntry};
 use crate::stream::{IntoStreamer, Streamer};
 use crate::fake_arr::FakeArr;
 
+/// The least number of keys below a node for its count to be stored by
+/// `Builder::with_counts`. Readers count the keys below smaller nodes.
+const COUNT_THRESHOLD: u64 = 64;
+
 /// A builder for creating a finite state transducer.
 ///
 /// This is not your average everyday builder. It has two important qualities
@@ -69,17 +83,201 @@ pub struct Builder<W> {
     last_addr: CompiledAddr,
     /// The number of keys added.
     len: Ulen,
+    /// The sum of all lengths inserted with `insert_length`.
+    running_total: u64,
+    /// The length of the longest key added.
+    max_key_len: usize,
+    /// Invalid keys that were skipped, when running in diagnostic mode.
+    ///
+    /// When this is `None`, invalid keys result in an error instead.
+    report: Option<KeyReport>,
+    /// The number of bytes written since the last flush at which
+    /// `poll_insert` asks the caller to wait.
+    high_water_mark: Option<u64>,
+    /// The number of bytes written as of the last call to `flush`.
+    flushed: u64,
+    /// The distinct values inserted so far and their indices, when values
+    /// are dictionary encoded.
+    value_table: Option<(Vec<u64>, HashMap<u64, u64>)>,
+    /// The number of keys below the nodes written so far that branch and
+    /// have at least `COUNT_THRESHOLD` keys below them, sorted by address,
+    /// when subtree counts are embedded.
+    counts: Option<Vec<(CompiledAddr, u64)>>,
+    /// The version and type of the fst, written again when the builder is
+    /// reset.
+    version: u64,
+    ty: FstType,
+    /// Whether runs of nodes with a single transition are written as chains.
+    chains: bool,
+    /// The number of checkpoints taken so far.
+    checkpoints: u64,
+    /// Ranges of checkpoints that can no longer be rolled back to, since
+    /// they were taken before a reset, or after the checkpoint of a
+    /// rollback.
+    stale: Vec<(u64, u64)>,
+}
+
+/// A report of the keys that a builder rejected in diagnostic mode.
+///
+/// See `Builder::record_invalid_keys`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KeyReport {
+    rejected: Vec<RejectedKey>,
+    total: u64,
+    cap: usize,
+}
+
+/// A key that was rejected by a builder in diagnostic mode.
+///
+/// Keys are recorded byte for byte exactly as they were given to the
+/// builder.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RejectedKey {
+    /// Why the key was rejected.
+    pub reason: RejectReason,
+    /// The last key that was successfully inserted before this one.
+    pub previous: Vec<u8>,
+    /// The rejected key.
+    pub key: Vec<u8>,
+    /// The value that was to be associated with the rejected key, if any.
+    pub value: Option<u64>,
+}
+
+/// The reason a key was rejected by a builder.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RejectReason {
+    /// The key was equal to the previous key.
+    Duplicate,
+    /// The key was less than the previous key.
+    OutOfOrder,
 }
 
+impl KeyReport {
+    fn new(cap: usize) -> KeyReport {
+        KeyReport {
+            rejected: vec![],
+            total: 0,
+            cap,
+        }
+    }
+
+    fn record(&mut self, rejected: RejectedKey) {
+        self.total += 1;
+        if self.rejected.len() < self.cap {
+            self.rejected.push(rejected);
+        }
+    }
+
+    /// Returns the recorded rejected keys, in the order they were given.
+    ///
+    /// At most the capacity given to `Builder::record_invalid_keys` keys are
+    /// recorded.
+    pub fn rejected(&self) -> &[RejectedKey] {
+        &self.rejected
+    }
+
+    /// Returns the total number of rejected keys, including those that were
+    /// not recorded because the report was full.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Returns true if and only if no keys were rejected.
+    pub fn is_empty(&self) -> bool {
+        self.total == 0
+    }
+
+    /// Returns true if and only if some rejected keys were not recorded
+    /// because the report was full.
+    pub fn is_truncated(&self) -> bool {
+        self.total > self.rejected.len() as u64
+    }
+}
+
+/// A point in the build of an fst that a builder can be rolled back to.
+///
+/// This is returned by `Builder::checkpoint` and consumed by
+/// `Builder::rollback`.
 #[derive(Debug)]
+pub struct Checkpoint {
+    seq: u64,
+    offset: u64,
+    unfinished: UnfinishedNodes,
+    last: Option<Vec<u8>>,
+    last_addr: CompiledAddr,
+    len: Ulen,
+    running_total: u64,
+    max_key_len: usize,
+    report: Option<KeyReport>,
+    values: usize,
+}
+
+/// A writer whose last bytes can be discarded, so that a builder writing to
+/// it can be rolled back with `Builder::rollback`.
+///
+/// Rolling back truncates every byte written since the checkpoint, so all of
+/// them must still be in the writer. Draining the writer in the meantime,
+/// for instance through `Builder::get_mut`, makes the rollback fail or cut
+/// into bytes that were written before the checkpoint.
+pub trait Truncate: io::Write {
+    /// Discards the last `n` bytes written.
+    ///
+    /// This returns an error if fewer than `n` bytes are in the writer.
+    fn truncate_end(&mut self, n: u64) -> io::Result<()>;
+}
+
+/// The error returned when asked to truncate more bytes than a writer holds.
+fn truncate_past_start(n: u64, len: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("cannot truncate {} bytes from a writer holding {}", n, len),
+    )
+}
+
+impl Truncate for Vec<u8> {
+    fn truncate_end(&mut self, n: u64) -> io::Result<()> {
+        let len = (self.len() as u64)
+            .checked_sub(n)
+            .ok_or_else(|| truncate_past_start(n, self.len() as u64))?;
+        self.truncate(len as usize);
+        Ok(())
+    }
+}
+
+impl Truncate for std::fs::File {
+    fn truncate_end(&mut self, n: u64) -> io::Result<()> {
+        use std::io::{Seek, SeekFrom};
+
+        let end = self.stream_position()?;
+        let pos = end.checked_sub(n).ok_or_else(|| truncate_past_start(n, end))?;
+        self.set_len(pos)?;
+        self.seek(SeekFrom::Start(pos))?;
+        Ok(())
+    }
+}
+
+impl<W: Truncate> Truncate for io::BufWriter<W> {
+    fn truncate_end(&mut self, n: u64) -> io::Result<()> {
+        self.flush()?;
+        self.get_mut().truncate_end(n)
+    }
+}
+
+#[derive(Clone, Debug)]
 struct UnfinishedNodes {
     stack: Vec<BuilderNodeUnfinished>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct BuilderNodeUnfinished {
     node: BuilderNode,
     last: Option<LastTransition>,
+    /// The index of the first key below this node. Every key from it on is
+    /// below it, until the node is compiled.
+    first_key: u64,
 }
 
 #[derive(Debug, Hash, Eq, PartialEq)]
@@ -89,7 +287,7 @@ pub struct BuilderNode {
     pub trans: Vec<Transition>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct LastTransition {
     inp: u8,
     out: Output,
@@ -103,6 +301,63 @@ impl Builder<Vec<u8>> {
     }
 }
 
+impl<W: Truncate> Builder<W> {
+    /// Returns this builder to `checkpoint`, as if no key was inserted
+    /// after it was taken.
+    ///
+    /// The nodes written since are truncated from the writer, and forgotten
+    /// by the registry of compiled nodes, as are the values and subtree
+    /// counts recorded for them. Keys inserted after the rollback are
+    /// compressed against the nodes written before the checkpoint, though
+    /// nodes the registry evicted in the meantime are not shared again.
+    ///
+    /// Rolling back invalidates the checkpoints taken after `checkpoint`,
+    /// while those taken before it can still be rolled back to.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the builder was reset, or rolled back to an earlier
+    /// checkpoint, since `checkpoint` was taken. It may panic or build a
+    /// corrupt fst if `checkpoint` was taken by another builder.
+    pub fn rollback(&mut self, checkpoint: Checkpoint) -> Result<()> {
+        let (seq, offset) = (checkpoint.seq, checkpoint.offset);
+        let stale = self.stale.iter().any(|&(lo, hi)| lo <= seq && seq <= hi);
+        assert!(
+            !stale && seq <= self.checkpoints && offset <= self.wtr.count(),
+            "checkpoint does not belong to the fst being built"
+        );
+        if seq < self.checkpoints {
+            self.stale.push((seq + 1, self.checkpoints));
+        }
+        self.wtr
+            .truncate_to(offset)
+            .map_err(|err| IoContext::new("truncating to a checkpoint", err).offset(offset))?;
+        self.registry.forget_from(offset as CompiledAddr);
+        if let Some(ref mut counts) = self.counts {
+            counts.retain(|&(addr, _)| addr < offset);
+        }
+        if let Some((ref mut values, ref mut index)) = self.value_table {
+            for value in values.drain(checkpoint.values..) {
+                index.remove(&value);
+            }
+        }
+        self.unfinished = checkpoint.unfinished;
+        self.last = checkpoint.last;
+        self.last_addr = checkpoint.last_addr;
+        self.len = checkpoint.len;
+        self.running_total = checkpoint.running_total;
+        self.max_key_len = checkpoint.max_key_len;
+        if let Some(ref mut report) = self.report {
+            // Diagnostic mode may have been switched on after the checkpoint.
+            *report = checkpoint
+                .report
+                .unwrap_or_else(|| KeyReport::new(report.cap));
+        }
+        self.flushed = cmp::min(self.flushed, offset);
+        Ok(())
+    }
+}
+
 impl<W: io::Write> Builder<W> {
     /// Create a builder that builds an fst by writing it to `wtr` in a
     /// streaming fashion.
@@ -113,11 +368,56 @@ impl<W: io::Write> Builder<W> {
     /// The same as `new`, except it sets the type of the fst to the type
     /// given.
     pub fn new_type(wtr: W, ty: FstType) -> Result<Builder<W>> {
+        Builder::new_version(wtr, ty, DEFAULT_VERSION)
+    }
+
+    /// The same as `new`, except every distinct value is stored once, in a
+    /// table in the footer, and indices into that table are used as outputs.
+    ///
+    /// This shrinks maps that store one of a handful of distinct values,
+    /// such as an enum, especially when those values are large. Indices are
+    /// assigned in the order values are first inserted, and the value `0`
+    /// always has index `0`. Readers map indices back to values, and
+    /// `Fst::distinct_values` returns the table.
+    ///
+    /// Output sums along paths are sums of indices, so
+    /// `Fst::output_along_path` is not meaningful for such fsts, and
+    /// `Fst::top_k_completions` falls back to streaming every key with the
+    /// prefix and ranking the decoded values.
+    ///
+    /// Fsts with a value table have version `VERSION`, so that versions of
+    /// this crate that predate it refuse them rather than return indices as
+    /// values.
+    pub fn new_dictionary_encoded(wtr: W) -> Result<Builder<W>> {
+        let mut builder = Builder::new_version(wtr, 0, VALUE_TABLE_VERSION)?;
+        builder.value_table = Some(Self::empty_value_table());
+        Ok(builder)
+    }
+
+    /// The same as `new`, except runs of nodes with a single transition are
+    /// written as chains.
+    ///
+    /// A chain stores the inputs of the run as one byte string behind a
+    /// single node header, which shrinks fsts whose keys have long unique
+    /// runs, like URLs and file paths. Chains are only written where they
+    /// are smaller than the nodes they replace. The nodes inside a chain
+    /// can't be shared with other keys though, so fsts whose keys share
+    /// long suffixes may grow instead.
+    ///
+    /// Fsts with chains have version `VERSION` and can't be read by
+    /// versions of this crate that predate it.
+    pub fn new_with_chains(wtr: W) -> Result<Builder<W>> {
+        let mut builder = Builder::new_version(wtr, 0, CHAINS_VERSION)?;
+        builder.chains = true;
+        Ok(builder)
+    }
+
+    fn new_version(wtr: W, ty: FstType, version: u64) -> Result<Builder<W>> {
         let mut wtr = CountingWriter::new(wtr);
         // Don't allow any nodes to have address 0-7. We use these to encode
         // the API version. We also use addresses `0` and `1` as special
         // sentinel values, so they should never correspond to a real node.
-        wtr.write_u64::<LittleEndian>(VERSION)?;
+        wtr.write_u64::<LittleEndian>(version)?;
         // Similarly for 8-15 for the fst type.
         wtr.write_u64::<LittleEndian>(ty)?;
         Ok(Builder {
@@ -127,15 +427,183 @@ impl<W: io::Write> Builder<W> {
             last: None,
             last_addr: NONE_ADDRESS,
             len: 0,
+            running_total: 0,
+            max_key_len: 0,
+            report: None,
+            high_water_mark: None,
+            flushed: 0,
+            value_table: None,
+            counts: None,
+            version,
+            ty,
+            chains: false,
+            checkpoints: 0,
+            stale: vec![],
         })
     }
 
+    /// Discards the fst being built and starts a new one, of the same type,
+    /// written to `wtr`. The previous writer is returned.
+    ///
+    /// The registry of compiled nodes and the internal buffers are kept, so
+    /// building many small fsts with one builder, such as the term
+    /// dictionaries of the blocks of an index, avoids allocating several
+    /// megabytes for each of them. Settings are kept too: diagnostic mode
+    /// starts over with an empty report, dictionary encoded values start
+    /// over with an empty table, and the observer and high-water mark stay
+    /// in place.
+    ///
+    /// Nothing more is written to the previous writer, so the fst that was
+    /// being built cannot be read. Use `finish_and_reset` to finish it
+    /// first.
+    pub fn reset(&mut self, wtr: W) -> Result<W> {
+        let old = self.wtr.replace(wtr);
+        self.wtr.write_u64::<LittleEndian>(self.version)?;
+        self.wtr.write_u64::<LittleEndian>(self.ty)?;
+        self.unfinished.clear();
+        self.registry.clear();
+        self.last = None;
+        self.last_addr = NONE_ADDRESS;
+        self.len = 0;
+        self.running_total = 0;
+        self.max_key_len = 0;
+        if let Some(ref mut report) = self.report {
+            *report = KeyReport::new(report.cap);
+        }
+        self.flushed = 0;
+        if self.value_table.is_some() {
+            self.value_table = Some(Self::empty_value_table());
+        }
+        if let Some(ref mut counts) = self.counts {
+            counts.clear();
+        }
+        self.stale.clear();
+        self.stale.push((1, self.checkpoints));
+        Ok(old)
+    }
+
+    /// Finishes the fst being built like `into_inner`, then starts a new
+    /// one written to `wtr` like `reset`. The writer holding the finished
+    /// fst is returned.
+    pub fn finish_and_reset(&mut self, wtr: W) -> Result<W> {
+        let footer = self.finish_body()?;
+        let offset = self.wtr.count();
+        self.wtr
+            .write_all(&footer)
+            .map_err(|err| IoContext::new("writing footer", err).offset(offset))?;
+        self.wtr
+            .flush()
+            .map_err(|err| IoContext::new("flushing", err))?;
+        self.reset(wtr)
+    }
+
+    /// Returns a checkpoint that `rollback` can later return this builder
+    /// to, undoing every key inserted after it.
+    ///
+    /// Taking a checkpoint copies the nodes of the last key inserted, and
+    /// does not write anything, so it can be taken before every batch of
+    /// keys of a long build.
+    pub fn checkpoint(&mut self) -> Checkpoint {
+        self.checkpoints += 1;
+        Checkpoint {
+            seq: self.checkpoints,
+            offset: self.wtr.count(),
+            unfinished: self.unfinished.clone(),
+            last: self.last.clone(),
+            last_addr: self.last_addr,
+            len: self.len,
+            running_total: self.running_total,
+            max_key_len: self.max_key_len,
+            report: self.report.clone(),
+            values: self.value_table.as_ref().map_or(0, |(values, _)| values.len()),
+        }
+    }
+
+    /// Switches this builder to diagnostic mode.
+    ///
+    /// In diagnostic mode, keys that are out of order or duplicates are not
+    /// reported as errors. Instead, they are skipped and recorded in a
+    /// `KeyReport`, which can be retrieved with `key_report` or
+    /// `into_inner_with_report`. At most `cap` rejected keys are recorded,
+    /// but all of them are counted.
+    ///
+    /// This is useful for bulk ingestion, where a handful of bad records
+    /// should be logged rather than abort the entire build.
+    pub fn record_invalid_keys(&mut self, cap: usize) {
+        self.report = Some(KeyReport::new(cap));
+    }
+
+    /// Returns the report of rejected keys, if this builder is in diagnostic
+    /// mode.
+    pub fn key_report(&self) -> Option<&KeyReport> {
+        self.report.as_ref()
+    }
+
+    /// The value table of a builder that was just created or reset, which
+    /// only holds the value `0`.
+    fn empty_value_table() -> (Vec<u64>, HashMap<u64, u64>) {
+        let mut index = HashMap::new();
+        index.insert(0, 0);
+        (vec![0], index)
+    }
+
+    /// Stores the number of keys below every node in the footer when
+    /// `enabled` is true.
+    ///
+    /// Readers use these counts, when present, to answer `Fst::get_index`,
+    /// `Fst::get_key` and `StreamBuilder::count` without counting the keys
+    /// below the nodes they visit. Without them, those keys are counted on
+    /// every call.
+    ///
+    /// Counts are only stored for nodes that branch and have at least 64
+    /// keys below them, which readers then only count below nodes with
+    /// fewer keys, or along runs of single transitions. So they take at most
+    /// 16 bytes per 32 keys, both in the fst and in the builder. Readers
+    /// look them up in place rather than loading them when the fst is
+    /// opened, and versions of this crate that predate them ignore them.
+    ///
+    /// This must be called before any key is inserted.
+    pub fn with_counts(&mut self, enabled: bool) {
+        assert!(self.len == 0, "counts must be embedded from the first key");
+        self.counts = if enabled { Some(vec![]) } else { None };
+    }
+
+    /// Records that `count` keys are below `node`, which is at `addr`, if
+    /// readers need that count.
+    fn record_count(&mut self, node: &BuilderNode, addr: CompiledAddr, count: u64) {
+        if let Some(ref mut counts) = self.counts {
+            let branches = node.trans.len() + node.is_final as usize > 1;
+            // A node found in the registry is recorded already, if at all.
+            let is_new = !matches!(counts.last(), Some(&(last, _)) if last >= addr);
+            if branches && count >= COUNT_THRESHOLD && is_new {
+                counts.push((addr, count));
+            }
+        }
+    }
+
+    /// Returns the output to store for `val`.
+    fn encode(&mut self, val: u64) -> Output {
+        match self.value_table {
+            Some((ref mut values, ref mut index)) => {
+                let next = values.len() as u64;
+                let i = *index.entry(val).or_insert_with(|| {
+                    values.push(val);
+                    next
+                });
+                Output::new(i)
+            }
+            None => Output::new(val),
+        }
+    }
+
     /// Adds a byte string to this FST with a zero output value.
     pub fn add<B>(&mut self, bs: B) -> Result<()>
     where
         B: AsRef<[u8]>,
     {
-        self.check_last_key(bs.as_ref(), false)?;
+        if let Err(err) = self.check_last_key(bs.as_ref(), false) {
+            return self.reject(err, None);
+        }
         self.insert_output(bs, None)
     }
 
@@ -152,8 +620,100 @@ impl<W: io::Write> Builder<W> {
     where
         B: AsRef<[u8]>,
     {
-        self.check_last_key(bs.as_ref(), true)?;
-        self.insert_output(bs, Some(Output::new(val)))
+        if let Err(err) = self.check_last_key(bs.as_ref(), true) {
+            return self.reject(err, Some(val));
+        }
+        let out = self.encode(val);
+        self.insert_output(bs, Some(out))
+    }
+
+    /// Reports the bytes written by this builder to `observer`.
+    ///
+    /// The bytes that were already written, such as the header, are
+    /// reported right away.
+    pub fn set_observer(&mut self, observer: Arc<dyn ResourceObserver>) {
+        observer.bytes_written(self.wtr.count());
+        self.wtr.set_observer(observer);
+    }
+
+    /// Sets the number of bytes written since the last `flush` at which
+    /// `poll_insert` stops accepting keys until the builder is flushed.
+    pub fn set_high_water_mark(&mut self, bytes: u64) {
+        self.high_water_mark = Some(bytes);
+    }
+
+    /// Returns the number of bytes written to the underlying writer since
+    /// the last call to `flush`.
+    ///
+    /// The builder does not buffer these bytes itself. They are counted as
+    /// they are handed to the writer, whatever the writer does with them.
+    pub fn bytes_since_flush(&self) -> u64 {
+        self.wtr.count() - self.flushed
+    }
+
+    /// Inserts a key-value pair unless the high-water mark was reached.
+    ///
+    /// This is the push side of an incremental build fed by an asynchronous
+    /// source. When the bytes written since the last `flush` reach the
+    /// high-water mark set with `set_high_water_mark`, the key is *not*
+    /// inserted and `Poll::Pending` is returned. The caller should then pause
+    /// its source until the writer caught up, for instance when it forwards
+    /// to an async sink, call `flush` and retry the same key. Otherwise, the
+    /// key is inserted just like with `insert` and `Poll::Ready` is returned.
+    ///
+    /// Without a high-water mark, this never returns `Poll::Pending`.
+    pub fn poll_insert<B>(&mut self, bs: B, val: u64) -> Result<Poll<()>>
+    where
+        B: AsRef<[u8]>,
+    {
+        if self
+            .high_water_mark
+            .is_some_and(|mark| self.bytes_since_flush() >= mark)
+        {
+            return Ok(Poll::Pending);
+        }
+        self.insert(bs, val).map(Poll::Ready)
+    }
+
+    /// Flushes the underlying writer and resets the count of bytes written
+    /// since the last flush.
+    pub fn flush(&mut self) -> Result<()> {
+        self.wtr
+            .flush()
+            .map_err(|err| IoContext::new("flushing", err))?;
+        self.flushed = self.wtr.count();
+        Ok(())
+    }
+
+    /// Insert a new key along with the length of its record, storing the
+    /// running sum of all lengths inserted so far as its value.
+    ///
+    /// This is the prefix-sum mode for positional dictionaries. When the
+    /// records of all keys are laid out back to back, in key order, in a
+    /// companion file, the value of each key is the offset just past its
+    /// record. `Fst::offset_of` and `Fst::length_of` then recover where each
+    /// record is. Keys inserted with `add` or `insert` do not contribute to
+    /// the running sum, so the modes should not be mixed.
+    ///
+    /// If the running sum overflows a `u64`, then an error is returned.
+    /// Otherwise, errors are reported just like `insert`.
+    pub fn insert_length<B>(&mut self, bs: B, len: u64) -> Result<()>
+    where
+        B: AsRef<[u8]>,
+    {
+        let bs = bs.as_ref();
+        // Overflow is checked before the key is recorded, so that the builder
+        // is left untouched by the error.
+        let end = match self.running_total.checked_add(len) {
+            Some(end) => end,
+            None => return Err(Error::OutputOverflow { got: bs.to_vec() }.into()),
+        };
+        if let Err(err) = self.check_last_key(bs, true) {
+            return self.reject(err, Some(len));
+        }
+        self.running_total = end;
+        let out = self.encode(end);
+        self.insert_output(bs, Some(out))
     }
 
     /// Calls insert on each item in the iterator.
@@ -203,16 +763,66 @@ impl<W: io::Write> Builder<W> {
         Ok(())
     }
 
+    /// Just like `into_inner`, except it also returns the report of rejected
+    /// keys.
+    ///
+    /// If this builder is not in diagnostic mode, the report is empty.
+    pub fn into_inner_with_report(mut self) -> Result<(W, KeyReport)> {
+        let report = self.report.take().unwrap_or_default();
+        Ok((self.into_inner()?, report))
+    }
+
     /// Just like `finish`, except it returns the underlying writer after
     /// flushing it.
     pub fn into_inner(mut self) -> Result<W> {
+        let footer = self.finish_body()?;
+        let offset = self.wtr.count();
+        self.wtr
+            .write_all(&footer)
+            .map_err(|err| IoContext::new("writing footer", err).offset(offset))?;
+        self.wtr
+            .flush()
+            .map_err(|err| IoContext::new("flushing", err))?;
+        Ok(self.wtr.into_inner())
+    }
+
+    /// Finishes the construction of the fst like `into_inner`, except the
+    /// footer is returned instead of being written to `W`.
+    ///
+    /// The fst cannot be read until the footer has been appended to the data
+    /// written to `W`. This makes it possible to publish an fst safely: sync
+    /// the body to durable storage first, then write the footer last and
+    /// treat its presence as the commit marker.
+    pub fn finish_into_parts(mut self) -> Result<(W, Vec<u8>)> {
+        let footer = self.finish_body()?;
+        self.wtr
+            .flush()
+            .map_err(|err| IoContext::new("flushing", err))?;
+        Ok((self.wtr.into_inner(), footer))
+    }
+
+    /// Compiles every remaining node and returns the footer that must follow
+    /// them.
+    fn finish_body(&mut self) -> Result<Vec<u8>> {
         self.compile_from(0)?;
         let root_node = self.unfinished.pop_root();
         let root_addr = self.compile(&root_node)?;
-        self.wtr.write_u64::<LittleEndian>(self.len as u64)?;
-        self.wtr.write_u64::<LittleEndian>(root_addr as u64)?;
-        self.wtr.flush()?;
-        Ok(self.wtr.into_inner())
+        self.record_count(&root_node, root_addr, self.len);
+        let max_key_len = (self.max_key_len as u64).to_le_bytes().to_vec();
+        let mut entries = vec![(TAG_MAX_KEY_LEN, max_key_len)];
+        if let Some((ref values, _)) = self.value_table {
+            let table = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+            entries.push((TAG_VALUE_TABLE, table));
+        }
+        if let Some(ref counts) = self.counts {
+            let table = counts
+                .iter()
+                .flat_map(|&(addr, n)| [addr.to_le_bytes(), n.to_le_bytes()])
+                .flatten()
+                .collect();
+            entries.push((TAG_SUBTREE_COUNTS, table));
+        }
+        Ok(write_footer(&entries, self.len, root_addr))
     }
 
     fn insert_output<B>(&mut self, bs: B, out: Option<Output>) -> Result<()>
@@ -241,27 +851,89 @@ impl<W: io::Write> Builder<W> {
             assert!(out.is_zero());
             return Ok(());
         }
-        self.len += 1;
+        self.max_key_len = cmp::max(self.max_key_len, bs.len());
         self.compile_from(prefix_len)?;
-        self.unfinished.add_suffix(&bs[prefix_len as usize..], out);
+        self.unfinished
+            .add_suffix(&bs[prefix_len as usize..], out, self.len);
+        self.len += 1;
         Ok(())
     }
 
     fn compile_from(&mut self, istate: Ulen) -> Result<()> {
         let mut addr = NONE_ADDRESS;
+        // The run of nodes that may be written as a chain, deepest first.
+        // Only the transition of the deepest one points to a node that was
+        // written already.
+        let mut chain = vec![];
         while istate + 1 < self.unfinished.len() {
-            let node = if addr == NONE_ADDRESS {
+            // Every key below the node has been inserted, since the node is
+            // not on the path of the key being inserted.
+            let count = self.len - self.unfinished.top_first_key();
+            let mut node = if addr == NONE_ADDRESS {
                 self.unfinished.pop_empty()
             } else {
                 self.unfinished.pop_freeze(addr)
             };
+            if self.chains && !node.is_final && node.trans.len() == 1 {
+                if chain.len() == MAX_CHAIN_LEN {
+                    addr = self.compile_chain(&mut chain)?;
+                    node.trans[0].addr = addr;
+                }
+                if chain.is_empty() {
+                    if let Some(found) = self.registry.find(&node) {
+                        addr = found;
+                        continue;
+                    }
+                }
+                // Only the first node of a chain may have an output.
+                let is_first = !node.trans[0].out.is_zero();
+                chain.push(node);
+                if is_first {
+                    addr = self.compile_chain(&mut chain)?;
+                }
+                continue;
+            }
+            if !chain.is_empty() {
+                addr = self.compile_chain(&mut chain)?;
+                node.trans.last_mut().unwrap().addr = addr;
+            }
             addr = self.compile(&node)?;
             assert_ne!(addr, NONE_ADDRESS);
+            self.record_count(&node, addr, count);
+        }
+        if !chain.is_empty() {
+            addr = self.compile_chain(&mut chain)?;
         }
         self.unfinished.top_last_freeze(addr);
         Ok(())
     }
 
+    /// Writes the run of nodes in `chain`, deepest first, and empties it.
+    ///
+    /// The run is written as a single chain if that is smaller, or as
+    /// separate nodes otherwise.
+    fn compile_chain(&mut self, chain: &mut Vec<BuilderNode>) -> Result<CompiledAddr> {
+        let target = chain[0].trans[0].addr;
+        let out = chain[chain.len() - 1].trans[0].out;
+        let label: Vec<u8> = chain.iter().rev().map(|node| node.trans[0].inp).collect();
+        let start_addr = self.wtr.count() as CompiledAddr;
+        let (chain_size, nodes_size) =
+            StateChain::encoded_sizes(self.last_addr, start_addr, &label, out, target);
+        if label.len() < 2 || chain_size >= nodes_size {
+            let mut addr = target;
+            for mut node in chain.drain(..) {
+                node.trans[0].addr = addr;
+                addr = self.compile(&node)?;
+            }
+            return Ok(addr);
+        }
+        chain.clear();
+        StateChain::compile(&mut self.wtr, start_addr, &label, out, target)
+            .map_err(|err| IoContext::new("writing node", err).offset(start_addr))?;
+        self.last_addr = self.wtr.count() as CompiledAddr - 1;
+        Ok(self.last_addr)
+    }
+
     fn compile(&mut self, node: &BuilderNode) -> Result<CompiledAddr> {
         if node.is_final && node.trans.is_empty() && node.final_output.is_zero() {
             return Ok(EMPTY_ADDRESS);
@@ -271,7 +943,8 @@ impl<W: io::Write> Builder<W> {
             return Ok(*addr);
         }
         let start_addr = self.wtr.count() as CompiledAddr;
-        node.compile_to(&mut self.wtr, self.last_addr, start_addr)?;
+        node.compile_to(&mut self.wtr, self.last_addr, start_addr)
+            .map_err(|err| IoContext::new("writing node", err).offset(start_addr))?;
         self.last_addr = self.wtr.count() as CompiledAddr - 1;
         if let RegistryEntry::NotFound(cell) = entry {
             cell.insert(self.last_addr);
@@ -279,17 +952,16 @@ impl<W: io::Write> Builder<W> {
         Ok(self.last_addr)
     }
 
-    fn check_last_key(&mut self, bs: &[u8], check_dupe: bool) -> Result<()> {
+    fn check_last_key(&mut self, bs: &[u8], check_dupe: bool) -> ::std::result::Result<(), Error> {
         if let Some(ref mut last) = self.last {
             if check_dupe && bs == &**last {
-                return Err(Error::DuplicateKey { got: bs.to_vec() }.into());
+                return Err(Error::DuplicateKey { got: bs.to_vec() });
             }
             if bs < &**last {
                 return Err(Error::OutOfOrder {
                     previous: last.to_vec(),
                     got: bs.to_vec(),
-                }
-                .into());
+                });
             }
             last.clear();
             for &b in bs {
@@ -301,15 +973,133 @@ impl<W: io::Write> Builder<W> {
         Ok(())
     }
 
+    /// Records an invalid key in diagnostic mode, or returns the error
+    /// otherwise.
+    fn reject(&mut self, err: Error, value: Option<u64>) -> Result<()> {
+        let report = match self.report {
+            Some(ref mut report) => report,
+            None => return Err(err.into()),
+        };
+        let previous = self.last.clone().unwrap_or_default();
+        let (reason, key) = match err {
+            Error::DuplicateKey { got } => (RejectReason::Duplicate, got),
+            Error::OutOfOrder { got, .. } => (RejectReason::OutOfOrder, got),
+            err => return Err(err.into()),
+        };
+        report.record(RejectedKey {
+            reason,
+            previous,
+            key,
+            value,
+        });
+        Ok(())
+    }
+
     /// Gets a reference to the underlying writer.
     pub fn get_ref(&self) -> &W {
         self.wtr.get_ref()
     }
 
+    /// Gets a mutable reference to the underlying writer.
+    ///
+    /// The builder never reads back what it wrote. However, `rollback`
+    /// truncates the bytes written since its checkpoint from the writer, so
+    /// taking bytes out of it makes rolling back fail. See `Truncate`.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.wtr.get_mut()
+    }
+
     /// Returns the number of bytes written to the underlying writer
     pub fn bytes_written(&self) -> u64 {
         self.wtr.count()
     }
+
+    /// Returns the number of bytes this builder holds on the heap, not
+    /// counting the writer.
+    ///
+    /// Most of it is the registry of compiled nodes, which is allocated
+    /// when the builder is created and does not grow. The rest grows with
+    /// the longest key, with the distinct values and subtree counts when
+    /// those are stored, and with the report of rejected keys. Hash tables
+    /// are estimated from their capacity.
+    pub fn heap_usage(&self) -> usize {
+        let last = self.last.as_ref().map_or(0, |k| k.capacity());
+        let report = self.report.as_ref().map_or(0, |report| {
+            let entry = mem::size_of::<RejectedKey>();
+            let keys = report.rejected.iter();
+            let keys = keys.map(|r| r.previous.capacity() + r.key.capacity());
+            report.rejected.capacity() * entry + keys.sum::<usize>()
+        });
+        let values = self.value_table.as_ref().map_or(0, |(values, index)| {
+            values.capacity() * mem::size_of::<u64>() + hash_map_heap_usage(index)
+        });
+        let counts = self
+            .counts
+            .as_ref()
+            .map_or(0, |c| c.capacity() * mem::size_of::<(CompiledAddr, u64)>());
+        self.unfinished.heap_usage() + self.registry.heap_usage() + last + report + values + counts
+    }
+
+    /// Returns a scope in which keys are inserted as suffixes of `prefix`.
+    ///
+    /// This is convenient for keys made of a fixed part followed by a
+    /// variable part, like a field name followed by a term. The scope reuses
+    /// one buffer for the full keys instead of allocating one per key. Keys
+    /// inserted through the scope are checked just like any other key, so
+    /// suffixes must be inserted in lexicographic order and the first key of
+    /// the scope must be greater than the last key inserted before it.
+    pub fn scope<B: AsRef<[u8]>>(&mut self, prefix: B) -> KeyScope<'_, W> {
+        let prefix = prefix.as_ref();
+        KeyScope {
+            builder: self,
+            key: prefix.to_vec(),
+            prefix_len: prefix.len(),
+        }
+    }
+}
+
+/// A scope of a `Builder` that accepts only the suffix of each key, all keys
+/// sharing the same prefix.
+///
+/// This is created by `Builder::scope`. Dropping the scope gives access to
+/// the builder again.
+pub struct KeyScope<'b, W> {
+    builder: &'b mut Builder<W>,
+    // The prefix, followed by the suffix of the last key inserted.
+    key: Vec<u8>,
+    prefix_len: usize,
+}
+
+impl<'b, W: io::Write> KeyScope<'b, W> {
+    /// Returns the prefix shared by all keys in this scope.
+    pub fn prefix(&self) -> &[u8] {
+        &self.key[..self.prefix_len]
+    }
+
+    /// Adds the key made of the prefix followed by `suffix`, with a zero
+    /// output value. See `Builder::add`.
+    pub fn add<B: AsRef<[u8]>>(&mut self, suffix: B) -> Result<()> {
+        self.set_suffix(suffix.as_ref());
+        self.builder.add(&self.key)
+    }
+
+    /// Inserts the key made of the prefix followed by `suffix`, with the
+    /// given value. See `Builder::insert`.
+    pub fn insert<B: AsRef<[u8]>>(&mut self, suffix: B, val: u64) -> Result<()> {
+        self.set_suffix(suffix.as_ref());
+        self.builder.insert(&self.key, val)
+    }
+
+    fn set_suffix(&mut self, suffix: &[u8]) {
+        self.key.truncate(self.prefix_len);
+        self.key.extend_from_slice(suffix);
+    }
+}
+
+/// Estimates the bytes on the heap of `map`, which stores its entries and
+/// one control byte for each of them.
+fn hash_map_heap_usage<K, V>(map: &HashMap<K, V>) -> usize {
+    map.capacity() * (mem::size_of::<(K, V)>() + 1)
 }
 
 impl UnfinishedNodes {
@@ -317,24 +1107,39 @@ impl UnfinishedNodes {
         let mut unfinished = UnfinishedNodes {
             stack: Vec::with_capacity(64),
         };
-        unfinished.push_empty(false);
+        unfinished.push_empty(false, 0);
         unfinished
     }
 
+    fn clear(&mut self) {
+        self.stack.clear();
+        self.push_empty(false, 0);
+    }
+
+    fn heap_usage(&self) -> usize {
+        let nodes = self.stack.iter().map(|n| n.node.heap_usage());
+        self.stack.capacity() * mem::size_of::<BuilderNodeUnfinished>() + nodes.sum::<usize>()
+    }
+
     fn len(&self) -> Ulen {
         self.stack.len() as Ulen
     }
 
-    fn push_empty(&mut self, is_final: bool) {
+    fn push_empty(&mut self, is_final: bool, first_key: u64) {
         self.stack.push(BuilderNodeUnfinished {
             node: BuilderNode {
                 is_final,
                 ..BuilderNode::default()
             },
             last: None,
+            first_key,
         });
     }
 
+    fn top_first_key(&self) -> u64 {
+        self.stack.last().unwrap().first_key
+    }
+
     fn pop_root(&mut self) -> BuilderNode {
         assert_eq!(self.stack.len(), 1);
         assert!(self.stack[0].last.is_none());
@@ -363,7 +1168,7 @@ impl UnfinishedNodes {
         self.stack[last].last_compiled(addr);
     }
 
-    fn add_suffix(&mut self, bs: &[u8], out: Output) {
+    fn add_suffix(&mut self, bs: &[u8], out: Output, key: u64) {
         if bs.is_empty() {
             return;
         }
@@ -377,9 +1182,10 @@ impl UnfinishedNodes {
                     inp: b,
                     out: Output::zero(),
                 }),
+                first_key: key,
             });
         }
-        self.push_empty(true);
+        self.push_empty(true, key);
     }
 
     fn find_common_prefix(&mut self, bs: &[u8]) -> Ulen {
@@ -452,6 +1258,13 @@ impl Clone for BuilderNode {
     }
 }
 
+impl BuilderNode {
+    /// Returns the number of bytes held by the transitions of this node.
+    pub fn heap_usage(&self) -> usize {
+        self.trans.capacity() * mem::size_of::<Transition>()
+    }
+}
+
 impl Default for BuilderNode {
     fn default() -> Self {
         BuilderNode {
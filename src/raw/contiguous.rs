@@ -0,0 +1,71 @@
+use crate::fake_arr::FakeArr;
+use crate::raw::node::{slice_final_output, slice_step};
+use crate::raw::{Fst, FstMeta, Output};
+
+/// A view of an fst whose bytes are all in memory.
+///
+/// Lookups through this view read nodes straight from the underlying slice
+/// instead of going through `FakeArr` ranges and `Node` values. `Fst::get`
+/// and `Fst::contains_key` use it whenever the data of the fst is
+/// contiguous, so it only needs to be used directly to skip that check.
+#[derive(Clone, Copy)]
+pub struct ContiguousFst<'f> {
+    meta: &'f FstMeta,
+    data: &'f [u8],
+}
+
+impl<'f> ContiguousFst<'f> {
+    /// Retrieves the value associated with a key.
+    ///
+    /// If the key does not exist, then `None` is returned.
+    #[inline]
+    pub fn get<B: AsRef<[u8]>>(&self, key: B) -> Option<Output> {
+        self.get_encoded(key.as_ref())
+            .map(|out| self.meta.decode(out))
+    }
+
+    /// Like `get`, except the output is returned as stored in the fst.
+    #[inline]
+    pub(crate) fn get_encoded(&self, key: &[u8]) -> Option<Output> {
+        let (addr, out) = self.walk(key)?;
+        let final_output = slice_final_output(self.meta.version, self.data, addr)?;
+        Some(out.cat(final_output))
+    }
+
+    /// Tests the membership of a single key.
+    #[inline]
+    pub fn contains_key<B: AsRef<[u8]>>(&self, key: B) -> bool {
+        self.walk(key.as_ref())
+            .and_then(|(addr, _)| slice_final_output(self.meta.version, self.data, addr))
+            .is_some()
+    }
+
+    /// Returns the bytes of the fst.
+    pub fn as_bytes(&self) -> &'f [u8] {
+        self.data
+    }
+
+    #[inline(always)]
+    fn walk(&self, key: &[u8]) -> Option<(u64, Output)> {
+        let mut addr = self.meta.root_addr;
+        let mut out = Output::zero();
+        for &b in key {
+            let (next, t_out) = slice_step(self.meta.version, self.data, addr, b)?;
+            addr = next;
+            out = out.cat(t_out);
+        }
+        Some((addr, out))
+    }
+}
+
+impl<Data: FakeArr> Fst<Data> {
+    /// Returns a view of this fst for faster point lookups, or `None` if
+    /// its data is not all in memory. See `FakeArr::as_contiguous`.
+    #[inline]
+    pub fn as_contiguous(&self) -> Option<ContiguousFst<'_>> {
+        self.data.as_contiguous().map(|data| ContiguousFst {
+            meta: &self.meta,
+            data,
+        })
+    }
+}
@@ -1,15 +1,29 @@
 use std::io;
+use std::sync::Arc;
+
+use crate::raw::build::Truncate;
+use crate::raw::ResourceObserver;
 
 /// Wraps any writer and counts bytes written.
 pub struct CountingWriter<W> {
     wtr: W,
     cnt: u64,
+    observer: Option<Arc<dyn ResourceObserver>>,
 }
 
 impl<W: io::Write> CountingWriter<W> {
     /// Wrap the given writer with a counter.
     pub fn new(wtr: W) -> CountingWriter<W> {
-        CountingWriter { wtr, cnt: 0 }
+        CountingWriter {
+            wtr,
+            cnt: 0,
+            observer: None,
+        }
+    }
+
+    /// Reports every write from now on to `observer`.
+    pub fn set_observer(&mut self, observer: Arc<dyn ResourceObserver>) {
+        self.observer = Some(observer);
     }
 
     /// Return the total number of bytes written to the underlying writer.
@@ -25,16 +39,41 @@ impl<W: io::Write> CountingWriter<W> {
         self.wtr
     }
 
+    /// Replaces the underlying writer and resets the count, keeping the
+    /// observer. The previous writer is returned.
+    pub fn replace(&mut self, wtr: W) -> W {
+        self.cnt = 0;
+        std::mem::replace(&mut self.wtr, wtr)
+    }
+
     /// Gets a reference to the underlying writer.
     pub fn get_ref(&self) -> &W {
         &self.wtr
     }
+
+    /// Gets a mutable reference to the underlying writer.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.wtr
+    }
+}
+
+impl<W: Truncate> CountingWriter<W> {
+    /// Discards the bytes written past `count`, which must not exceed the
+    /// number of bytes written.
+    pub fn truncate_to(&mut self, count: u64) -> io::Result<()> {
+        self.wtr.truncate_end(self.cnt - count)?;
+        self.cnt = count;
+        Ok(())
+    }
 }
 
 impl<W: io::Write> io::Write for CountingWriter<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let n = self.wtr.write(buf)?;
         self.cnt += n as u64;
+        if let Some(ref observer) = self.observer {
+            observer.bytes_written(n as u64);
+        }
         Ok(n)
     }
 
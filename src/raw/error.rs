@@ -56,6 +56,30 @@ pub enum Error {
     },
     /// An error that occurred when trying to decode a UTF-8 byte key.
     FromUtf8(FromUtf8Error),
+    /// The running sum of lengths overflowed a `u64` when inserting a key
+    /// with `Builder::insert_length`.
+    OutputOverflow {
+        /// The key that caused this error to occur.
+        got: Vec<u8>,
+    },
+    /// A composite key could not be split because it contains an invalid
+    /// escape sequence. See the `escape` module.
+    BadEscape {
+        /// The key that caused this error to occur.
+        got: Vec<u8>,
+    },
+    /// A key could not be decoded with the parts requested from a
+    /// `keys::KeyDecoder`.
+    InvalidKey {
+        /// The key that caused this error to occur.
+        got: Vec<u8>,
+    },
+    /// A key was pushed to a `delta::DeltaBuilder` that is longer than the
+    /// `u32::MAX` bytes a delta file can store.
+    KeyTooLong {
+        /// The length of the key that caused this error to occur.
+        len: u64,
+    },
 }
 
 impl fmt::Display for Error {
@@ -102,6 +126,31 @@ inserted in lexicographic order.",
                  Error opening FST: expected type {}, got type {}.",
                 expected, got
             ),
+            OutputOverflow { ref got } => write!(
+                f,
+                "\
+                 Error inserting key: {}. The running sum of lengths overflows.",
+                format_bytes(got)
+            ),
+            BadEscape { ref got } => write!(
+                f,
+                "\
+                 Error splitting key: {}. It contains an invalid escape sequence.",
+                format_bytes(got)
+            ),
+            InvalidKey { ref got } => write!(
+                f,
+                "\
+                 Error decoding key: {}. Its parts do not match the requested types.",
+                format_bytes(got)
+            ),
+            KeyTooLong { len } => write!(
+                f,
+                "\
+                 Error inserting key of {} bytes. Delta files store keys of at most {} bytes.",
+                len,
+                u32::MAX
+            ),
         }
     }
 }
@@ -113,9 +162,10 @@ impl fmt::Debug for Error {
 }
 
 impl error::Error for Error {
-    fn cause(&self) -> Option<&dyn error::Error> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        // `FromUtf8` displays as the error it wraps, so it is transparent.
         match *self {
-            Error::FromUtf8(ref err) => Some(err),
+            Error::FromUtf8(ref err) => err.source(),
             _ => None,
         }
     }
@@ -0,0 +1,144 @@
+use std::fmt;
+
+use crate::fake_arr::FakeArr;
+use crate::raw::{CompiledAddr, Fst, Output};
+
+/// The trace of a lookup, as returned by `Fst::explain`.
+///
+/// Its `Display` implementation prints one line per node visited, which is
+/// handy when tracking down why a key has an unexpected value, for instance
+/// after a merge.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Explanation {
+    /// The key that was looked up.
+    pub key: Vec<u8>,
+    /// The transitions taken, one per byte of the key that was matched.
+    ///
+    /// If there are fewer steps than bytes in the key, the node reached by
+    /// the last step has no transition for the next byte.
+    pub steps: Vec<ExplainStep>,
+    /// The address of the node the lookup stopped at.
+    pub node: CompiledAddr,
+    /// The final output of the node the lookup stopped at, if the whole key
+    /// was matched and that node is final.
+    pub final_output: Option<Output>,
+    /// The value of the key, or `None` if the key does not exist.
+    ///
+    /// This is the sum of the outputs of all steps and the final output,
+    /// mapped through the value table of fsts built with
+    /// `Builder::new_dictionary_encoded`.
+    pub value: Option<Output>,
+}
+
+/// A transition taken by a lookup. See `Explanation`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExplainStep {
+    /// The address of the node the transition leaves from.
+    pub from: CompiledAddr,
+    /// The input byte of the transition.
+    pub input: u8,
+    /// The output of the transition, which is added to the value.
+    pub output: Output,
+    /// The address of the node the transition leads to.
+    pub to: CompiledAddr,
+}
+
+impl Explanation {
+    /// Returns true if every byte of the key was matched.
+    pub fn matched(&self) -> bool {
+        self.steps.len() == self.key.len()
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "key {:?}", EscapedBytes(&self.key))?;
+        let mut sum = Output::zero();
+        for step in &self.steps {
+            sum = sum.cat(step.output);
+            writeln!(
+                f,
+                "  node {} --{:?}/+{}--> node {} (sum {})",
+                step.from,
+                EscapedBytes(&[step.input]),
+                step.output.value(),
+                step.to,
+                sum.value(),
+            )?;
+        }
+        if !self.matched() {
+            let next = self.key[self.steps.len()];
+            return write!(
+                f,
+                "  node {} has no transition for {:?}: not found",
+                self.node,
+                EscapedBytes(&[next]),
+            );
+        }
+        match (self.final_output, self.value) {
+            (Some(out), Some(value)) => write!(
+                f,
+                "  node {} is final with output +{}: value {}",
+                self.node,
+                out.value(),
+                value.value(),
+            ),
+            _ => write!(f, "  node {} is not final: not found", self.node),
+        }
+    }
+}
+
+struct EscapedBytes<'a>(&'a [u8]);
+
+impl<'a> fmt::Debug for EscapedBytes<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"")?;
+        for &b in self.0 {
+            write!(f, "{}", std::ascii::escape_default(b))?;
+        }
+        f.write_str("\"")
+    }
+}
+
+impl<Data: FakeArr> Fst<Data> {
+    /// Looks up a key and returns the trace of the lookup: every node it
+    /// visits, the transitions it takes with their outputs, and the final
+    /// output it ends with.
+    ///
+    /// The value in the trace is always the same as the one `get` returns.
+    pub fn explain<B: AsRef<[u8]>>(&self, key: B) -> Explanation {
+        let key = key.as_ref();
+        let mut node = self.root();
+        let mut out = Output::zero();
+        let mut steps = Vec::new();
+        for &b in key {
+            let i = match node.find_input(b) {
+                None => break,
+                Some(i) => i,
+            };
+            let t = node.transition(i);
+            steps.push(ExplainStep {
+                from: node.addr(),
+                input: b,
+                output: t.out,
+                to: t.addr,
+            });
+            out = out.cat(t.out);
+            node = self.node(t.addr);
+        }
+        let final_output = if steps.len() == key.len() && node.is_final() {
+            Some(node.final_output())
+        } else {
+            None
+        };
+        Explanation {
+            key: key.to_vec(),
+            steps,
+            node: node.addr(),
+            final_output,
+            value: final_output.map(|fin| self.meta.decode(out.cat(fin))),
+        }
+    }
+}
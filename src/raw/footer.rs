@@ -0,0 +1,235 @@
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use crate::error::Result;
+use crate::fake_arr::{FakeArr, Ulen};
+use crate::raw::{u64_to_Ulen, CompiledAddr, Error, FstMeta, FstType, EMPTY_ADDRESS};
+use crate::slic;
+
+// The footer follows the last node. In versions 1 and 2, it is made of the
+// number of keys and the root address, both as little endian `u64`s.
+//
+// Version 3 prepends a metadata section to this, followed by its length in
+// bytes:
+//
+//     [entry]* [metadata length] [number of keys] [root address]
+//
+// Each entry is a tag, the length of its payload and the payload itself:
+//
+//     [tag: u64] [payload length: u64] [payload]
+//
+// Readers skip entries whose tag they don't know about. Tag `0` is never
+// used, and all tags are less than `MAX_TAG`.
+
+/// The number of bytes in the header: the version and the FST type.
+pub(crate) const HEADER_LEN: Ulen = 16;
+
+/// The tag of the entry holding the length of the longest key, as a `u64`.
+pub(crate) const TAG_MAX_KEY_LEN: u64 = 1;
+
+/// The tag of the entry holding the value table of a dictionary encoded
+/// FST, as little endian `u64`s. Outputs are indices into this table.
+///
+/// Readers that skipped the table would return indices as values, so it is
+/// only valid in FSTs of version `VALUE_TABLE_VERSION` or later.
+pub(crate) const TAG_VALUE_TABLE: u64 = 2;
+
+/// The first version whose FSTs may have a value table.
+pub(crate) const VALUE_TABLE_VERSION: u64 = 4;
+
+/// The tag of the entry holding the number of keys below nodes, as pairs of
+/// little endian `u64`s: the address of the node and its count, sorted by
+/// address.
+///
+/// Writers may leave out nodes with a single transition and no final
+/// output, and any node with fewer keys below it than some threshold. If a
+/// node is left out while having several transitions or being final, then
+/// so are all the nodes below it.
+pub(crate) const TAG_SUBTREE_COUNTS: u64 = 3;
+
+/// All tags are less than this, which helps telling them apart from the
+/// metadata length while salvaging a truncated footer.
+const MAX_TAG: u64 = 16;
+
+/// Writes a version 3 footer with the given metadata entries.
+pub(crate) fn write_footer(
+    entries: &[(u64, Vec<u8>)],
+    len: Ulen,
+    root_addr: CompiledAddr,
+) -> Vec<u8> {
+    let mut footer = vec![];
+    for (tag, payload) in entries {
+        debug_assert!(*tag > 0 && *tag < MAX_TAG);
+        footer.write_u64::<LittleEndian>(*tag).unwrap();
+        footer
+            .write_u64::<LittleEndian>(payload.len() as u64)
+            .unwrap();
+        footer.extend_from_slice(payload);
+    }
+    let meta_len = footer.len() as u64;
+    footer.write_u64::<LittleEndian>(meta_len).unwrap();
+    footer.write_u64::<LittleEndian>(len).unwrap();
+    footer.write_u64::<LittleEndian>(root_addr).unwrap();
+    footer
+}
+
+pub(crate) fn read_u64<D: FakeArr + ?Sized>(data: &D, at: Ulen) -> u64 {
+    LittleEndian::read_u64(&slic!(data[at..(at + 8)]).actually_read_it())
+}
+
+impl FstMeta {
+    /// Reads the footer of an FST whose header has already been read.
+    ///
+    /// The root node is always the last node written, so its address should
+    /// be right before the footer. (The root address points to the last byte
+    /// of the root node.) If the footer is not where the root address says
+    /// it should be, then an error is returned.
+    ///
+    /// If this check passes, it is still possible that the FST is invalid
+    /// but probably unlikely. If this check reports a false positive, then
+    /// the program will probably panic. In the worst case, the FST will
+    /// operate but be subtly wrong. (This would require the bytes to be in
+    /// a format expected by an FST, which is incredibly unlikely.)
+    ///
+    /// An empty FST has a root node that is empty and final, which means it
+    /// has the special address `0`. In that case, the footer immediately
+    /// follows the header.
+    ///
+    /// This is essentially our own little checksum.
+    pub(crate) fn from_footer<D: FakeArr + ?Sized>(
+        version: u64,
+        ty: FstType,
+        data: &D,
+    ) -> Result<FstMeta> {
+        let tail_len = if version >= 3 { 24 } else { 16 };
+        if data.len() < HEADER_LEN + tail_len {
+            return Err(Error::Format.into());
+        }
+        let root_addr = u64_to_Ulen(read_u64(data, data.len() - 8));
+        let len = u64_to_Ulen(read_u64(data, data.len() - 16));
+        let nodes_end = if root_addr == EMPTY_ADDRESS {
+            HEADER_LEN
+        } else {
+            root_addr.checked_add(1).ok_or(Error::Format)?
+        };
+        let mut meta = FstMeta {
+            version,
+            root_addr,
+            ty,
+            len,
+            max_key_len: None,
+            value_table: None,
+            subtree_counts: None,
+        };
+        let meta_len = if version >= 3 {
+            u64_to_Ulen(read_u64(data, data.len() - 24))
+        } else {
+            0
+        };
+        let expected_len = nodes_end
+            .checked_add(meta_len)
+            .and_then(|n| n.checked_add(tail_len))
+            .ok_or(Error::Format)?;
+        if data.len() != expected_len {
+            return Err(Error::Format.into());
+        }
+
+        let mut at = nodes_end;
+        let meta_end = nodes_end + meta_len;
+        while at < meta_end {
+            if meta_end - at < 16 {
+                return Err(Error::Format.into());
+            }
+            let tag = read_u64(data, at);
+            let payload_len = u64_to_Ulen(read_u64(data, at + 8));
+            at += 16;
+            if payload_len > meta_end - at {
+                return Err(Error::Format.into());
+            }
+            // Entries from newer writers are skipped.
+            if tag == TAG_MAX_KEY_LEN {
+                if payload_len != 8 {
+                    return Err(Error::Format.into());
+                }
+                meta.max_key_len = Some(read_u64(data, at) as usize);
+            } else if tag == TAG_VALUE_TABLE {
+                if version < VALUE_TABLE_VERSION || payload_len % 8 != 0 {
+                    return Err(Error::Format.into());
+                }
+                let table: Vec<u64> = (0..payload_len / 8)
+                    .map(|i| read_u64(data, at + i * 8))
+                    .collect();
+                meta.value_table = Some(table.into());
+            } else if tag == TAG_SUBTREE_COUNTS {
+                if payload_len % 16 != 0 {
+                    return Err(Error::Format.into());
+                }
+                meta.subtree_counts = Some((at, payload_len / 16));
+            }
+            at += payload_len;
+        }
+        Ok(meta)
+    }
+}
+
+/// Returns true if `tail`, which is everything after the node at `root_addr`,
+/// could be the beginning of the footer of an FST rooted there.
+///
+/// `count` returns the number of keys below the root, or `None` if there is
+/// no valid root there. It is only called if the number of keys is part of
+/// `tail`.
+pub(crate) fn is_footer_prefix<F>(
+    version: u64,
+    tail: &[u8],
+    root_addr: CompiledAddr,
+    count: F,
+) -> bool
+where
+    F: FnOnce() -> Option<Ulen>,
+{
+    let mut at = 0;
+    if version >= 3 {
+        loop {
+            if tail.len() < at + 8 {
+                // Only part of a tag or of the metadata length is present.
+                let part = &tail[at..];
+                let is_tag = part.first().is_none_or(|&b| b != 0 && (b as u64) < MAX_TAG)
+                    && part.iter().skip(1).all(|&b| b == 0);
+                return is_tag || (at as u64).to_le_bytes().starts_with(part);
+            }
+            let v = LittleEndian::read_u64(&tail[at..]);
+            if v == at as u64 {
+                // This is the metadata length.
+                at += 8;
+                break;
+            }
+            if v == 0 || v >= MAX_TAG {
+                return false;
+            }
+            if tail.len() < at + 16 {
+                return true;
+            }
+            let payload_len = LittleEndian::read_u64(&tail[at + 8..]);
+            let end = (at as u64 + 16).saturating_add(payload_len);
+            if end > tail.len() as u64 {
+                // The payload is cut short, so nothing follows it.
+                return true;
+            }
+            at = end as usize;
+        }
+    }
+    let rest = &tail[at..];
+    if rest.is_empty() {
+        return true;
+    }
+    if rest.len() > 16 {
+        return false;
+    }
+    let count = match count() {
+        None => return false,
+        Some(count) => count,
+    };
+    let mut expected = vec![];
+    expected.write_u64::<LittleEndian>(count).unwrap();
+    expected.write_u64::<LittleEndian>(root_addr).unwrap();
+    expected[..rest.len()] == *rest
+}
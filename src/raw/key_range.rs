@@ -0,0 +1,94 @@
+use std::ops::{Bound, Range, RangeFrom, RangeFull, RangeInclusive, RangeTo, RangeToInclusive};
+
+/// A range of keys, as accepted by `StreamBuilder::range_bounds`.
+///
+/// This is implemented for every standard range type over keys that can be
+/// viewed as bytes, such as `"a".."m"`, `b"a"..=b"m"`, `"k"..` or `..`, and
+/// for pairs of `Bound`. It plays the part of `RangeBounds`, which cannot
+/// be used directly since `b"a"..b"m"` is a range over both `&[u8; 1]` and
+/// `[u8; 1]`, and so leaves its key type ambiguous.
+pub trait KeyRange {
+    /// Returns the lower bound of the range.
+    fn lower(&self) -> Bound<&[u8]>;
+
+    /// Returns the upper bound of the range.
+    fn upper(&self) -> Bound<&[u8]>;
+}
+
+fn bound_ref<K: AsRef<[u8]>>(bound: &Bound<K>) -> Bound<&[u8]> {
+    match *bound {
+        Bound::Included(ref k) => Bound::Included(k.as_ref()),
+        Bound::Excluded(ref k) => Bound::Excluded(k.as_ref()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+impl<K: AsRef<[u8]>> KeyRange for Range<K> {
+    fn lower(&self) -> Bound<&[u8]> {
+        Bound::Included(self.start.as_ref())
+    }
+
+    fn upper(&self) -> Bound<&[u8]> {
+        Bound::Excluded(self.end.as_ref())
+    }
+}
+
+impl<K: AsRef<[u8]>> KeyRange for RangeInclusive<K> {
+    fn lower(&self) -> Bound<&[u8]> {
+        Bound::Included(self.start().as_ref())
+    }
+
+    fn upper(&self) -> Bound<&[u8]> {
+        Bound::Included(self.end().as_ref())
+    }
+}
+
+impl<K: AsRef<[u8]>> KeyRange for RangeFrom<K> {
+    fn lower(&self) -> Bound<&[u8]> {
+        Bound::Included(self.start.as_ref())
+    }
+
+    fn upper(&self) -> Bound<&[u8]> {
+        Bound::Unbounded
+    }
+}
+
+impl<K: AsRef<[u8]>> KeyRange for RangeTo<K> {
+    fn lower(&self) -> Bound<&[u8]> {
+        Bound::Unbounded
+    }
+
+    fn upper(&self) -> Bound<&[u8]> {
+        Bound::Excluded(self.end.as_ref())
+    }
+}
+
+impl<K: AsRef<[u8]>> KeyRange for RangeToInclusive<K> {
+    fn lower(&self) -> Bound<&[u8]> {
+        Bound::Unbounded
+    }
+
+    fn upper(&self) -> Bound<&[u8]> {
+        Bound::Included(self.end.as_ref())
+    }
+}
+
+impl KeyRange for RangeFull {
+    fn lower(&self) -> Bound<&[u8]> {
+        Bound::Unbounded
+    }
+
+    fn upper(&self) -> Bound<&[u8]> {
+        Bound::Unbounded
+    }
+}
+
+impl<K: AsRef<[u8]>> KeyRange for (Bound<K>, Bound<K>) {
+    fn lower(&self) -> Bound<&[u8]> {
+        bound_ref(&self.0)
+    }
+
+    fn upper(&self) -> Bound<&[u8]> {
+        bound_ref(&self.1)
+    }
+}
@@ -18,57 +18,118 @@ option of specifying a merge strategy for output values.
 
 Most of the rest of the types are streams from set operations.
 */
-use std::io::Cursor;
-use std::{cmp, mem};
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::io::{self, Cursor};
+use std::mem;
+use std::sync::Arc;
 use std::{
     fmt,
-    ops::{Index, Range, RangeFrom},
+    ops::{ControlFlow, Index, Range, RangeFrom},
+};
+use std::{
+    io::Read,
+    ops::{Deref, DerefMut},
 };
-use std::{io::Read, ops::Deref};
 
 use byteorder::{LittleEndian, ReadBytesExt};
 
+use crate::bytes_util::prefix_upper_bound;
 use crate::{
     automaton::{AlwaysMatch, Automaton},
     fake_arr::{empty, slice_to_fake_arr, FakeArr, FakeArrRef, Ulen},
 };
 use crate::{error::Result, slic};
 use crate::{
-    fake_arr::{full_slice, FakeArrSlice, ShRange},
-    stream::{IntoStreamer, Streamer},
+    fake_arr::{expect_read, full_slice, FakeArrSlice, ShRange},
+    stream::{IntoStreamer, Streamer, TryStreamer},
 };
 
-pub use self::build::Builder;
+/// Asserts an internal invariant, but only when the `strict-checks` feature
+/// is enabled.
+///
+/// These checks catch corrupted data and bugs in node decoding or in the
+/// stream state machine close to where they happen, at a cost that is not
+/// worth paying otherwise.
+macro_rules! strict_assert {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "strict-checks")]
+        {
+            assert!($($arg)*);
+        }
+    };
+}
+
+#[cfg(feature = "async")]
+pub use self::async_stream::AsyncStream;
+pub use self::budget::{Budget, Page};
+pub use self::build::{
+    Builder, Checkpoint, KeyReport, KeyScope, RejectReason, RejectedKey, Truncate,
+};
+pub use self::contiguous::ContiguousFst;
 pub use self::error::Error;
-use self::node::node_new;
+pub use self::explain::{ExplainStep, Explanation};
+pub use self::key_range::KeyRange;
+use self::node::{node_new, try_node_new};
 pub use self::node::{Node, Transitions};
+pub use self::observer::{ResourceCounter, ResourceObserver};
 pub use self::ops::{
-    Difference, IndexedValue, Intersection, OpBuilder, SymmetricDifference, Union,
+    Difference, IndexedValue, Intersection, OpBuilder, OpSearch, SymmetricDifference, Union,
 };
-
+pub use self::pushing::PushingViolation;
+pub use self::recover::Recovered;
+pub use self::scan::ScanReport;
+pub use self::shard::ShardRouter;
+pub use self::shared::SharedReport;
+pub use self::top_k::{OutputSidecar, SidecarKind};
+
+#[cfg(feature = "async")]
+mod async_stream;
+mod budget;
 mod build;
 mod common_inputs;
+mod contiguous;
 mod counting_writer;
 mod error;
+mod explain;
+mod footer;
+mod key_range;
 mod node;
+mod observer;
 mod ops;
 mod pack;
+mod pushing;
+mod rank;
+mod recover;
 mod registry;
 mod registry_minimal;
+mod scan;
+mod shard;
+mod shared;
 #[cfg(test)]
 mod tests;
+mod top_k;
 
 /// The API version of this crate.
 ///
-/// This version number is written to every finite state transducer created by
-/// this crate. When a finite state transducer is read, its version number is
-/// checked against this value.
+/// This is the newest version of the format that this crate reads and
+/// writes. Finite state transducers are written with version `3`, unless
+/// they use features that need a newer version, like the chains written by
+/// `Builder::new_with_chains` or the value table written by
+/// `Builder::new_dictionary_encoded`. When a finite state transducer is
+/// read, its version number is checked against this value.
 ///
-/// Currently, any version mismatch results in an error. Fixing this requires
-/// regenerating the finite state transducer or switching to a version of this
-/// crate that is compatible with the serialized transducer. This particular
-/// behavior may be relaxed in future versions.
-pub const VERSION: u64 = 2;
+/// Currently, reading a newer version results in an error. Fixing this
+/// requires regenerating the finite state transducer or switching to a
+/// version of this crate that is compatible with the serialized transducer.
+/// This particular behavior may be relaxed in future versions.
+pub const VERSION: u64 = 4;
+
+/// The version written to finite state transducers that don't need a newer
+/// one.
+const DEFAULT_VERSION: u64 = 3;
 
 /// A sentinel value used to indicate an empty final state.
 const EMPTY_ADDRESS: CompiledAddr = 0;
@@ -81,6 +142,17 @@ const NONE_ADDRESS: CompiledAddr = 1;
 /// Default capacity for the key buffer of a stream.
 const KEY_BUFFER_CAPACITY: usize = 128;
 
+/// The most key bytes a stream allocates room for up front because of the
+/// length of the longest key recorded in the footer. That length comes from
+/// the file, so a corrupt one must not make streams allocate without bound.
+const MAX_KEY_BUFFER_CAPACITY: usize = 64 * 1024;
+
+/// The number of key bytes a stream can hold without allocating.
+///
+/// Most keys are short, so keeping them inline means that iterating over
+/// them does not allocate at all.
+const INLINE_KEY_CAPACITY: usize = 64;
+
 /// FstType is a convention used to indicate the type of the underlying
 /// transducer.
 ///
@@ -287,11 +359,17 @@ pub struct Fst<Data: FakeArr = Vec<u8>> {
     data: Data,
 }
 
+#[derive(Clone)]
 struct FstMeta {
     version: u64,
     root_addr: CompiledAddr,
     ty: FstType,
     len: Ulen,
+    max_key_len: Option<usize>,
+    value_table: Option<Arc<[u64]>>,
+    /// The offset of the table of subtree counts in the data, and the
+    /// number of entries in it. The table is read in place.
+    subtree_counts: Option<(Ulen, Ulen)>,
 }
 
 impl FstMeta {
@@ -305,12 +383,47 @@ impl FstMeta {
         node_new(self.version, addr, data)
     }
 
-    fn empty_final_output(&self, data: FakeArrRef<'_>) -> Option<Output> {
-        let root = self.root(data);
+    #[inline(always)]
+    fn try_node<'f>(&self, addr: CompiledAddr, data: FakeArrRef<'f>) -> io::Result<Node<'f>> {
+        try_node_new(self.version, addr, data)
+    }
+
+    /// Maps an output as stored in the fst to the value it stands for.
+    ///
+    /// Outputs are indices into the value table of fsts built with
+    /// `Builder::new_dictionary_encoded`, and values otherwise.
+    #[inline(always)]
+    fn decode(&self, out: Output) -> Output {
+        expect_read(self.try_decode(out))
+    }
+
+    /// Like `decode`, but returns an error for an index past the end of the
+    /// value table, which only a corrupt fst has.
+    #[inline(always)]
+    fn try_decode(&self, out: Output) -> io::Result<Output> {
+        let table = match self.value_table {
+            Some(ref table) => table,
+            None => return Ok(out),
+        };
+        match table.get(out.value() as usize) {
+            Some(&value) => Ok(Output::new(value)),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "value index {} is past the end of the table of {} values",
+                    out.value(),
+                    table.len()
+                ),
+            )),
+        }
+    }
+
+    fn empty_final_output(&self, data: FakeArrRef<'_>) -> io::Result<Option<Output>> {
+        let root = self.try_node(self.root_addr, data)?;
         if root.is_final() {
-            Some(root.final_output())
+            Ok(Some(root.final_output()))
         } else {
-            None
+            Ok(None)
         }
     }
 }
@@ -333,61 +446,13 @@ impl<Data: FakeArr> Fst<Data> {
         flonk.read(&mut buf64).await.unwrap();
 
         let version = Cursor::new(buf64).read_u64::<LittleEndian>().unwrap();
-        if version == 0 || version > VERSION {
-            return Err(Error::Version {
-                expected: VERSION,
-                got: version,
-            }
-            .into());
-        }
+        check_version(version)?;
         let mut bonk = slic!(data[8..]);
 
         bonk.read(&mut buf64).await.unwrap();
         let ty = Cursor::new(buf64).read_u64::<LittleEndian>().unwrap();
-        let root_addr = {
-            let mut last = slic!(data[(data.len() - 8)..]);
-            last.read(&mut buf64).await.unwrap();
-            // println!("len={}, d={:#?}, data={:?}, full={:#?}", data.len(), last, last.to_vec(), data.to_vec());
-            u64_to_Ulen(Cursor::new(buf64).read_u64::<LittleEndian>().unwrap())
-        };
-        let len = {
-            let mut last2 = slic!(data[(data.len() - 16)..]);
-            last2.read(&mut buf64).await.unwrap();
-            u64_to_Ulen(Cursor::new(buf64).read_u64::<LittleEndian>().unwrap())
-        };
-        println!("root={}, len={}", root_addr, len);
-        // The root node is always the last node written, so its address should
-        // be near the end. After the root node is written, we still have to
-        // write the root *address* and the number of keys in the FST.
-        // That's 16 bytes. The extra byte comes from the fact that the root
-        // address points to the last byte in the root node, rather than the
-        // byte immediately following the root node.
-        //
-        // If this check passes, it is still possible that the FST is invalid
-        // but probably unlikely. If this check reports a false positive, then
-        // the program will probably panic. In the worst case, the FST will
-        // operate but be subtly wrong. (This would require the bytes to be in
-        // a format expected by an FST, which is incredibly unlikely.)
-        //
-        // The special check for EMPTY_ADDRESS is needed since an empty FST
-        // has a root node that is empty and final, which means it has the
-        // special address `0`. In that case, the FST is the smallest it can
-        // be: the version, type, root address and number of nodes. That's
-        // 32 bytes (8 byte u64 each).
-        //
-        // This is essentially our own little checksum.
-        if (root_addr == EMPTY_ADDRESS && data.len() != 32) && root_addr + 17 != data.len() {
-            return Err(Error::Format.into());
-        }
-        Ok(Fst {
-            data,
-            meta: FstMeta {
-                version,
-                root_addr,
-                ty,
-                len,
-            },
-        })
+        let meta = FstMeta::from_footer(version, ty, &data)?;
+        Ok(Fst { data, meta })
     }
 
     /// Retrieves the value associated with a key.
@@ -395,9 +460,100 @@ impl<Data: FakeArr> Fst<Data> {
     /// If the key does not exist, then `None` is returned.
     #[inline(never)]
     pub fn get<B: AsRef<[u8]>>(&self, key: B) -> Option<Output> {
+        if let Some(fst) = self.as_contiguous() {
+            return fst.get(key);
+        }
+        expect_read(self.get_from(self.root(), Output::zero(), key.as_ref()))
+    }
+
+    /// Just like `get`, except a read of the underlying data that fails
+    /// returns an error instead of panicking.
+    ///
+    /// This is meant for fsts backed by a `FakeArr` that reads from disk or
+    /// from the network, so that callers can retry a failed lookup.
+    pub fn try_get<B: AsRef<[u8]>>(&self, key: B) -> Result<Option<Output>> {
+        if let Some(fst) = self.as_contiguous() {
+            return match fst.get_encoded(key.as_ref()) {
+                None => Ok(None),
+                Some(out) => Ok(Some(self.meta.try_decode(out)?)),
+            };
+        }
+        let root = self.try_node(self.meta.root_addr)?;
+        Ok(self.get_from(root, Output::zero(), key.as_ref())?)
+    }
+
+    /// Walks `prefix` once and returns a cursor at the node it reaches, or
+    /// `None` if no key starts with `prefix`.
+    ///
+    /// The cursor can then be passed to `get_suffix` any number of times to
+    /// look up keys starting with `prefix` without walking `prefix` again.
+    /// This helps when many lookups share a long prefix, like keys made of a
+    /// field name followed by a term.
+    pub fn get_with_prefix_state<B: AsRef<[u8]>>(&self, prefix: B) -> Option<PrefixCursor> {
+        let mut node = self.root();
+        let mut out = Output::zero();
+        for &b in prefix.as_ref() {
+            let t = node.transition(node.find_input(b)?);
+            out = out.cat(t.out);
+            node = self.node(t.addr);
+        }
+        Some(PrefixCursor {
+            addr: node.addr(),
+            out,
+        })
+    }
+
+    /// Retrieves the value associated with the key made of the prefix of
+    /// `cursor` followed by `rest`.
+    ///
+    /// If the key does not exist, then `None` is returned. `cursor` must
+    /// have been returned by `get_with_prefix_state` on this fst; otherwise,
+    /// the result is meaningless.
+    pub fn get_suffix<B: AsRef<[u8]>>(&self, cursor: &PrefixCursor, rest: B) -> Option<Output> {
+        expect_read(self.get_from(self.node(cursor.addr), cursor.out, rest.as_ref()))
+    }
+
+    #[inline(always)]
+    fn get_from<'f>(
+        &'f self,
+        mut node: Node<'f>,
+        mut out: Output,
+        key: &[u8],
+    ) -> io::Result<Option<Output>> {
+        for &b in key {
+            node = match node.try_find_input(b)? {
+                None => return Ok(None),
+                Some(i) => {
+                    let t = node.try_transition(i)?;
+                    out = out.cat(t.out);
+                    self.try_node(t.addr)?
+                }
+            }
+        }
+        if !node.is_final() {
+            Ok(None)
+        } else {
+            Ok(Some(self.meta.try_decode(out.cat(node.final_output()))?))
+        }
+    }
+
+    /// Returns the output accumulated along the path of the given key prefix,
+    /// or `None` if no key starts with `prefix`.
+    ///
+    /// Since the builder pushes outputs as close to the root as possible, for
+    /// a non-empty `prefix` this is the `prefix` (for `u64`, the minimum) of
+    /// the outputs of all keys that start with it. The final output of the
+    /// node reached is not included, so if `prefix` is itself a key, its
+    /// output is `fst.output_along_path(key)?.cat(node.final_output())`.
+    ///
+    /// Outputs are not mapped through the value table of fsts built with
+    /// `Builder::new_dictionary_encoded`, since partial sums of table
+    /// indices do not stand for any value. `top_k_completions` streams and
+    /// decodes the keys of such fsts instead of relying on these sums.
+    pub fn output_along_path<B: AsRef<[u8]>>(&self, prefix: B) -> Option<Output> {
         let mut node = self.root();
         let mut out = Output::zero();
-        for &b in key.as_ref() {
+        for &b in prefix.as_ref() {
             node = match node.find_input(b) {
                 None => return None,
                 Some(i) => {
@@ -407,23 +563,308 @@ impl<Data: FakeArr> Fst<Data> {
                 }
             }
         }
-        if !node.is_final() {
-            None
-        } else {
-            Some(out.cat(node.final_output()))
-        }
+        Some(out)
+    }
+
+    /// Returns the offset of the record of the given key, for an fst built
+    /// with `Builder::insert_length`.
+    ///
+    /// This is the sum of the lengths of all keys before it. If the key does
+    /// not exist, then `None` is returned.
+    pub fn offset_of<B: AsRef<[u8]>>(&self, key: B) -> Option<u64> {
+        let key = key.as_ref();
+        self.get(key)?;
+        Some(self.end_before(key))
+    }
+
+    /// Returns the length of the record of the given key, for an fst built
+    /// with `Builder::insert_length`.
+    ///
+    /// If the key does not exist, then `None` is returned.
+    pub fn length_of<B: AsRef<[u8]>>(&self, key: B) -> Option<u64> {
+        let key = key.as_ref();
+        let end = self.get(key)?.value();
+        end.checked_sub(self.end_before(key))
+    }
+
+    /// Returns the output of the greatest key less than `key`, which is the
+    /// end of its record in prefix-sum mode, or zero if there is none.
+    fn end_before(&self, key: &[u8]) -> u64 {
+        let mut stream = self.range().lt(key).backward().into_stream();
+        stream.next().map_or(0, |(_, out)| out.value())
     }
 
     /// Returns true if and only if the given key is in this FST.
     pub fn contains_key<B: AsRef<[u8]>>(&self, key: B) -> bool {
+        if let Some(fst) = self.as_contiguous() {
+            return fst.contains_key(key);
+        }
+        expect_read(self.contains_from(self.root(), key.as_ref()))
+    }
+
+    /// Just like `contains_key`, except a read of the underlying data that
+    /// fails returns an error instead of panicking. See `try_get`.
+    pub fn try_contains_key<B: AsRef<[u8]>>(&self, key: B) -> Result<bool> {
+        if let Some(fst) = self.as_contiguous() {
+            return Ok(fst.contains_key(key));
+        }
+        let root = self.try_node(self.meta.root_addr)?;
+        Ok(self.contains_from(root, key.as_ref())?)
+    }
+
+    fn contains_from<'f>(&'f self, mut node: Node<'f>, key: &[u8]) -> io::Result<bool> {
+        for &b in key {
+            node = match node.try_find_input(b)? {
+                None => return Ok(false),
+                Some(i) => self.try_node(node.try_transition_addr(i)?)?,
+            }
+        }
+        Ok(node.is_final())
+    }
+
+    /// Returns true if and only if some key in this FST starts with
+    /// `prefix`.
+    ///
+    /// Only the path of `prefix` is walked. Every node of an FST leads to at
+    /// least one key, except the root of an empty FST, so reaching the end
+    /// of the path is enough.
+    pub fn contains_prefix<B: AsRef<[u8]>>(&self, prefix: B) -> bool {
         let mut node = self.root();
-        for &b in key.as_ref() {
+        for &b in prefix.as_ref() {
             node = match node.find_input(b) {
                 None => return false,
                 Some(i) => self.node(node.transition_addr(i)),
             }
         }
-        node.is_final()
+        node.is_final() || !node.is_empty()
+    }
+
+    /// Tests the membership of many keys at once, returning whether each of
+    /// them is in this FST.
+    ///
+    /// The keys are looked up in a single walk that keeps the path to the
+    /// previous key, so the prefix a key shares with the previous one is not
+    /// walked again. Any order is correct, but sorted keys share the longest
+    /// prefixes, which makes this much faster than calling `contains_key`
+    /// for every key of a large sorted candidate list.
+    pub fn contains_all<B: AsRef<[u8]>>(&self, keys: &[B]) -> Vec<bool> {
+        let mut found = Vec::with_capacity(keys.len());
+        self.walk_keys(keys, |hit| {
+            found.push(hit);
+            true
+        });
+        found
+    }
+
+    /// Returns true if any of the keys is in this FST.
+    ///
+    /// This walks the keys like `contains_all` and stops at the first key
+    /// found.
+    pub fn contains_any<B: AsRef<[u8]>>(&self, keys: &[B]) -> bool {
+        let mut any = false;
+        self.walk_keys(keys, |hit| {
+            any = hit;
+            !hit
+        });
+        any
+    }
+
+    /// Returns true if and only if no key `k` of this fst satisfies
+    /// `lo <= k < hi`.
+    ///
+    /// This walks the path of `lo` and then the leftmost path to the
+    /// smallest key after it, so it costs two descents no matter how many
+    /// keys are in the range. It is meant for skipping whole fsts while
+    /// planning queries, without building a stream.
+    pub fn is_disjoint_range<B1: AsRef<[u8]>, B2: AsRef<[u8]>>(&self, lo: B1, hi: B2) -> bool {
+        let (lo, hi) = (lo.as_ref(), hi.as_ref());
+        if lo >= hi {
+            return true;
+        }
+        match self.ceiling_key(lo) {
+            None => true,
+            Some(key) => key.as_slice() >= hi,
+        }
+    }
+
+    /// Returns the smallest key that is greater than or equal to `key`,
+    /// along with its value, or `None` if every key is less than `key`.
+    ///
+    /// This walks the path of `key` and then the leftmost path to the key
+    /// after it, without building a stream.
+    pub fn get_ceiling<B: AsRef<[u8]>>(&self, key: B) -> Option<(Vec<u8>, Output)> {
+        let lo = key.as_ref();
+        let path = self.path_of(lo);
+        let (node, out) = &path[path.len() - 1];
+        if path.len() == lo.len() + 1 && node.is_final() {
+            let value = self.meta.decode(out.cat(node.final_output()));
+            return Some((lo.to_vec(), value));
+        }
+        // Branch off the path of `lo` as deep as possible, to the smallest
+        // byte after it, and then follow the smallest bytes to a key.
+        for (i, (node, out)) in path.iter().enumerate().rev() {
+            let branch = match lo.get(i) {
+                None => node.transitions().next(),
+                Some(&b) => node.transitions().find(|t| t.inp > b),
+            };
+            if let Some(t) = branch {
+                let mut key = lo[..i].to_vec();
+                key.push(t.inp);
+                return Some(self.leftmost(key, out.cat(t.out), self.node(t.addr)));
+            }
+        }
+        None
+    }
+
+    /// Returns the greatest key that is less than or equal to `key`, along
+    /// with its value, or `None` if every key is greater than `key`.
+    ///
+    /// This walks the path of `key` and then the rightmost path to the key
+    /// before it, without building a stream.
+    pub fn get_floor<B: AsRef<[u8]>>(&self, key: B) -> Option<(Vec<u8>, Output)> {
+        let hi = key.as_ref();
+        let path = self.path_of(hi);
+        let (node, out) = &path[path.len() - 1];
+        if path.len() == hi.len() + 1 && node.is_final() {
+            let value = self.meta.decode(out.cat(node.final_output()));
+            return Some((hi.to_vec(), value));
+        }
+        // Branch off the path of `hi` as deep as possible, to the greatest
+        // byte before it, and then follow the greatest bytes to a key. The
+        // prefixes of `hi` come before anything that branches off them.
+        for (i, (node, out)) in path.iter().enumerate().rev() {
+            let branch = match hi.get(i) {
+                None => None,
+                Some(&b) => node.transitions().filter(|t| t.inp < b).last(),
+            };
+            if let Some(t) = branch {
+                let mut key = hi[..i].to_vec();
+                key.push(t.inp);
+                return Some(self.rightmost(key, out.cat(t.out), self.node(t.addr)));
+            }
+            if i < hi.len() && node.is_final() {
+                let value = self.meta.decode(out.cat(node.final_output()));
+                return Some((hi[..i].to_vec(), value));
+            }
+        }
+        None
+    }
+
+    /// Returns the smallest key in this fst along with its value, or `None`
+    /// if it is empty.
+    ///
+    /// This follows the first transition of every node from the root, so it
+    /// costs one descent as long as the key.
+    pub fn min_key(&self) -> Option<(Vec<u8>, Output)> {
+        let root = self.root();
+        if root.is_empty() && !root.is_final() {
+            return None;
+        }
+        Some(self.leftmost(vec![], Output::zero(), root))
+    }
+
+    /// Returns the greatest key in this fst along with its value, or `None`
+    /// if it is empty.
+    ///
+    /// This follows the last transition of every node from the root, so it
+    /// costs one descent as long as the key.
+    pub fn max_key(&self) -> Option<(Vec<u8>, Output)> {
+        let root = self.root();
+        if root.is_empty() && !root.is_final() {
+            return None;
+        }
+        Some(self.rightmost(vec![], Output::zero(), root))
+    }
+
+    /// Returns the smallest key starting at `node`, which is reached by
+    /// `key` with the output `out`, along with its value.
+    fn leftmost<'f>(
+        &'f self,
+        mut key: Vec<u8>,
+        mut out: Output,
+        mut node: Node<'f>,
+    ) -> (Vec<u8>, Output) {
+        while !node.is_final() {
+            let t = node.transition(0);
+            key.push(t.inp);
+            out = out.cat(t.out);
+            node = self.node(t.addr);
+        }
+        (key, self.meta.decode(out.cat(node.final_output())))
+    }
+
+    /// Returns the greatest key starting at `node`, which is reached by
+    /// `key` with the output `out`, along with its value.
+    fn rightmost<'f>(
+        &'f self,
+        mut key: Vec<u8>,
+        mut out: Output,
+        mut node: Node<'f>,
+    ) -> (Vec<u8>, Output) {
+        while !node.is_empty() {
+            let t = node.transition(node.len() - 1);
+            key.push(t.inp);
+            out = out.cat(t.out);
+            node = self.node(t.addr);
+        }
+        (key, self.meta.decode(out.cat(node.final_output())))
+    }
+
+    /// Returns the smallest key that is greater than or equal to `lo`.
+    pub(crate) fn ceiling_key(&self, lo: &[u8]) -> Option<Vec<u8>> {
+        self.get_ceiling(lo).map(|(key, _)| key)
+    }
+
+    /// Returns the nodes on the path of `key`, along with the output
+    /// accumulated up to each of them, for as long as the path exists.
+    ///
+    /// The `i`th node is reached by the first `i` bytes of `key`.
+    fn path_of(&self, key: &[u8]) -> Vec<(Node<'_>, Output)> {
+        let mut path = vec![(self.root(), Output::zero())];
+        for &b in key {
+            let (node, out) = &path[path.len() - 1];
+            match node.find_input(b) {
+                None => break,
+                Some(i) => {
+                    let t = node.transition(i);
+                    let next = (self.node(t.addr), out.cat(t.out));
+                    path.push(next);
+                }
+            }
+        }
+        path
+    }
+
+    /// Looks up every key in turn, reusing the path to the previous key, and
+    /// calls `f` with whether it was found until `f` returns false.
+    fn walk_keys<B, F>(&self, keys: &[B], mut f: F)
+    where
+        B: AsRef<[u8]>,
+        F: FnMut(bool) -> bool,
+    {
+        // `path[i]` is the node reached by the first `i` bytes of `prev`.
+        let mut path = vec![self.root()];
+        let mut prev: &[u8] = &[];
+        for key in keys {
+            let key = key.as_ref();
+            let common = prev.iter().zip(key).take_while(|(a, b)| a == b).count();
+            path.truncate(cmp::min(common, path.len() - 1) + 1);
+            while path.len() <= key.len() {
+                let node = &path[path.len() - 1];
+                match node.find_input(key[path.len() - 1]) {
+                    None => break,
+                    Some(i) => {
+                        let next = self.node(node.transition_addr(i));
+                        path.push(next);
+                    }
+                }
+            }
+            let hit = path.len() == key.len() + 1 && path[key.len()].is_final();
+            prev = key;
+            if !f(hit) {
+                return;
+            }
+        }
     }
 
     /// Return a lexicographically ordered stream of all key-value pairs in
@@ -433,6 +874,14 @@ impl<Data: FakeArr> Fst<Data> {
         self.stream_builder(AlwaysMatch).into_stream()
     }
 
+    /// Return a stream of all key-value pairs in this fst, in reverse
+    /// lexicographic order.
+    ///
+    /// This is a shorthand for `range().backward().into_stream()`.
+    pub fn stream_rev(&self) -> Stream<'_> {
+        self.range().backward().into_stream()
+    }
+
     fn stream_builder<A: Automaton>(&self, aut: A) -> StreamBuilder<A> {
         StreamBuilder::new(&self.meta, slic!(self.data[..]), aut)
     }
@@ -446,9 +895,34 @@ impl<Data: FakeArr> Fst<Data> {
         self.stream_builder(AlwaysMatch)
     }
 
+    /// Return a builder for the keys in `range`, which is any standard
+    /// range of byte strings. See `StreamBuilder::range_bounds`.
+    pub fn range_bounds<R: KeyRange>(&self, range: R) -> StreamBuilder<'_> {
+        self.range().range_bounds(range)
+    }
+
+    /// Returns a lexicographically ordered stream of the key-value pairs in
+    /// this fst whose keys start with `prefix`.
+    ///
+    /// The stream starts at the first key with `prefix` and ends before the
+    /// first key after it, so nothing outside of the keys with `prefix` is
+    /// visited.
+    pub fn stream_prefix<B: AsRef<[u8]>>(&self, prefix: B) -> Stream<'_> {
+        let prefix = prefix.as_ref();
+        let mut builder = self.range().ge(prefix);
+        if let Some(end) = prefix_upper_bound(prefix) {
+            builder = builder.lt(end);
+        }
+        builder.into_stream()
+    }
+
     /// Executes an automaton on the keys of this map.
+    ///
+    /// If the automaton reports a literal prefix, the stream starts out
+    /// bounded to the keys with that prefix, so that the rest of the fst is
+    /// never visited. Setting bounds on the returned builder replaces these.
     pub fn search<A: Automaton>(&self, aut: A) -> StreamBuilder<A> {
-        self.stream_builder(aut)
+        self.stream_builder(aut).bound_to_literal_prefix()
     }
 
     /// Returns the number of keys in this fst.
@@ -463,6 +937,42 @@ impl<Data: FakeArr> Fst<Data> {
         self.len() == 0
     }
 
+    /// Returns the length of the longest key in this fst, if it is known.
+    ///
+    /// This is recorded in the footer when the fst is built, so it is known
+    /// for every fst written by this version of the crate. It is `None` for
+    /// fsts written before it was recorded.
+    #[inline]
+    pub fn max_key_len(&self) -> Option<usize> {
+        self.meta.max_key_len
+    }
+
+    /// Returns the distinct values of an fst built with
+    /// `Builder::new_dictionary_encoded`, in the order they were first
+    /// inserted, or `None` if its values are stored directly.
+    pub fn distinct_values(&self) -> Option<&[u64]> {
+        self.meta.value_table.as_deref()
+    }
+
+    /// Returns true if this fst was built with `Builder::with_counts`, so
+    /// the number of keys below its larger nodes is stored in it.
+    pub fn has_subtree_counts(&self) -> bool {
+        self.meta.subtree_counts.is_some()
+    }
+
+    /// Returns the number of bytes this fst holds on the heap, not counting
+    /// its data.
+    ///
+    /// This is the table decoded from the footer of the distinct values of
+    /// `Builder::new_dictionary_encoded`. It is shared with the copies
+    /// of an fst made by `to_vec_backed` or `as_slice_view`, each of which
+    /// reports it. The subtree counts of `Builder::with_counts` are read in
+    /// place and take no memory.
+    pub fn heap_usage(&self) -> usize {
+        let values = self.meta.value_table.as_ref();
+        values.map_or(0, |t| mem::size_of_val(&**t))
+    }
+
     /// Returns the number of bytes used by this fst.
     #[inline]
     pub fn size(&self) -> Ulen {
@@ -555,11 +1065,156 @@ impl<Data: FakeArr> Fst<Data> {
         self.meta.node(addr, slic!(self.data[..]))
     }
 
+    /// Like `node`, but returns the error of a read of the underlying data
+    /// that fails.
+    pub fn try_node(&self, addr: CompiledAddr) -> io::Result<Node<'_>> {
+        self.meta.try_node(addr, slic!(self.data[..]))
+    }
+
+    /// Visits every distinct node of this fst once, depth first, starting
+    /// at the root.
+    ///
+    /// `f` is called with each node and the number of transitions taken to
+    /// reach it the first time. Nodes shared by several keys are visited
+    /// only once, so this walks the structure of the fst rather than its
+    /// keys. Transitions are followed in increasing order of input byte.
+    ///
+    /// If `f` returns `ControlFlow::Break`, the walk stops and its value is
+    /// returned.
+    pub fn walk<B, F>(&self, mut f: F) -> ControlFlow<B>
+    where
+        F: FnMut(&Node<'_>, usize) -> ControlFlow<B>,
+    {
+        let mut seen = HashSet::new();
+        let mut stack = vec![(self.meta.root_addr, 0)];
+        while let Some((addr, depth)) = stack.pop() {
+            if !seen.insert(addr) {
+                continue;
+            }
+            let node = self.node(addr);
+            f(&node, depth)?;
+            for i in (0..node.len()).rev() {
+                let addr = node.transition_addr(i);
+                if !seen.contains(&addr) {
+                    stack.push((addr, depth + 1));
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Returns every transition of this fst as a weighted edge
+    /// `(from, input, to, output)`, where `from` and `to` are node
+    /// addresses.
+    ///
+    /// Edges are listed node by node, in the order `walk` visits them, so
+    /// transitions shared by several keys appear once. This is meant for
+    /// loading the fst into graph tooling, for instance to see how far the
+    /// builder pushed outputs towards the root. The final output of a node
+    /// is not an edge; it is returned by `Node::final_output`.
+    pub fn to_weighted_edges(&self) -> Vec<(CompiledAddr, u8, CompiledAddr, Output)> {
+        let mut edges = vec![];
+        let _ = self.walk::<(), _>(|node, _| {
+            edges.extend(
+                node.transitions()
+                    .map(|t| (node.addr(), t.inp, t.addr, t.out)),
+            );
+            ControlFlow::Continue(())
+        });
+        edges
+    }
+
     /// Returns a copy of the binary contents of this FST.
     #[inline]
     pub fn to_vec(&self) -> Vec<u8> {
         self.data.to_vec()
     }
+
+    /// Returns a copy of this fst that is backed by a `Vec<u8>`.
+    ///
+    /// This reads the whole fst once, which is useful to keep a lazily
+    /// loaded fst in memory for serving. The format is not validated again.
+    pub fn to_vec_backed(&self) -> Fst<Vec<u8>> {
+        Fst {
+            meta: self.meta.clone(),
+            data: self.data.to_vec(),
+        }
+    }
+
+    /// Returns the data this fst was opened from.
+    #[inline]
+    pub fn as_inner(&self) -> &Data {
+        &self.data
+    }
+
+    /// Consumes this fst and returns the data it was opened from.
+    #[inline]
+    pub fn into_inner(self) -> Data {
+        self.data
+    }
+
+    /// Erases the type of the data of this fst, so that fsts with different
+    /// backends have the same type. The fst is not parsed again.
+    pub fn into_dyn(self) -> Fst<Box<dyn FakeArr + Send + Sync>>
+    where
+        Data: Send + Sync + 'static,
+    {
+        Fst {
+            meta: self.meta,
+            data: Box::new(self.data),
+        }
+    }
+}
+
+impl<Data: FakeArr + AsRef<[u8]>> Fst<Data> {
+    /// Returns the binary contents of this fst without copying them.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        self.data.as_ref()
+    }
+
+    /// Returns a view of this fst that borrows its bytes.
+    ///
+    /// This is useful to pass an owned fst to APIs that are generic over
+    /// slice backed fsts. The format is not validated again.
+    pub fn as_slice_view(&self) -> Fst<&[u8]> {
+        Fst {
+            meta: self.meta.clone(),
+            data: self.data.as_ref(),
+        }
+    }
+}
+
+impl<'a> Fst<&'a [u8]> {
+    /// Opens a `Fst` from a byte slice without awaiting.
+    ///
+    /// This does the same checks as `Fst::new`, but since the bytes are all
+    /// in memory, it can be called outside of an async context.
+    pub fn from_slice(data: &'a [u8]) -> Result<Fst<&'a [u8]>> {
+        if data.len() < 32 {
+            return Err(Error::Format.into());
+        }
+        let version = Cursor::new(&data[0..8]).read_u64::<LittleEndian>().unwrap();
+        check_version(version)?;
+        let ty = Cursor::new(&data[8..16])
+            .read_u64::<LittleEndian>()
+            .unwrap();
+        let meta = FstMeta::from_footer(version, ty, &data)?;
+        Ok(Fst { data, meta })
+    }
+}
+
+/// Returns an error if FSTs of the given version cannot be read by this
+/// crate.
+fn check_version(version: u64) -> Result<()> {
+    if version == 0 || version > VERSION {
+        return Err(Error::Version {
+            expected: VERSION,
+            got: version,
+        }
+        .into());
+    }
+    Ok(())
 }
 
 impl<'a, 'f, Data> IntoStreamer<'a> for &'f Fst<Data>
@@ -593,7 +1248,14 @@ pub struct StreamBuilder<'f, A = AlwaysMatch> {
     aut: A,
     min: Bound,
     max: Bound,
+    /// Whether `min` and `max` were derived from the literal prefix of the
+    /// automaton, rather than set explicitly. Derived bounds only save work,
+    /// and are dropped when the automaton is replaced.
+    min_derived: bool,
+    max_derived: bool,
     backward: bool,
+    key_capacity: usize,
+    observer: Option<Arc<dyn ResourceObserver>>,
 }
 
 impl<'f, A: Automaton> StreamBuilder<'f, A> {
@@ -604,31 +1266,78 @@ impl<'f, A: Automaton> StreamBuilder<'f, A> {
             aut,
             min: Bound::Unbounded,
             max: Bound::Unbounded,
+            min_derived: false,
+            max_derived: false,
             backward: false,
+            key_capacity: meta
+                .max_key_len
+                .map_or(KEY_BUFFER_CAPACITY, |len| len.min(MAX_KEY_BUFFER_CAPACITY)),
+            observer: None,
         }
     }
 
+    /// Bounds the stream to the keys that start with the literal prefix of
+    /// the automaton, where no bound was set explicitly.
+    fn bound_to_literal_prefix(mut self) -> Self {
+        let prefix = self.aut.literal_prefix();
+        if prefix.is_empty() {
+            return self;
+        }
+        if let Bound::Unbounded = self.max {
+            if let Some(end) = prefix_upper_bound(prefix) {
+                self.max = Bound::Excluded(end);
+                self.max_derived = true;
+            }
+        }
+        if let Bound::Unbounded = self.min {
+            self.min = Bound::Included(prefix.to_vec());
+            self.min_derived = true;
+        }
+        self
+    }
+
     /// Specify a greater-than-or-equal-to bound.
     pub fn ge<T: AsRef<[u8]>>(mut self, bound: T) -> Self {
-        self.min = Bound::Included(bound.as_ref().to_owned());
+        self.set_min(Bound::Included(bound.as_ref().to_owned()));
         self
     }
 
     /// Specify a greater-than bound.
     pub fn gt<T: AsRef<[u8]>>(mut self, bound: T) -> Self {
-        self.min = Bound::Excluded(bound.as_ref().to_owned());
+        self.set_min(Bound::Excluded(bound.as_ref().to_owned()));
         self
     }
 
     /// Specify a less-than-or-equal-to bound.
     pub fn le<T: AsRef<[u8]>>(mut self, bound: T) -> Self {
-        self.max = Bound::Included(bound.as_ref().to_owned());
+        self.set_max(Bound::Included(bound.as_ref().to_owned()));
         self
     }
 
     /// Specify a less-than bound.
     pub fn lt<T: AsRef<[u8]>>(mut self, bound: T) -> Self {
-        self.max = Bound::Excluded(bound.as_ref().to_owned());
+        self.set_max(Bound::Excluded(bound.as_ref().to_owned()));
+        self
+    }
+
+    fn set_min(&mut self, bound: Bound) {
+        self.min = bound;
+        self.min_derived = false;
+    }
+
+    fn set_max(&mut self, bound: Bound) {
+        self.max = bound;
+        self.max_derived = false;
+    }
+
+    /// Specify both bounds from a standard range, such as `"a".."m"` or
+    /// `b"k"..`. See `KeyRange`.
+    ///
+    /// This replaces any bounds set so far, including the unbounded ends
+    /// of `range`.
+    pub fn range_bounds<R: KeyRange>(mut self, range: R) -> Self {
+        self.set_min(Bound::from_std(range.lower()));
+        self.set_max(Bound::from_std(range.upper()));
         self
     }
 
@@ -638,6 +1347,91 @@ impl<'f, A: Automaton> StreamBuilder<'f, A> {
         self
     }
 
+    /// Streams backward from just below `key`.
+    ///
+    /// This is a shorthand for `lt(key).backward()`. The stream starts at
+    /// the largest matching key less than `key` without visiting the keys
+    /// before it, which makes it suited for paging backward from a cursor.
+    pub fn before<T: AsRef<[u8]>>(self, key: T) -> Self {
+        self.lt(key).backward()
+    }
+
+    /// Specify an upper bound from a `BoundCursor`.
+    ///
+    /// This replaces any bound set by `le` or `lt`.
+    pub fn upper(mut self, bound: BoundCursor) -> Self {
+        self.set_max(bound.0);
+        self
+    }
+
+    /// Sets the number of key bytes the stream allocates room for up front.
+    ///
+    /// The stream keeps the current key and one frame per key byte on its
+    /// internal stack. Both grow as needed, but when the longest key is
+    /// known, pre-allocating for it avoids reallocating during iteration and
+    /// makes the memory used by the stream predictable. The default is the
+    /// length of the longest key in the fst when it is known, up to 64 KiB,
+    /// and 128 otherwise.
+    pub fn key_capacity(mut self, capacity: usize) -> Self {
+        self.key_capacity = capacity;
+        self
+    }
+
+    /// Reports every node the stream visits to `observer`.
+    pub fn observer(mut self, observer: Arc<dyn ResourceObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Returns the lower and upper bounds set on this builder.
+    ///
+    /// This lets crates that wrap streams inspect the range of a query
+    /// without keeping track of it themselves. Where no bound was set, these
+    /// include the bounds that `Fst::search` derives from the literal prefix
+    /// of the automaton.
+    pub fn bounds(&self) -> (std::ops::Bound<&[u8]>, std::ops::Bound<&[u8]>) {
+        (self.min.as_std(), self.max.as_std())
+    }
+
+    /// Returns the automaton that filters the stream.
+    pub fn automaton(&self) -> &A {
+        &self.aut
+    }
+
+    /// Returns true if the stream will be streamed backward.
+    pub fn is_backward(&self) -> bool {
+        self.backward
+    }
+
+    /// Replaces the automaton with `f(automaton)`, keeping every other
+    /// setting of this builder.
+    ///
+    /// This is useful to wrap the automaton of a query, for instance to
+    /// intersect it with another one, after its bounds have been set. The
+    /// bounds derived by `Fst::search` from the literal prefix of the old
+    /// automaton are dropped, and derived again from the new one, while the
+    /// bounds set explicitly are kept.
+    pub fn map_automaton<B, F>(self, f: F) -> StreamBuilder<'f, B>
+    where
+        B: Automaton,
+        F: FnOnce(A) -> B,
+    {
+        let unless = |derived, bound| if derived { Bound::Unbounded } else { bound };
+        StreamBuilder {
+            meta: self.meta,
+            data: self.data,
+            aut: f(self.aut),
+            min: unless(self.min_derived, self.min),
+            max: unless(self.max_derived, self.max),
+            min_derived: false,
+            max_derived: false,
+            backward: self.backward,
+            key_capacity: self.key_capacity,
+            observer: self.observer,
+        }
+        .bound_to_literal_prefix()
+    }
+
     /// Return this builder and gives the automaton states
     /// along with the results.
     pub fn with_state(self) -> StreamWithStateBuilder<'f, A> {
@@ -650,14 +1444,7 @@ impl<'a, 'f, A: Automaton> IntoStreamer<'a> for StreamBuilder<'f, A> {
     type Into = Stream<'f, A>;
 
     fn into_stream(self) -> Stream<'f, A> {
-        Stream::new(
-            self.meta,
-            self.data,
-            self.aut,
-            self.min,
-            self.max,
-            self.backward,
-        )
+        Stream(StreamWithState::from_builder(self), Back::new())
     }
 }
 
@@ -684,18 +1471,11 @@ where
     type Into = StreamWithState<'f, A>;
 
     fn into_stream(self) -> StreamWithState<'f, A> {
-        StreamWithState::new(
-            self.0.meta,
-            self.0.data,
-            self.0.aut,
-            self.0.min,
-            self.0.max,
-            self.0.backward,
-        )
+        StreamWithState::from_builder(self.0)
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 enum Bound {
     Included(Vec<u8>),
     Excluded(Vec<u8>),
@@ -703,6 +1483,22 @@ enum Bound {
 }
 
 impl Bound {
+    fn from_std(bound: std::ops::Bound<&[u8]>) -> Bound {
+        match bound {
+            std::ops::Bound::Included(v) => Bound::Included(v.to_vec()),
+            std::ops::Bound::Excluded(v) => Bound::Excluded(v.to_vec()),
+            std::ops::Bound::Unbounded => Bound::Unbounded,
+        }
+    }
+
+    fn as_std(&self) -> std::ops::Bound<&[u8]> {
+        match *self {
+            Bound::Included(ref v) => std::ops::Bound::Included(v),
+            Bound::Excluded(ref v) => std::ops::Bound::Excluded(v),
+            Bound::Unbounded => std::ops::Bound::Unbounded,
+        }
+    }
+
     fn exceeded_by(&self, inp: &[u8]) -> bool {
         match *self {
             Bound::Included(ref v) => inp > v,
@@ -733,25 +1529,126 @@ impl Bound {
             _ => true,
         }
     }
+
+    /// Returns the tighter of two upper bounds.
+    fn min_upper(self, other: Bound) -> Bound {
+        match (self, other) {
+            (Bound::Unbounded, b) | (b, Bound::Unbounded) => b,
+            (Bound::Included(a), Bound::Included(b)) => Bound::Included(cmp::min(a, b)),
+            (Bound::Excluded(a), Bound::Excluded(b)) => Bound::Excluded(cmp::min(a, b)),
+            (Bound::Included(a), Bound::Excluded(b)) | (Bound::Excluded(b), Bound::Included(a)) => {
+                if a < b {
+                    Bound::Included(a)
+                } else {
+                    Bound::Excluded(b)
+                }
+            }
+        }
+    }
+}
+
+/// An opaque position in an fst, reached by walking a key prefix.
+///
+/// This is created by `Fst::get_with_prefix_state` and used by
+/// `Fst::get_suffix`. It is only meaningful for the fst that created it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PrefixCursor {
+    addr: CompiledAddr,
+    out: Output,
+}
+
+/// An opaque upper or lower bound on the keys emitted by a stream.
+///
+/// A `BoundCursor` exposes the bound representation used internally by
+/// `StreamBuilder` and `Stream` without committing to its layout. It exists
+/// so that an external merge driver (for example, a WAND-style top-k
+/// algorithm) can clamp several streams to a shared upper bound that moves
+/// as the iteration progresses.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BoundCursor(Bound);
+
+impl BoundCursor {
+    /// A bound that does not restrict any key.
+    pub fn unbounded() -> BoundCursor {
+        BoundCursor(Bound::Unbounded)
+    }
+
+    /// A bound that includes the given key.
+    pub fn included<T: AsRef<[u8]>>(key: T) -> BoundCursor {
+        BoundCursor(Bound::Included(key.as_ref().to_vec()))
+    }
+
+    /// A bound that excludes the given key.
+    pub fn excluded<T: AsRef<[u8]>>(key: T) -> BoundCursor {
+        BoundCursor(Bound::Excluded(key.as_ref().to_vec()))
+    }
+
+    /// Returns true if and only if this bound does not restrict any key.
+    pub fn is_unbounded(&self) -> bool {
+        self.0 == Bound::Unbounded
+    }
+
+    /// Returns true if `key` lies above this bound when it is used as an
+    /// upper bound.
+    pub fn exceeded_by(&self, key: &[u8]) -> bool {
+        self.0.exceeded_by(key)
+    }
+
+    /// Returns true if `key` lies below this bound when it is used as a
+    /// lower bound.
+    pub fn subceeded_by(&self, key: &[u8]) -> bool {
+        self.0.subceeded_by(key)
+    }
+
+    /// Returns the tighter of two upper bounds.
+    ///
+    /// An unbounded cursor never wins over a bounded one.
+    pub fn min_upper(self, other: BoundCursor) -> BoundCursor {
+        BoundCursor(self.0.min_upper(other.0))
+    }
 }
 
 /// Stream of `key, value` not exposing the state of the automaton.
-pub struct Stream<'f, A = AlwaysMatch>(StreamWithState<'f, A>)
+///
+/// Besides `next`, keys can be taken from the other end with `next_back`.
+pub struct Stream<'f, A = AlwaysMatch>(StreamWithState<'f, A>, Back<'f, A>)
 where
     A: Automaton;
 
-impl<'f, A: Automaton> Stream<'f, A> {
-    fn new(
-        meta: &'f FstMeta,
-        data: FakeArrRef<'f>,
-        aut: A,
-        min: Bound,
-        max: Bound,
-        backward: bool,
-    ) -> Self {
-        Self(StreamWithState::new(meta, data, aut, min, max, backward))
+/// The state of the second end of a `Stream`, which is only set up once
+/// `next_back` is first called.
+struct Back<'f, A: Automaton> {
+    stream: Option<Box<StreamWithState<'f, A>>>,
+    // The lengths of the keys each end yielded last. Each key is still in
+    // the buffer of its end, since that end has not advanced since.
+    front_len: Option<usize>,
+    back_len: Option<usize>,
+    // Set once either end runs out or the two ends meet.
+    done: bool,
+}
+
+impl<'f, A: Automaton> Back<'f, A> {
+    fn new() -> Self {
+        Back {
+            stream: None,
+            front_len: None,
+            back_len: None,
+            done: false,
+        }
+    }
+}
+
+/// Returns true if `key`, just yielded by an end of a double-ended stream,
+/// is at or past the last key yielded by the other end, `other`.
+fn ends_met(key: &[u8], other: &[u8], reversed: bool) -> bool {
+    if reversed {
+        key <= other
+    } else {
+        key >= other
     }
+}
 
+impl<'f, A: Automaton> Stream<'f, A> {
     /// Convert this stream into a vector of byte strings and outputs.
     ///
     /// Note that this creates a new allocation for every key in the stream.
@@ -769,6 +1666,7 @@ impl<'f, A: Automaton> Stream<'f, A> {
     /// and a UTF-8 decoding error is returned.
     ///
     /// Note that this creates a new allocation for every key in the stream.
+    /// Use `into_str_stream` to avoid it.
     pub fn into_str_vec(mut self) -> Result<Vec<(String, u64)>> {
         let mut vs = vec![];
         while let Some((k, v)) = self.next() {
@@ -778,6 +1676,31 @@ impl<'f, A: Automaton> Stream<'f, A> {
         Ok(vs)
     }
 
+    /// Convert this stream into a stream of Unicode strings and outputs.
+    ///
+    /// Unlike `into_str_vec`, this does not allocate for every key. Keys are
+    /// yielded as slices of the stream's internal buffer, and only the part
+    /// of each key that differs from the previous key is validated as UTF-8.
+    pub fn into_str_stream(self) -> StrStream<'f, A> {
+        StrStream {
+            stream: self,
+            valid: 0,
+        }
+    }
+
+    /// Convert this stream into a stream of keys transformed by `map` and
+    /// outputs.
+    ///
+    /// `map` is given each key as a slice of the stream's internal buffer. It
+    /// may borrow from it, for instance to strip a prefix, or return an owned
+    /// key, for instance to decode an escape scheme.
+    pub fn into_mapped<F>(self, map: F) -> MappedStream<'f, F, A>
+    where
+        F: for<'k> FnMut(&'k [u8]) -> Cow<'k, [u8]>,
+    {
+        MappedStream { stream: self, map }
+    }
+
     /// Convert this stream into a vector of byte strings.
     ///
     /// Note that this creates a new allocation for every key in the stream.
@@ -812,13 +1735,261 @@ impl<'f, A: Automaton> Stream<'f, A> {
         }
         vs
     }
+
+    /// Appends up to `max_items` key-value pairs to flat, columnar buffers
+    /// and returns the number of pairs appended. Zero is returned only when
+    /// `max_items` is zero or the stream is exhausted.
+    ///
+    /// Key bytes are appended back to back to `keys_buf`, and for every key,
+    /// the offset in `keys_buf` just past its last byte is pushed to
+    /// `offsets`, along with its value to `values`. This amortizes the cost
+    /// of handing out one item at a time for consumers that process results
+    /// in batches, and the buffers can be reused across calls.
+    ///
+    /// # Panics
+    ///
+    /// This panics if `keys_buf` grows beyond `u32::MAX` bytes.
+    pub fn fill(
+        &mut self,
+        keys_buf: &mut Vec<u8>,
+        offsets: &mut Vec<u32>,
+        values: &mut Vec<u64>,
+        max_items: usize,
+    ) -> usize {
+        let mut n = 0;
+        while n < max_items {
+            let (key, out) = match self.next() {
+                None => break,
+                Some(item) => item,
+            };
+            let start = keys_buf.len();
+            keys_buf.resize(start + key.len() as usize, 0);
+            key.read_into(0, &mut keys_buf[start..]).unwrap();
+            let end = u32::try_from(keys_buf.len()).expect("keys_buf exceeds u32::MAX bytes");
+            offsets.push(end);
+            values.push(out.value());
+            n += 1;
+        }
+        n
+    }
+
+    /// Returns the current upper bound of this stream.
+    pub fn upper_bound(&self) -> BoundCursor {
+        self.0.upper_bound()
+    }
+
+    /// Returns the number of bytes this stream holds on the heap.
+    ///
+    /// This counts the buffer of the current key and the stack of nodes
+    /// leading to it, for both ends if `next_back` was called. Neither the
+    /// automaton nor its states are counted, even if they hold memory of
+    /// their own.
+    pub fn heap_usage(&self) -> usize {
+        let back = self.1.stream.as_ref().map_or(0, |back| {
+            mem::size_of::<StreamWithState<'f, A>>() + back.heap_usage()
+        });
+        self.0.heap_usage() + back
+    }
+
+    /// Tightens the upper bound of this stream.
+    ///
+    /// See `StreamWithState::clamp_upper`.
+    pub fn clamp_upper(&mut self, bound: &BoundCursor) {
+        self.0.clamp_upper(bound);
+        if let Some(ref mut back) = self.1.stream {
+            back.clamp_upper(bound);
+        }
+    }
+}
+
+impl<'f, A: Automaton> Stream<'f, A> {
+    /// Advances the front of this stream, and returns the length of the key
+    /// it reached along with its output.
+    fn advance_front(&mut self) -> io::Result<Option<(usize, Output)>> {
+        if self.1.done {
+            return Ok(None);
+        }
+        let (len, out, _) = match self.0.try_advance(|_| ())? {
+            Some(item) => item,
+            None => {
+                self.1.done = true;
+                return Ok(None);
+            }
+        };
+        if let (Some(back), Some(back_len)) = (&self.1.stream, self.1.back_len) {
+            let (key, other) = (self.0.inp.prefix(len), back.inp.prefix(back_len));
+            if ends_met(key, other, self.0.reversed) {
+                self.1.done = true;
+                return Ok(None);
+            }
+        }
+        self.1.front_len = Some(len);
+        Ok(Some((len, out)))
+    }
 }
 
 impl<'f, 'a, A: Automaton> Streamer<'a> for Stream<'f, A> {
     type Item = (FakeArrRef<'a>, Output);
 
     fn next(&'a mut self) -> Option<Self::Item> {
-        self.0.next(|_| ()).map(|(key, out, _)| (key, out))
+        let (len, out) = expect_read(self.advance_front())?;
+        Some((self.0.key(len), out))
+    }
+}
+
+impl<'f, 'a, A: Automaton> TryStreamer<'a> for Stream<'f, A> {
+    type Item = (FakeArrRef<'a>, Output);
+
+    fn try_next(&'a mut self) -> Option<Result<Self::Item>> {
+        match self.advance_front() {
+            Err(err) => Some(Err(err.into())),
+            Ok(None) => None,
+            Ok(Some((len, out))) => Some(Ok((self.0.key(len), out))),
+        }
+    }
+}
+
+impl<'f, A: Automaton + Clone> Stream<'f, A> {
+    /// Returns the next key and output from the other end of this stream:
+    /// the greatest remaining key, or the smallest if this stream was built
+    /// to stream backward.
+    ///
+    /// `next` and `next_back` can be mixed freely. Once they meet, both
+    /// return `None`, and no key is ever yielded by both. The other end is
+    /// a second stream over the same bounds and automaton, which is only
+    /// set up, with a clone of the automaton, when this is first called.
+    ///
+    /// The methods that convert this stream, such as `into_byte_vec`,
+    /// `into_str_stream` or `fill`, take keys from the front, and stop
+    /// before the keys already yielded by `next_back`.
+    pub fn next_back(&mut self) -> Option<(FakeArrRef<'_>, Output)> {
+        if self.1.done {
+            return None;
+        }
+        if self.1.stream.is_none() {
+            let front = &self.0;
+            let back = StreamWithState::from_builder(StreamBuilder {
+                meta: front.fst,
+                data: front.data,
+                aut: front.aut.clone(),
+                min: front.min.clone(),
+                max: front.max.clone(),
+                min_derived: false,
+                max_derived: false,
+                backward: !front.reversed,
+                key_capacity: front.inp.capacity(),
+                observer: front.observer.clone(),
+            });
+            self.1.stream = Some(Box::new(back));
+        }
+        let back = self.1.stream.as_mut().unwrap();
+        let (len, out, _) = match back.advance(|_| ()) {
+            Some(item) => item,
+            None => {
+                self.1.done = true;
+                return None;
+            }
+        };
+        if let Some(front_len) = self.1.front_len {
+            let (key, other) = (back.inp.prefix(len), self.0.inp.prefix(front_len));
+            if ends_met(key, other, back.reversed) {
+                self.1.done = true;
+                return None;
+            }
+        }
+        self.1.back_len = Some(len);
+        Some((back.key(len), out))
+    }
+}
+
+/// A stream of Unicode keys and outputs.
+///
+/// This is created by `Stream::into_str_stream`. If a key is not valid UTF-8,
+/// then a UTF-8 decoding error is yielded in its place and iteration may
+/// continue with the next key.
+pub struct StrStream<'f, A = AlwaysMatch>
+where
+    A: Automaton,
+{
+    stream: Stream<'f, A>,
+    // The length of the previous key if it was valid UTF-8, and 0 otherwise.
+    valid: usize,
+}
+
+impl<'f, 'a, A: Automaton> Streamer<'a> for StrStream<'f, A> {
+    type Item = Result<(&'a str, Output)>;
+
+    fn next(&'a mut self) -> Option<Self::Item> {
+        let (len, out) = expect_read(self.stream.advance_front())?;
+        let inp = &mut self.stream.0.inp;
+        // Bytes below the low water mark are shared with the previous key,
+        // so the part of them that ends on a character boundary is already
+        // known to be valid.
+        let shared = cmp::min(self.valid, inp.take_low_water());
+        let key = inp.prefix(len);
+        let valid = utf8_boundary(&key[..cmp::min(shared, len)]);
+        if std::str::from_utf8(&key[valid..]).is_err() {
+            self.valid = 0;
+            let err = String::from_utf8(key.to_vec()).unwrap_err();
+            return Some(Err(Error::from(err).into()));
+        }
+        self.valid = len;
+        // SAFETY: `key[..valid]` is a prefix of the previous key that ends on
+        // a character boundary and `key[valid..]` was validated above.
+        let key = unsafe { std::str::from_utf8_unchecked(key) };
+        Some(Ok((key, out)))
+    }
+}
+
+/// A stream of transformed keys and outputs.
+///
+/// This is created by `Stream::into_mapped`.
+pub struct MappedStream<'f, F, A = AlwaysMatch>
+where
+    A: Automaton,
+{
+    stream: Stream<'f, A>,
+    map: F,
+}
+
+impl<'f, 'a, F, A> Streamer<'a> for MappedStream<'f, F, A>
+where
+    F: for<'k> FnMut(&'k [u8]) -> Cow<'k, [u8]>,
+    A: Automaton,
+{
+    type Item = (Cow<'a, [u8]>, Output);
+
+    fn next(&'a mut self) -> Option<Self::Item> {
+        let (len, out) = expect_read(self.stream.advance_front())?;
+        let key = (self.map)(self.stream.0.inp.prefix(len));
+        Some((key, out))
+    }
+}
+
+/// Returns the length of the longest prefix of `bytes` that ends on a
+/// character boundary, assuming `bytes` is a prefix of valid UTF-8.
+fn utf8_boundary(bytes: &[u8]) -> usize {
+    let mut start = bytes.len();
+    while start > 0 && bytes[start - 1] & 0xC0 == 0x80 {
+        start -= 1;
+    }
+    if start == 0 {
+        return 0;
+    }
+    let lead = bytes[start - 1];
+    let width = if lead < 0x80 {
+        1
+    } else if lead < 0xE0 {
+        2
+    } else if lead < 0xF0 {
+        3
+    } else {
+        4
+    };
+    if start - 1 + width <= bytes.len() {
+        bytes.len()
+    } else {
+        start - 1
     }
 }
 
@@ -844,6 +2015,11 @@ where
     min: Bound,
     max: Bound,
     reversed: bool,
+    observer: Option<Arc<dyn ResourceObserver>>,
+    // Whether the stack was set up at the start bound yet. This is done by
+    // the first call to `next`, so that a read that fails while seeking is
+    // returned like any other.
+    positioned: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -857,31 +2033,73 @@ struct StreamState<'f, S> {
 }
 
 impl<'f, A: Automaton> StreamWithState<'f, A> {
-    fn new(
-        fst: &'f FstMeta,
-        data: FakeArrRef<'f>,
-        aut: A,
-        min: Bound,
-        max: Bound,
-        backward: bool,
-    ) -> Self {
-        let min_2 = min.clone();
-        let max_2 = max.clone();
+    fn from_builder(builder: StreamBuilder<'f, A>) -> Self {
+        let StreamBuilder {
+            meta,
+            data,
+            aut,
+            min,
+            max,
+            backward,
+            key_capacity,
+            observer,
+            ..
+        } = builder;
         let end_at: Bound = if !backward { max.clone() } else { min.clone() };
-        let mut stream = StreamWithState {
-            fst,
+        StreamWithState {
+            fst: meta,
             data,
             aut,
-            inp: Buffer::new(),
+            inp: Buffer::with_capacity(key_capacity),
             empty_output: None,
-            stack: vec![],
+            // One frame for the root and one for every byte of the key.
+            stack: Vec::with_capacity(key_capacity + 1),
             end_at,
-            min: min_2,
-            max: max_2,
+            min,
+            max,
             reversed: backward,
-        };
-        stream.seek(&min, &max);
-        stream
+            observer,
+            positioned: false,
+        }
+    }
+
+    /// Decodes the node at `addr` and reports it to the observer, if any.
+    #[inline]
+    fn node(&self, addr: CompiledAddr) -> io::Result<Node<'f>> {
+        let node = self.fst.try_node(addr, self.data)?;
+        if let Some(ref observer) = self.observer {
+            observer.node_visited(node.size());
+        }
+        Ok(node)
+    }
+
+    /// Returns the current upper bound of this stream.
+    pub fn upper_bound(&self) -> BoundCursor {
+        BoundCursor(self.max.clone())
+    }
+
+    /// Returns the number of bytes held by the key buffer and the stack of
+    /// this stream. See `Stream::heap_usage`.
+    fn heap_usage(&self) -> usize {
+        let frame = mem::size_of::<StreamState<'f, A::State>>();
+        self.inp.heap_usage() + self.stack.capacity() * frame
+    }
+
+    /// Tightens the upper bound of this stream to the smaller of its current
+    /// upper bound and `bound`.
+    ///
+    /// This may be called at any point during iteration. Keys that have
+    /// already been emitted are unaffected, and the bound can only ever
+    /// shrink, which makes it safe to share a single moving bound between
+    /// several streams.
+    pub fn clamp_upper(&mut self, bound: &BoundCursor) {
+        self.max = self.max.clone().min_upper(bound.0.clone());
+        if self.max.exceeded_by(&[]) {
+            self.empty_output = None;
+        }
+        if !self.reversed {
+            self.end_at = self.max.clone();
+        }
     }
 
     /// Seeks the underlying stream such that the next key to be read is the
@@ -891,14 +2109,14 @@ impl<'f, A: Automaton> StreamWithState<'f, A> {
     /// This theoretically should be straight-forward, but we need to make
     /// sure our stack is correct, which includes accounting for automaton
     /// states.
-    fn seek(&mut self, min: &Bound, max: &Bound) {
+    fn seek(&mut self, min: &Bound, max: &Bound) -> io::Result<()> {
         let start_bound = if self.reversed { &max } else { &min };
         if min.is_empty() && min.is_inclusive() {
-            self.empty_output = self.resolve_empty_output(min, max);
+            self.empty_output = self.resolve_empty_output(min, max)?;
         }
         if start_bound.is_empty() {
             self.stack.clear();
-            let node = self.fst.root(self.data);
+            let node = self.node(self.fst.root_addr)?;
             let transition = self.starting_transition(&node);
             self.stack = vec![StreamState {
                 node,
@@ -907,7 +2125,7 @@ impl<'f, A: Automaton> StreamWithState<'f, A> {
                 aut_state: self.aut.start(),
                 done: transition.is_none(),
             }];
-            return;
+            return Ok(());
         }
         let (key, inclusive) = match start_bound {
             Bound::Excluded(ref start_bound) => (start_bound, false),
@@ -920,13 +2138,13 @@ impl<'f, A: Automaton> StreamWithState<'f, A> {
         // N.B. We do not necessarily need to stop in a final state, unlike
         // the one-off `find` method. For the example, the given bound might
         // not actually exist in the FST.
-        let mut node = self.fst.root(self.data);
+        let mut node = self.node(self.fst.root_addr)?;
         let mut out = Output::zero();
         let mut aut_state = self.aut.start();
         for &b in key {
-            match node.find_input(b) {
+            match node.try_find_input(b)? {
                 Some(i) => {
-                    let t = node.transition(i);
+                    let t = node.try_transition(i)?;
                     let prev_state = aut_state;
                     aut_state = self.aut.accept(&prev_state, b);
                     self.inp.push(b);
@@ -939,7 +2157,7 @@ impl<'f, A: Automaton> StreamWithState<'f, A> {
                         done: transition.is_none(),
                     });
                     out = out.cat(t.out);
-                    node = self.fst.node(t.addr, self.data);
+                    node = self.node(t.addr)?;
                 }
                 None => {
                     // This is a little tricky. We're in this case if the
@@ -947,7 +2165,7 @@ impl<'f, A: Automaton> StreamWithState<'f, A> {
                     // Since this is a minimum bound, we need to find the
                     // first transition in this node that proceeds the current
                     // input byte.
-                    let trans = self.transition_within_bound(&node, b);
+                    let trans = self.transition_within_bound(&node, b)?;
                     self.stack.push(StreamState {
                         node,
                         trans: trans.unwrap_or_default(),
@@ -955,12 +2173,12 @@ impl<'f, A: Automaton> StreamWithState<'f, A> {
                         aut_state,
                         done: trans.is_none(),
                     });
-                    return;
+                    return Ok(());
                 }
             }
         }
         if self.stack.is_empty() {
-            return;
+            return Ok(());
         }
         let last = self.stack.len() - 1;
         let state = &self.stack[last];
@@ -974,10 +2192,8 @@ impl<'f, A: Automaton> StreamWithState<'f, A> {
             self.stack[last].done = transition.is_none();
             self.inp.pop();
         } else {
-            let next_node = self.fst.node(
-                state.node.transition(transition.unwrap_or_default()).addr,
-                self.data,
-            );
+            let t = state.node.try_transition(transition.unwrap_or_default())?;
+            let next_node = self.node(t.addr)?;
             let starting_transition = self.starting_transition(&next_node);
             self.stack.push(StreamState {
                 node: next_node,
@@ -987,6 +2203,7 @@ impl<'f, A: Automaton> StreamWithState<'f, A> {
                 done: starting_transition.is_none(),
             });
         }
+        Ok(())
     }
 
     #[inline]
@@ -994,14 +2211,72 @@ impl<'f, A: Automaton> StreamWithState<'f, A> {
     where
         F: Fn(&A::State) -> T,
     {
-        println!("next()");
+        let (len, out, state) = self.advance(transform)?;
+        Some((self.key(len), out, state))
+    }
+
+    /// Returns the prefix of the given length of the input buffer, which is
+    /// the key that `advance` returned the length of.
+    #[inline]
+    fn key(&self, len: usize) -> FakeArrRef<'_> {
+        if len == 0 {
+            return empty();
+        }
+        slice_to_fake_arr(self.inp.prefix(len))
+    }
+
+    /// Advances the stream to the next key and returns its length.
+    ///
+    /// The key itself is the prefix of the given length of the input buffer.
+    /// (When streaming backward, the key may extend past the current length
+    /// of the buffer.)
+    #[inline]
+    fn advance<F, T>(&mut self, transform: F) -> Option<(usize, Output, T)>
+    where
+        F: Fn(&A::State) -> T,
+    {
+        expect_read(self.try_advance(transform))
+    }
+
+    /// Like `advance`, but returns the error of a read that fails.
+    #[inline]
+    fn try_advance<F, T>(&mut self, transform: F) -> io::Result<Option<(usize, Output, T)>>
+    where
+        F: Fn(&A::State) -> T,
+    {
+        let next = self.advance_encoded(transform)?;
+        match next {
+            None => Ok(None),
+            Some((len, out, state)) => Ok(Some((len, self.fst.try_decode(out)?, state))),
+        }
+    }
+
+    /// Like `try_advance`, except outputs are returned as stored in the fst.
+    #[inline]
+    fn advance_encoded<F, T>(&mut self, transform: F) -> io::Result<Option<(usize, Output, T)>>
+    where
+        F: Fn(&A::State) -> T,
+    {
+        if !self.positioned {
+            let (min, max) = (self.min.clone(), self.max.clone());
+            self.seek(&min, &max)?;
+            self.positioned = true;
+        }
         if !self.reversed {
             // Inorder empty output (will be first).
             if let Some(out) = self.empty_output.take() {
-                return Some((empty(), out, transform(&self.aut.start())));
+                return Ok(Some((0, out, transform(&self.aut.start()))));
             }
         }
         while let Some(state) = self.stack.pop() {
+            // There is one frame for the root and one per byte of input.
+            strict_assert!(
+                self.stack.len() == self.inp.len,
+                "stream has {} frames below the top for a {} byte key",
+                self.stack.len(),
+                self.inp.len
+            );
+            strict_assert!(state.done || state.trans < state.node.len());
             if state.done || !self.aut.can_match(&state.aut_state) {
                 if state.node.addr() != self.fst.root_addr {
                     // Reversed return next logic.
@@ -1010,20 +2285,20 @@ impl<'f, A: Automaton> StreamWithState<'f, A> {
                         let out_of_bounds =
                             self.min.subceeded_by(&self.inp) || self.max.exceeded_by(&self.inp);
                         if !out_of_bounds && self.aut.is_match(&state.aut_state) {
-                            let opli: &'a [u8] = self.inp.pop();
-                            let ar = slice_to_fake_arr(opli);
-                            return Some((ar, state.out, transform(&state.aut_state)));
+                            let len = self.inp.pop().len();
+                            let out = state.out.cat(state.node.final_output());
+                            return Ok(Some((len, out, transform(&state.aut_state))));
                         }
                     }
                     self.inp.pop();
                 }
                 continue;
             }
-            let trans = state.node.transition(state.trans);
+            let trans = state.node.try_transition(state.trans)?;
             let out = state.out.cat(trans.out);
             let next_state = self.aut.accept(&state.aut_state, trans.inp);
             let is_match = self.aut.is_match(&next_state);
-            let next_node = self.fst.node(trans.addr, self.data);
+            let next_node = self.node(trans.addr)?;
             self.inp.push(trans.inp);
             let current_transition = self.next_transition(&state.node, state.trans);
             self.stack.push(StreamState {
@@ -1045,51 +2320,52 @@ impl<'f, A: Automaton> StreamWithState<'f, A> {
                 if self.end_at.exceeded_by(&self.inp) {
                     // We are done, forever.
                     self.stack.clear();
-                    return None;
+                    return Ok(None);
                 } else if !self.reversed && next_node.is_final() && is_match {
-                    return Some((slic!(self.inp[..]), out.cat(next_node.final_output()), ns));
+                    return Ok(Some((self.inp.len, out.cat(next_node.final_output()), ns)));
                 }
             }
         }
         // If we are streaming backward, we still need to return the empty output, if empty is
         // part of our fst, matches the range and the automaton
-        self.empty_output
+        Ok(self
+            .empty_output
             .take()
-            .map(|out| (empty(), out, transform(&self.aut.start())))
+            .map(|out| (0, out, transform(&self.aut.start()))))
     }
 
     // The first transition that is in a bound for a given node.
     #[inline]
-    fn transition_within_bound(&self, node: &Node<'f>, bound: u8) -> Option<Ulen> {
+    fn transition_within_bound(&self, node: &Node<'f>, bound: u8) -> io::Result<Option<Ulen>> {
         let mut trans;
         if let Some(t) = self.starting_transition(&node) {
             trans = t;
         } else {
-            return None;
+            return Ok(None);
         }
         loop {
-            let transition = node.transition(trans);
+            let transition = node.try_transition(trans)?;
             if (!self.reversed && transition.inp > bound)
                 || (self.reversed && transition.inp < bound)
             {
-                return Some(trans);
+                return Ok(Some(trans));
             } else if let Some(t) = self.next_transition(&node, trans) {
                 trans = t;
             } else {
-                return None;
+                return Ok(None);
             }
         }
     }
 
     /// Resolves value of the empty output. Will be none if the empty output should not be returned.
     #[inline]
-    fn resolve_empty_output(&mut self, min: &Bound, max: &Bound) -> Option<Output> {
+    fn resolve_empty_output(&mut self, min: &Bound, max: &Bound) -> io::Result<Option<Output>> {
         if min.subceeded_by(&[]) || max.exceeded_by(&[]) {
-            return None;
+            return Ok(None);
         }
         let start = self.aut.start();
         if !self.aut.is_match(&start) {
-            return None;
+            return Ok(None);
         }
         self.fst.empty_final_output(self.data)
     }
@@ -1173,6 +2449,21 @@ where
     }
 }
 
+impl<'f, 'a, A: 'a + Automaton> TryStreamer<'a> for StreamWithState<'f, A>
+where
+    A::State: Clone,
+{
+    type Item = (FakeArrRef<'a>, Output, A::State);
+
+    fn try_next(&'a mut self) -> Option<Result<Self::Item>> {
+        match self.try_advance(Clone::clone) {
+            Err(err) => Some(Err(err.into())),
+            Ok(None) => None,
+            Ok(Some((len, out, state))) => Some(Ok((self.key(len), out, state))),
+        }
+    }
+}
+
 /// An output is a value that is associated with a key in a finite state
 /// transducer.
 ///
@@ -1183,16 +2474,68 @@ where
 /// pairs of operands where the first operand is greater than or equal to the
 /// second operand.
 ///
-/// Currently, output values must be `u64`. However, in theory, an output value
-/// can be anything that satisfies the above algebra. Future versions of this
-/// crate may make outputs generic on this algebra.
+/// Output values are always `u64`. In theory, an output value can be anything
+/// that satisfies the above algebra, but outputs are stored packed into the
+/// fewest bytes that hold each integer, and the dictionary encoding reads
+/// them as indices into its table of values. Other kinds of values can be
+/// stored in a separate table and indexed by output.
+///
+/// For `u64`, `prefix` is the minimum, `cat` is addition and `sub` is
+/// subtraction. For all outputs `a`, `b` and `c`, the following laws hold:
+///
+/// * `zero` is the identity of `cat`: `a.cat(Output::zero()) == a`.
+/// * `cat` is associative and commutative.
+/// * `prefix` is associative, commutative and idempotent, and
+///   `a.prefix(Output::zero()) == Output::zero()`.
+/// * `a.prefix(b)` can be subtracted from both `a` and `b`, and
+///   `a.prefix(b).cat(a.sub(a.prefix(b))) == a`.
+/// * `sub` undoes `cat`: `a.cat(b).sub(b) == a`.
+/// * `cat` distributes over `prefix`:
+///   `a.cat(b.prefix(c)) == a.cat(b).prefix(a.cat(c))`.
+///
+/// The builder relies on these laws to push outputs as close to the root as
+/// possible: the output of a transition is the `prefix` of the outputs of all
+/// keys reachable through it, less the outputs accumulated before it. The
+/// value of a key is the `cat` of all outputs along its path, including the
+/// final output of its last node. See `Fst::output_along_path`.
 #[derive(Copy, Clone, Debug, Hash, Eq, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Output(u64);
 
+#[derive(Clone, Debug)]
+enum KeyBytes {
+    Inline([u8; INLINE_KEY_CAPACITY]),
+    Heap(Box<[u8]>),
+}
+
+impl Deref for KeyBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            KeyBytes::Inline(buf) => buf,
+            KeyBytes::Heap(buf) => buf,
+        }
+    }
+}
+
+impl DerefMut for KeyBytes {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            KeyBytes::Inline(buf) => buf,
+            KeyBytes::Heap(buf) => buf,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 struct Buffer {
-    buf: Box<[u8]>,
+    // Keys are stored inline until they outgrow `INLINE_KEY_CAPACITY`.
+    buf: KeyBytes,
     len: usize,
+    // The smallest length this buffer has had since the last call to
+    // `take_low_water`. Bytes below this length have not been touched.
+    low_water: usize,
 }
 
 impl FakeArr for Buffer {
@@ -1209,6 +2552,10 @@ impl FakeArr for Buffer {
         self.buf[offset as usize]
     }
 
+    fn as_contiguous(&self) -> Option<&[u8]> {
+        Some(&self.buf[..self.len])
+    }
+
     fn slice<'a>(&'a self, b: ShRange<Ulen>) -> FakeArrSlice<'a> {
         let x = full_slice(self);
         x.slice2(b)
@@ -1227,10 +2574,16 @@ impl FakeArr for Buffer {
 }
 
 impl Buffer {
-    fn new() -> Self {
+    fn with_capacity(capacity: usize) -> Self {
+        let buf = if capacity <= INLINE_KEY_CAPACITY {
+            KeyBytes::Inline([0u8; INLINE_KEY_CAPACITY])
+        } else {
+            KeyBytes::Heap(vec![0u8; capacity].into_boxed_slice())
+        };
         Buffer {
-            buf: vec![0u8; KEY_BUFFER_CAPACITY].into_boxed_slice(),
+            buf,
             len: 0,
+            low_water: 0,
         }
     }
 
@@ -1243,7 +2596,7 @@ impl Buffer {
         let new_cap = old_cap * 2;
         let mut new_buf = vec![0u8; new_cap].into_boxed_slice();
         new_buf[..old_cap].copy_from_slice(&self.buf[..old_cap]);
-        mem::replace(&mut self.buf, new_buf);
+        self.buf = KeyBytes::Heap(new_buf);
     }
 
     fn push(&mut self, b: u8) {
@@ -1258,8 +2611,31 @@ impl Buffer {
     fn pop(&mut self) -> &[u8] {
         let len = self.len;
         self.len = len - 1;
+        self.low_water = cmp::min(self.low_water, self.len);
+        &self.buf[..len]
+    }
+
+    // Returns the first `len` bytes of the buffer, which may include bytes
+    // that were popped but not yet overwritten.
+    fn prefix(&self, len: usize) -> &[u8] {
         &self.buf[..len]
     }
+
+    // Returns the low water mark and resets it to the current length.
+    fn take_low_water(&mut self) -> usize {
+        let low = self.low_water;
+        self.low_water = self.len;
+        low
+    }
+
+    // Returns the number of bytes on the heap, which is zero while keys fit
+    // inline.
+    fn heap_usage(&self) -> usize {
+        match self.buf {
+            KeyBytes::Inline(_) => 0,
+            KeyBytes::Heap(ref buf) => buf.len(),
+        }
+    }
 }
 
 impl Deref for Buffer {
@@ -1295,13 +2671,13 @@ impl Output {
         self.0 == 0
     }
 
-    /// Returns the prefix of this output and `o`.
+    /// Returns the prefix of this output and `o`, which is their minimum.
     #[inline]
     pub fn prefix(self, o: Output) -> Output {
         Output(cmp::min(self.0, o.0))
     }
 
-    /// Returns the concatenation of this output and `o`.
+    /// Returns the concatenation of this output and `o`, which is their sum.
     #[inline]
     pub fn cat(self, o: Output) -> Output {
         Output(self.0 + o.0)
@@ -1309,7 +2685,7 @@ impl Output {
 
     /// Returns the subtraction of `o` from this output.
     ///
-    /// This function panics if `self > o`.
+    /// This function panics if `o > self`.
     #[inline]
     pub fn sub(self, o: Output) -> Output {
         Output(
@@ -6,10 +6,26 @@ use std::ops::Range;
 use byteorder::WriteBytesExt;
 
 use crate::raw::common_inputs::{COMMON_INPUTS, COMMON_INPUTS_INV};
-use crate::raw::pack::{pack_size, pack_uint, pack_uint_in, unpack_uint};
+use crate::raw::pack::{pack_size, pack_uint, pack_uint_in, try_unpack_uint, unpack_uint};
 use crate::raw::{u64_to_Ulen, CompiledAddr, Output, Transition, EMPTY_ADDRESS};
+
+/// The version from which nodes may be chains. See `StateChain`.
+pub(crate) const CHAINS_VERSION: u64 = 4;
+
+/// The longest label of a chain.
+pub(crate) const MAX_CHAIN_LEN: usize = 255;
+
+/// The byte that follows the state byte of a chain, in place of a number of
+/// transitions that would never be written out separately.
+const CHAIN_TAG: u8 = 2;
+
+/// The bit at which the offset into a chain starts in a chain address.
+const CHAIN_OFFSET_SHIFT: u32 = 56;
+
+/// The bits of a chain address that hold the address of the chain itself.
+const CHAIN_ADDR_MASK: CompiledAddr = (1 << CHAIN_OFFSET_SHIFT) - 1;
 use crate::{
-    fake_arr::{empty, FakeArr, FakeArrRef, Ulen},
+    fake_arr::{empty, expect_read, FakeArr, FakeArrRef, Ulen},
     raw::build::BuilderNode,
     slic, slic2,
 };
@@ -25,6 +41,16 @@ const TRANS_INDEX_THRESHOLD: Ulen = 32;
 #[derive(Clone, Copy)]
 pub struct Node<'f> {
     data: FakeArrRef<'f>,
+    head: NodeHead,
+}
+
+/// What `Node` decodes from the bytes of a node, to read its transitions
+/// from those bytes.
+///
+/// This is decoded the same way from any `NodeBytes`, which lets lookups on
+/// fsts held in memory read nodes straight from a slice.
+#[derive(Clone, Copy)]
+struct NodeHead {
     version: u64,
     state: State,
     start: CompiledAddr,
@@ -35,12 +61,65 @@ pub struct Node<'f> {
     final_output: Output,
 }
 
+/// The bytes of an fst that nodes are decoded from.
+trait NodeBytes: Copy {
+    /// Reads the byte at `at`.
+    fn byte(self, at: Ulen) -> io::Result<u8>;
+
+    /// Reads the integer packed into the `nbytes` bytes starting at `at`.
+    fn uint(self, at: Ulen, nbytes: Ulen) -> io::Result<u64>;
+
+    /// Reads `buf.len()` bytes starting at `at` into `buf`.
+    fn read(self, at: Ulen, buf: &mut [u8]) -> io::Result<()>;
+}
+
+impl NodeBytes for FakeArrRef<'_> {
+    #[inline(always)]
+    fn byte(self, at: Ulen) -> io::Result<u8> {
+        self.try_get_byte(at)
+    }
+
+    #[inline(always)]
+    fn uint(self, at: Ulen, nbytes: Ulen) -> io::Result<u64> {
+        try_unpack_uint(slic!(self[at..]), nbytes as u8)
+    }
+
+    #[inline(always)]
+    fn read(self, at: Ulen, buf: &mut [u8]) -> io::Result<()> {
+        self.read_into(at, buf)
+    }
+}
+
+impl NodeBytes for &[u8] {
+    #[inline(always)]
+    fn byte(self, at: Ulen) -> io::Result<u8> {
+        Ok(self[at as usize])
+    }
+
+    #[inline(always)]
+    fn uint(self, at: Ulen, nbytes: Ulen) -> io::Result<u64> {
+        let at = at as usize;
+        let mut n = 0;
+        for (i, &b) in self[at..at + nbytes as usize].iter().enumerate() {
+            n |= (b as u64) << (8 * i);
+        }
+        Ok(n)
+    }
+
+    #[inline(always)]
+    fn read(self, at: Ulen, buf: &mut [u8]) -> io::Result<()> {
+        let at = at as usize;
+        buf.copy_from_slice(&self[at..at + buf.len()]);
+        Ok(())
+    }
+}
+
 impl<'f> fmt::Debug for Node<'f> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "NODE@{}", self.start)?;
-        writeln!(f, "  end_addr: {}", self.end)?;
+        writeln!(f, "NODE@{}", self.head.start)?;
+        writeln!(f, "  end_addr: {}", self.head.end)?;
         writeln!(f, "  size: {} bytes", self.as_slice().len())?;
-        writeln!(f, "  state: {:?}", self.state)?;
+        writeln!(f, "  state: {:?}", self.head.state)?;
         writeln!(f, "  is_final: {}", self.is_final())?;
         writeln!(f, "  final_output: {:?}", self.final_output())?;
         writeln!(f, "  # transitions: {}", self.len())?;
@@ -60,68 +139,179 @@ impl<'f> fmt::Debug for Node<'f> {
 /// not to consumers of this crate.
 #[inline(always)]
 pub fn node_new(version: u64, addr: CompiledAddr, data: FakeArrRef<'_>) -> Node {
-    use self::State::*;
-    let state = State::new(data, addr);
-    match state {
-        EmptyFinal => Node {
-            data: empty(),
-            version,
-            state: State::EmptyFinal,
-            start: EMPTY_ADDRESS,
-            end: EMPTY_ADDRESS,
-            is_final: true,
-            ntrans: 0,
-            sizes: PackSizes::new(),
-            final_output: Output::zero(),
-        },
-        OneTransNext(s) => {
-            let data = slic2!(data[..=addr]);
-            Node {
-                data,
-                version,
-                state,
-                start: addr,
-                end: s.end_addr(data),
-                is_final: false,
-                sizes: PackSizes::new(),
-                ntrans: 1,
-                final_output: Output::zero(),
+    expect_read(try_node_new(version, addr, data))
+}
+
+/// Like `node_new`, but returns the error of a read that fails.
+#[inline(always)]
+pub fn try_node_new(
+    version: u64,
+    addr: CompiledAddr,
+    data: FakeArrRef<'_>,
+) -> io::Result<Node<'_>> {
+    strict_assert!(
+        addr == EMPTY_ADDRESS || addr & CHAIN_ADDR_MASK < data.len(),
+        "node address {} out of bounds",
+        addr
+    );
+    let data = if addr == EMPTY_ADDRESS {
+        empty()
+    } else {
+        slic2!(data[..=(addr & CHAIN_ADDR_MASK)])
+    };
+    let head = NodeHead::decode(version, addr, data)?;
+    strict_assert!(
+        addr == EMPTY_ADDRESS
+            || (head.end <= addr & CHAIN_ADDR_MASK
+                && head.ntrans <= 256
+                && head.sizes.transition_pack_size() <= 8
+                && head.sizes.output_pack_size() <= 8),
+        "malformed node at address {}",
+        addr
+    );
+    Ok(Node { data, head })
+}
+
+/// Returns the end address of the node whose state byte is at `addr`, or
+/// `None` if the bytes there cannot encode a node lying entirely at or after
+/// `min_addr`.
+///
+/// Unlike `node_new`, this never trusts the data it reads, which makes it
+/// suitable for scanning a damaged FST. Only the node's framing is checked;
+/// its transitions can be checked with `Node::transition_addr_checked` once
+/// the node is known to be well framed.
+pub fn node_end_checked(
+    version: u64,
+    addr: CompiledAddr,
+    data: FakeArrRef<'_>,
+    min_addr: Ulen,
+) -> Option<Ulen> {
+    if addr < min_addr || addr >= data.len() {
+        return None;
+    }
+    let v = data.get_byte(addr);
+    let end = match (v & 0b11_000000) >> 6 {
+        0b11 => addr.checked_sub(StateOneTransNext(v).input_len())?,
+        0b10 => {
+            let s = StateOneTrans(v);
+            let at = addr.checked_sub(s.input_len() + 1)?;
+            if at < min_addr {
+                return None;
             }
-        }
-        OneTrans(s) => {
-            let data = slic2!(data[..=addr]);
-            let sizes = s.sizes(data);
-            Node {
-                data,
-                version,
-                state,
-                start: addr,
-                end: s.end_addr(data, sizes),
-                is_final: false,
-                ntrans: 1,
-                sizes,
-                final_output: Output::zero(),
+            let sizes = PackSizes::decode(data.get_byte(at));
+            let (tsize, osize) = (sizes.transition_pack_size(), sizes.output_pack_size());
+            if tsize == 0 || tsize > 8 || osize > 8 {
+                return None;
             }
+            at.checked_sub(tsize + osize)?
         }
-        AnyTrans(s) => {
-            let data = slic2!(data[..=addr]);
-            let sizes = s.sizes(data);
-            let ntrans = s.ntrans(data);
-            Node {
-                data,
-                version,
-                state,
-                start: addr,
-                end: s.end_addr(version, data, sizes, ntrans),
-                is_final: s.is_final_state(),
-                ntrans,
-                sizes,
-                final_output: s.final_output(version, data, sizes, ntrans),
+        _ => {
+            let s = StateAnyTrans(v);
+            let ntrans = match s.state_ntrans() {
+                Some(n) => n as Ulen,
+                None => {
+                    let at = addr.checked_sub(1)?;
+                    if at < min_addr {
+                        return None;
+                    }
+                    match data.get_byte(at) {
+                        1 => 256,
+                        CHAIN_TAG if version >= CHAINS_VERSION && !s.is_final_state() => {
+                            return chain_end_checked(addr, data, min_addr);
+                        }
+                        // Anything that fits in the state byte is never
+                        // written out separately.
+                        2..=0b00_111111 => return None,
+                        n => n as Ulen,
+                    }
+                }
+            };
+            // A non-final node without transitions is never compiled, except
+            // as the root of an empty FST.
+            if ntrans == 0 && !s.is_final_state() {
+                return None;
+            }
+            let at = addr.checked_sub(s.ntrans_len() + 1)?;
+            if at < min_addr {
+                return None;
+            }
+            let sizes = PackSizes::decode(data.get_byte(at));
+            let (tsize, osize) = (sizes.transition_pack_size(), sizes.output_pack_size());
+            if (tsize == 0) != (ntrans == 0) || tsize > 8 || osize > 8 {
+                return None;
             }
+            let final_osize = if s.is_final_state() { osize } else { 0 };
+            at.checked_sub(s.total_trans_size(version, sizes, ntrans))?
+                .checked_sub(ntrans * osize)?
+                .checked_sub(final_osize)?
         }
+    };
+    if end < min_addr {
+        None
+    } else {
+        Some(end)
     }
 }
 
+/// Returns the end address of the chain whose state byte is at `addr`, like
+/// `node_end_checked`.
+fn chain_end_checked(addr: CompiledAddr, data: FakeArrRef<'_>, min_addr: Ulen) -> Option<Ulen> {
+    let at = addr.checked_sub(3)?;
+    if at < min_addr {
+        return None;
+    }
+    let sizes = PackSizes::decode(data.get_byte(addr - 2));
+    let (tsize, osize) = (sizes.transition_pack_size(), sizes.output_pack_size());
+    let len = data.get_byte(at) as Ulen;
+    if tsize == 0 || tsize > 8 || osize > 8 || len < 2 {
+        return None;
+    }
+    let end = at.checked_sub(len + tsize + osize)?;
+    if end < min_addr {
+        None
+    } else {
+        Some(end)
+    }
+}
+
+/// Follows the transition for `b` out of the node at `addr`, reading the
+/// node straight from `data`.
+///
+/// Returns the address reached and the output of the transition, or `None`
+/// if there is no such transition. This decodes nodes like `Node` does,
+/// without going through `FakeArr` reads, which makes point lookups on
+/// in-memory FSTs cheaper.
+#[inline(always)]
+pub(crate) fn slice_step(
+    version: u64,
+    data: &[u8],
+    addr: CompiledAddr,
+    b: u8,
+) -> Option<(CompiledAddr, Output)> {
+    let node = expect_read(NodeHead::decode(version, addr, data));
+    let i = expect_read(node.find_input(data, b))?;
+    let addr = expect_read(node.transition_addr(data, i));
+    Some((addr, expect_read(node.transition_output(data, i))))
+}
+
+/// Returns the final output of the node at `addr`, reading the node straight
+/// from `data`, or `None` if the node is not final.
+#[inline(always)]
+pub(crate) fn slice_final_output(version: u64, data: &[u8], addr: CompiledAddr) -> Option<Output> {
+    let node = expect_read(NodeHead::decode(version, addr, data));
+    if node.is_final {
+        Some(node.final_output)
+    } else {
+        None
+    }
+}
+
+/// Returns the address of the node at `offset` in the chain at `addr`.
+#[inline(always)]
+pub(crate) fn chain_addr(addr: CompiledAddr, offset: usize) -> CompiledAddr {
+    addr | (offset as CompiledAddr) << CHAIN_OFFSET_SHIFT
+}
+
 impl<'f> Node<'f> {
     /// Returns an iterator over all transitions in this node in lexicographic
     /// order.
@@ -136,48 +326,69 @@ impl<'f> Node<'f> {
     /// Returns the transition at index `i`.
     #[inline(always)]
     pub fn transition(&self, i: Ulen) -> Transition {
-        use self::State::*;
-        match self.state {
-            OneTransNext(s) => {
-                assert_eq!(i, 0);
-                Transition {
-                    inp: s.input(self),
-                    out: Output::zero(),
-                    addr: s.trans_addr(self),
-                }
-            }
-            OneTrans(s) => {
-                assert_eq!(i, 0);
-                Transition {
-                    inp: s.input(self),
-                    out: s.output(self),
-                    addr: s.trans_addr(self),
-                }
-            }
-            AnyTrans(s) => Transition {
-                inp: s.input(self, i),
-                out: s.output(self, i),
-                addr: s.trans_addr(self, i),
-            },
-            EmptyFinal => panic!("out of bounds"),
-        }
+        expect_read(self.try_transition(i))
+    }
+
+    /// Like `transition`, but returns the error of a read that fails.
+    #[inline(always)]
+    pub fn try_transition(&self, i: Ulen) -> io::Result<Transition> {
+        self.head.transition(self.data, i)
     }
 
     /// Returns the transition address of the `i`th transition.
     #[inline(always)]
     pub fn transition_addr(&self, i: Ulen) -> CompiledAddr {
+        expect_read(self.try_transition_addr(i))
+    }
+
+    /// Like `transition_addr`, but returns the error of a read that fails.
+    #[inline(always)]
+    pub fn try_transition_addr(&self, i: Ulen) -> io::Result<CompiledAddr> {
+        self.head.transition_addr(self.data, i)
+    }
+
+    /// Returns the output of the `i`th transition.
+    #[inline(always)]
+    pub fn transition_output(&self, i: Ulen) -> Output {
+        expect_read(self.head.transition_output(self.data, i))
+    }
+
+    /// Like `transition_addr`, but returns `None` instead of panicking when
+    /// the encoded address would point before the start of the FST.
+    ///
+    /// This is meant for nodes that were found with `node_end_checked`. For
+    /// a chain, this is the address of the node that the whole chain leads
+    /// to, since the nodes inside it are not found on their own.
+    pub(crate) fn transition_addr_checked(&self, i: Ulen) -> Option<CompiledAddr> {
         use self::State::*;
-        match self.state {
-            OneTransNext(s) => {
-                assert_eq!(i, 0);
-                s.trans_addr(self)
+        let (at, tsize) = match self.head.state {
+            Chain(s) if i == 0 => {
+                let tsize = self.head.sizes.transition_pack_size();
+                (s.addr - 3 - expect_read(s.len(self.data)) - tsize, tsize)
             }
-            OneTrans(s) => {
-                assert_eq!(i, 0);
-                s.trans_addr(self)
+            OneTransNext(_) if i == 0 => return self.head.end.checked_sub(1),
+            OneTrans(s) if i == 0 => {
+                let tsize = self.head.sizes.transition_pack_size();
+                (self.head.start - s.input_len() - 1 - tsize, tsize)
             }
-            AnyTrans(s) => s.trans_addr(self, i),
-            EmptyFinal => panic!("out of bounds"),
+            AnyTrans(s) if i < self.head.ntrans => {
+                let tsize = self.head.sizes.transition_pack_size();
+                let at = self.head.start
+                         - s.ntrans_len()
+                         - 1 // pack size
+                         - s.trans_index_size(self.head.version, self.head.ntrans)
+                         - self.head.ntrans // inputs
+                         - (i * tsize) // the previous transition addresses
+                         - tsize; // the desired transition address
+                (at, tsize)
+            }
+            _ => return None,
+        };
+        let delta = u64_to_Ulen(unpack_uint(slic!(self.data[at..]), tsize as u8));
+        if delta == EMPTY_ADDRESS {
+            Some(EMPTY_ADDRESS)
+        } else {
+            self.head.end.checked_sub(delta)
         }
     }
 
@@ -186,29 +397,27 @@ impl<'f> Node<'f> {
     /// If no transition for this byte exists, then `None` is returned.
     #[inline(always)]
     pub fn find_input(&self, b: u8) -> Option<Ulen> {
-        use self::State::*;
-        match self.state {
-            OneTransNext(s) if s.input(self) == b => Some(0),
-            OneTransNext(_) => None,
-            OneTrans(s) if s.input(self) == b => Some(0),
-            OneTrans(_) => None,
-            AnyTrans(s) => s.find_input(self, b),
-            EmptyFinal => None,
-        }
+        expect_read(self.try_find_input(b))
+    }
+
+    /// Like `find_input`, but returns the error of a read that fails.
+    #[inline(always)]
+    pub fn try_find_input(&self, b: u8) -> io::Result<Option<Ulen>> {
+        self.head.find_input(self.data, b)
     }
 
     /// If this node is final and has a terminal output value, then it is
     /// returned. Otherwise, a zero output is returned.
     #[inline(always)]
     pub fn final_output(&self) -> Output {
-        self.final_output
+        self.head.final_output
     }
 
     /// Returns true if and only if this node corresponds to a final or "match"
     /// state in the finite state transducer.
     #[inline(always)]
     pub fn is_final(&self) -> bool {
-        self.is_final
+        self.head.is_final
     }
 
     /// Returns the number of transitions in this node.
@@ -216,35 +425,53 @@ impl<'f> Node<'f> {
     /// The maximum number of transitions is 256.
     #[inline(always)]
     pub fn len(&self) -> Ulen {
-        self.ntrans
+        self.head.ntrans
     }
 
     /// Returns true if and only if this node has zero transitions.
     #[inline(always)]
     pub fn is_empty(&self) -> bool {
-        self.ntrans == 0
+        self.head.ntrans == 0
     }
 
     /// Return the address of this node.
     #[inline(always)]
     pub fn addr(&self) -> CompiledAddr {
-        self.start
+        self.head.start
+    }
+
+    /// Returns the number of bytes this node takes up.
+    ///
+    /// The bytes of a chain are counted for its first node only.
+    #[inline(always)]
+    pub(crate) fn size(&self) -> Ulen {
+        match self.head.state {
+            State::Chain(s) if s.offset > 0 => 0,
+            _ => self.data.len() - self.head.end,
+        }
+    }
+
+    /// Return the address of the first byte of this node.
+    #[inline(always)]
+    pub(crate) fn end_addr(&self) -> Ulen {
+        self.head.end
     }
 
     #[doc(hidden)]
     #[inline(always)]
     pub fn as_slice(&self) -> Vec<u8> {
-        slic!(self.data[(self.end)..]).to_vec()
+        slic!(self.data[(self.head.end)..]).to_vec()
     }
 
     #[doc(hidden)]
     #[inline(always)]
     pub fn state(&self) -> &'static str {
         use self::State::*;
-        match self.state {
+        match self.head.state {
             OneTransNext(_) => "OTN",
             OneTrans(_) => "OT",
             AnyTrans(_) => "AT",
+            Chain(_) => "CH",
             EmptyFinal => "EF",
         }
     }
@@ -268,6 +495,178 @@ impl<'f> Node<'f> {
     }
 }
 
+impl NodeHead {
+    #[inline(always)]
+    fn decode<D: NodeBytes>(version: u64, addr: CompiledAddr, data: D) -> io::Result<NodeHead> {
+        use self::State::*;
+        let state = State::new(version, data, addr)?;
+        Ok(match state {
+            EmptyFinal => NodeHead {
+                version,
+                state,
+                start: EMPTY_ADDRESS,
+                end: EMPTY_ADDRESS,
+                is_final: true,
+                ntrans: 0,
+                sizes: PackSizes::new(),
+                final_output: Output::zero(),
+            },
+            OneTransNext(s) => NodeHead {
+                version,
+                state,
+                start: addr,
+                end: s.end_addr(addr),
+                is_final: false,
+                sizes: PackSizes::new(),
+                ntrans: 1,
+                final_output: Output::zero(),
+            },
+            OneTrans(s) => {
+                let sizes = s.sizes(data, addr)?;
+                NodeHead {
+                    version,
+                    state,
+                    start: addr,
+                    end: s.end_addr(addr, sizes),
+                    is_final: false,
+                    ntrans: 1,
+                    sizes,
+                    final_output: Output::zero(),
+                }
+            }
+            Chain(s) => {
+                let sizes = s.sizes(data)?;
+                NodeHead {
+                    version,
+                    state,
+                    start: addr,
+                    end: s.end_addr(data, sizes)?,
+                    is_final: false,
+                    ntrans: 1,
+                    sizes,
+                    final_output: Output::zero(),
+                }
+            }
+            AnyTrans(s) => {
+                let sizes = s.sizes(data, addr)?;
+                let ntrans = s.ntrans(data, addr)?;
+                NodeHead {
+                    version,
+                    state,
+                    start: addr,
+                    end: s.end_addr(version, addr, sizes, ntrans),
+                    is_final: s.is_final_state(),
+                    ntrans,
+                    sizes,
+                    final_output: s.final_output(version, data, addr, sizes, ntrans)?,
+                }
+            }
+        })
+    }
+
+    #[inline(always)]
+    fn transition<D: NodeBytes>(&self, data: D, i: Ulen) -> io::Result<Transition> {
+        use self::State::*;
+        strict_assert!(i < self.ntrans, "transition {} out of bounds", i);
+        let t = match self.state {
+            OneTransNext(s) => {
+                assert_eq!(i, 0);
+                Transition {
+                    inp: s.input(self, data)?,
+                    out: Output::zero(),
+                    addr: s.trans_addr(self),
+                }
+            }
+            OneTrans(s) => {
+                assert_eq!(i, 0);
+                Transition {
+                    inp: s.input(self, data)?,
+                    out: s.output(self, data)?,
+                    addr: s.trans_addr(self, data)?,
+                }
+            }
+            AnyTrans(s) => Transition {
+                inp: s.input(self, data, i)?,
+                out: s.output(self, data, i)?,
+                addr: s.trans_addr(self, data, i)?,
+            },
+            Chain(s) => {
+                assert_eq!(i, 0);
+                Transition {
+                    inp: s.input(data)?,
+                    out: s.output(self, data)?,
+                    addr: s.trans_addr(self, data)?,
+                }
+            }
+            EmptyFinal => panic!("out of bounds"),
+        };
+        // Nodes are written after the nodes they point to, except for the
+        // nodes inside a chain.
+        strict_assert!(
+            t.addr == EMPTY_ADDRESS || t.addr < self.end || t.addr > CHAIN_ADDR_MASK,
+            "transition of node {} points forward to {}",
+            self.start,
+            t.addr
+        );
+        Ok(t)
+    }
+
+    #[inline(always)]
+    fn transition_addr<D: NodeBytes>(&self, data: D, i: Ulen) -> io::Result<CompiledAddr> {
+        use self::State::*;
+        match self.state {
+            OneTransNext(s) => {
+                assert_eq!(i, 0);
+                Ok(s.trans_addr(self))
+            }
+            OneTrans(s) => {
+                assert_eq!(i, 0);
+                s.trans_addr(self, data)
+            }
+            AnyTrans(s) => s.trans_addr(self, data, i),
+            Chain(s) => {
+                assert_eq!(i, 0);
+                s.trans_addr(self, data)
+            }
+            EmptyFinal => panic!("out of bounds"),
+        }
+    }
+
+    #[inline(always)]
+    fn transition_output<D: NodeBytes>(&self, data: D, i: Ulen) -> io::Result<Output> {
+        use self::State::*;
+        match self.state {
+            OneTransNext(_) => {
+                assert_eq!(i, 0);
+                Ok(Output::zero())
+            }
+            OneTrans(s) => {
+                assert_eq!(i, 0);
+                s.output(self, data)
+            }
+            AnyTrans(s) => s.output(self, data, i),
+            Chain(s) => {
+                assert_eq!(i, 0);
+                s.output(self, data)
+            }
+            EmptyFinal => panic!("out of bounds"),
+        }
+    }
+
+    #[inline(always)]
+    fn find_input<D: NodeBytes>(&self, data: D, b: u8) -> io::Result<Option<Ulen>> {
+        use self::State::*;
+        let inp = match self.state {
+            OneTransNext(s) => s.input(self, data)?,
+            OneTrans(s) => s.input(self, data)?,
+            AnyTrans(s) => return s.find_input(self, data, b),
+            Chain(s) => s.input(data)?,
+            EmptyFinal => return Ok(None),
+        };
+        Ok(if inp == b { Some(0) } else { None })
+    }
+}
+
 impl BuilderNode {
     pub fn compile_to<W: io::Write>(
         &self,
@@ -284,6 +683,7 @@ enum State {
     OneTransNext(StateOneTransNext),
     OneTrans(StateOneTrans),
     AnyTrans(StateAnyTrans),
+    Chain(StateChain),
     EmptyFinal,
 }
 
@@ -296,20 +696,38 @@ struct StateOneTrans(u8);
 // one trans flag (0), final flag, # transitions
 #[derive(Clone, Copy, Debug)]
 struct StateAnyTrans(u8);
+// A run of non-final nodes with one transition each, the first of which may
+// have an output, stored as a single node with the inputs as its label:
+//
+//     [output] [transition address] [label, last byte first] [label length]
+//     [pack sizes] [CHAIN_TAG] [state byte: 0]
+//
+// The node after reading `offset` bytes of the label has the address of the
+// chain with `offset` in its high bits. See `chain_addr`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct StateChain {
+    addr: CompiledAddr,
+    offset: Ulen,
+}
 
 impl State {
     #[inline(always)]
-    fn new(data: FakeArrRef<'_>, addr: CompiledAddr) -> State {
+    fn new<D: NodeBytes>(version: u64, data: D, addr: CompiledAddr) -> io::Result<State> {
         use self::State::*;
         if addr == EMPTY_ADDRESS {
-            return EmptyFinal;
+            return Ok(EmptyFinal);
+        }
+        let offset = addr >> CHAIN_OFFSET_SHIFT;
+        let addr = addr & CHAIN_ADDR_MASK;
+        let v = data.byte(addr)?;
+        if offset > 0 || (v == 0 && version >= CHAINS_VERSION && data.byte(addr - 1)? == CHAIN_TAG) {
+            return Ok(Chain(StateChain { addr, offset }));
         }
-        let v = slic!(data[addr]);
-        match (v & 0b11_000000) >> 6 {
+        Ok(match (v & 0b11_000000) >> 6 {
             0b11 => OneTransNext(StateOneTransNext(v)),
             0b10 => OneTrans(StateOneTrans(v)),
             _ => AnyTrans(StateAnyTrans(v)),
-        }
+        })
     }
 }
 
@@ -347,21 +765,20 @@ impl StateOneTransNext {
     }
 
     #[inline(always)]
-    fn end_addr(self, data: FakeArrRef) -> Ulen {
-        data.len() - 1 - self.input_len()
+    fn end_addr(self, addr: CompiledAddr) -> Ulen {
+        addr - self.input_len()
     }
 
     #[inline(always)]
-    fn input(self, node: &Node) -> u8 {
-        if let Some(inp) = self.common_input() {
-            inp
-        } else {
-            slic!(node.data[(node.start - 1)])
+    fn input<D: NodeBytes>(self, node: &NodeHead, data: D) -> io::Result<u8> {
+        match self.common_input() {
+            Some(inp) => Ok(inp),
+            None => data.byte(node.start - 1),
         }
     }
 
     #[inline(always)]
-    fn trans_addr(self, node: &Node) -> CompiledAddr {
+    fn trans_addr(self, node: &NodeHead) -> CompiledAddr {
         node.end as CompiledAddr - 1
     }
 }
@@ -412,14 +829,14 @@ impl StateOneTrans {
     }
 
     #[inline(always)]
-    fn sizes(self, data: FakeArrRef) -> PackSizes {
-        let i = data.len() - 1 - self.input_len() - 1;
-        PackSizes::decode(slic!(data[i]))
+    fn sizes<D: NodeBytes>(self, data: D, addr: CompiledAddr) -> io::Result<PackSizes> {
+        let i = addr - self.input_len() - 1;
+        Ok(PackSizes::decode(data.byte(i)?))
     }
 
     #[inline(always)]
-    fn end_addr(self, data: FakeArrRef, sizes: PackSizes) -> Ulen {
-        data.len() - 1
+    fn end_addr(self, addr: CompiledAddr, sizes: PackSizes) -> Ulen {
+        addr
         - self.input_len()
         - 1 // pack size
         - sizes.transition_pack_size()
@@ -427,36 +844,35 @@ impl StateOneTrans {
     }
 
     #[inline(always)]
-    fn input(self, node: &Node) -> u8 {
-        if let Some(inp) = self.common_input() {
-            inp
-        } else {
-            slic!(node.data[(node.start - 1)])
+    fn input<D: NodeBytes>(self, node: &NodeHead, data: D) -> io::Result<u8> {
+        match self.common_input() {
+            Some(inp) => Ok(inp),
+            None => data.byte(node.start - 1),
         }
     }
 
     #[inline(always)]
-    fn output(self, node: &Node) -> Output {
+    fn output<D: NodeBytes>(self, node: &NodeHead, data: D) -> io::Result<Output> {
         let osize = node.sizes.output_pack_size();
         if osize == 0 {
-            return Output::zero();
+            return Ok(Output::zero());
         }
         let tsize = node.sizes.transition_pack_size();
         let i = node.start
                 - self.input_len()
                 - 1 // pack size
                 - tsize - osize;
-        Output::new(unpack_uint(slic!(node.data[i..]), osize as u8))
+        Ok(Output::new(data.uint(i, osize)?))
     }
 
     #[inline(always)]
-    fn trans_addr(self, node: &Node) -> CompiledAddr {
+    fn trans_addr<D: NodeBytes>(self, node: &NodeHead, data: D) -> io::Result<CompiledAddr> {
         let tsize = node.sizes.transition_pack_size();
         let i = node.start
                 - self.input_len()
                 - 1 // pack size
                 - tsize;
-        unpack_delta(slic!(node.data[i..]), tsize, node.end)
+        unpack_delta(data, i, tsize, node.end)
     }
 }
 
@@ -558,9 +974,9 @@ impl StateAnyTrans {
     }
 
     #[inline(always)]
-    fn sizes(self, data: FakeArrRef) -> PackSizes {
-        let i = data.len() - 1 - self.ntrans_len() - 1;
-        PackSizes::decode(slic!(data[i]))
+    fn sizes<D: NodeBytes>(self, data: D, addr: CompiledAddr) -> io::Result<PackSizes> {
+        let i = addr - self.ntrans_len() - 1;
+        Ok(PackSizes::decode(data.byte(i)?))
     }
 
     #[inline(always)]
@@ -588,47 +1004,48 @@ impl StateAnyTrans {
     }
 
     #[inline(always)]
-    fn ntrans(self, data: FakeArrRef) -> Ulen {
+    fn ntrans<D: NodeBytes>(self, data: D, addr: CompiledAddr) -> io::Result<Ulen> {
         if let Some(n) = self.state_ntrans() {
-            n as Ulen
+            Ok(n as Ulen)
         } else {
-            let n = slic!(data[(data.len() - 2)]) as Ulen;
+            let n = data.byte(addr - 1)? as Ulen;
             if n == 1 {
                 // "1" is never a normal legal value here, because if there
                 // is only 1 transition, then it is encoded in the state byte.
-                256
+                Ok(256)
             } else {
-                n
+                Ok(n)
             }
         }
     }
 
     #[inline(always)]
-    fn final_output(
+    fn final_output<D: NodeBytes>(
         self,
         version: u64,
-        data: FakeArrRef,
+        data: D,
+        addr: CompiledAddr,
         sizes: PackSizes,
         ntrans: Ulen,
-    ) -> Output {
+    ) -> io::Result<Output> {
         let osize = sizes.output_pack_size();
         if osize == 0 || !self.is_final_state() {
-            return Output::zero();
+            return Ok(Output::zero());
         }
-        let at = data.len() - 1
+        let at = addr
                  - self.ntrans_len()
                  - 1 // pack size
                  - self.total_trans_size(version, sizes, ntrans)
                  - (ntrans * osize) // output values
                  - osize; // the desired output value
-        Output::new(unpack_uint(slic!(data[at..]), osize as u8))
+        Ok(Output::new(data.uint(at, osize)?))
     }
 
     #[inline(always)]
-    fn end_addr(self, version: u64, data: FakeArrRef, sizes: PackSizes, ntrans: Ulen) -> Ulen {
+    fn end_addr(self, version: u64, addr: CompiledAddr, sizes: PackSizes, ntrans: Ulen) -> Ulen {
         let osize = sizes.output_pack_size();
         let final_osize = if !self.is_final_state() { 0 } else { osize };
-        data.len() - 1
+        addr
         - self.ntrans_len()
         - 1 // pack size
         - self.total_trans_size(version, sizes, ntrans)
@@ -637,7 +1054,7 @@ impl StateAnyTrans {
     }
 
     #[inline(always)]
-    fn trans_addr(self, node: &Node, i: Ulen) -> CompiledAddr {
+    fn trans_addr<D: NodeBytes>(self, node: &NodeHead, data: D, i: Ulen) -> io::Result<CompiledAddr> {
         assert!(i < node.ntrans);
         let tsize = node.sizes.transition_pack_size();
         let at = node.start
@@ -647,54 +1064,55 @@ impl StateAnyTrans {
                  - node.ntrans // inputs
                  - (i * tsize) // the previous transition addresses
                  - tsize; // the desired transition address
-        unpack_delta(slic!(node.data[at..]), tsize, node.end)
+        unpack_delta(data, at, tsize, node.end)
     }
 
     #[inline(always)]
-    fn input(self, node: &Node, i: Ulen) -> u8 {
+    fn input<D: NodeBytes>(self, node: &NodeHead, data: D, i: Ulen) -> io::Result<u8> {
         let at = node.start
                  - self.ntrans_len()
                  - 1 // pack size
                  - self.trans_index_size(node.version, node.ntrans)
                  - i
                  - 1; // the input byte
-        slic!(node.data[at])
+        data.byte(at)
     }
 
     #[inline(always)]
-    fn find_input(self, node: &Node, b: u8) -> Option<Ulen> {
+    fn find_input<D: NodeBytes>(self, node: &NodeHead, data: D, b: u8) -> io::Result<Option<Ulen>> {
         if node.version >= 2 && node.ntrans > TRANS_INDEX_THRESHOLD {
             let start = node.start
                         - self.ntrans_len()
                         - 1 // pack size
                         - self.trans_index_size(node.version, node.ntrans);
-            let i = node.data.get_byte((start + b as Ulen)) as Ulen;
+            let i = data.byte(start + b as Ulen)? as Ulen;
             if i >= node.ntrans {
-                None
+                Ok(None)
             } else {
-                Some(i)
+                Ok(Some(i))
             }
         } else {
             let start = node.start
                         - self.ntrans_len()
                         - 1 // pack size
                         - node.ntrans; // inputs
-            let end = start + node.ntrans;
-            let inputs = slic!(node.data[start..end]);
-            for i in 0..inputs.len() {
-                if inputs.get_byte(i) == b {
-                    return Some(node.ntrans - i - 1);
-                }
-            }
-            None
+            // Version 1 nodes have no index, however many transitions they
+            // have.
+            let mut inputs = [0; 256];
+            let inputs = &mut inputs[..node.ntrans as usize];
+            data.read(start, inputs)?;
+            Ok(inputs
+                .iter()
+                .position(|&inp| inp == b)
+                .map(|i| node.ntrans - i as Ulen - 1))
         }
     }
 
     #[inline(always)]
-    fn output(self, node: &Node, i: Ulen) -> Output {
+    fn output<D: NodeBytes>(self, node: &NodeHead, data: D, i: Ulen) -> io::Result<Output> {
         let osize = node.sizes.output_pack_size();
         if osize == 0 {
-            return Output::zero();
+            return Ok(Output::zero());
         }
         let at = node.start
                  - self.ntrans_len()
@@ -702,7 +1120,118 @@ impl StateAnyTrans {
                  - self.total_trans_size(node.version, node.sizes, node.ntrans)
                  - (i * osize) // the previous outputs
                  - osize; // the desired output value
-        Output::new(unpack_uint(slic!(node.data[at..]), osize as u8))
+        Ok(Output::new(data.uint(at, osize)?))
+    }
+}
+
+impl StateChain {
+    /// Writes a chain with the given label, whose first transition has the
+    /// output `out` and whose last transition leads to `target`.
+    pub(crate) fn compile<W: io::Write>(
+        mut wtr: W,
+        addr: CompiledAddr,
+        label: &[u8],
+        out: Output,
+        target: CompiledAddr,
+    ) -> io::Result<()> {
+        assert!(label.len() >= 2 && label.len() <= MAX_CHAIN_LEN);
+        let output_pack_size = if out.is_zero() {
+            0
+        } else {
+            pack_uint(&mut wtr, out.value())?
+        };
+        let trans_pack_size = pack_delta(&mut wtr, addr, target)?;
+        for &b in label.iter().rev() {
+            wtr.write_u8(b)?;
+        }
+        wtr.write_u8(label.len() as u8)?;
+
+        let mut pack_sizes = PackSizes::new();
+        pack_sizes.set_output_pack_size(output_pack_size);
+        pack_sizes.set_transition_pack_size(trans_pack_size);
+        wtr.write_u8(pack_sizes.encode())?;
+        wtr.write_u8(CHAIN_TAG)?;
+        wtr.write_u8(StateAnyTrans::new().0)
+    }
+
+    /// Returns the number of bytes a chain takes up, next to the number of
+    /// bytes the nodes it replaces would take up.
+    ///
+    /// `last_addr` is the address of the last node written, which `addr`
+    /// would follow.
+    pub(crate) fn encoded_sizes(
+        last_addr: CompiledAddr,
+        addr: CompiledAddr,
+        label: &[u8],
+        out: Output,
+        target: CompiledAddr,
+    ) -> (u64, u64) {
+        let osize = if out.is_zero() {
+            0
+        } else {
+            pack_size(out.value()) as u64
+        };
+        let chain = 4 + label.len() as u64 + osize + pack_delta_size(addr, target) as u64;
+        // Every node is written right after the node it leads to, except
+        // perhaps the last one, and only the first one has an output.
+        let mut nodes = 0;
+        for (i, &b) in label.iter().enumerate() {
+            let input_len = (common_idx(b, 0b11_1111) == 0) as u64;
+            let is_last = i + 1 == label.len();
+            let next = !is_last || target == last_addr;
+            if next && (i > 0 || out.is_zero()) {
+                nodes += 1 + input_len;
+            } else {
+                let tsize = if next {
+                    1
+                } else {
+                    pack_delta_size(addr, target) as u64
+                };
+                nodes += 2 + input_len + tsize + if i == 0 { osize } else { 0 };
+            }
+        }
+        (chain, nodes)
+    }
+
+    #[inline(always)]
+    fn sizes<D: NodeBytes>(self, data: D) -> io::Result<PackSizes> {
+        Ok(PackSizes::decode(data.byte(self.addr - 2)?))
+    }
+
+    #[inline(always)]
+    fn end_addr<D: NodeBytes>(self, data: D, sizes: PackSizes) -> io::Result<Ulen> {
+        let len = self.len(data)?;
+        Ok(self.addr - 3 - len - sizes.transition_pack_size() - sizes.output_pack_size())
+    }
+
+    #[inline(always)]
+    fn len<D: NodeBytes>(self, data: D) -> io::Result<Ulen> {
+        Ok(data.byte(self.addr - 3)? as Ulen)
+    }
+
+    #[inline(always)]
+    fn input<D: NodeBytes>(self, data: D) -> io::Result<u8> {
+        data.byte(self.addr - 4 - self.offset)
+    }
+
+    #[inline(always)]
+    fn output<D: NodeBytes>(self, node: &NodeHead, data: D) -> io::Result<Output> {
+        let osize = node.sizes.output_pack_size();
+        if self.offset > 0 || osize == 0 {
+            return Ok(Output::zero());
+        }
+        Ok(Output::new(data.uint(node.end, osize)?))
+    }
+
+    #[inline(always)]
+    fn trans_addr<D: NodeBytes>(self, node: &NodeHead, data: D) -> io::Result<CompiledAddr> {
+        let len = self.len(data)?;
+        if self.offset + 1 < len {
+            return Ok(chain_addr(self.addr, (self.offset + 1) as usize));
+        }
+        let tsize = node.sizes.transition_pack_size();
+        let i = self.addr - 3 - len - tsize;
+        unpack_delta(data, i, tsize, node.end)
     }
 }
 
@@ -835,13 +1364,18 @@ fn pack_delta_size(node_addr: CompiledAddr, trans_addr: CompiledAddr) -> u8 {
 }
 
 #[inline(always)]
-fn unpack_delta(slice: FakeArrRef<'_>, trans_pack_size: Ulen, node_addr: Ulen) -> CompiledAddr {
-    let delta = unpack_uint(slice, trans_pack_size as u8);
+fn unpack_delta<D: NodeBytes>(
+    data: D,
+    at: Ulen,
+    trans_pack_size: Ulen,
+    node_addr: Ulen,
+) -> io::Result<CompiledAddr> {
+    let delta = data.uint(at, trans_pack_size)?;
     let delta_addr = u64_to_Ulen(delta);
     if delta_addr == EMPTY_ADDRESS {
-        EMPTY_ADDRESS
+        Ok(EMPTY_ADDRESS)
     } else {
-        node_addr - delta_addr
+        Ok(node_addr - delta_addr)
     }
 }
 
@@ -881,7 +1415,6 @@ mod tests {
     }
 
     fn nodes_equal(compiled: &Node, uncompiled: &BuilderNode) -> bool {
-        println!("{:?}", compiled);
         assert_eq!(compiled.is_final(), uncompiled.is_final);
         assert_eq!(compiled.len(), uncompiled.trans.len() as Ulen);
         assert_eq!(compiled.final_output(), uncompiled.final_output);
@@ -0,0 +1,61 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Hooks that report the resources used by builders and searches.
+///
+/// An observer is attached to a single build with `Builder::set_observer`
+/// or to a single search with `StreamBuilder::observer`. This lets an
+/// embedding search engine attribute the work done by this crate to a
+/// tenant, and enforce quotas, without wrapping the underlying data or
+/// writer.
+///
+/// Every method does nothing by default. Methods take `&self`, since one
+/// observer is typically shared by many builds and searches at once.
+pub trait ResourceObserver: Send + Sync {
+    /// Called whenever a search decodes a node, with the size of the node in
+    /// bytes.
+    fn node_visited(&self, _bytes_read: u64) {}
+
+    /// Called whenever a builder writes bytes to its writer.
+    fn bytes_written(&self, _bytes: u64) {}
+}
+
+/// A `ResourceObserver` that adds up everything it is told about.
+#[derive(Debug, Default)]
+pub struct ResourceCounter {
+    nodes_visited: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+impl ResourceCounter {
+    /// Creates a counter with every count at zero.
+    pub fn new() -> ResourceCounter {
+        ResourceCounter::default()
+    }
+
+    /// Returns the number of nodes visited by searches.
+    pub fn nodes_visited(&self) -> u64 {
+        self.nodes_visited.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bytes read by searches.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bytes written by builders.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+}
+
+impl ResourceObserver for ResourceCounter {
+    fn node_visited(&self, bytes_read: u64) {
+        self.nodes_visited.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(bytes_read, Ordering::Relaxed);
+    }
+
+    fn bytes_written(&self, bytes: u64) {
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
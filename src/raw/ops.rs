@@ -3,6 +3,7 @@ use std::collections::BinaryHeap;
 use std::iter::FromIterator;
 
 use crate::{fake_arr::{FakeArr, FakeArrRef, Ulen, slice_to_fake_arr}, raw::Output};
+use crate::automaton::Automaton;
 use crate::stream::{IntoStreamer, Streamer};
 
 /// Permits stream operations to be hetergeneous with respect to streams.
@@ -16,6 +17,7 @@ type BoxedStream<'f> = Box<dyn for<'a> Streamer<'a, Item = (FakeArrRef<'a>, Outp
 /// and the value corresponds to the value associated with a particular key
 /// in that stream.
 #[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct IndexedValue {
     /// The index of the stream that produced this value (starting at `0`).
     pub index: Ulen,
@@ -356,6 +358,135 @@ impl<'a, 'f> Streamer<'a> for SymmetricDifference<'f> {
     }
 }
 
+macro_rules! op_search {
+    ($op:ident) => {
+        impl<'f> $op<'f> {
+            /// Emits only the keys of this operation that `aut` matches.
+            ///
+            /// The automaton filters the keys after they are merged, so the
+            /// input streams still emit every key. See `OpSearch`.
+            pub fn search<A: Automaton>(self, aut: A) -> OpSearch<'f, A> {
+                OpSearch::new(Op::$op(self), aut)
+            }
+        }
+    };
+}
+
+op_search!(Union);
+op_search!(Intersection);
+op_search!(Difference);
+op_search!(SymmetricDifference);
+
+enum Op<'f> {
+    Union(Union<'f>),
+    Intersection(Intersection<'f>),
+    Difference(Difference<'f>),
+    SymmetricDifference(SymmetricDifference<'f>),
+}
+
+impl<'f> Op<'f> {
+    /// Writes the next key and its values into `key` and `outs`, returning
+    /// false once the operation is exhausted.
+    fn next_into(&mut self, key: &mut Vec<u8>, outs: &mut Vec<IndexedValue>) -> bool {
+        let (k, vs) = match self {
+            Op::Union(op) => match op.next() {
+                None => return false,
+                Some((k, vs)) => (k.to_vec(), vs),
+            },
+            Op::Intersection(op) => match op.next() {
+                None => return false,
+                Some((k, vs)) => (k.to_vec(), vs),
+            },
+            Op::Difference(op) => match op.next() {
+                None => return false,
+                Some((k, vs)) => (k.to_vec(), vs),
+            },
+            Op::SymmetricDifference(op) => match op.next() {
+                None => return false,
+                Some((k, vs)) => (k.to_vec(), vs),
+            },
+        };
+        *key = k;
+        outs.clear();
+        outs.extend_from_slice(vs);
+        true
+    }
+}
+
+/// A set operation whose keys are filtered by an automaton.
+///
+/// The automaton filters the keys after they are merged. It runs once over
+/// each merged key, rather than once per input stream that has the key,
+/// and reuses the states it reached for the prefix that a key shares with
+/// the previous one.
+///
+/// Since the input streams are not searched, they still visit and emit
+/// every key, and the merge still compares all of them. This pays off when
+/// running the automaton costs more than reading the keys, such as for a
+/// union of many streams that share most of their keys. When the automaton
+/// rejects most keys, searching each input with `StreamBuilder::search`
+/// before adding it is faster, as it skips the parts of each fst that
+/// cannot match.
+///
+/// The `'f` lifetime parameter refers to the lifetime of the underlying fst.
+pub struct OpSearch<'f, A: Automaton> {
+    op: Op<'f>,
+    aut: A,
+    // `states[i]` is the state reached by the first `i` bytes of `key`, for
+    // as long as the automaton can match.
+    states: Vec<A::State>,
+    key: Vec<u8>,
+    next_key: Vec<u8>,
+    outs: Vec<IndexedValue>,
+}
+
+impl<'f, A: Automaton> OpSearch<'f, A> {
+    fn new(op: Op<'f>, aut: A) -> OpSearch<'f, A> {
+        let start = aut.start();
+        OpSearch {
+            op,
+            aut,
+            states: vec![start],
+            key: vec![],
+            next_key: vec![],
+            outs: vec![],
+        }
+    }
+}
+
+impl<'a, 'f, A: Automaton> Streamer<'a> for OpSearch<'f, A> {
+    type Item = (&'a [u8], &'a [IndexedValue]);
+
+    fn next(&'a mut self) -> Option<Self::Item> {
+        loop {
+            if !self.op.next_into(&mut self.next_key, &mut self.outs) {
+                return None;
+            }
+            let common = self
+                .key
+                .iter()
+                .zip(&self.next_key)
+                .take_while(|(a, b)| a == b)
+                .count();
+            self.states.truncate(cmp::min(common + 1, self.states.len()));
+            std::mem::swap(&mut self.key, &mut self.next_key);
+            while self.states.len() <= self.key.len() {
+                let state = &self.states[self.states.len() - 1];
+                if !self.aut.can_match(state) {
+                    break;
+                }
+                let next = self.aut.accept(state, self.key[self.states.len() - 1]);
+                self.states.push(next);
+            }
+            if self.states.len() == self.key.len() + 1
+                && self.aut.is_match(&self.states[self.key.len()])
+            {
+                return Some((&self.key, &self.outs));
+            }
+        }
+    }
+}
+
 struct StreamHeap<'f> {
     rdrs: Vec<BoxedStream<'f>>,
     heap: BinaryHeap<Slot>,
@@ -2,7 +2,7 @@ use std::io;
 
 use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
 
-use crate::{fake_arr::{FakeArr, FakeArrRef, Ulen}, slic};
+use crate::fake_arr::{expect_read, FakeArr, FakeArrRef};
 
 /// pack_uint packs the given integer in the smallest number of bytes possible,
 /// and writes it to the given writer. The number of bytes written is returned
@@ -28,10 +28,16 @@ pub fn pack_uint_in<W: io::Write>(mut wtr: W, n: u64, nbytes: u8) -> io::Result<
 /// `nbytes` must be >= 1 and <= 8.
 #[inline(always)]
 pub fn unpack_uint(slice: FakeArrRef<'_>, nbytes: u8) -> u64 {
-    LittleEndian::read_uint(
-        &slic!(slice[0..(nbytes as Ulen)]).actually_read_it(),
-        nbytes as usize,
-    )
+    expect_read(try_unpack_uint(slice, nbytes))
+}
+
+/// Like `unpack_uint`, but returns the error of a read that fails.
+#[inline(always)]
+pub fn try_unpack_uint(slice: FakeArrRef<'_>, nbytes: u8) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    let buf = &mut buf[..nbytes as usize];
+    slice.read_into(0, buf)?;
+    Ok(LittleEndian::read_uint(buf, nbytes as usize))
 }
 
 /// pack_size returns the smallest number of bytes that can encode `n`.
@@ -0,0 +1,53 @@
+use std::ops::ControlFlow;
+
+use crate::fake_arr::FakeArr;
+use crate::raw::{CompiledAddr, Fst, Output};
+
+/// A node whose outputs could have been pushed further towards the root,
+/// as reported by `Fst::check_weight_pushing`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PushingViolation {
+    /// The address of the node.
+    pub addr: CompiledAddr,
+    /// The common prefix of the final output of the node, if it is final,
+    /// and of the outputs of its transitions. It could have been moved to
+    /// the transitions leading to the node.
+    pub residual: Output,
+}
+
+impl<Data: FakeArr> Fst<Data> {
+    /// Checks that outputs were pushed towards the root as far as possible,
+    /// and returns the nodes where they were not.
+    ///
+    /// The builder moves the common prefix of everything that leaves a node,
+    /// its final output and the outputs of its transitions, to the
+    /// transitions that lead to it. So apart from the root, which has no
+    /// transition leading to it, every node of an fst written by `Builder`
+    /// has a zero common prefix. A violation does not make an fst return
+    /// wrong values, but it points at a builder regression, or at an fst
+    /// produced by other means that streams and searches relying on this
+    /// invariant, such as `top_k_completions`, may not handle well.
+    ///
+    /// Every distinct node is visited once, in the order of `walk`.
+    pub fn check_weight_pushing(&self) -> Vec<PushingViolation> {
+        let root = self.root().addr();
+        let mut violations = vec![];
+        let _ = self.walk::<(), _>(|node, _| {
+            if node.addr() == root {
+                return ControlFlow::Continue(());
+            }
+            let finals = Some(node.final_output()).filter(|_| node.is_final());
+            let outs = finals.into_iter().chain(node.transitions().map(|t| t.out));
+            let residual = outs.reduce(Output::prefix).unwrap_or_else(Output::zero);
+            if !residual.is_zero() {
+                violations.push(PushingViolation {
+                    addr: node.addr(),
+                    residual,
+                });
+            }
+            ControlFlow::Continue(())
+        });
+        violations
+    }
+}
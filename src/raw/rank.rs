@@ -0,0 +1,355 @@
+use std::cmp::{self, Ordering};
+use std::collections::HashMap;
+
+use crate::automaton::Automaton;
+use crate::error::Result;
+use crate::fake_arr::{FakeArr, FakeArrRef, Ulen};
+use crate::raw::footer::read_u64;
+use crate::raw::{Bound, CompiledAddr, Error, Fst, FstMeta, Output, StreamBuilder};
+use crate::slic;
+
+impl<Data: FakeArr> Fst<Data> {
+    /// Returns the ordinal of `key`, which is the number of keys in this
+    /// fst that are less than it, or `None` if `key` is not in this fst.
+    ///
+    /// Along the path of `key`, every key that branches off to a smaller
+    /// byte, or that ends before `key` does, is counted. Those keys are
+    /// counted per node rather than enumerated, and nodes shared by several
+    /// branches are only counted once, so this visits at most every node of
+    /// the fst and usually far fewer.
+    pub fn get_index<B: AsRef<[u8]>>(&self, key: B) -> Option<u64> {
+        let mut counts = HashMap::new();
+        let mut ord = 0;
+        let mut node = self.root();
+        for &b in key.as_ref() {
+            if node.is_final() {
+                ord += 1;
+            }
+            let mut next = None;
+            for t in node.transitions() {
+                if t.inp < b {
+                    ord += self.count_keys_from(t.addr, &mut counts);
+                } else {
+                    if t.inp == b {
+                        next = Some(t.addr);
+                    }
+                    break;
+                }
+            }
+            node = self.node(next?);
+        }
+        if node.is_final() {
+            Some(ord)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the ordinal of each of the given keys, or `None` for keys
+    /// that are not in this fst.
+    ///
+    /// `sorted_keys` must be in lexicographic order, with duplicates
+    /// allowed. If it is not, then an error is returned.
+    ///
+    /// Each key is looked up like `get_index`, starting from the node
+    /// reached by the prefix it shares with the previous key. The number of
+    /// keys below each node is counted once and shared by all keys, so the
+    /// whole batch visits every node at most once, and far fewer if the fst
+    /// was built with `Builder::with_counts`.
+    pub fn ordinals_for<B: AsRef<[u8]>>(&self, sorted_keys: &[B]) -> Result<Vec<Option<u64>>> {
+        for pair in sorted_keys.windows(2) {
+            let (previous, got) = (pair[0].as_ref(), pair[1].as_ref());
+            if previous > got {
+                return Err(Error::OutOfOrder {
+                    previous: previous.to_vec(),
+                    got: got.to_vec(),
+                }
+                .into());
+            }
+        }
+        let mut counts = HashMap::new();
+        let mut ords = Vec::with_capacity(sorted_keys.len());
+        // `path[i]` is the node reached by the first `i` bytes of `prev`,
+        // along with the number of keys less than those bytes.
+        let mut path = vec![(self.root(), 0)];
+        let mut prev: &[u8] = &[];
+        for key in sorted_keys {
+            let key = key.as_ref();
+            let common = prev.iter().zip(key).take_while(|(a, b)| a == b).count();
+            path.truncate(cmp::min(common, path.len() - 1) + 1);
+            while path.len() <= key.len() {
+                let (node, mut ord) = path[path.len() - 1];
+                let b = key[path.len() - 1];
+                if node.is_final() {
+                    ord += 1;
+                }
+                let mut next = None;
+                for t in node.transitions() {
+                    if t.inp < b {
+                        ord += self.count_keys_from(t.addr, &mut counts);
+                    } else {
+                        if t.inp == b {
+                            next = Some(t.addr);
+                        }
+                        break;
+                    }
+                }
+                match next {
+                    None => break,
+                    Some(addr) => path.push((self.node(addr), ord)),
+                }
+            }
+            let (node, ord) = path[path.len() - 1];
+            let found = path.len() == key.len() + 1 && node.is_final();
+            ords.push(if found { Some(ord) } else { None });
+            prev = key;
+        }
+        Ok(ords)
+    }
+
+    /// Returns the key whose ordinal is `index`, along with its value, or
+    /// `None` if this fst has `index` keys or fewer.
+    ///
+    /// This is the inverse of `get_index`. At every node on the way to the
+    /// key, the number of keys below each transition is counted, just like
+    /// `get_index` does, to find the transition that leads to it.
+    pub fn get_key(&self, index: u64) -> Option<(Vec<u8>, Output)> {
+        self.key_at(index, &mut HashMap::new())
+    }
+
+    /// Returns up to `n - 1` keys that split this fst into `n` ranges with
+    /// nearly the same number of keys each.
+    ///
+    /// The `i`th key is the one whose ordinal is `len * i / n`, so the first
+    /// range is every key less than the first point, the last range every
+    /// key greater than or equal to the last point, and the others lie
+    /// between two consecutive points. Each range can then be streamed on
+    /// its own, for example by a separate worker, with `ge` and `lt` bounds.
+    ///
+    /// If this fst has fewer than `n` keys, then fewer points are returned,
+    /// so that no range is empty. No points are returned if `n` is `0`.
+    ///
+    /// The number of keys below each node is counted once and shared by all
+    /// points, so this visits every node at most once, and far fewer if the
+    /// fst was built with `Builder::with_counts`.
+    pub fn partition_points(&self, n: usize) -> Vec<Vec<u8>> {
+        let mut counts = HashMap::new();
+        let mut points = vec![];
+        let mut last = 0;
+        for i in 1..n as u128 {
+            let index = (self.len() as u128 * i / n as u128) as u64;
+            if index == last {
+                continue;
+            }
+            last = index;
+            if let Some((key, _)) = self.key_at(index, &mut counts) {
+                points.push(key);
+            }
+        }
+        points
+    }
+
+    /// Returns the key whose ordinal is `index`, along with its value.
+    ///
+    /// `counts` caches the number of keys reachable from every node counted
+    /// so far, as in `count_keys_from`.
+    fn key_at(
+        &self,
+        index: u64,
+        counts: &mut HashMap<CompiledAddr, u64>,
+    ) -> Option<(Vec<u8>, Output)> {
+        let mut rest = index;
+        let mut key = vec![];
+        let mut out = Output::zero();
+        let mut node = self.root();
+        'walk: loop {
+            if node.is_final() {
+                if rest == 0 {
+                    let value = self.meta.decode(out.cat(node.final_output()));
+                    return Some((key, value));
+                }
+                rest -= 1;
+            }
+            for t in node.transitions() {
+                let count = self.count_keys_from(t.addr, counts);
+                if rest < count {
+                    key.push(t.inp);
+                    out = out.cat(t.out);
+                    node = self.node(t.addr);
+                    continue 'walk;
+                }
+                rest -= count;
+            }
+            return None;
+        }
+    }
+
+    /// Returns the number of keys reachable from the node at `addr`,
+    /// counting the empty key if that node is final.
+    ///
+    /// `counts` caches the number of keys reachable from every node counted
+    /// so far.
+    pub(crate) fn count_keys_from(
+        &self,
+        addr: CompiledAddr,
+        counts: &mut HashMap<CompiledAddr, u64>,
+    ) -> u64 {
+        self.meta
+            .count_keys_from(slic!(self.data[..]), addr, counts)
+    }
+}
+
+impl FstMeta {
+    fn count_keys_from(
+        &self,
+        data: FakeArrRef<'_>,
+        addr: CompiledAddr,
+        counts: &mut HashMap<CompiledAddr, u64>,
+    ) -> u64 {
+        // Nodes are counted in post-order with an explicit stack, since
+        // long keys would otherwise recurse as deep as they are long. Nodes
+        // are marked as small below a node that could have a stored count
+        // but doesn't, since none of their counts are stored either.
+        let mut stack = vec![(addr, false, false)];
+        while let Some((addr, expanded, small)) = stack.pop() {
+            if counts.contains_key(&addr) {
+                continue;
+            }
+            let node = self.node(addr, data);
+            if expanded {
+                let mut count = node.is_final() as u64;
+                for t in node.transitions() {
+                    count += counts[&t.addr];
+                }
+                counts.insert(addr, count);
+                continue;
+            }
+            let mut small = small;
+            let branches = node.len() + node.is_final() as Ulen > 1;
+            if branches && !small && self.subtree_counts.is_some() {
+                match self.stored_count(addr, data) {
+                    Some(n) => {
+                        counts.insert(addr, n);
+                        continue;
+                    }
+                    None => small = true,
+                }
+            }
+            stack.push((addr, true, small));
+            for t in node.transitions() {
+                if !counts.contains_key(&t.addr) {
+                    stack.push((t.addr, false, small));
+                }
+            }
+        }
+        counts[&addr]
+    }
+
+    /// Returns the number of keys below the node at `addr`, if the fst
+    /// stores it.
+    ///
+    /// The table is binary searched in place, so that opening an fst does
+    /// not read it.
+    fn stored_count(&self, addr: CompiledAddr, data: FakeArrRef<'_>) -> Option<u64> {
+        let (start, len) = self.subtree_counts?;
+        let (mut lo, mut hi) = (0, len);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let at = start + mid * 16;
+            match read_u64(&data, at).cmp(&addr) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal => return Some(read_u64(&data, at + 8)),
+            }
+        }
+        None
+    }
+}
+
+impl<'f, A: Automaton> StreamBuilder<'f, A> {
+    /// Returns the number of keys this builder would stream, without
+    /// streaming them.
+    ///
+    /// Whole subtrees whose keys all lie within the bounds, and which the
+    /// automaton matches whatever follows, are counted node by node rather
+    /// than key by key. With no automaton, only the nodes along the paths
+    /// of the two bounds are descended into, so counting a range of a large
+    /// fst is much cheaper than streaming it.
+    pub fn count(self) -> u64 {
+        let mut counts = HashMap::new();
+        let mut total = 0;
+        let mut stack = vec![(self.meta.root_addr, vec![], self.aut.start())];
+        while let Some((addr, key, state)) = stack.pop() {
+            if !self.aut.can_match(&state) {
+                continue;
+            }
+            let inside = match (lower_side(&self.min, &key), upper_side(&self.max, &key)) {
+                (Side::Outside, _) | (_, Side::Outside) => continue,
+                (Side::Inside, Side::Inside) => true,
+                _ => false,
+            };
+            if inside && self.aut.will_always_match(&state) {
+                total += self.meta.count_keys_from(self.data, addr, &mut counts);
+                continue;
+            }
+            let node = self.meta.node(addr, self.data);
+            if node.is_final()
+                && self.aut.is_match(&state)
+                && !self.min.subceeded_by(&key)
+                && !self.max.exceeded_by(&key)
+            {
+                total += 1;
+            }
+            for t in node.transitions() {
+                let mut next = key.clone();
+                next.push(t.inp);
+                stack.push((t.addr, next, self.aut.accept(&state, t.inp)));
+            }
+        }
+        total
+    }
+}
+
+/// Where the keys starting with some prefix lie relative to a bound.
+enum Side {
+    /// All of them satisfy the bound.
+    Inside,
+    /// None of them satisfy the bound.
+    Outside,
+    /// Some of them might.
+    Straddling,
+}
+
+/// Returns where the keys starting with `prefix` lie relative to the lower
+/// bound `min`.
+fn lower_side(min: &Bound, prefix: &[u8]) -> Side {
+    match *min {
+        Bound::Unbounded => Side::Inside,
+        Bound::Included(ref v) if prefix >= &v[..] => Side::Inside,
+        Bound::Excluded(ref v) if prefix > &v[..] => Side::Inside,
+        Bound::Included(ref v) | Bound::Excluded(ref v) => {
+            if v.starts_with(prefix) {
+                Side::Straddling
+            } else {
+                Side::Outside
+            }
+        }
+    }
+}
+
+/// Returns where the keys starting with `prefix` lie relative to the upper
+/// bound `max`.
+fn upper_side(max: &Bound, prefix: &[u8]) -> Side {
+    match *max {
+        Bound::Unbounded => Side::Inside,
+        Bound::Included(ref v) | Bound::Excluded(ref v) => {
+            if v.starts_with(prefix) {
+                Side::Straddling
+            } else if prefix < &v[..] {
+                Side::Inside
+            } else {
+                Side::Outside
+            }
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use std::cmp;
+use std::collections::HashMap;
+
+use crate::automaton::AlwaysMatch;
+use crate::error::Result;
+use crate::fake_arr::{FakeArr, FakeArrRef, Ulen};
+use crate::raw::footer::{is_footer_prefix, read_u64, HEADER_LEN};
+use crate::raw::node::{node_end_checked, node_new};
+use crate::raw::{
+    CompiledAddr, Error, FstMeta, FstType, Stream, StreamBuilder, EMPTY_ADDRESS, VERSION,
+};
+use crate::slic;
+use crate::stream::{IntoStreamer, Streamer};
+
+/// How many bytes from the end are searched for the start of a partially
+/// written footer. Footers are much shorter than this.
+const FOOTER_PROBE: Ulen = 4096;
+
+/// The keys that could be salvaged from a possibly truncated FST.
+///
+/// A recovered FST is made of one or more *fragments*. A fragment is a
+/// subtree of the original FST whose root node was written in full but was
+/// never referenced by a later node, which happens when writing stopped
+/// before the nodes above it were compiled.
+///
+/// If the FST was not actually damaged, then there is exactly one fragment
+/// containing every key, and `is_complete` returns true. Otherwise, the
+/// result is partial: keys that were never written are lost, and since the
+/// nodes leading to a fragment are lost too, each key in a fragment is only
+/// the *suffix* of an original key. (The common case of a crash that only
+/// lost the footer is an exception: the root node is then intact and the last
+/// fragment contains every key.) Fragments are ordered by the position at
+/// which they were written, which follows the lexicographic order of the
+/// original keys.
+pub struct Recovered<Data: FakeArr = Vec<u8>> {
+    data: Data,
+    fragments: Vec<FstMeta>,
+    complete: bool,
+    recovered_len: Ulen,
+}
+
+impl<Data: FakeArr> Recovered<Data> {
+    /// Recovers as much as possible from the given FST bytes.
+    ///
+    /// This returns an error only if the header is missing or has an
+    /// unsupported version. Otherwise, the bytes are scanned backwards for
+    /// the last run of fully written nodes.
+    pub fn new(data: Data) -> Result<Recovered<Data>> {
+        if data.len() < HEADER_LEN {
+            return Err(Error::Format.into());
+        }
+        let version = read_u64(&data, 0);
+        if version == 0 || version > VERSION {
+            return Err(Error::Version {
+                expected: VERSION,
+                got: version,
+            }
+            .into());
+        }
+        let ty = read_u64(&data, 8);
+        if let Ok(meta) = FstMeta::from_footer(version, ty, &data) {
+            return Ok(Recovered {
+                recovered_len: data.len(),
+                data,
+                fragments: vec![meta],
+                complete: true,
+            });
+        }
+
+        let mut scanner = Scanner::new(version, slic!(data[..]));
+        let len = data.len();
+        // Most crashes happen after the last node was written, while writing
+        // the footer. So first check whether some prefix of the footer is
+        // present and agrees with the node right before it.
+        let probe_start = cmp::max(HEADER_LEN, len.saturating_sub(FOOTER_PROBE));
+        let probe = slic!(data[probe_start..]).actually_read_it();
+        for root_addr in (probe_start..len.saturating_sub(1)).rev() {
+            // Checking the footer first is much cheaper than checking the
+            // nodes, and rules out almost every position.
+            let tail = &probe[(root_addr + 1 - probe_start) as usize..];
+            let mut nodes = None;
+            let is_footer = is_footer_prefix(version, tail, root_addr, || {
+                nodes = scanner.chain(root_addr);
+                nodes.as_ref()?;
+                Some(fragment_meta(version, ty, root_addr, slic!(data[..])).len)
+            });
+            if !is_footer {
+                continue;
+            }
+            if let Some(nodes) = nodes.or_else(|| scanner.chain(root_addr)) {
+                return Ok(Recovered::from_nodes(data, version, ty, &nodes));
+            }
+        }
+        let nodes = scanner.find();
+        Ok(Recovered::from_nodes(data, version, ty, &nodes))
+    }
+
+    /// Builds the fragments rooted at the nodes in `nodes` that no other node
+    /// refers to.
+    fn from_nodes(data: Data, version: u64, ty: FstType, nodes: &[CompiledAddr]) -> Self {
+        let mut referenced = vec![false; nodes.len()];
+        for &addr in nodes {
+            let node = node_new(version, addr, slic!(data[..]));
+            for i in 0..node.len() {
+                let to = node.transition_addr_checked(i).unwrap();
+                if let Ok(j) = nodes.binary_search(&to) {
+                    referenced[j] = true;
+                }
+            }
+        }
+        let fragments = nodes
+            .iter()
+            .zip(referenced)
+            .filter(|&(_, referenced)| !referenced)
+            .map(|(&root_addr, _)| fragment_meta(version, ty, root_addr, slic!(data[..])))
+            .filter(|meta| meta.len > 0)
+            .collect();
+        Recovered {
+            recovered_len: nodes.last().map_or(HEADER_LEN, |&addr| addr + 1),
+            data,
+            fragments,
+            complete: false,
+        }
+    }
+
+    /// Returns true if the FST was intact, in which case the only fragment
+    /// is the whole FST.
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Returns the number of leading bytes, including the header, that were
+    /// recovered.
+    pub fn recovered_len(&self) -> Ulen {
+        self.recovered_len
+    }
+
+    /// Returns the number of fragments that were recovered.
+    pub fn len(&self) -> usize {
+        self.fragments.len()
+    }
+
+    /// Returns true if nothing could be recovered.
+    pub fn is_empty(&self) -> bool {
+        self.fragments.is_empty()
+    }
+
+    /// Returns the number of keys in the `i`th fragment.
+    ///
+    /// # Panics
+    ///
+    /// This panics if there is no `i`th fragment.
+    pub fn fragment_len(&self, i: usize) -> Ulen {
+        self.fragments[i].len
+    }
+
+    /// Returns a lexicographically ordered stream of the key-value pairs in
+    /// the `i`th fragment.
+    ///
+    /// Unless the FST is complete, keys are suffixes of the original keys and
+    /// values are only the part of the original values that was stored below
+    /// the fragment's root.
+    ///
+    /// # Panics
+    ///
+    /// This panics if there is no `i`th fragment.
+    pub fn stream(&self, i: usize) -> Stream<'_> {
+        StreamBuilder::new(&self.fragments[i], slic!(self.data[..]), AlwaysMatch).into_stream()
+    }
+}
+
+/// Returns the metadata of the fragment rooted at `root_addr`, counting its
+/// keys.
+fn fragment_meta(
+    version: u64,
+    ty: FstType,
+    root_addr: CompiledAddr,
+    data: FakeArrRef<'_>,
+) -> FstMeta {
+    let mut meta = FstMeta {
+        version,
+        root_addr,
+        ty,
+        len: 0,
+        max_key_len: None,
+        value_table: None,
+        subtree_counts: None,
+    };
+    let mut count = 0;
+    let mut stream = StreamBuilder::new(&meta, data, AlwaysMatch).into_stream();
+    while stream.next().is_some() {
+        count += 1;
+    }
+    meta.len = count;
+    meta
+}
+
+/// Finds runs of well formed nodes that start right after the header.
+///
+/// Runs are followed backwards from their last node down to the header, and
+/// the verdict for every position on the way is memoized, so that scanning
+/// every candidate position is linear in the size of the data.
+struct Scanner<'f> {
+    version: u64,
+    data: FakeArrRef<'f>,
+    /// `true` if a well formed node has its state byte at the given
+    /// position and, transitively, so do all nodes before it down to the
+    /// header.
+    good: HashMap<Ulen, bool>,
+}
+
+impl<'f> Scanner<'f> {
+    fn new(version: u64, data: FakeArrRef<'f>) -> Self {
+        Scanner {
+            version,
+            data,
+            good: HashMap::new(),
+        }
+    }
+
+    /// Returns the longest run of nodes that ends at or before the end of
+    /// the data, or an empty run if there is none.
+    fn find(&mut self) -> Vec<CompiledAddr> {
+        let mut candidate = self.data.len();
+        while candidate > HEADER_LEN {
+            candidate -= 1;
+            if let Some(nodes) = self.chain(candidate) {
+                return nodes;
+            }
+        }
+        vec![]
+    }
+
+    /// Returns the addresses, in ascending order, of the run of nodes whose
+    /// last node is at `last`, if that run is well formed and all of its
+    /// transitions point to nodes within the run.
+    fn chain(&mut self, last: CompiledAddr) -> Option<Vec<CompiledAddr>> {
+        let (version, data) = (self.version, self.data);
+        let mut path = vec![];
+        let mut pos = last;
+        let mut ok = loop {
+            if pos == HEADER_LEN - 1 {
+                break true;
+            }
+            if let Some(&verdict) = self.good.get(&pos) {
+                break verdict;
+            }
+            match node_end_checked(version, pos, data, HEADER_LEN) {
+                None => {
+                    self.good.insert(pos, false);
+                    break false;
+                }
+                Some(end) => {
+                    path.push(pos);
+                    pos = end - 1;
+                }
+            }
+        };
+        // Check transitions bottom up, now that everything below is known.
+        for &addr in path.iter().rev() {
+            ok = ok && transitions_ok(version, addr, data, &self.good);
+            self.good.insert(addr, ok);
+        }
+        if !ok {
+            return None;
+        }
+        let mut nodes = vec![];
+        let mut pos = last;
+        while pos != HEADER_LEN - 1 {
+            nodes.push(pos);
+            pos = node_new(version, pos, data).end_addr() - 1;
+        }
+        nodes.reverse();
+        // Memoization may have let a transition point at a well formed node
+        // from a different run, so check membership in this one.
+        let closed = nodes.iter().all(|&addr| {
+            let node = node_new(version, addr, data);
+            (0..node.len()).all(|i| {
+                let to = node.transition_addr_checked(i).unwrap();
+                to == EMPTY_ADDRESS || nodes.binary_search(&to).is_ok()
+            })
+        });
+        if closed {
+            Some(nodes)
+        } else {
+            None
+        }
+    }
+}
+
+fn transitions_ok(
+    version: u64,
+    addr: CompiledAddr,
+    data: FakeArrRef<'_>,
+    good: &HashMap<Ulen, bool>,
+) -> bool {
+    let node = node_new(version, addr, data);
+    let addrs_ok = (0..node.len()).all(|i| match node.transition_addr_checked(i) {
+        Some(EMPTY_ADDRESS) => true,
+        Some(to) => to < node.end_addr() && good.get(&to) == Some(&true),
+        None => false,
+    });
+    // The builder always writes transitions in strictly increasing order of
+    // their input bytes, which rules out most stray bytes that happen to
+    // look like a node.
+    addrs_ok
+        && node
+            .transitions()
+            .zip(node.transitions().skip(1))
+            .all(|(t1, t2)| t1.inp < t2.inp)
+}
@@ -37,6 +37,42 @@ impl Registry {
         }
     }
 
+    /// Forgets every node, keeping the memory of the table.
+    pub fn clear(&mut self) {
+        for cell in &mut self.table {
+            cell.addr = NONE_ADDRESS;
+        }
+    }
+
+    /// Forgets every node written at or after `addr`.
+    pub fn forget_from(&mut self, addr: CompiledAddr) {
+        for cell in &mut self.table {
+            if !cell.is_none() && cell.addr >= addr {
+                cell.addr = NONE_ADDRESS;
+            }
+        }
+    }
+
+    /// Returns the number of bytes held by the table and by the nodes in
+    /// it.
+    pub fn heap_usage(&self) -> usize {
+        let nodes = self.table.iter().map(|c| c.node.heap_usage());
+        self.table.capacity() * std::mem::size_of::<RegistryCell>() + nodes.sum::<usize>()
+    }
+
+    /// Returns the address of `node` if it was registered, without
+    /// registering it otherwise.
+    pub fn find(&self, node: &BuilderNode) -> Option<CompiledAddr> {
+        if self.table.is_empty() {
+            return None;
+        }
+        let start = self.mru_size * self.hash(node);
+        self.table[start..start + self.mru_size]
+            .iter()
+            .find(|c| !c.is_none() && &c.node == node)
+            .map(|c| c.addr)
+    }
+
     pub fn entry(&mut self, node: &BuilderNode) -> RegistryEntry {
         if self.table.is_empty() {
             return RegistryEntry::Rejected;
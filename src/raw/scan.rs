@@ -0,0 +1,73 @@
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use crate::automaton::{AlwaysMatch, Automaton};
+use crate::fake_arr::FakeArr;
+use crate::raw::Fst;
+use crate::stream::{IntoStreamer, Streamer};
+
+/// The throughput of a scan over an fst, as measured by
+/// `Fst::scan_benchmark`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ScanReport {
+    /// The number of keys the scan visited.
+    pub keys: u64,
+    /// The total length of the keys the scan visited, in bytes.
+    pub bytes: u64,
+    /// How long the scan took.
+    pub elapsed: Duration,
+}
+
+impl ScanReport {
+    /// Returns the number of keys visited per second.
+    pub fn keys_per_sec(&self) -> f64 {
+        per_sec(self.keys, self.elapsed)
+    }
+
+    /// Returns the number of key bytes visited per second.
+    pub fn bytes_per_sec(&self) -> f64 {
+        per_sec(self.bytes, self.elapsed)
+    }
+}
+
+fn per_sec(n: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs == 0.0 {
+        0.0
+    } else {
+        n as f64 / secs
+    }
+}
+
+impl<Data: FakeArr> Fst<Data> {
+    /// Streams every key-value pair in this fst and reports how fast it
+    /// went.
+    ///
+    /// Every key and value is passed through `std::hint::black_box`, so the
+    /// work cannot be optimized away. This makes it easy to compare the
+    /// performance of different backing stores for the same fst without
+    /// writing a harness against the stream internals.
+    pub fn scan_benchmark(&self) -> ScanReport {
+        self.scan_benchmark_with(AlwaysMatch)
+    }
+
+    /// Just like `scan_benchmark`, except only the keys matched by `aut` are
+    /// visited.
+    pub fn scan_benchmark_with<A: Automaton>(&self, aut: A) -> ScanReport {
+        let (mut keys, mut bytes) = (0, 0);
+        let start = Instant::now();
+        let mut stream = self.search(aut).into_stream();
+        while let Some((key, out)) = stream.next() {
+            black_box(&key);
+            black_box(out);
+            keys += 1;
+            bytes += key.len();
+        }
+        ScanReport {
+            keys,
+            bytes,
+            elapsed: start.elapsed(),
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use std::ops::{Bound, Range};
+
+use crate::automaton::{bounds, Automaton};
+
+/// Maps keys, ranges and automata to the shards of a key space that must be
+/// queried for them.
+///
+/// A key space is split into shards by their first keys, in increasing
+/// order. Shard `i` holds the keys from its first key up to, but excluding,
+/// the first key of shard `i + 1`. Keys below the first key of shard `0`
+/// belong to shard `0` too, so every key belongs to exactly one shard.
+///
+/// Routing is conservative: the shards returned for a range or an automaton
+/// may hold no matching key, but the shards left out never do.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ShardRouter {
+    first_keys: Vec<Vec<u8>>,
+}
+
+impl ShardRouter {
+    /// Creates a router from the first key of every shard.
+    ///
+    /// # Panics
+    ///
+    /// This panics if there are no keys or if the keys are not in strictly
+    /// increasing order.
+    pub fn new<I, K>(first_keys: I) -> ShardRouter
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<[u8]>,
+    {
+        let first_keys: Vec<Vec<u8>> = first_keys
+            .into_iter()
+            .map(|k| k.as_ref().to_vec())
+            .collect();
+        assert!(!first_keys.is_empty(), "a router needs at least one shard");
+        assert!(
+            first_keys.windows(2).all(|w| w[0] < w[1]),
+            "the first keys of shards must be strictly increasing"
+        );
+        ShardRouter { first_keys }
+    }
+
+    /// Returns the number of shards.
+    pub fn len(&self) -> usize {
+        self.first_keys.len()
+    }
+
+    /// Always returns false, since a router has at least one shard.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Returns the first key of every shard.
+    pub fn first_keys(&self) -> &[Vec<u8>] {
+        &self.first_keys
+    }
+
+    /// Returns the shard that holds `key`.
+    pub fn shard_for_key<K: AsRef<[u8]>>(&self, key: K) -> usize {
+        let key = key.as_ref();
+        self.first_keys
+            .partition_point(|first| first.as_slice() <= key)
+            .saturating_sub(1)
+    }
+
+    /// Returns the shards that hold keys within the given bounds.
+    ///
+    /// The result is empty if the bounds do not contain any key.
+    pub fn shards_for_range(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Range<usize> {
+        let start = match lower {
+            Bound::Included(k) | Bound::Excluded(k) => self.shard_for_key(k),
+            Bound::Unbounded => 0,
+        };
+        let end = match upper {
+            Bound::Included(k) => self.shard_for_key(k) + 1,
+            Bound::Excluded(k) => {
+                let below = self
+                    .first_keys
+                    .partition_point(|first| first.as_slice() < k);
+                below.max(1)
+            }
+            Bound::Unbounded => self.len(),
+        };
+        let empty = match (lower, upper) {
+            (Bound::Included(lo), Bound::Included(hi)) => lo > hi,
+            (Bound::Included(lo), Bound::Excluded(hi))
+            | (Bound::Excluded(lo), Bound::Included(hi))
+            | (Bound::Excluded(lo), Bound::Excluded(hi)) => lo >= hi,
+            _ => false,
+        };
+        if empty || start >= end {
+            start..start
+        } else {
+            start..end
+        }
+    }
+
+    /// Returns the shards that may hold keys matched by `aut`.
+    ///
+    /// The automaton is routed by the smallest and largest strings it
+    /// accepts, as computed by `automaton::bounds`. Those strings are only
+    /// computed as far as they can change the result, which is one byte past
+    /// the longest first key.
+    pub fn shards_for_automaton<A: Automaton>(&self, aut: &A) -> Range<usize> {
+        let max_len = self.first_keys.iter().map(Vec::len).max().unwrap_or(0) + 1;
+        let bounds = match bounds(aut, max_len) {
+            Some(bounds) => bounds,
+            None => return 0..0,
+        };
+        match bounds.upper() {
+            Bound::Included(end) => self.shards_for_range(bounds.lower(), Bound::Included(&end)),
+            Bound::Excluded(end) => self.shards_for_range(bounds.lower(), Bound::Excluded(&end)),
+            Bound::Unbounded => self.shards_for_range(bounds.lower(), Bound::Unbounded),
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+
+use crate::fake_arr::FakeArr;
+use crate::raw::{CompiledAddr, Fst};
+
+/// How much structure two fsts share, as computed by
+/// `Fst::shared_structure`.
+///
+/// Two nodes are considered the same if they have the same finality, final
+/// output and transitions, and if their transitions lead to nodes that are
+/// the same. This is what a container that stores nodes shared by several
+/// fsts only once would deduplicate.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SharedReport {
+    /// The number of distinct nodes in the first fst.
+    pub nodes: u64,
+    /// The number of distinct nodes in the second fst.
+    pub other_nodes: u64,
+    /// The number of nodes of the second fst that are also in the first.
+    pub shared_nodes: u64,
+    /// The size of the nodes of the second fst, in bytes.
+    pub other_bytes: u64,
+    /// The size of the nodes of the second fst that are also in the first,
+    /// in bytes.
+    pub shared_bytes: u64,
+}
+
+impl SharedReport {
+    /// Returns the fraction of the node bytes of the second fst that a
+    /// shared container would not need to store again.
+    pub fn shared_fraction(&self) -> f64 {
+        if self.other_bytes == 0 {
+            0.0
+        } else {
+            self.shared_bytes as f64 / self.other_bytes as f64
+        }
+    }
+}
+
+impl<Data: FakeArr> Fst<Data> {
+    /// Reports how many nodes of `other` are structurally the same as a
+    /// node of this fst.
+    ///
+    /// Nodes are compared by hashing their contents bottom up, so this reads
+    /// every node of both fsts once. Hash collisions make the result an
+    /// estimate, albeit a very close one.
+    pub fn shared_structure<D: FakeArr>(&self, other: &Fst<D>) -> SharedReport {
+        let mine = structural_hashes(self);
+        let theirs = structural_hashes(other);
+        let mut report = SharedReport {
+            nodes: mine.len() as u64,
+            other_nodes: theirs.len() as u64,
+            ..SharedReport::default()
+        };
+        let mine: HashSet<u64> = mine.values().map(|&(hash, _)| hash).collect();
+        for (hash, size) in theirs.values() {
+            report.other_bytes += size;
+            if mine.contains(hash) {
+                report.shared_nodes += 1;
+                report.shared_bytes += size;
+            }
+        }
+        report
+    }
+}
+
+/// Returns the structural hash and the size of every distinct node.
+fn structural_hashes<D: FakeArr>(fst: &Fst<D>) -> HashMap<CompiledAddr, (u64, u64)> {
+    let mut hashes: HashMap<CompiledAddr, (u64, u64)> = HashMap::new();
+    // Nodes are hashed after their children, so each node is pushed once to
+    // be expanded and once more to be hashed.
+    let mut stack = vec![(fst.root().addr(), false)];
+    while let Some((addr, expanded)) = stack.pop() {
+        if hashes.contains_key(&addr) {
+            continue;
+        }
+        let node = fst.node(addr);
+        if !expanded {
+            stack.push((addr, true));
+            for t in node.transitions() {
+                if !hashes.contains_key(&t.addr) {
+                    stack.push((t.addr, false));
+                }
+            }
+            continue;
+        }
+        let mut hasher = DefaultHasher::new();
+        node.is_final().hash(&mut hasher);
+        node.final_output().value().hash(&mut hasher);
+        for t in node.transitions() {
+            t.inp.hash(&mut hasher);
+            t.out.value().hash(&mut hasher);
+            hashes[&t.addr].0.hash(&mut hasher);
+        }
+        hashes.insert(addr, (hasher.finish(), node.size()));
+    }
+    hashes
+}
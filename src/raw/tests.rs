@@ -1,6 +1,9 @@
 use crate::error::Error;
 use crate::inner_automaton::Automaton;
-use crate::raw::{self, Bound, Buffer, Builder, Fst, Output, Stream, VERSION};
+use crate::raw::{
+    self, Bound, Buffer, Builder, Fst, FstMeta, Output, Stream, StreamBuilder, INLINE_KEY_CAPACITY,
+    KEY_BUFFER_CAPACITY, VERSION,
+};
 use crate::slic;
 use crate::stream::Streamer;
 use crate::{
@@ -14,7 +17,7 @@ const TEXT: &'static str = include_str!("./../../data/words-100000");
 
 #[test]
 fn test_buffer() {
-    let mut buffer = Buffer::new();
+    let mut buffer = Buffer::with_capacity(KEY_BUFFER_CAPACITY);
     let v: Vec<u8> = (0..10_000).map(|val| (val % 256) as u8).collect();
     for b in v.iter().cloned() {
         buffer.push(b);
@@ -24,6 +27,25 @@ fn test_buffer() {
     }
 }
 
+#[test]
+fn test_buffer_inline() {
+    let mut buffer = Buffer::with_capacity(0);
+    assert_eq!(buffer.capacity(), INLINE_KEY_CAPACITY);
+    let v: Vec<u8> = (0..200).map(|val| val as u8).collect();
+    for &b in &v[..INLINE_KEY_CAPACITY] {
+        buffer.push(b);
+    }
+    assert_eq!(buffer.capacity(), INLINE_KEY_CAPACITY);
+    for &b in &v[INLINE_KEY_CAPACITY..] {
+        buffer.push(b);
+    }
+    assert_eq!(&*buffer, &v[..]);
+    assert!(buffer.capacity() >= v.len());
+    for len in (1..=v.len()).rev() {
+        assert_eq!(&v[..len], buffer.pop());
+    }
+}
+
 pub fn fst_set<I, S>(ss: I) -> Fst
 where
     I: IntoIterator<Item = S>,
@@ -262,6 +284,25 @@ fn fst_set_zero() {
     assert_eq!(rdr.next().map(to_mem), None);
 }
 
+impl<'f, A: Automaton> Stream<'f, A> {
+    /// Streams the keys of the fst between `min` and `max` that `aut`
+    /// matches, like `StreamBuilder::into_stream`.
+    fn new(
+        meta: &'f FstMeta,
+        data: FakeArrRef<'f>,
+        aut: A,
+        min: Bound,
+        max: Bound,
+        backward: bool,
+    ) -> Self {
+        let mut builder = StreamBuilder::new(meta, data, aut);
+        builder.min = min;
+        builder.max = max;
+        builder.backward = backward;
+        builder.into_stream()
+    }
+}
+
 macro_rules! test_range {
     (
         $name:ident,
@@ -731,11 +772,11 @@ fn test_transition_within_bound() {
     let fst: Fst = fst_map(items.clone()).into();
     let stream = fst.stream();
     let a = fst.node(fst.root().transition(0).addr);
-    assert_eq!(stream.0.transition_within_bound(&a, 'z' as u8), None);
-    assert_eq!(stream.0.transition_within_bound(&a, 'd' as u8), None);
-    assert_eq!(stream.0.transition_within_bound(&a, 'c' as u8), Some(2));
-    assert_eq!(stream.0.transition_within_bound(&a, 'b' as u8), Some(1));
-    assert_eq!(stream.0.transition_within_bound(&a, 'a' as u8), Some(0));
+    assert_eq!(stream.0.transition_within_bound(&a, 'z' as u8).unwrap(), None);
+    assert_eq!(stream.0.transition_within_bound(&a, 'd' as u8).unwrap(), None);
+    assert_eq!(stream.0.transition_within_bound(&a, 'c' as u8).unwrap(), Some(2));
+    assert_eq!(stream.0.transition_within_bound(&a, 'b' as u8).unwrap(), Some(1));
+    assert_eq!(stream.0.transition_within_bound(&a, 'a' as u8).unwrap(), Some(0));
 }
 
 fn automaton_match<A: Automaton>(aut: &A, inp: &[u8]) -> bool {
@@ -929,7 +970,7 @@ fn bytes_written() {
     let counted_len = bfst1.bytes_written();
     let bytes = bfst1.into_inner().unwrap();
     let fst1_len = bytes.len() as u64;
-    let footer_size = 24;
+    let footer_size = 56;
     assert_eq!(counted_len + footer_size, fst1_len);
 }
 
@@ -1082,3 +1123,4302 @@ proptest! {
         test_range_with_aut_fn(vec.clone(), Regex::new(&r).unwrap(), min, max);
     }
 }
+
+#[test]
+fn clamp_upper_shared_bound() {
+    use crate::raw::BoundCursor;
+
+    let fst1 = fst_set(vec!["a", "c", "e", "g"]);
+    let fst2 = fst_set(vec!["b", "d", "f", "h"]);
+    let mut s1 = fst1.stream();
+    let mut s2 = fst2.stream();
+    let shared = BoundCursor::included("d");
+    s1.clamp_upper(&shared);
+    s2.clamp_upper(&shared);
+    assert_eq!(s1.upper_bound(), shared);
+
+    let mut keys = vec![];
+    while let Some((k, _)) = s1.next() {
+        keys.push(k.to_vec());
+    }
+    // Tightening the bound mid-stream stops at the new bound.
+    assert_eq!(s2.next().map(to_mem).unwrap().0, b"b".to_vec());
+    s2.clamp_upper(&BoundCursor::excluded("d"));
+    assert!(s2.next().is_none());
+    assert_eq!(keys, vec![b"a".to_vec(), b"c".to_vec()]);
+
+    // Loosening never happens.
+    let mut s3 = fst2.range().backward().into_stream();
+    s3.clamp_upper(&BoundCursor::excluded("f"));
+    s3.clamp_upper(&BoundCursor::unbounded());
+    assert_eq!(s3.into_byte_keys(), vec![b"d".to_vec(), b"b".to_vec()]);
+}
+
+#[test]
+fn table_dfa_search() {
+    use crate::automaton::TableDfa;
+
+    // Matches `ab*`.
+    let mut s0 = [None; 256];
+    s0[b'a' as usize] = Some(1);
+    let mut s1 = [None; 256];
+    s1[b'b' as usize] = Some(1);
+    let dfa = TableDfa::new(vec![s0, s1], vec![1]);
+    assert!(dfa.can_match(&dfa.start()));
+    assert!(!dfa.can_match(&dfa.accept(&dfa.start(), b'b')));
+
+    let fst = fst_set(vec!["a", "ab", "abb", "abc", "b", "ba"]);
+    let keys = fst.search(&dfa).into_stream().into_str_keys().unwrap();
+    assert_eq!(keys, vec!["a", "ab", "abb"]);
+}
+
+#[cfg(feature = "regex-automata")]
+#[test]
+fn regex_automata_dfa_search() {
+    use regex_automata::dfa::{dense, sparse};
+    use regex_automata::MatchKind;
+
+    let dense = dense::Builder::new()
+        .configure(dense::Config::new().match_kind(MatchKind::All))
+        .build(r"(?-u:a\b)|ab+")
+        .unwrap();
+    let sparse = sparse::DFA::new(r"b[a-z]").unwrap();
+    let fst = fst_set(vec!["a", "ab", "abb", "abc", "b", "ba", "bz"]);
+    let keys = fst.search(&dense).into_stream().into_str_keys().unwrap();
+    assert_eq!(keys, vec!["a", "ab", "abb"]);
+    let keys = fst.search(&sparse).into_stream().into_str_keys().unwrap();
+    assert_eq!(keys, vec!["ba", "bz"]);
+}
+
+#[test]
+fn str_stream_incremental_utf8() {
+    // "é" is 0xC3 0xA9 and "ë" is 0xC3 0xAB, so these keys diverge in the
+    // middle of a character.
+    let keys: Vec<&[u8]> = vec![
+        b"a",
+        "caf\u{e9}".as_bytes(),
+        "caf\u{e9}s".as_bytes(),
+        "caf\u{eb}".as_bytes(),
+        b"caf\xc3",
+        b"z",
+    ];
+    let fst = fst_set(keys.clone());
+    let mut stream = fst.stream().into_str_stream();
+    let mut got = vec![];
+    while let Some(item) = stream.next() {
+        got.push(item.map(|(k, _)| k.to_string()).ok());
+    }
+    let mut sorted = keys.clone();
+    sorted.sort();
+    let expected: Vec<Option<String>> = sorted
+        .iter()
+        .map(|k| String::from_utf8(k.to_vec()).ok())
+        .collect();
+    assert_eq!(got, expected);
+
+    let mut stream = fst.range().backward().into_stream().into_str_stream();
+    let mut got = vec![];
+    while let Some(item) = stream.next() {
+        got.push(item.map(|(k, _)| k.to_string()).ok());
+    }
+    let mut expected = expected;
+    expected.reverse();
+    assert_eq!(got, expected);
+}
+
+#[test]
+fn builder_diagnostic_mode() {
+    use crate::raw::{RejectReason, RejectedKey};
+
+    let mut bfst = Builder::memory();
+    bfst.record_invalid_keys(2);
+    bfst.insert("Bar", 1).unwrap();
+    bfst.insert("Foo", 2).unwrap();
+    bfst.insert("Foo", 3).unwrap();
+    bfst.insert("Baz", 4).unwrap();
+    bfst.add("Aaa").unwrap();
+    bfst.insert("Zoo", 5).unwrap();
+    let (bytes, report) = bfst.into_inner_with_report().unwrap();
+
+    assert_eq!(report.total(), 3);
+    assert!(report.is_truncated());
+    assert_eq!(
+        report.rejected(),
+        &[
+            RejectedKey {
+                reason: RejectReason::Duplicate,
+                previous: b"Foo".to_vec(),
+                key: b"Foo".to_vec(),
+                value: Some(3),
+            },
+            RejectedKey {
+                reason: RejectReason::OutOfOrder,
+                previous: b"Foo".to_vec(),
+                key: b"Baz".to_vec(),
+                value: Some(4),
+            },
+        ]
+    );
+    let fst = tokio_test::block_on(Fst::new(bytes)).unwrap();
+    assert_eq!(
+        fst.stream().into_str_vec().unwrap(),
+        vec![
+            ("Bar".to_string(), 1),
+            ("Foo".to_string(), 2),
+            ("Zoo".to_string(), 5)
+        ]
+    );
+
+    let mut bfst = Builder::memory();
+    bfst.insert("b", 1).unwrap();
+    assert!(bfst.insert("a", 1).is_err());
+    assert!(bfst.key_report().is_none());
+}
+
+#[test]
+fn recover_truncated() {
+    use crate::raw::Recovered;
+
+    let mut words: Vec<&str> = TEXT.lines().take(1000).collect();
+    words.sort();
+    words.dedup();
+    let mut bfst = Builder::memory();
+    for (i, w) in words.iter().enumerate() {
+        bfst.insert(w, i as u64).unwrap();
+    }
+    let bytes = bfst.into_inner().unwrap();
+    let expected: Vec<(String, u64)> = words
+        .iter()
+        .enumerate()
+        .map(|(i, w)| (w.to_string(), i as u64))
+        .collect();
+
+    let intact = Recovered::new(bytes.clone()).unwrap();
+    assert!(intact.is_complete());
+    assert_eq!(intact.len(), 1);
+    assert_eq!(intact.stream(0).into_str_vec().unwrap(), expected);
+
+    // Losing only the footer leaves the root intact.
+    for &cut in &[1, 8, 20, 24, 33, 48] {
+        let rec = Recovered::new(bytes[..bytes.len() - cut].to_vec()).unwrap();
+        assert!(!rec.is_complete());
+        assert_eq!(rec.recovered_len(), bytes.len() as u64 - 48);
+        let last = rec.len() - 1;
+        assert_eq!(rec.fragment_len(last), expected.len() as u64);
+        assert_eq!(rec.stream(last).into_str_vec().unwrap(), expected);
+    }
+
+    let suffixes: ::std::collections::HashSet<&[u8]> = words
+        .iter()
+        .flat_map(|w| (0..=w.len()).map(move |i| &w.as_bytes()[i..]))
+        .collect();
+    for &cut in &[bytes.len() / 2, bytes.len() / 3, bytes.len() - 100] {
+        let rec = Recovered::new(bytes[..cut].to_vec()).unwrap();
+        assert!(!rec.is_complete());
+        assert!(!rec.is_empty());
+        assert!(rec.recovered_len() <= cut as u64);
+        for i in 0..rec.len() {
+            for key in rec.stream(i).into_byte_keys() {
+                assert!(suffixes.contains(&key[..]));
+            }
+        }
+    }
+
+    assert!(Recovered::new(bytes[..8].to_vec()).is_err());
+    assert!(Recovered::new(bytes[..16].to_vec()).unwrap().is_empty());
+}
+
+#[test]
+fn finish_into_parts() {
+    let mut bfst = Builder::memory();
+    bfst.insert("bar", 1).unwrap();
+    bfst.insert("foo", 2).unwrap();
+    let (mut body, footer) = bfst.finish_into_parts().unwrap();
+    assert_eq!(footer.len(), 48);
+    assert!(tokio_test::block_on(Fst::new(body.clone())).is_err());
+
+    body.extend_from_slice(&footer);
+    let fst = tokio_test::block_on(Fst::new(body)).unwrap();
+    assert_eq!(
+        fst.stream().into_str_vec().unwrap(),
+        vec![("bar".to_string(), 1), ("foo".to_string(), 2)]
+    );
+}
+
+#[test]
+fn output_along_path() {
+    let keys: Vec<(&str, u64)> = vec![
+        ("a", 10),
+        ("ab", 7),
+        ("abc", 12),
+        ("abd", 9),
+        ("b", 3),
+        ("ba", 30),
+    ];
+    let fst = fst_map(keys.clone());
+    for prefix in &["a", "ab", "abc", "abd", "b", "ba"] {
+        let min = keys
+            .iter()
+            .filter(|(k, _)| k.starts_with(prefix))
+            .map(|&(_, v)| v)
+            .min()
+            .unwrap();
+        assert_eq!(fst.output_along_path(prefix), Some(Output::new(min)));
+    }
+    assert_eq!(fst.output_along_path(""), Some(Output::zero()));
+    assert_eq!(fst.output_along_path("c"), None);
+    assert_eq!(fst.output_along_path("abe"), None);
+}
+
+#[test]
+fn top_k_completions() {
+    let mut words: Vec<&str> = TEXT.lines().take(3000).collect();
+    words.sort();
+    words.dedup();
+    let value = |w: &str| w.bytes().map(|b| b as u64 * 7 % 31).sum::<u64>() % 50;
+    let fst = fst_map(words.iter().map(|&w| (w, value(w))));
+
+    for prefix in &["", "a", "ab", "mar", "zz", "abandon"] {
+        for &k in &[0, 1, 5, 100] {
+            let mut expected: Vec<(Vec<u8>, Output)> = words
+                .iter()
+                .filter(|w| w.starts_with(prefix))
+                .map(|&w| (w.as_bytes().to_vec(), Output::new(value(w))))
+                .collect();
+            expected.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            expected.truncate(k);
+            assert_eq!(fst.top_k_completions(prefix, k), expected);
+        }
+    }
+}
+
+#[test]
+fn output_sidecar() {
+    use crate::raw::{OutputSidecar, SidecarKind};
+
+    let mut words: Vec<&str> = TEXT.lines().take(3000).collect();
+    words.sort();
+    words.dedup();
+    let value = |w: &str| w.bytes().map(|b| b as u64 * 13 % 37).sum::<u64>() * 1000;
+    let fst = fst_map(words.iter().map(|&w| (w, value(w))));
+
+    let max = OutputSidecar::new(&fst, SidecarKind::Max);
+    let min = OutputSidecar::new(&fst, SidecarKind::Min);
+    assert_eq!(OutputSidecar::from_bytes(&max.to_bytes()).unwrap(), max);
+    assert_eq!(OutputSidecar::from_bytes(&min.to_bytes()).unwrap(), min);
+    assert!(OutputSidecar::from_bytes(&max.to_bytes()[1..]).is_err());
+
+    for prefix in &["", "a", "ab", "mar", "abandon"] {
+        let values = || {
+            words
+                .iter()
+                .filter(|w| w.starts_with(prefix))
+                .map(|&w| Output::new(value(w)))
+        };
+        assert_eq!(max.along_path(&fst, prefix), values().max());
+        assert_eq!(min.along_path(&fst, prefix), values().min());
+        assert_eq!(
+            fst.top_k_completions_with(prefix, 10, &max),
+            fst.top_k_completions(prefix, 10)
+        );
+    }
+    assert_eq!(max.along_path(&fst, "zzz"), None);
+}
+
+#[test]
+fn top_k_completions_dictionary_encoded() {
+    use crate::raw::{OutputSidecar, SidecarKind};
+
+    // Indices are assigned in order of first insertion, so the value with the
+    // greatest index is the least one.
+    let keys = vec![("ab", 0), ("abc", 900), ("abd", 5), ("b", 900), ("bc", 1)];
+    let mut bld = Builder::new_dictionary_encoded(vec![]).unwrap();
+    for &(key, value) in &keys {
+        bld.insert(key, value).unwrap();
+    }
+    let bytes = bld.into_inner().unwrap();
+    let fst = Fst::from_slice(&bytes).unwrap();
+    let out = |key: &str, value| (key.as_bytes().to_vec(), Output::new(value));
+
+    assert_eq!(
+        fst.top_k_completions("", 3),
+        vec![out("abc", 900), out("b", 900), out("abd", 5)]
+    );
+    assert_eq!(
+        fst.top_k_completions("ab", 2),
+        vec![out("abc", 900), out("abd", 5)]
+    );
+    assert_eq!(fst.top_k_completions("b", 5), vec![out("b", 900), out("bc", 1)]);
+    assert_eq!(fst.top_k_completions("c", 5), vec![]);
+    assert_eq!(fst.top_k_completions("", 0), vec![]);
+
+    let max = OutputSidecar::new(&fst, SidecarKind::Max);
+    assert_eq!(
+        fst.top_k_completions_with("a", 10, &max),
+        fst.top_k_completions("a", 10)
+    );
+}
+
+#[test]
+fn prefix_sum_outputs() {
+    let records: Vec<(&str, u64)> = vec![("a", 5), ("b", 0), ("bc", 12), ("d", 3)];
+    let mut bfst = Builder::memory();
+    for &(key, len) in &records {
+        bfst.insert_length(key, len).unwrap();
+    }
+    let fst = tokio_test::block_on(Fst::new(bfst.into_inner().unwrap())).unwrap();
+
+    let mut offset = 0;
+    for &(key, len) in &records {
+        assert_eq!(fst.offset_of(key), Some(offset));
+        assert_eq!(fst.length_of(key), Some(len));
+        offset += len;
+    }
+    assert_eq!(fst.offset_of("c"), None);
+    assert_eq!(fst.length_of("c"), None);
+
+    let mut bfst = Builder::memory();
+    bfst.insert_length("a", u64::MAX).unwrap();
+    match bfst.insert_length("b", 1) {
+        Err(crate::Error::Fst(raw::Error::OutputOverflow { got })) => assert_eq!(got, b"b"),
+        r => panic!("unexpected result: {:?}", r),
+    }
+    // The failed insertion left no trace, so "b" can still be inserted.
+    bfst.insert_length("b", 0).unwrap();
+    let fst = tokio_test::block_on(Fst::new(bfst.into_inner().unwrap())).unwrap();
+    assert_eq!(fst.len(), 2);
+    assert_eq!(fst.offset_of("b"), Some(u64::MAX));
+}
+
+#[test]
+fn stream_key_capacity() {
+    let long = "x".repeat(1000);
+    let fst = fst_set(vec!["a", "ab", "abc", "b", &long]);
+    let expected = fst.stream().into_byte_keys();
+    for &cap in &[0, 1, 3, 2000] {
+        let keys = fst.range().key_capacity(cap).into_stream().into_byte_keys();
+        assert_eq!(keys, expected);
+        let keys = fst
+            .range()
+            .key_capacity(cap)
+            .backward()
+            .into_stream()
+            .into_byte_keys();
+        assert_eq!(keys, expected.iter().rev().cloned().collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn max_key_len_footer() {
+    use crate::raw::footer;
+    use byteorder::{ByteOrder, LittleEndian};
+
+    let fst = fst_set(vec!["a", "abcdef", "xyz"]);
+    assert_eq!(fst.max_key_len(), Some(6));
+    assert_eq!(fst_set(Vec::<&str>::new()).max_key_len(), Some(0));
+
+    // A version 2 fst has no metadata, but is still readable.
+    let mut bfst = Builder::memory();
+    bfst.add("abc").unwrap();
+    bfst.add("abcdef").unwrap();
+    let (mut bytes, footer) = bfst.finish_into_parts().unwrap();
+    LittleEndian::write_u64(&mut bytes[0..8], 2);
+    bytes.extend_from_slice(&footer[footer.len() - 16..]);
+    let fst = tokio_test::block_on(Fst::new(bytes.clone())).unwrap();
+    assert_eq!(fst.max_key_len(), None);
+    assert_eq!(
+        fst.stream().into_byte_keys(),
+        vec![b"abc".to_vec(), b"abcdef".to_vec()]
+    );
+
+    // Entries with unknown tags are skipped.
+    let (body, _) = bytes.split_at(bytes.len() - 16);
+    let mut bytes = body.to_vec();
+    LittleEndian::write_u64(&mut bytes[0..8], 3);
+    let len = fst.len();
+    let root = bytes.len() as u64 - 1;
+    bytes.extend_from_slice(&footer::write_footer(
+        &[
+            (7, vec![1, 2, 3]),
+            (footer::TAG_MAX_KEY_LEN, 6u64.to_le_bytes().to_vec()),
+        ],
+        len,
+        root,
+    ));
+    let fst = tokio_test::block_on(Fst::new(bytes)).unwrap();
+    assert_eq!(fst.max_key_len(), Some(6));
+    assert_eq!(fst.len(), 2);
+}
+
+#[test]
+fn version_1_node_without_index() {
+    use byteorder::{ByteOrder, LittleEndian};
+
+    // Every transition of the root leads to the empty final node, so the
+    // root can be rewritten without its index and stay valid.
+    let keys: Vec<u8> = (b'0'..b'0' + 40).collect();
+    let mut bfst = Builder::memory();
+    for &k in &keys {
+        bfst.add([k]).unwrap();
+    }
+    let (mut bytes, _) = bfst.finish_into_parts().unwrap();
+    let mut index = [255u8; 256];
+    for (i, &k) in keys.iter().enumerate() {
+        index[k as usize] = i as u8;
+    }
+    let at = bytes.windows(256).position(|w| w == &index[..]).unwrap();
+    bytes.drain(at..at + 256);
+    LittleEndian::write_u64(&mut bytes[0..8], 1);
+    let root_addr = bytes.len() as u64 - 1;
+    bytes.extend_from_slice(&(keys.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&root_addr.to_le_bytes());
+
+    let fst = tokio_test::block_on(Fst::new(bytes)).unwrap();
+    for &k in &keys {
+        assert_eq!(fst.get([k]), Some(Output::zero()));
+    }
+    assert_eq!(fst.get("~"), None);
+    assert_eq!(fst.stream().into_byte_keys().len(), keys.len());
+}
+
+#[test]
+fn scan_benchmark() {
+    let fst = fst_set(vec!["a", "abc", "b", "bcd"]);
+    let report = fst.scan_benchmark();
+    assert_eq!(report.keys, 4);
+    assert_eq!(report.bytes, 8);
+    assert!(report.keys_per_sec() >= 0.0);
+
+    let report = fst.scan_benchmark_with(Regex::new("a.*").unwrap());
+    assert_eq!(report.keys, 2);
+    assert_eq!(report.bytes, 4);
+}
+
+#[test]
+fn stream_fill() {
+    let fst = fst_map(vec![("a", 1), ("bc", 2), ("def", 3)]);
+    let mut stream = fst.stream();
+    let (mut keys, mut offsets, mut values) = (vec![], vec![], vec![]);
+    assert_eq!(stream.fill(&mut keys, &mut offsets, &mut values, 0), 0);
+    assert_eq!(stream.fill(&mut keys, &mut offsets, &mut values, 2), 2);
+    assert_eq!(keys, b"abc");
+    assert_eq!(offsets, vec![1, 3]);
+    assert_eq!(values, vec![1, 2]);
+    assert_eq!(stream.fill(&mut keys, &mut offsets, &mut values, 2), 1);
+    assert_eq!(keys, b"abcdef");
+    assert_eq!(offsets, vec![1, 3, 6]);
+    assert_eq!(values, vec![1, 2, 3]);
+    assert_eq!(stream.fill(&mut keys, &mut offsets, &mut values, 2), 0);
+}
+
+#[test]
+fn get_with_prefix_state() {
+    let fst = fst_map(vec![
+        ("title:fst", 1),
+        ("title:map", 2),
+        ("url:fst", 3),
+        ("title:", 4),
+    ]);
+    let cursor = fst.get_with_prefix_state("title:").unwrap();
+    assert_eq!(fst.get_suffix(&cursor, "fst"), Some(Output::new(1)));
+    assert_eq!(fst.get_suffix(&cursor, "map"), Some(Output::new(2)));
+    assert_eq!(fst.get_suffix(&cursor, ""), Some(Output::new(4)));
+    assert_eq!(fst.get_suffix(&cursor, "set"), None);
+
+    let cursor = fst.get_with_prefix_state("").unwrap();
+    assert_eq!(fst.get_suffix(&cursor, "url:fst"), Some(Output::new(3)));
+    assert!(fst.get_with_prefix_state("title:x").is_none());
+}
+
+#[test]
+fn builder_key_scope() {
+    let mut bfst = Builder::memory();
+    bfst.insert("a", 1).unwrap();
+    {
+        let mut scope = bfst.scope("title:");
+        assert_eq!(scope.prefix(), b"title:");
+        scope.insert("fst", 2).unwrap();
+        scope.insert("map", 3).unwrap();
+        assert!(scope.insert("abc", 3).is_err());
+    }
+    {
+        let mut scope = bfst.scope("t");
+        assert!(scope.add("").is_err());
+        scope.insert("url:", 4).unwrap();
+    }
+    let fst = tokio_test::block_on(Fst::new(bfst.into_inner().unwrap())).unwrap();
+    assert_eq!(
+        fst.stream().into_str_vec().unwrap(),
+        vec![
+            ("a".to_string(), 1),
+            ("title:fst".to_string(), 2),
+            ("title:map".to_string(), 3),
+            ("turl:".to_string(), 4),
+        ]
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_round_trip() {
+    use crate::raw::{IndexedValue, SidecarKind};
+
+    let iv = IndexedValue {
+        index: 1,
+        value: 42,
+    };
+    let json = serde_json::to_string(&iv).unwrap();
+    assert_eq!(json, r#"{"index":1,"value":42}"#);
+    assert_eq!(serde_json::from_str::<IndexedValue>(&json).unwrap(), iv);
+
+    let out = Output::new(7);
+    assert_eq!(serde_json::to_string(&out).unwrap(), "7");
+    assert_eq!(serde_json::from_str::<Output>("7").unwrap(), out);
+
+    let mut bfst = Builder::memory();
+    bfst.record_invalid_keys(10);
+    bfst.insert("b", 1).unwrap();
+    bfst.insert("a", 2).unwrap();
+    let report = bfst.key_report().unwrap().clone();
+    let json = serde_json::to_string(&report).unwrap();
+    assert_eq!(
+        serde_json::from_str::<raw::KeyReport>(&json).unwrap(),
+        report
+    );
+
+    let fst = fst_map(vec![("a", 1), ("b", 2)]);
+    let sidecar = raw::OutputSidecar::new(&fst, SidecarKind::Max);
+    let json = serde_json::to_string(&sidecar).unwrap();
+    assert_eq!(
+        serde_json::from_str::<raw::OutputSidecar>(&json).unwrap(),
+        sidecar
+    );
+}
+
+#[test]
+fn error_source_chain() {
+    use std::error::Error as _;
+    use std::io::{self, Write};
+
+    fn assert_send_sync<T: Send + Sync + 'static>() {}
+    assert_send_sync::<Error>();
+
+    // Accepts the header and fails on everything after it.
+    struct FailAfterHeader(usize);
+    impl Write for FailAfterHeader {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.0 + buf.len() > 16 {
+                return Err(io::Error::other("disk full"));
+            }
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut bfst = Builder::new(FailAfterHeader(0)).unwrap();
+    bfst.add("a").unwrap();
+    let err = match bfst.into_inner() {
+        Err(err) => err,
+        Ok(_) => panic!("expected an IO error"),
+    };
+    match err {
+        Error::IoContext(ref ctx) => {
+            assert_eq!(ctx.operation(), "writing node");
+            assert_eq!(ctx.byte_offset(), Some(16));
+        }
+        ref err => panic!("expected an IO error with context, got {:?}", err),
+    }
+    assert_eq!(err.to_string(), "IO error while writing node at offset 16");
+    assert_eq!(err.source().unwrap().to_string(), "disk full");
+
+    let err = Error::from(raw::Error::Format);
+    assert!(err.source().is_none());
+}
+
+#[cfg(feature = "strict-checks")]
+#[test]
+#[should_panic(expected = "transition 3 out of bounds")]
+fn strict_checks_transition_bounds() {
+    let fst = fst_set(vec!["a", "b", "c"]);
+    fst.root().transition(3);
+}
+
+#[test]
+fn limited_automaton() {
+    use crate::automaton::{Automaton, Limited};
+
+    let fst = fst_set(vec!["a", "ab", "abc", "b", "bc"]);
+    let aut = Limited::new(AlwaysMatch, 100);
+    assert_eq!(fst.search(&aut).into_stream().into_byte_keys().len(), 5);
+    assert_eq!(aut.steps(), 5);
+    assert!(!aut.is_exhausted());
+
+    let aut = Limited::new(AlwaysMatch, 2);
+    let keys = fst.search(&aut).into_stream().into_byte_keys();
+    assert_eq!(keys, vec![b"a".to_vec(), b"ab".to_vec()]);
+    assert!(aut.is_exhausted());
+
+    let aut = Limited::new(Regex::new("b.*").unwrap(), 0);
+    assert!(!aut.can_match(&aut.accept(&aut.start(), b'b')));
+    assert!(aut.is_exhausted());
+}
+
+#[test]
+fn regex_literal_prefix_pushdown() {
+    assert_eq!(Regex::new("foo[0-9]+").unwrap().literal_prefix(), b"foo");
+    assert_eq!(Regex::new("(foo|fob)x").unwrap().literal_prefix(), b"fo");
+    assert_eq!(Regex::new("foo|bar").unwrap().literal_prefix(), b"");
+    assert_eq!(Regex::new("a*").unwrap().literal_prefix(), b"");
+    assert_eq!(
+        crate::bytes_util::prefix_upper_bound(b"ab"),
+        Some(b"ac".to_vec())
+    );
+    assert_eq!(
+        crate::bytes_util::prefix_upper_bound(b"a\xFF"),
+        Some(b"b".to_vec())
+    );
+    assert_eq!(crate::bytes_util::prefix_upper_bound(b"\xFF\xFF"), None);
+
+    let fst = fst_set(vec!["abc", "foo", "foo1", "foo22", "foox", "fop", "zzz"]);
+    let keys = fst
+        .search(Regex::new("foo[0-9]+").unwrap())
+        .into_stream()
+        .into_byte_keys();
+    assert_eq!(keys, vec![b"foo1".to_vec(), b"foo22".to_vec()]);
+    let keys = fst
+        .search(Regex::new("foo[0-9]+").unwrap())
+        .backward()
+        .into_stream()
+        .into_byte_keys();
+    assert_eq!(keys, vec![b"foo22".to_vec(), b"foo1".to_vec()]);
+
+    // Explicit bounds replace the ones implied by the prefix.
+    let keys = fst
+        .search(Regex::new("foo[0-9]+").unwrap())
+        .ge("foo2")
+        .into_stream()
+        .into_byte_keys();
+    assert_eq!(keys, vec![b"foo22".to_vec()]);
+}
+
+#[test]
+fn regex_required_literals() {
+    fn lits(re: &str) -> Vec<Vec<u8>> {
+        Regex::new(re).unwrap().required_literals().to_vec()
+    }
+    assert_eq!(lits("foo[0-9]+bar"), vec![b"foo".to_vec(), b"bar".to_vec()]);
+    assert_eq!(lits("foo"), vec![b"foo".to_vec()]);
+    assert_eq!(lits("(ab)+c(de)?"), vec![b"ab".to_vec(), b"c".to_vec()]);
+    assert_eq!(lits("x(ab){2,3}"), vec![b"x".to_vec(), b"ab".to_vec()]);
+    assert_eq!(lits(".*snow.*snow"), vec![b"snow".to_vec()]);
+    assert_eq!(lits("é+"), vec!["é".as_bytes().to_vec()]);
+    assert!(lits("foo|bar").is_empty());
+    assert!(lits("a*").is_empty());
+    assert!(lits("(?i)abc").is_empty());
+}
+
+#[test]
+fn regex_builder_layout() {
+    use crate::RegexBuilder;
+
+    let fst = fst_set(vec!["a1", "b2", "héllo", "zz"]);
+    for &max in &[0, 8, 256] {
+        let re = RegexBuilder::new(r"\w+")
+            .sparse_max_ranges(max)
+            .build()
+            .unwrap();
+        let debug = format!("{:?}", re);
+        match max {
+            0 => assert!(!debug.contains("(sparse)")),
+            256 => assert!(!debug.contains("(dense)")),
+            _ => assert!(debug.contains("(sparse)")),
+        }
+        let keys = fst.search(&re).into_stream().into_byte_keys();
+        assert_eq!(keys.len(), 4);
+    }
+
+    let err = RegexBuilder::new(r"\w{50}").state_limit(10).build();
+    assert!(err.is_err());
+}
+
+#[test]
+fn keys_and_values_with_state() {
+    use crate::automaton::Subsequence;
+    use crate::Map;
+
+    let map = Map::from(fst_map(vec![("abc", 1), ("axc", 2), ("bc", 3)]));
+    let aut = Subsequence::new("ac");
+
+    let mut keys = vec![];
+    let mut stream = map.search(&aut).keys_with_state();
+    while let Some((key, state)) = stream.next() {
+        keys.push((key.to_vec(), state));
+    }
+    assert_eq!(keys, vec![(b"abc".to_vec(), 2), (b"axc".to_vec(), 2)]);
+
+    let mut values = vec![];
+    let mut stream = map.search(&aut).ge("ax").values_with_state();
+    while let Some(item) = stream.next() {
+        values.push(item);
+    }
+    assert_eq!(values, vec![(2, 2)]);
+}
+
+#[test]
+fn fuzzy_search() {
+    use crate::Map;
+
+    let map = Map::from(fst_map(vec![
+        ("bar", 1),
+        ("baz", 2),
+        ("foo", 3),
+        ("fop", 4),
+        ("fooo", 5),
+        ("föo", 6),
+        ("xyz", 7),
+    ]));
+    let fuzzy = |term: &str, max_dist: u8| {
+        let mut found = vec![];
+        let mut stream = map.fuzzy_search(term, max_dist);
+        while let Some((key, value, dist)) = stream.next() {
+            found.push((String::from_utf8(key.to_vec()).unwrap(), value, dist));
+        }
+        found
+    };
+    assert_eq!(fuzzy("foo", 0), vec![("foo".to_string(), 3, 0)]);
+    assert_eq!(
+        fuzzy("foo", 1),
+        vec![
+            ("foo".to_string(), 3, 0),
+            ("fooo".to_string(), 5, 1),
+            ("fop".to_string(), 4, 1),
+            ("föo".to_string(), 6, 1),
+        ]
+    );
+    assert_eq!(
+        fuzzy("ba", 1),
+        vec![("bar".to_string(), 1, 1), ("baz".to_string(), 2, 1)]
+    );
+    assert!(fuzzy("qqq", 2).is_empty());
+}
+
+#[test]
+fn convert_backing_types() {
+    use crate::Map;
+
+    let items = vec![("a", 1), ("bc", 2), ("bd", 3)];
+    let map = Map::from(fst_map(items.clone()));
+    let view: Map<&[u8]> = map.as_slice_view();
+    assert_eq!(view.len(), 3);
+    assert_eq!(view.get("bc"), Some(2));
+    assert_eq!(view.max_key_len(), map.max_key_len());
+
+    let owned: Map<Vec<u8>> = view.to_vec_backed();
+    assert_eq!(owned.as_fst().to_vec(), map.as_fst().to_vec());
+    assert_eq!(owned.as_bytes(), map.as_bytes());
+    assert_eq!(view.as_inner().as_ptr(), map.as_bytes().as_ptr());
+    let mut got = vec![];
+    let mut stream = owned.stream();
+    while let Some((k, v)) = stream.next() {
+        got.push((k.to_vec(), v));
+    }
+    let expected: Vec<(Vec<u8>, u64)> = items
+        .into_iter()
+        .map(|(k, v)| (k.as_bytes().to_vec(), v))
+        .collect();
+    assert_eq!(got, expected);
+}
+
+#[test]
+fn fst_inner_data() {
+    use crate::Map;
+
+    let fst = fst_map(vec![("a", 1), ("b", 2)]);
+    let bytes = fst.to_vec();
+    assert_eq!(fst.as_bytes(), &bytes[..]);
+    assert_eq!(fst.as_inner(), &bytes);
+    let map = Map::from(fst);
+    assert_eq!(map.as_bytes(), &bytes[..]);
+    assert_eq!(map.into_inner(), bytes);
+}
+
+#[test]
+fn map_from_reader() {
+    use crate::Map;
+
+    let bytes = fst_map(vec![("a", 1), ("b", 2)]).to_vec();
+    for &hint in &[0, 10, bytes.len(), 1 << 16] {
+        let map = tokio_test::block_on(Map::from_reader(&bytes[..], hint)).unwrap();
+        assert_eq!(map.get("b"), Some(2));
+        assert_eq!(map.as_bytes(), &bytes[..]);
+    }
+    assert!(tokio_test::block_on(Map::from_reader(&bytes[..3], 0)).is_err());
+
+    let err = tokio_test::block_on(Map::from_reader(FailingReader, 0)).unwrap_err();
+    assert!(err.to_string().contains("reading map"), "{}", err);
+}
+
+struct FailingReader;
+
+impl std::io::Read for FailingReader {
+    fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+        Err(std::io::Error::other("boom"))
+    }
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn compressed_transport() {
+    use crate::Map;
+
+    let keys: Vec<String> = (0..1000).map(|i| format!("key{:05}", i)).collect();
+    let map = Map::from(fst_map(keys.iter().map(|k| (k, k.len() as u64))));
+    let mut compressed = vec![];
+    map.write_compressed(&mut compressed).unwrap();
+    assert!(compressed.len() < map.as_bytes().len());
+
+    let got = tokio_test::block_on(Map::from_compressed_reader(&compressed[..])).unwrap();
+    assert_eq!(got.as_bytes(), map.as_bytes());
+
+    // A stream cut short fails to decompress or fails the footer check.
+    let cut = &compressed[..compressed.len() - 8];
+    assert!(tokio_test::block_on(Map::from_compressed_reader(cut)).is_err());
+    assert!(tokio_test::block_on(Map::from_compressed_reader(&b"junk"[..])).is_err());
+}
+
+#[test]
+fn builder_poll_insert() {
+    use std::task::Poll;
+
+    let keys: Vec<String> = (0..500).map(|i| format!("{:04}", i * 7)).collect();
+    let mut bfst = Builder::memory();
+    bfst.set_high_water_mark(64);
+    let (mut out, mut pauses) = (vec![], 0);
+    for (i, key) in keys.iter().enumerate() {
+        loop {
+            match bfst.poll_insert(key, i as u64).unwrap() {
+                Poll::Ready(()) => break,
+                Poll::Pending => {
+                    pauses += 1;
+                    assert!(bfst.bytes_since_flush() >= 64);
+                    out.append(bfst.get_mut());
+                    bfst.flush().unwrap();
+                    assert_eq!(bfst.bytes_since_flush(), 0);
+                }
+            }
+        }
+    }
+    assert!(pauses > 0);
+    out.extend(bfst.into_inner().unwrap());
+
+    let fst = tokio_test::block_on(Fst::new(out)).unwrap();
+    assert_eq!(fst.len(), keys.len() as u64);
+    assert_eq!(fst.get("0014").map(|o| o.value()), Some(2));
+}
+
+#[test]
+fn builder_poll_insert_rollback() {
+    fn poll_all(bfst: &mut Builder<Vec<u8>>, keys: &[String], drain: bool) -> Vec<u8> {
+        let mut out = vec![];
+        for (i, key) in keys.iter().enumerate() {
+            while bfst.poll_insert(key, i as u64).unwrap().is_pending() {
+                if drain {
+                    out.append(bfst.get_mut());
+                }
+                bfst.flush().unwrap();
+            }
+        }
+        out
+    }
+
+    let keys: Vec<String> = (0..200).map(|i| format!("{:04}", i * 7)).collect();
+
+    // Flushing without draining keeps every byte in the writer, so the keys
+    // inserted since the checkpoint can be rolled back.
+    let mut bfst = Builder::memory();
+    bfst.set_high_water_mark(64);
+    poll_all(&mut bfst, &keys[..50], false);
+    let checkpoint = bfst.checkpoint();
+    poll_all(&mut bfst, &keys[50..], false);
+    bfst.rollback(checkpoint).unwrap();
+    let fst = tokio_test::block_on(Fst::new(bfst.into_inner().unwrap())).unwrap();
+    assert_eq!(fst.len(), 50);
+
+    // Draining hands off bytes that rolling back would need to truncate.
+    let mut bfst = Builder::memory();
+    bfst.set_high_water_mark(64);
+    poll_all(&mut bfst, &keys[..50], false);
+    let checkpoint = bfst.checkpoint();
+    assert!(!poll_all(&mut bfst, &keys[50..], true).is_empty());
+    assert!(bfst.rollback(checkpoint).is_err());
+}
+
+#[test]
+fn resource_observer() {
+    use crate::raw::ResourceCounter;
+    use std::sync::Arc;
+
+    let counter = Arc::new(ResourceCounter::new());
+    let mut bfst = Builder::memory();
+    bfst.set_observer(counter.clone());
+    for key in &["abc", "abd", "b", "bcd"] {
+        bfst.add(key).unwrap();
+    }
+    let bytes = bfst.into_inner().unwrap();
+    assert_eq!(counter.bytes_written(), bytes.len() as u64);
+    assert_eq!(counter.nodes_visited(), 0);
+
+    let fst = tokio_test::block_on(Fst::new(bytes)).unwrap();
+    let keys = fst
+        .range()
+        .observer(counter.clone())
+        .into_stream()
+        .into_byte_keys();
+    assert_eq!(keys.len(), 4);
+    let visited = counter.nodes_visited();
+    assert!(visited >= 4);
+    assert!(counter.bytes_read() > 0);
+
+    // A narrower search visits fewer nodes.
+    let keys = fst
+        .range()
+        .ge("b")
+        .lt("bc")
+        .observer(counter.clone())
+        .into_stream()
+        .into_byte_keys();
+    assert_eq!(keys, vec![b"b".to_vec()]);
+    assert!(counter.nodes_visited() - visited < visited);
+}
+
+#[test]
+fn complement_max_len() {
+    use crate::automaton::Automaton;
+    use crate::raw::ResourceCounter;
+    use std::sync::Arc;
+
+    let fst = fst_set(vec!["a", "ab", "abcdef", "b", "bcd", "c", "cdefgh"]);
+    let not_b = || Regex::new("b.*").unwrap().complement();
+    let keys = fst.search(not_b()).into_stream().into_byte_keys();
+    assert_eq!(
+        keys,
+        vec![
+            b"a".to_vec(),
+            b"ab".to_vec(),
+            b"abcdef".to_vec(),
+            b"c".to_vec(),
+            b"cdefgh".to_vec(),
+        ]
+    );
+
+    let uncapped = Arc::new(ResourceCounter::new());
+    fst.search(not_b())
+        .observer(uncapped.clone())
+        .into_stream()
+        .into_byte_keys();
+    let capped = Arc::new(ResourceCounter::new());
+    let keys = fst
+        .search(not_b().max_len(2))
+        .observer(capped.clone())
+        .into_stream()
+        .into_byte_keys();
+    assert_eq!(keys, vec![b"a".to_vec(), b"ab".to_vec(), b"c".to_vec()]);
+    assert!(capped.nodes_visited() < uncapped.nodes_visited());
+
+    let keys = fst
+        .search(not_b().max_len(0))
+        .into_stream()
+        .into_byte_keys();
+    assert!(keys.is_empty());
+}
+
+#[test]
+fn shard_router() {
+    use crate::raw::ShardRouter;
+    use std::ops::Bound::{Excluded, Included, Unbounded};
+
+    let router = ShardRouter::new(vec!["", "f", "m", "t"]);
+    assert_eq!(router.len(), 4);
+    assert_eq!(router.shard_for_key(""), 0);
+    assert_eq!(router.shard_for_key("apple"), 0);
+    assert_eq!(router.shard_for_key("f"), 1);
+    assert_eq!(router.shard_for_key("lemon"), 1);
+    assert_eq!(router.shard_for_key("zebra"), 3);
+
+    let range = |lo, hi| router.shards_for_range(lo, hi);
+    assert_eq!(range(Unbounded, Unbounded), 0..4);
+    assert_eq!(range(Included(&b"g"[..]), Excluded(&b"m"[..])), 1..2);
+    assert_eq!(range(Included(&b"g"[..]), Included(&b"m"[..])), 1..3);
+    assert_eq!(range(Excluded(&b"a"[..]), Unbounded), 0..4);
+    assert_eq!(range(Included(&b"n"[..]), Excluded(&b"n"[..])), 2..2);
+
+    let aut = |re: &str| router.shards_for_automaton(&Regex::new(re).unwrap());
+    assert_eq!(aut("mango|melon"), 2..3);
+    assert_eq!(aut("f[a-z]+"), 1..2);
+    assert_eq!(aut("[a-z]+"), 0..4);
+    assert_eq!(aut("[g-l]+"), 1..2);
+    assert_eq!(aut("x|y"), 3..4);
+    let nothing = crate::automaton::AlwaysMatch.complement();
+    assert_eq!(router.shards_for_automaton(&nothing), 0..0);
+
+    // Keys below the first shard's first key go to the first shard.
+    let router = ShardRouter::new(vec!["m"]);
+    assert_eq!(router.shard_for_key("a"), 0);
+    assert_eq!(
+        router.shards_for_range(Unbounded, Excluded(&b"c"[..])),
+        0..1
+    );
+}
+
+#[test]
+fn automaton_bounds() {
+    use crate::automaton::{bounds, AlwaysMatch, Subsequence};
+    use std::ops::Bound::{Excluded, Included, Unbounded};
+
+    let re = Regex::new("b[a-c]|xy").unwrap();
+    let b = bounds(&re, 10).unwrap();
+    assert_eq!((&b.min[..], b.min_is_prefix), (&b"ba"[..], false));
+    assert_eq!((&b.max[..], b.max_is_prefix), (&b"xy"[..], false));
+    assert_eq!(b.lower(), Included(&b"ba"[..]));
+    assert_eq!(b.upper(), Included(b"xy".to_vec()));
+
+    // Strings longer than the limit are cut off and become prefix bounds.
+    let re = Regex::new("ab+").unwrap();
+    let b = bounds(&re, 3).unwrap();
+    assert_eq!((&b.min[..], b.min_is_prefix), (&b"ab"[..], false));
+    assert_eq!((&b.max[..], b.max_is_prefix), (&b"abb"[..], true));
+    assert_eq!(b.upper(), Excluded(b"abc".to_vec()));
+
+    let b = bounds(&Subsequence::new("q"), 2).unwrap();
+    assert_eq!((&b.min[..], b.min_is_prefix), (&b"\x00\x00"[..], true));
+    assert_eq!(b.upper(), Unbounded);
+
+    let b = bounds(&AlwaysMatch, 4).unwrap();
+    assert_eq!(b.lower(), Included(&b""[..]));
+    assert_eq!(b.upper(), Unbounded);
+
+    assert_eq!(bounds(&AlwaysMatch.complement(), 5), None);
+    let b = bounds(&Regex::new("abcdef").unwrap(), 3).unwrap();
+    assert_eq!((&b.min[..], b.min_is_prefix), (&b"abc"[..], true));
+
+    // The bounds are a valid pre-filter for a search.
+    let fst = fst_set(vec!["aa", "ab", "abb", "abbb", "ac", "b"]);
+    let re = Regex::new("ab+").unwrap();
+    let b = bounds(&re, 2).unwrap();
+    let builder = fst.search(&re).ge(&b.min);
+    let builder = match b.upper() {
+        Included(end) => builder.le(end),
+        Excluded(end) => builder.lt(end),
+        Unbounded => builder,
+    };
+    let keys = builder.into_stream().into_byte_keys();
+    assert_eq!(
+        keys,
+        vec![b"ab".to_vec(), b"abb".to_vec(), b"abbb".to_vec()]
+    );
+}
+
+#[test]
+fn stream_mapped() {
+    use crate::Map;
+    use std::borrow::Cow;
+
+    let map = Map::from(fst_map(vec![("f:a", 1), ("f:b", 2), ("g:%41", 3)]));
+    let mut stream = map.stream_mapped(|key| match key.strip_prefix(b"f:") {
+        Some(rest) => Cow::Borrowed(rest),
+        None => Cow::Owned(
+            key.iter()
+                .map(|&b| if b == b'%' { b'#' } else { b })
+                .collect(),
+        ),
+    });
+    let mut items = vec![];
+    while let Some((key, value)) = stream.next() {
+        items.push((key.into_owned(), value));
+    }
+    assert_eq!(
+        items,
+        vec![
+            (b"a".to_vec(), 1),
+            (b"b".to_vec(), 2),
+            (b"g:#41".to_vec(), 3),
+        ]
+    );
+
+    let mut stream = map
+        .range()
+        .ge("g")
+        .into_stream()
+        .into_mapped(|key| Cow::Owned(key.to_ascii_uppercase()));
+    assert_eq!(stream.next(), Some((Cow::Owned(b"G:%41".to_vec()), 3)));
+    assert_eq!(stream.next(), None);
+}
+
+#[test]
+fn dictionary_values() {
+    use crate::{Map, MapBuilder};
+
+    const BIG: u64 = 1 << 60;
+    let values = [BIG, 7, BIG + 1, 0];
+    let keys: Vec<String> = (0..300).map(|i| format!("{:03}", i)).collect();
+    let build = |dict: bool| {
+        let mut builder = if dict {
+            MapBuilder::new_dictionary_encoded(vec![]).unwrap()
+        } else {
+            MapBuilder::memory()
+        };
+        for (i, key) in keys.iter().enumerate() {
+            builder.insert(key, values[i % 4]).unwrap();
+        }
+        let bytes = builder.into_inner().unwrap();
+        Map::from(tokio_test::block_on(Fst::new(bytes)).unwrap())
+    };
+    let plain = build(false);
+    let map = build(true);
+    assert!(map.as_bytes().len() < plain.as_bytes().len());
+    assert_eq!(plain.distinct_values(), None);
+    assert_eq!(map.distinct_values(), Some(&[0, BIG, 7, BIG + 1][..]));
+
+    assert_eq!(map.get("000"), Some(BIG));
+    assert_eq!(map.get("001"), Some(7));
+    assert_eq!(map.get("003"), Some(0));
+    assert_eq!(map.get("300"), None);
+    assert_eq!(map.stream().into_byte_vec(), plain.stream().into_byte_vec());
+    assert_eq!(
+        map.range().ge("100").backward().into_stream().into_values(),
+        plain
+            .range()
+            .ge("100")
+            .backward()
+            .into_stream()
+            .into_values()
+    );
+
+    let raw = Fst::from_slice(map.as_bytes()).unwrap();
+    assert_eq!(raw.get("002").map(|o| o.value()), Some(BIG + 1));
+    assert_eq!(raw.distinct_values(), map.distinct_values());
+}
+
+#[test]
+fn stream_builder_accessors() {
+    use crate::automaton::Subsequence;
+    use crate::Map;
+    use std::ops::Bound::{Excluded, Included, Unbounded};
+
+    let map = Map::from(fst_map(vec![("abc", 1), ("axc", 2), ("bc", 3), ("xbc", 4)]));
+    let builder = map.range().ge("a").lt("x");
+    assert_eq!(builder.bounds(), (Included(&b"a"[..]), Excluded(&b"x"[..])));
+    assert!(!builder.is_backward());
+    assert!(map.range().backward().is_backward());
+    assert_eq!(map.range().bounds(), (Unbounded, Unbounded));
+
+    // A wrapping crate can narrow the automaton of a query after the fact.
+    let builder = map.search(Subsequence::new("bc")).ge("a").lt("x");
+    assert_eq!(builder.automaton().start(), 0);
+    let builder = builder.map_automaton(|aut| aut.intersection(Subsequence::new("a")));
+    assert_eq!(builder.bounds().1, Excluded(&b"x"[..]));
+    assert_eq!(builder.into_stream().into_values(), vec![1]);
+
+    let raw = map.range().gt("b").into_raw();
+    assert_eq!(raw.bounds().0, Excluded(&b"b"[..]));
+    assert_eq!(raw.into_stream().into_values(), vec![3, 4]);
+}
+
+#[test]
+fn keys_into_fst() {
+    use crate::Map;
+
+    let items: Vec<(String, u64)> = (0..1000u64)
+        .map(|i| (format!("{:04}", i), i * 7919 % 1000 + (1 << 40)))
+        .collect();
+    let map = Map::from(fst_map(items.clone()));
+    let bytes = map.keys_into_fst(vec![]).unwrap();
+    assert!(bytes.len() * 2 < map.as_bytes().len());
+
+    let set = Fst::from_slice(&bytes).unwrap();
+    assert_eq!(set.len(), map.len());
+    assert_eq!(set.fst_type(), map.as_fst().fst_type());
+    assert_eq!(set.stream().into_byte_keys(), map.stream().into_byte_keys());
+    assert!(set.stream().into_values().iter().all(|&v| v == 0));
+}
+
+#[test]
+fn highlight_spans() {
+    use crate::automaton::{Highlight, Subsequence};
+    use crate::Map;
+
+    let map = Map::from(fst_map(vec![
+        ("fast", 1),
+        ("forest", 2),
+        ("fst", 3),
+        ("xyz", 4),
+    ]));
+    let mut stream = map
+        .search(Highlight::new(Subsequence::new("fst")))
+        .keys_with_state();
+    let mut hits = vec![];
+    while let Some((key, state)) = stream.next() {
+        let spans: Vec<_> = state.spans().iter().map(|r| (r.start, r.end)).collect();
+        hits.push((key.to_vec(), spans));
+    }
+    assert_eq!(
+        hits,
+        vec![
+            (b"fast".to_vec(), vec![(0, 1), (2, 4)]),
+            (b"forest".to_vec(), vec![(0, 1), (4, 6)]),
+            (b"fst".to_vec(), vec![(0, 3)]),
+        ]
+    );
+}
+
+#[test]
+fn multi_map_tie_break() {
+    use crate::automaton::Subsequence;
+    use crate::map::{MultiMap, TieBreak};
+    use crate::Map;
+    use std::sync::Arc;
+
+    let old = Map::from(fst_map(vec![("a", 1), ("b", 20), ("c", 3)]));
+    let mid = Map::from(fst_map(vec![("b", 5), ("d", 4)]));
+    let new = Map::from(fst_map(vec![("b", 10), ("c", 30)]));
+    let collect = |multi: &MultiMap<'_, Vec<u8>>| {
+        let mut stream = multi.stream();
+        let mut items = vec![];
+        while let Some((key, value)) = stream.next() {
+            items.push((key.to_vec(), value));
+        }
+        items
+    };
+
+    let multi = MultiMap::new(vec![&old, &mid, &new]);
+    assert_eq!(multi.len(), 3);
+    assert_eq!(multi.get("b"), Some(10));
+    assert_eq!(multi.get("d"), Some(4));
+    assert_eq!(multi.get("e"), None);
+    assert!(multi.contains_key("a"));
+    assert_eq!(
+        collect(&multi),
+        vec![
+            (b"a".to_vec(), 1),
+            (b"b".to_vec(), 10),
+            (b"c".to_vec(), 30),
+            (b"d".to_vec(), 4),
+        ]
+    );
+
+    let policies = vec![
+        (TieBreak::Oldest, 20),
+        (TieBreak::SmallestValue, 5),
+        (TieBreak::LargestValue, 20),
+        (
+            TieBreak::Custom(Arc::new(|vs| vs.iter().map(|v| v.value).sum())),
+            35,
+        ),
+    ];
+    for (policy, b) in policies {
+        let multi = MultiMap::new(vec![&old, &mid, &new]).tie_break(policy);
+        assert_eq!(multi.get("b"), Some(b));
+        assert_eq!(collect(&multi)[1], (b"b".to_vec(), b));
+        let mut stream = multi.search(Subsequence::new("b"));
+        assert_eq!(
+            stream.next().map(|(k, v)| (k.to_vec(), v)),
+            Some((b"b".to_vec(), b))
+        );
+        assert!(stream.next().is_none());
+    }
+}
+
+#[test]
+fn contains_all_and_any() {
+    use crate::Map;
+
+    let map = Map::from(fst_map(vec![
+        ("", 0),
+        ("ab", 1),
+        ("abc", 2),
+        ("abd", 3),
+        ("b", 4),
+    ]));
+    let keys = ["", "a", "ab", "abc", "abcd", "abd", "ac", "b", "ba"];
+    let found = map.contains_all(&keys);
+    assert_eq!(
+        found,
+        vec![true, false, true, true, false, true, false, true, false]
+    );
+    let expected: Vec<bool> = keys.iter().map(|k| map.contains_key(k)).collect();
+    assert_eq!(found, expected);
+    // Unsorted keys give the same answers.
+    let shuffled = ["abd", "a", "abcd", "", "ba", "abc"];
+    assert_eq!(
+        map.contains_all(&shuffled),
+        vec![true, false, false, true, false, true]
+    );
+
+    assert!(map.contains_any(&["a", "aa", "b"]));
+    assert!(!map.contains_any(&["a", "aa", "bb"]));
+    assert!(!map.contains_any::<&str>(&[]));
+    assert_eq!(map.contains_all::<&str>(&[]), Vec::<bool>::new());
+}
+
+#[test]
+fn walk_distinct_nodes() {
+    use std::collections::HashSet;
+    use std::ops::ControlFlow;
+
+    // Every key ends at the same final node, which is visited once.
+    let fst = fst_set(vec!["ab", "cb", "cd"]);
+    let mut visits = vec![];
+    let res: ControlFlow<()> = fst.walk(|node, depth| {
+        visits.push((node.addr(), depth, node.len()));
+        ControlFlow::Continue(())
+    });
+    assert_eq!(res, ControlFlow::Continue(()));
+    let addrs: HashSet<_> = visits.iter().map(|v| v.0).collect();
+    assert_eq!(addrs.len(), visits.len());
+    assert_eq!(visits.len(), 4);
+    assert_eq!(visits[0], (fst.root().addr(), 0, 2));
+    assert_eq!(visits.iter().map(|v| v.1).max(), Some(2));
+
+    // Breaking stops the walk and returns the value.
+    let mut count = 0;
+    let res = fst.walk(|node, depth| {
+        count += 1;
+        if depth == 1 {
+            ControlFlow::Break(node.len())
+        } else {
+            ControlFlow::Continue(())
+        }
+    });
+    assert_eq!(res, ControlFlow::Break(1));
+    assert_eq!(count, 2);
+}
+
+#[test]
+fn shared_structure() {
+    let a = fst_map(vec![("apple", 1), ("banana", 2), ("cherry", 3)]);
+    let report = a.shared_structure(&a);
+    assert_eq!(report.nodes, report.other_nodes);
+    assert_eq!(report.shared_nodes, report.nodes);
+    assert_eq!(report.shared_fraction(), 1.0);
+
+    // "xbanana" shares the whole "banana" chain of the first fst, but the
+    // roots and the other chains differ.
+    let b = fst_map(vec![("banana", 2), ("xbanana", 7)]);
+    let report = a.shared_structure(&b);
+    assert!(report.shared_nodes > 0);
+    assert!(report.shared_nodes < report.other_nodes);
+    assert!(report.shared_bytes < report.other_bytes);
+
+    // Different outputs make otherwise equal nodes different.
+    let c = fst_map(vec![("apple", 5), ("banana", 6), ("cherry", 7)]);
+    let report = a.shared_structure(&c);
+    assert!(report.shared_nodes < report.other_nodes);
+}
+
+#[test]
+fn search_before() {
+    use crate::Map;
+
+    let keys = vec![
+        "a", "ab", "abc", "abd", "b", "ba", "bab", "bb", "c", "ca", "cab",
+    ];
+    let fst = fst_set(keys.clone());
+    let matches = |r: &Regex, key: &str| {
+        let mut state = r.start();
+        for &b in key.as_bytes() {
+            state = r.accept(&state, b);
+        }
+        r.is_match(&state)
+    };
+    for re in &["a.*", ".*b", ".*", "b.*|c", "[ac]b?"] {
+        let r = Regex::new(re).unwrap();
+        for &k in &[
+            "", "a", "aa", "ab", "abz", "b", "bab", "bz", "c", "cab", "d",
+        ] {
+            let got = fst.search(&r).before(k).into_stream().into_byte_keys();
+            let mut want: Vec<Vec<u8>> = keys
+                .iter()
+                .filter(|&&x| x < k && matches(&r, x))
+                .map(|x| x.as_bytes().to_vec())
+                .collect();
+            want.reverse();
+            assert_eq!(got, want, "{} before {:?}", re, k);
+        }
+    }
+
+    let map = Map::from(fst_map(keys.iter().enumerate().map(|(i, k)| (k, i as u64))));
+    let page = map.preceding(Regex::new(".*b").unwrap(), "bb", 2);
+    assert_eq!(page, vec![(b"b".to_vec(), 4), (b"bab".to_vec(), 6)]);
+    assert!(map
+        .preceding(Regex::new(".*b").unwrap(), "ab", 5)
+        .is_empty());
+}
+
+#[test]
+fn regex_with_flags() {
+    use crate::regex::Error as RegexError;
+    use crate::RegexFlags;
+
+    let fst = fst_set(vec!["FOO", "Foo", "a\nb", "axb", "foo", "über"]);
+    let search = |re: &str, flags: RegexFlags| {
+        let re = Regex::new_with_flags(re, flags).unwrap();
+        fst.search(&re).into_stream().into_byte_keys()
+    };
+    let flags = RegexFlags::default();
+    assert_eq!(search("foo", flags), vec![b"foo".to_vec()]);
+
+    let icase = RegexFlags {
+        case_insensitive: true,
+        ..flags
+    };
+    assert_eq!(search("foo", icase).len(), 3);
+    assert_eq!(search("ÜBER", icase), vec!["über".as_bytes().to_vec()]);
+    // Inline flags still override the given ones.
+    assert_eq!(search("(?-i)foo", icase), vec![b"foo".to_vec()]);
+
+    let dotall = RegexFlags {
+        dot_matches_new_line: true,
+        ..flags
+    };
+    assert_eq!(search("a.b", flags), vec![b"axb".to_vec()]);
+    assert_eq!(search("a.b", dotall).len(), 2);
+
+    let ascii = RegexFlags {
+        unicode: false,
+        ..flags
+    };
+    assert_eq!(search("\\w+", ascii).len(), 4);
+    assert_eq!(search("(?i)FOO", ascii).len(), 3);
+    for re in &[".*", "[^a]", "über"] {
+        match Regex::new_with_flags(re, ascii) {
+            Err(RegexError::UnicodeDisabled(_)) => {}
+            res => panic!("{}: {:?}", re, res.map(|_| ())),
+        }
+    }
+    assert!(Regex::new_with_flags("(", ascii).is_err());
+}
+
+#[test]
+fn suggest() {
+    use crate::map::Suggestion;
+    use crate::Map;
+
+    let map = Map::from(fst_map(vec![
+        ("cart", 40),
+        ("cat", 100),
+        ("coat", 5),
+        ("cut", 80),
+        ("dog", 500),
+        ("hat", 90),
+    ]));
+    let suggest = |term, k| -> Vec<(String, u64, u8)> {
+        map.suggest(term, 1, k)
+            .into_iter()
+            .map(|s| (String::from_utf8(s.key).unwrap(), s.value, s.distance))
+            .collect()
+    };
+    assert_eq!(
+        suggest("cat", 10),
+        vec![
+            ("cat".to_string(), 100, 0),
+            ("hat".to_string(), 90, 1),
+            ("cut".to_string(), 80, 1),
+            ("cart".to_string(), 40, 1),
+            ("coat".to_string(), 5, 1),
+        ]
+    );
+    assert_eq!(
+        suggest("cat", 2),
+        vec![("cat".to_string(), 100, 0), ("hat".to_string(), 90, 1)]
+    );
+    assert!(suggest("cat", 0).is_empty());
+    assert_eq!(
+        map.suggest("dgo", 2, 1),
+        vec![Suggestion {
+            key: b"dog".to_vec(),
+            value: 500,
+            distance: 2,
+        }]
+    );
+}
+
+#[test]
+fn escape_composite_keys() {
+    use crate::escape;
+    use crate::{Map, MapBuilder};
+
+    let tuples: Vec<Vec<&[u8]>> = vec![
+        vec![b"a"],
+        vec![b"a", b""],
+        vec![b"a", b"\x00"],
+        vec![b"a", b"\x00b"],
+        vec![b"a", b"b"],
+        vec![b"a\x00"],
+        vec![b"a\x00", b"b"],
+        vec![b"a\x01"],
+        vec![b"ab"],
+    ];
+    let keys: Vec<Vec<u8>> = tuples.iter().map(escape::join).collect();
+    let mut sorted = keys.clone();
+    sorted.sort();
+    assert_eq!(keys, sorted);
+    for (tuple, key) in tuples.iter().zip(&keys) {
+        assert_eq!(&escape::split(key).unwrap(), tuple);
+    }
+    assert_eq!(
+        escape::unescape(&escape::escape(b"\x00a\x00")).unwrap(),
+        b"\x00a\x00"
+    );
+    assert!(escape::unescape(&escape::join(["a", "b"])).is_err());
+    assert!(escape::split(b"a\x00").is_err());
+    assert!(escape::split(b"a\x00\x02").is_err());
+
+    let mut builder = MapBuilder::memory();
+    for (i, tuple) in tuples.iter().enumerate() {
+        builder.insert_components(tuple, i as u64).unwrap();
+    }
+    let map = Map::from(tokio_test::block_on(Fst::new(builder.into_inner().unwrap())).unwrap());
+    assert_eq!(map.get_components([&b"a\x00"[..], b"b"]), Some(6));
+    assert_eq!(map.get_components(["a", "c"]), None);
+    let prefix = escape::prefix(["a"]);
+    let under_a = map
+        .range()
+        .ge(&prefix)
+        .lt(crate::bytes_util::prefix_upper_bound(&prefix).unwrap())
+        .into_stream()
+        .into_values();
+    assert_eq!(under_a, vec![1, 2, 3, 4]);
+}
+
+#[test]
+fn keys_order_preserving() {
+    use crate::keys::{KeyDecoder, KeyEncoder};
+    use crate::{Map, MapBuilder};
+
+    let tuples: Vec<(&str, i64, f64)> = vec![
+        ("", i64::MIN, -1.5),
+        ("a", -1, f64::NEG_INFINITY),
+        ("a", -1, -0.0),
+        ("a", -1, 0.0),
+        ("a", 0, 2.5),
+        ("a", 7, -3.0),
+        ("a\u{0}", i64::MIN, 0.0),
+        ("ab", 0, f64::INFINITY),
+        ("b", i64::MAX, 1e-300),
+    ];
+    let keys: Vec<Vec<u8>> = tuples
+        .iter()
+        .map(|&(s, i, f)| KeyEncoder::new().str(s).i64(i).f64(f).finish())
+        .collect();
+    let mut sorted = keys.clone();
+    sorted.sort();
+    assert_eq!(keys, sorted);
+    for (&(s, i, f), key) in tuples.iter().zip(&keys) {
+        let mut dec = KeyDecoder::new(key);
+        assert_eq!(dec.str().unwrap(), s);
+        assert_eq!(dec.i64().unwrap(), i);
+        assert_eq!(dec.f64().unwrap().to_bits(), f.to_bits());
+        assert!(dec.is_empty());
+        assert!(dec.u64().is_err());
+    }
+    assert!(KeyDecoder::new(b"a").bytes().is_err());
+    assert!(KeyDecoder::new(b"\xFF\xFE\x00\x01").str().is_err());
+
+    let mut builder = MapBuilder::memory();
+    for (i, key) in keys.iter().enumerate() {
+        builder.insert(key, i as u64).unwrap();
+    }
+    let map = Map::from(tokio_test::block_on(Fst::new(builder.into_inner().unwrap())).unwrap());
+    let lo = KeyEncoder::new().str("a").i64(-1).finish();
+    let hi = KeyEncoder::new().str("a").i64(7).finish();
+    let values = map.range().ge(lo).lt(hi).into_stream().into_values();
+    assert_eq!(values, vec![1, 2, 3, 4]);
+}
+
+#[test]
+fn contiguous_get() {
+    use crate::fake_arr::{FakeArr, Ulen};
+
+    // Hides the bytes of an fst so that lookups take the generic path.
+    #[derive(Debug)]
+    struct Scattered(Vec<u8>);
+
+    impl FakeArr for Scattered {
+        fn len(&self) -> Ulen {
+            self.0.len() as Ulen
+        }
+
+        fn read_into(&self, offset: Ulen, buf: &mut [u8]) -> std::io::Result<()> {
+            self.0.read_into(offset, buf)
+        }
+
+        fn as_dyn(&self) -> &dyn FakeArr {
+            self
+        }
+    }
+
+    let mut keys: Vec<(Vec<u8>, u64)> = vec![];
+    for a in 0..=255u8 {
+        keys.push((vec![a], a as u64 * 1000));
+        if a % 7 == 0 {
+            for b in (0..=255u8).step_by(5) {
+                keys.push((vec![a, b, b'x'], (a as u64) << 20 | b as u64));
+            }
+        }
+    }
+    keys.push((b"\x07longer key with a tail".to_vec(), u64::MAX));
+    keys.sort();
+    let mut bld = Builder::memory();
+    bld.insert(b"", 42).unwrap();
+    for (k, v) in &keys {
+        bld.insert(k, *v).unwrap();
+    }
+    let bytes = bld.into_inner().unwrap();
+    let fst = Fst::from_slice(&bytes).unwrap();
+    let scattered = tokio_test::block_on(Fst::new(Scattered(bytes.clone()))).unwrap();
+    assert!(fst.as_contiguous().is_some());
+    assert!(scattered.as_contiguous().is_none());
+
+    let mut probes: Vec<Vec<u8>> = keys.iter().map(|(k, _)| k.clone()).collect();
+    probes.extend(vec![
+        vec![],
+        vec![0, 1],
+        vec![7, 5],
+        vec![7, 5, b'y'],
+        vec![255, 255, b'x', 0],
+        b"\x07longer".to_vec(),
+    ]);
+    for probe in &probes {
+        assert_eq!(fst.get(probe), scattered.get(probe), "{:?}", probe);
+        assert_eq!(fst.contains_key(probe), scattered.contains_key(probe));
+    }
+    assert_eq!(fst.get(b""), Some(Output::new(42)));
+    assert_eq!(
+        fst.get(b"\x07longer key with a tail"),
+        Some(Output::new(u64::MAX))
+    );
+
+    let mut bld = Builder::new_dictionary_encoded(vec![]).unwrap();
+    bld.insert(b"a", 500).unwrap();
+    bld.insert(b"b", 7).unwrap();
+    let bytes = bld.into_inner().unwrap();
+    let fst = Fst::from_slice(&bytes).unwrap();
+    assert_eq!(
+        fst.as_contiguous().unwrap().get(b"a"),
+        Some(Output::new(500))
+    );
+    assert_eq!(fst.get(b"b"), Some(Output::new(7)));
+}
+
+#[test]
+fn ordinals_for() {
+    use crate::Map;
+
+    let map = Map::from(fst_map(vec![
+        ("a", 10),
+        ("ab", 20),
+        ("b", 30),
+        ("ba", 40),
+        ("c", 50),
+    ]));
+    assert_eq!(
+        map.ordinals_for(&["", "a", "aa", "b", "b", "c", "d"])
+            .unwrap(),
+        vec![None, Some(0), None, Some(2), Some(2), Some(4), None]
+    );
+    assert_eq!(map.ordinals_for(&["ba"]).unwrap(), vec![Some(3)]);
+    assert!(map.ordinals_for::<&str>(&[]).unwrap().is_empty());
+    assert!(map.ordinals_for(&["b", "a"]).is_err());
+
+    let mut words: Vec<&str> = TEXT.lines().collect();
+    words.sort();
+    words.dedup();
+    let mut queries: Vec<String> = words.iter().step_by(997).map(|w| w.to_string()).collect();
+    queries.extend(words.iter().step_by(1009).map(|w| format!("{}~", w)));
+    queries.sort();
+    let words: Vec<&str> = words.into_iter().step_by(7).collect();
+    for &counts in &[false, true] {
+        let mut builder = Builder::memory();
+        builder.with_counts(counts);
+        for w in &words {
+            builder.add(w).unwrap();
+        }
+        let fst = tokio_test::block_on(Fst::new(builder.into_inner().unwrap())).unwrap();
+        let expected: Vec<Option<u64>> = queries.iter().map(|q| fst.get_index(q)).collect();
+        assert_eq!(fst.ordinals_for(&queries).unwrap(), expected);
+    }
+}
+
+#[test]
+fn op_search() {
+    use crate::automaton::Subsequence;
+    use crate::map::IndexedValue;
+    use crate::Map;
+
+    let map1 = Map::from(fst_map(vec![("abc", 1), ("ac", 2), ("bac", 3), ("bc", 4)]));
+    let map2 = Map::from(fst_map(vec![
+        ("ac", 12),
+        ("acc", 13),
+        ("b", 14),
+        ("bc", 15),
+    ]));
+    let map3 = Map::from(fst_map(vec![("abc", 21), ("bcac", 22), ("c", 23)]));
+    let collect = |mut stream: crate::map::OpSearch<'_, Subsequence<'_>>| {
+        let mut got = vec![];
+        while let Some((k, vs)) = stream.next() {
+            got.push((String::from_utf8(k.to_vec()).unwrap(), vs.to_vec()));
+        }
+        got
+    };
+    let iv = |index, value| IndexedValue { index, value };
+
+    let aut = Subsequence::new("ac");
+    let union = map1.op().add(&map2).add(&map3).union().search(aut.clone());
+    assert_eq!(
+        collect(union),
+        vec![
+            ("abc".to_string(), vec![iv(0, 1), iv(2, 21)]),
+            ("ac".to_string(), vec![iv(0, 2), iv(1, 12)]),
+            ("acc".to_string(), vec![iv(1, 13)]),
+            ("bac".to_string(), vec![iv(0, 3)]),
+            ("bcac".to_string(), vec![iv(2, 22)]),
+        ]
+    );
+    let intersection = map1.op().add(&map2).intersection().search(aut.clone());
+    assert_eq!(
+        collect(intersection),
+        vec![("ac".to_string(), vec![iv(0, 2), iv(1, 12)])]
+    );
+    let difference = map1.op().add(&map2).difference().search(aut.clone());
+    assert_eq!(
+        collect(difference),
+        vec![
+            ("abc".to_string(), vec![iv(0, 1)]),
+            ("bac".to_string(), vec![iv(0, 3)]),
+        ]
+    );
+    let sym = map1.op().add(&map3).symmetric_difference().search(aut);
+    assert_eq!(
+        collect(sym),
+        vec![
+            ("ac".to_string(), vec![iv(0, 2)]),
+            ("bac".to_string(), vec![iv(0, 3)]),
+            ("bcac".to_string(), vec![iv(1, 22)]),
+        ]
+    );
+}
+
+#[test]
+fn set_api() {
+    use crate::automaton::Subsequence;
+    use crate::set::OpBuilder;
+    use crate::{Set, SetBuilder};
+
+    let mut builder = SetBuilder::memory();
+    builder.extend_iter(vec!["a", "b", "b", "c", "d"]).unwrap();
+    assert!(builder.insert("a").is_err());
+    let set1 = Set::from(tokio_test::block_on(Fst::new(builder.into_inner().unwrap())).unwrap());
+    let set2 = Set::from(fst_set(vec!["b", "d", "e"]));
+    let set3 = Set::from(fst_set(vec!["d"]));
+
+    assert_eq!(set1.len(), 4);
+    assert!(set1.contains("b") && !set1.contains("e"));
+    assert_eq!(set1.stream().into_strs().unwrap(), vec!["a", "b", "c", "d"]);
+    assert_eq!(
+        set1.range().gt("a").lt("d").into_stream().into_bytes(),
+        vec![b"b".to_vec(), b"c".to_vec()]
+    );
+    assert_eq!(
+        set1.search(Subsequence::new("c"))
+            .into_stream()
+            .into_bytes(),
+        vec![b"c".to_vec()]
+    );
+    assert_eq!(
+        set1.range().backward().into_stream().into_strs().unwrap(),
+        vec!["d", "c", "b", "a"]
+    );
+    assert_eq!(format!("{:?}", set2), "Set([b, d, e])");
+
+    let keys = |mut stream: crate::set::Union<'_>| {
+        let mut keys = vec![];
+        while let Some(k) = stream.next() {
+            keys.push(k.to_vec());
+        }
+        keys
+    };
+    assert_eq!(
+        keys(set1.op().add(&set2).union()),
+        vec![b"a", b"b", b"c", b"d", b"e"]
+    );
+    let mut inter = set1.op().add(&set2).intersection();
+    assert_eq!(inter.next().unwrap().to_vec(), b"b");
+    assert_eq!(inter.next().unwrap().to_vec(), b"d");
+    assert!(inter.next().is_none());
+    let mut diff = set1.op().add(&set2).difference();
+    assert_eq!(diff.next(), Some(&b"a"[..]));
+    assert_eq!(diff.next(), Some(&b"c"[..]));
+    assert_eq!(diff.next(), None);
+    let mut sym: crate::set::SymmetricDifference<'_> = vec![&set1, &set2, &set3]
+        .into_iter()
+        .collect::<OpBuilder<'_>>()
+        .symmetric_difference();
+    assert_eq!(sym.next(), Some(&b"a"[..]));
+    assert_eq!(sym.next(), Some(&b"c"[..]));
+    assert_eq!(sym.next(), Some(&b"d"[..]));
+    assert_eq!(sym.next(), Some(&b"e"[..]));
+    assert_eq!(sym.next(), None);
+
+    assert!(set3.is_subset(&set1) && !set2.is_subset(&set1));
+    assert!(set1.is_superset(&set3) && !set1.is_superset(&set2));
+    assert!(set3.is_disjoint(&Set::from(fst_set(vec!["a", "e"]))));
+    assert!(!set1.is_disjoint(&set2));
+
+    let mut builder = SetBuilder::memory();
+    builder.extend_stream(set1.range().ge("c")).unwrap();
+    let copy = Set::from(tokio_test::block_on(Fst::new(builder.into_inner().unwrap())).unwrap());
+    assert_eq!(copy.stream().into_strs().unwrap(), vec!["c", "d"]);
+}
+
+#[test]
+fn levenshtein_public() {
+    use crate::automaton::levenshtein::LevenshteinState;
+    use crate::automaton::{Automaton, Levenshtein};
+    use crate::Map;
+
+    let map = Map::from(fst_map(vec![
+        ("cat", 1),
+        ("cats", 2),
+        ("cut", 3),
+        ("dog", 4),
+        ("héllo", 5),
+        ("hello", 6),
+    ]));
+    let found = map
+        .search(Levenshtein::new("cat", 1))
+        .into_stream()
+        .into_str_vec()
+        .unwrap();
+    assert_eq!(
+        found,
+        vec![
+            ("cat".to_string(), 1),
+            ("cats".to_string(), 2),
+            ("cut".to_string(), 3),
+        ]
+    );
+    let found = map
+        .search(Levenshtein::new("hallo", 1))
+        .into_stream()
+        .into_str_keys()
+        .unwrap();
+    assert_eq!(found, vec!["hello", "héllo"]);
+    let prefixed = map
+        .search(Levenshtein::new("ca", 0).starts_with())
+        .into_stream()
+        .into_str_keys()
+        .unwrap();
+    assert_eq!(prefixed, vec!["cat", "cats"]);
+
+    let aut = Levenshtein::new("dog", 2);
+    let state: LevenshteinState = b"dig".iter().fold(aut.start(), |s, &b| aut.accept(&s, b));
+    assert_eq!(state.unwrap().edit_distance(), Some(1));
+}
+
+#[test]
+fn is_disjoint_range() {
+    use crate::Map;
+
+    let keys = vec!["", "ab", "abc", "abd", "b", "ba", "bzz", "c\u{ff}", "d"];
+    let map = Map::from(fst_map(keys.iter().map(|&k| (k, 1))));
+    let bounds = vec![
+        "", "a", "ab", "aba", "abc", "abcd", "abz", "b", "b\x00", "bb", "bzz", "bzzz", "c",
+        "c\u{ff}", "cz", "d", "d\x00", "e",
+    ];
+    for lo in &bounds {
+        for hi in &bounds {
+            let expected = !keys.iter().any(|k| k >= lo && k < hi);
+            assert_eq!(
+                map.is_disjoint_range(lo, hi),
+                expected,
+                "{:?}..{:?}",
+                lo,
+                hi
+            );
+        }
+    }
+    assert!(Map::from(fst_map(Vec::<(&str, u64)>::new())).is_disjoint_range("", "z"));
+}
+
+#[test]
+fn delta_layered_map() {
+    use crate::delta::{Change, DeltaBuilder, DeltaReader, LayeredMap};
+    use crate::Map;
+
+    let base = Map::from(fst_map(vec![("a", 1u64), ("b", 2), ("c", 3), ("e", 5)]));
+
+    let mut old = DeltaBuilder::memory();
+    old.insert("b", 20).unwrap();
+    old.remove("c").unwrap();
+    old.insert("d", 4).unwrap();
+    assert!(old.insert("d", 4).is_err());
+    assert!(old.insert("a", 1).is_err());
+    let old = old.into_inner().unwrap();
+
+    let mut new = DeltaBuilder::memory();
+    new.insert("c", 30).unwrap();
+    new.remove("d").unwrap();
+    new.remove("e").unwrap();
+    new.insert("f", 6).unwrap();
+    let new = new.into_inner().unwrap();
+
+    let old = DeltaReader::new(&old).unwrap();
+    let new = DeltaReader::new(&new).unwrap();
+    assert_eq!(old.len(), 3);
+    assert_eq!(old.get("c"), Some(Change::Remove));
+    assert_eq!(old.get("d"), Some(Change::Insert(4)));
+    assert_eq!(old.get("z"), None);
+
+    let layered = LayeredMap::new(&base).with_delta(old).with_delta(new);
+    assert_eq!(layered.get("a"), Some(1));
+    assert_eq!(layered.get("b"), Some(20));
+    assert_eq!(layered.get("c"), Some(30));
+    assert_eq!(layered.get("d"), None);
+    assert!(!layered.contains_key("e"));
+    assert_eq!(layered.get("f"), Some(6));
+
+    let mut got = vec![];
+    let mut stream = layered.stream();
+    while let Some((k, v)) = stream.next() {
+        got.push((k.to_vec(), v));
+    }
+    assert_eq!(
+        got,
+        vec![
+            (b"a".to_vec(), 1),
+            (b"b".to_vec(), 20),
+            (b"c".to_vec(), 30),
+            (b"f".to_vec(), 6),
+        ]
+    );
+
+    // A delta without its footer is rejected.
+    let mut partial = DeltaBuilder::memory();
+    partial.insert("x", 1).unwrap();
+    let partial = partial.into_inner().unwrap();
+    assert!(DeltaReader::new(&partial[..partial.len() - 1]).is_err());
+    assert!(DeltaReader::new(&[]).is_err());
+}
+
+#[test]
+fn snapshot_layer_swap() {
+    use crate::delta::{DeltaBuilder, DeltaReader, LayeredMap};
+    use crate::snapshot::Snapshots;
+    use crate::Map;
+    use std::sync::Arc;
+
+    let old = Arc::new(Map::from(fst_map(vec![("a", 1u64), ("b", 2)])));
+    let new = Arc::new(Map::from(fst_map(vec![("b", 20u64), ("c", 30)])));
+    let layers = Snapshots::new(vec![old.clone()]);
+
+    let before = layers.snapshot();
+    let multi = before.multi_map();
+    let mut stream = multi.stream();
+    assert_eq!(
+        stream.next().map(|(k, v)| (k.to_vec(), v)),
+        Some((b"a".to_vec(), 1))
+    );
+
+    assert_eq!(layers.install(vec![old, new]), 1);
+    assert_eq!(layers.epoch(), 1);
+
+    // The stream taken before the swap keeps reading the old layers.
+    assert_eq!(
+        stream.next().map(|(k, v)| (k.to_vec(), v)),
+        Some((b"b".to_vec(), 2))
+    );
+    assert!(stream.next().is_none());
+    assert_eq!(before.epoch(), 0);
+
+    let after = layers.snapshot();
+    assert_eq!(after.epoch(), 1);
+    assert_eq!(after.multi_map().get("b"), Some(20));
+    assert_eq!(after.multi_map().get("c"), Some(30));
+
+    // Snapshots can hold the base and deltas of a `LayeredMap` too.
+    let mut delta = DeltaBuilder::memory();
+    delta.remove("a").unwrap();
+    let delta = delta.into_inner().unwrap();
+    let base = Map::from(fst_map(vec![("a", 1u64)]));
+    let layers = Snapshots::new((base, vec![delta]));
+    let snap = layers.snapshot();
+    let mut layered = LayeredMap::new(&snap.0);
+    for delta in &snap.1 {
+        layered.push(DeltaReader::new(delta).unwrap());
+    }
+    assert_eq!(layered.get("a"), None);
+
+    // Concurrent installs each get their own epoch.
+    let layers = Snapshots::new(0u64);
+    let layers = &layers;
+    let mut epochs: Vec<u64> = std::thread::scope(|scope| {
+        let installs: Vec<_> = (1..=8)
+            .map(|i| scope.spawn(move || (0..100).map(|_| layers.install(i)).collect::<Vec<_>>()))
+            .collect();
+        installs.into_iter().flat_map(|h| h.join().unwrap()).collect()
+    });
+    epochs.sort_unstable();
+    assert_eq!(epochs, (1..=800).collect::<Vec<_>>());
+    assert_eq!(layers.snapshot().epoch(), 800);
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn map_from_path() {
+    use crate::Map;
+
+    let map = Map::from(fst_map(vec![("a", 1u64), ("b", 2)]));
+    let path = std::env::temp_dir().join(format!("fst-from-path-{}", std::process::id()));
+    std::fs::write(&path, map.as_fst().as_inner().to_vec()).unwrap();
+
+    // SAFETY: nothing else writes to the file while it is mapped.
+    let mapped = tokio_test::block_on(unsafe { Map::from_path(&path) }).unwrap();
+    assert_eq!(mapped.get("b"), Some(2));
+    assert!(mapped.as_fst().as_contiguous().is_some());
+    assert_eq!(mapped.stream().into_str_keys().unwrap(), vec!["a", "b"]);
+    drop(mapped);
+    std::fs::remove_file(&path).unwrap();
+
+    match tokio_test::block_on(unsafe { Map::from_path(&path) }) {
+        Err(crate::Error::IoContext(ctx)) => {
+            assert_eq!(ctx.operation(), "opening map");
+            assert_eq!(ctx.file_path(), Some(path.as_path()));
+        }
+        r => panic!("unexpected result: {:?}", r.map(|_| ())),
+    }
+}
+
+#[test]
+fn builder_reset() {
+    let mut bfst = Builder::new_dictionary_encoded(vec![]).unwrap();
+    bfst.insert("abc", 7).unwrap();
+    bfst.insert("abd", 9).unwrap();
+    let first = bfst.finish_and_reset(vec![]).unwrap();
+    assert_eq!(bfst.bytes_written(), 16);
+
+    // Keys are checked against the new fst only, and nodes compiled for the
+    // first fst are not reused.
+    bfst.insert("abc", 9).unwrap();
+    bfst.insert("b", 1).unwrap();
+    let discarded = bfst.reset(vec![]).unwrap();
+    assert!(Fst::from_slice(&discarded).is_err());
+
+    bfst.insert("abd", 9).unwrap();
+    bfst.insert("x", 1).unwrap();
+    let second = bfst.into_inner().unwrap();
+
+    let first = Fst::from_slice(&first).unwrap();
+    let second = Fst::from_slice(&second).unwrap();
+    assert_eq!(first.len(), 2);
+    assert_eq!(first.get("abc"), Some(Output::new(7)));
+    assert_eq!(first.get("abd"), Some(Output::new(9)));
+    assert_eq!(second.len(), 2);
+    assert_eq!(second.get("abc"), None);
+    assert_eq!(second.get("abd"), Some(Output::new(9)));
+    assert_eq!(second.get("x"), Some(Output::new(1)));
+    assert_eq!(second.distinct_values(), Some(&[0, 9, 1][..]));
+}
+
+#[test]
+fn get_index() {
+    use crate::{Map, Set};
+
+    let keys = vec!["", "a", "ab", "abc", "abd", "b", "ba", "bb", "c"];
+    let map = Map::from(fst_map(keys.iter().map(|&k| (k, k.len() as u64))));
+    for (i, key) in keys.iter().enumerate() {
+        assert_eq!(map.get_index(key), Some(i as u64), "key {:?}", key);
+    }
+    assert_eq!(map.get_index("abe"), None);
+    assert_eq!(map.get_index("z"), None);
+
+    let set = Set::from(fst_set(vec!["bar", "baz", "foo"]));
+    assert_eq!(set.get_index("foo"), Some(2));
+    assert_eq!(set.get_index("fo"), None);
+    assert_eq!(Set::from(fst_set(Vec::<&str>::new())).get_index(""), None);
+}
+
+#[test]
+fn inline_chains() {
+    use crate::automaton::Subsequence;
+    use crate::fake_arr::{FakeArr, Ulen};
+    use crate::Map;
+    use byteorder::{ByteOrder, LittleEndian};
+
+    #[derive(Debug)]
+    struct Scattered(Vec<u8>);
+
+    impl FakeArr for Scattered {
+        fn len(&self) -> Ulen {
+            self.0.len() as Ulen
+        }
+
+        fn read_into(&self, offset: Ulen, buf: &mut [u8]) -> std::io::Result<()> {
+            self.0.read_into(offset, buf)
+        }
+
+        fn as_dyn(&self) -> &dyn FakeArr {
+            self
+        }
+    }
+
+    let mut keys: Vec<(Vec<u8>, u64)> = vec![(vec![], 3)];
+    let mut seed = 7u64;
+    for i in 0..500u64 {
+        seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+        let mut key = format!("https://Host{}.example/{}/", i % 13, i % 41).into_bytes();
+        for j in 0..(seed >> 58) + (i % 300) {
+            key.push((seed >> (j % 50)) as u8 | 0x80);
+        }
+        keys.push((key, seed >> 40));
+    }
+    keys.sort();
+    keys.dedup_by(|a, b| a.0 == b.0);
+
+    let build = |chains: bool| {
+        let mut bld = if chains {
+            Builder::new_with_chains(vec![]).unwrap()
+        } else {
+            Builder::memory()
+        };
+        for (k, v) in &keys {
+            bld.insert(k, *v).unwrap();
+        }
+        bld.into_inner().unwrap()
+    };
+    let plain = build(false);
+    let chained = build(true);
+    assert!(
+        chained.len() < plain.len(),
+        "{} >= {}",
+        chained.len(),
+        plain.len()
+    );
+    assert_eq!(LittleEndian::read_u64(&plain[..8]), 3);
+    assert_eq!(LittleEndian::read_u64(&chained[..8]), VERSION);
+
+    let plain = Map::from(Fst::from_slice(&plain).unwrap());
+    let fst = Fst::from_slice(&chained).unwrap();
+    let scattered = tokio_test::block_on(Fst::new(Scattered(chained.clone()))).unwrap();
+    for (k, v) in &keys {
+        assert_eq!(fst.get(k), Some(Output::new(*v)));
+        assert_eq!(scattered.get(k), Some(Output::new(*v)));
+        for probe in [&k[..k.len() / 2], &[&k[..], b"!"].concat()[..]] {
+            assert_eq!(fst.get(probe), plain.get(probe).map(Output::new));
+            assert_eq!(scattered.get(probe), plain.get(probe).map(Output::new));
+        }
+    }
+    let map = Map::from(fst);
+    assert_eq!(
+        map.stream().into_byte_keys(),
+        plain.stream().into_byte_keys()
+    );
+    assert_eq!(map.stream().into_values(), plain.stream().into_values());
+
+    let (lo, hi) = (&keys[100].0, &keys[200].0);
+    let lo = &lo[..lo.len() - 3];
+    let range = |m: &Map<_>| m.range().ge(lo).lt(hi).into_stream().into_byte_keys();
+    assert_eq!(range(&map), range(&plain));
+    let search = |m: &Map<_>| {
+        m.search(Subsequence::new("Host7.example/1"))
+            .into_stream()
+            .into_values()
+    };
+    assert_eq!(search(&map), search(&plain));
+    assert_eq!(map.get_index(&keys[250].0), Some(250));
+
+    // Recovery finds the nodes of a truncated fst with chains too.
+    let cut = &chained[..chained.len() / 2];
+    let recovered = Map::open_truncated(cut).unwrap();
+    for i in 0..recovered.len() {
+        for key in recovered.stream(i).into_byte_keys() {
+            assert!(keys.iter().any(|(k, _)| k.ends_with(&key)));
+        }
+    }
+}
+
+#[test]
+fn get_key() {
+    use crate::{Map, Set};
+
+    let keys = vec!["", "a", "ab", "abc", "abd", "b", "ba", "bb", "c"];
+    let map = Map::from(fst_map(keys.iter().map(|&k| (k, k.len() as u64 * 10))));
+    for (i, key) in keys.iter().enumerate() {
+        let got = map.get_key(i as u64);
+        assert_eq!(got, Some((key.as_bytes().to_vec(), key.len() as u64 * 10)));
+        assert_eq!(map.get_index(got.unwrap().0), Some(i as u64));
+    }
+    assert_eq!(map.get_key(keys.len() as u64), None);
+
+    let set = Set::from(fst_set(vec!["bar", "baz", "foo"]));
+    assert_eq!(set.get_key(1), Some(b"baz".to_vec()));
+    assert_eq!(set.get_key(3), None);
+    assert_eq!(Set::from(fst_set(Vec::<&str>::new())).get_key(0), None);
+}
+
+#[test]
+fn regex_byte_classes() {
+    use crate::RegexBuilder;
+
+    let fst = fst_set(vec!["0", "12", "1a", "9", "a", "é"]);
+    for &max in &[0, 256] {
+        let re = RegexBuilder::new(r"[0-9]+")
+            .sparse_max_ranges(max)
+            .build()
+            .unwrap();
+        // Bytes below, inside and above `0-9`.
+        assert!(format!("{:?}", re).contains(", 3 byte classes"));
+        let keys = fst.search(&re).into_stream().into_byte_keys();
+        assert_eq!(keys, vec![b"0".to_vec(), b"12".to_vec(), b"9".to_vec()]);
+    }
+}
+
+#[test]
+fn stream_builder_count() {
+    use crate::automaton::{Automaton, Subsequence};
+    use crate::Map;
+
+    let keys: Vec<String> = (0..2000).map(|i| format!("{:05}", i * 7)).collect();
+    let map = Map::from(fst_map(keys.iter().map(|k| (k.as_str(), 1))));
+    fn streamed<A: Automaton>(sb: crate::map::StreamBuilder<A>) -> u64 {
+        sb.into_stream().into_byte_keys().len() as u64
+    }
+
+    assert_eq!(map.range().count(), 2000);
+    for &(lo, hi) in &[
+        ("", "0"),
+        ("00100", "05000"),
+        ("00700", "00707"),
+        ("5", "9"),
+    ] {
+        assert_eq!(
+            map.range().ge(lo).lt(hi).count(),
+            streamed(map.range().ge(lo).lt(hi))
+        );
+        assert_eq!(
+            map.range().gt(lo).le(hi).count(),
+            streamed(map.range().gt(lo).le(hi))
+        );
+    }
+    assert_eq!(map.range().ge("00700").le("00700").count(), 1);
+    assert_eq!(map.range().gt("00700").lt("00707").count(), 0);
+
+    let aut = Subsequence::new("77");
+    assert_eq!(
+        map.search(&aut).ge("01").count(),
+        streamed(map.search(&aut).ge("01"))
+    );
+    let aut = Subsequence::new("1").starts_with();
+    assert_eq!(
+        map.search(&aut).lt("09").count(),
+        streamed(map.search(&aut).lt("09"))
+    );
+
+    let set = crate::Set::from(fst_set(vec!["", "a", "ab", "b"]));
+    assert_eq!(set.range().count(), 4);
+    assert_eq!(set.range().gt("").lt("b").count(), 2);
+}
+
+#[test]
+fn automata_are_send_sync() {
+    use crate::automaton::{AlwaysMatch, Bounds, Levenshtein, Limited, Subsequence, TableDfa};
+    use crate::automaton::{Complement, Highlight, Intersection, StartsWith, Union};
+    use crate::{Regex, Set};
+    use std::sync::Arc;
+    use std::thread;
+
+    fn assert_send_sync<T: Send + Sync + 'static>() {}
+    assert_send_sync::<Regex>();
+    assert_send_sync::<Levenshtein>();
+    assert_send_sync::<Subsequence<'static>>();
+    assert_send_sync::<AlwaysMatch>();
+    assert_send_sync::<Bounds>();
+    assert_send_sync::<TableDfa>();
+    assert_send_sync::<StartsWith<Regex>>();
+    assert_send_sync::<Union<Regex, Levenshtein>>();
+    assert_send_sync::<Intersection<Regex, Subsequence<'static>>>();
+    assert_send_sync::<Complement<Regex>>();
+    assert_send_sync::<Limited<Regex>>();
+    assert_send_sync::<Highlight<Regex>>();
+
+    let set = Arc::new(Set::from(fst_set(vec!["abc", "abd", "bcd", "xyz"])));
+    let re = Arc::new(Regex::new("[ab].*").unwrap());
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let (set, re) = (set.clone(), re.clone());
+            thread::spawn(move || set.search(&*re).into_stream().into_strs().unwrap())
+        })
+        .collect();
+    for handle in handles {
+        assert_eq!(handle.join().unwrap(), vec!["abc", "abd", "bcd"]);
+    }
+}
+
+#[test]
+fn builder_with_counts() {
+    use crate::{Map, MapBuilder};
+
+    let keys: Vec<String> = (0..500)
+        .map(|i| format!("{}/{:03}", ["ab", "abc", "b", "bcd"][i % 4], i))
+        .collect();
+    let mut keys = keys;
+    keys.sort();
+    let build = |counts: bool, chains: bool| {
+        let mut builder = if chains {
+            MapBuilder::new_with_chains(vec![]).unwrap()
+        } else {
+            MapBuilder::memory()
+        };
+        builder.with_counts(counts);
+        for (i, key) in keys.iter().enumerate() {
+            builder.insert(key, i as u64).unwrap();
+        }
+        Map::from(tokio_test::block_on(Fst::new(builder.into_inner().unwrap())).unwrap())
+    };
+
+    let plain = build(false, false);
+    assert!(!plain.as_fst().has_subtree_counts());
+    for &chains in &[false, true] {
+        let map = build(true, chains);
+        assert!(map.as_fst().has_subtree_counts());
+        assert!(map.as_fst().size() > plain.as_fst().size() || chains);
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(map.get_index(key), Some(i as u64));
+            assert_eq!(
+                map.get_key(i as u64),
+                Some((key.as_bytes().to_vec(), i as u64))
+            );
+        }
+        assert_eq!(map.get_key(keys.len() as u64), None);
+        for &(lo, hi) in &[("", "z"), ("ab/", "abc/"), ("abc/1", "b/3"), ("b", "b/")] {
+            assert_eq!(
+                map.range().ge(lo).lt(hi).count(),
+                plain.range().ge(lo).lt(hi).count()
+            );
+        }
+    }
+
+    // Counts are only stored for nodes with many keys below them, so they
+    // take at most 16 bytes per 32 keys.
+    let mut words: Vec<&str> = TEXT.lines().collect();
+    words.sort();
+    words.dedup();
+    let build = |counts: bool| {
+        let mut builder = Builder::memory();
+        builder.with_counts(counts);
+        for (i, w) in words.iter().enumerate() {
+            builder.insert(w, i as u64).unwrap();
+        }
+        tokio_test::block_on(Fst::new(builder.into_inner().unwrap())).unwrap()
+    };
+    let (plain, fst) = (build(false), build(true));
+    assert!(fst.size() > plain.size() + 16);
+    assert!(fst.size() <= plain.size() + 16 + words.len() as u64 / 2);
+    for (i, w) in words.iter().enumerate().step_by(997) {
+        assert_eq!(fst.get_index(w), Some(i as u64));
+        assert_eq!(fst.get_key(i as u64).unwrap().0, w.as_bytes());
+    }
+    for &(lo, hi) in &[("", "zzz"), ("a", "b"), ("ca", "cat"), ("m", "")] {
+        let count = |fst: &Fst| fst.range().ge(lo).lt(hi).count();
+        assert_eq!(count(&fst), count(&plain));
+    }
+
+    let mut builder = Builder::memory();
+    builder.with_counts(true);
+    builder.insert("a", 1).unwrap();
+    builder.finish_and_reset(vec![]).unwrap();
+    builder.insert("b", 2).unwrap();
+    let fst = tokio_test::block_on(Fst::new(builder.into_inner().unwrap())).unwrap();
+    assert!(fst.has_subtree_counts());
+    assert_eq!(fst.get_index("b"), Some(0));
+}
+
+#[test]
+fn search_results_collect() {
+    use crate::automaton::Subsequence;
+    use crate::results::SearchResults;
+    use crate::Map;
+
+    let map = Map::from(fst_map(vec![("abc", 1), ("axc", 2), ("bc", 3), ("xac", 4)]));
+    let aut = Subsequence::new("ac");
+
+    let all = SearchResults::collect(map.search(&aut).with_state(), 10);
+    assert!(!all.truncated);
+    assert_eq!(all.len(), 3);
+    assert_eq!(all.hits[1].key_str(), Some("axc"));
+    assert_eq!(all.hits[1].value, 2);
+    assert_eq!(all.hits[1].state, 2);
+
+    let page = SearchResults::collect(map.search(&aut).with_state(), 2);
+    assert!(page.truncated);
+    let keys: Vec<_> = page.hits.iter().map(|hit| hit.key_str().unwrap()).collect();
+    assert_eq!(keys, vec!["abc", "axc"]);
+    let page = page.map_states(|_| ());
+    assert_eq!(page.hits[0].state, ());
+
+    let exact = SearchResults::collect(map.search(&aut).with_state(), 3);
+    assert!(!exact.truncated);
+    assert!(SearchResults::collect(map.search(&aut).with_state(), 0).truncated);
+}
+
+#[test]
+fn weighted_edges() {
+    let fst = fst_map(vec![("a", 5), ("ab", 7), ("b", 3)]);
+    let edges = fst.to_weighted_edges();
+    assert_eq!(edges.len(), 3);
+    for &(from, inp, to, out) in &edges {
+        let node = fst.node(from);
+        let i = node.find_input(inp).unwrap();
+        assert_eq!(node.transition_addr(i), to);
+        assert_eq!(node.transition_output(i), out);
+    }
+
+    // Following the edges along a key sums to its value.
+    let root = fst.root();
+    let a = root.find_input(b'a').unwrap();
+    let a_node = fst.node(root.transition_addr(a));
+    let b = a_node.find_input(b'b').unwrap();
+    let total = root.transition_output(a).cat(a_node.transition_output(b));
+    assert_eq!(
+        total
+            .cat(fst.node(a_node.transition_addr(b)).final_output())
+            .value(),
+        7
+    );
+
+    assert!(fst_set(Vec::<&str>::new()).to_weighted_edges().is_empty());
+}
+
+#[test]
+fn contains_prefix() {
+    use crate::{Map, Set};
+
+    let map = Map::from(fst_map(vec![("abc", 1), ("abd", 2), ("b", 3)]));
+    assert!(map.contains_prefix(""));
+    assert!(map.contains_prefix("a"));
+    assert!(map.contains_prefix("ab"));
+    assert!(map.contains_prefix("abd"));
+    assert!(map.contains_prefix("b"));
+    assert!(!map.contains_prefix("abe"));
+    assert!(!map.contains_prefix("abcd"));
+    assert!(!map.contains_prefix("c"));
+
+    let empty = Set::from(fst_set(Vec::<&str>::new()));
+    assert!(!empty.contains_prefix(""));
+    let only_empty = Set::from(fst_set(vec![""]));
+    assert!(only_empty.contains_prefix(""));
+    assert!(!only_empty.contains_prefix("a"));
+}
+
+#[test]
+fn check_weight_pushing() {
+    use crate::raw::build::BuilderNode;
+    use crate::raw::{footer, CompiledAddr, PushingViolation, Transition, NONE_ADDRESS};
+    use byteorder::{LittleEndian, WriteBytesExt};
+
+    let fst = fst_map(vec![("a", 5), ("ab", 7), ("abc", 3), ("b", 0), ("ba", 9)]);
+    assert!(fst.check_weight_pushing().is_empty());
+    let keys: Vec<String> = (0..300).map(|i| format!("{:04}", i * 13)).collect();
+    let fst = fst_map(
+        keys.iter()
+            .enumerate()
+            .map(|(i, k)| (k.as_str(), (i * 7919 % 1000) as u64)),
+    );
+    assert!(fst.check_weight_pushing().is_empty());
+
+    // The root leads to a final node with an output that belongs on the
+    // transition to it.
+    let mut bytes = vec![];
+    bytes.write_u64::<LittleEndian>(3).unwrap();
+    bytes.write_u64::<LittleEndian>(0).unwrap();
+    let leaf = BuilderNode {
+        is_final: true,
+        final_output: Output::new(5),
+        trans: vec![],
+    };
+    leaf.compile_to(&mut bytes, NONE_ADDRESS, 16).unwrap();
+    let leaf_addr = bytes.len() as CompiledAddr - 1;
+    let root = BuilderNode {
+        is_final: false,
+        final_output: Output::zero(),
+        trans: vec![Transition {
+            inp: b'a',
+            out: Output::new(2),
+            addr: leaf_addr,
+        }],
+    };
+    root.compile_to(&mut bytes, leaf_addr, leaf_addr + 1)
+        .unwrap();
+    let root_addr = bytes.len() as CompiledAddr - 1;
+    bytes.extend_from_slice(&footer::write_footer(&[], 1, root_addr));
+    let fst = Fst::from_slice(&bytes).unwrap();
+    assert_eq!(fst.get("a"), Some(Output::new(7)));
+    assert_eq!(
+        fst.check_weight_pushing(),
+        vec![PushingViolation {
+            addr: leaf_addr,
+            residual: Output::new(5),
+        }]
+    );
+}
+
+#[test]
+fn get_floor_and_ceiling() {
+    use crate::{Map, Set};
+
+    let keys = ["", "abc", "abd", "abdz", "b", "ba", "bz", "c"];
+    let map = Map::from(fst_map(
+        keys.iter().enumerate().map(|(i, &k)| (k, i as u64 * 3)),
+    ));
+    let queries = [
+        "", "\x00", "a", "abc", "abca", "abcz", "abd", "abe", "az", "b", "b\x00", "bb", "bz",
+        "bza", "c", "ca", "d",
+    ];
+    for q in queries {
+        let floor = keys.iter().enumerate().rev().find(|&(_, &k)| k <= q);
+        let floor = floor.map(|(i, k)| (k.as_bytes().to_vec(), i as u64 * 3));
+        assert_eq!(map.get_floor(q), floor, "floor of {:?}", q);
+        let ceiling = keys.iter().enumerate().find(|&(_, &k)| k >= q);
+        let ceiling = ceiling.map(|(i, k)| (k.as_bytes().to_vec(), i as u64 * 3));
+        assert_eq!(map.get_ceiling(q), ceiling, "ceiling of {:?}", q);
+    }
+
+    let set = Set::from(fst_set(vec!["m", "n"]));
+    assert_eq!(set.get_floor("a"), None);
+    assert_eq!(set.get_floor("mm"), Some(b"m".to_vec()));
+    assert_eq!(set.get_ceiling("mm"), Some(b"n".to_vec()));
+    assert_eq!(set.get_ceiling("o"), None);
+    assert_eq!(Set::from(fst_set(Vec::<&str>::new())).get_floor("a"), None);
+}
+
+#[test]
+fn stream_adapters() {
+    use crate::stream::{filter, map_items, take_while};
+    use crate::Map;
+
+    let map = Map::from(fst_map(vec![
+        ("a", 1),
+        ("b", 2),
+        ("c", 3),
+        ("d", 4),
+        ("e", 5),
+    ]));
+
+    let mut stream = filter(map.stream(), |&(_, v): &(FakeArrRef<'_>, u64)| v % 2 == 1);
+    let mut keys = vec![];
+    while let Some((k, _)) = stream.next() {
+        keys.push(k.to_vec());
+    }
+    assert_eq!(keys, vec![b"a".to_vec(), b"c".to_vec(), b"e".to_vec()]);
+
+    let stream = take_while(map.stream(), |&(_, v): &(FakeArrRef<'_>, u64)| v < 4);
+    let mut stream = map_items(stream, |(k, v): (FakeArrRef<'_>, u64)| (k.to_vec(), v * 10));
+    let mut items = vec![];
+    while let Some(item) = stream.next() {
+        items.push(item);
+    }
+    assert_eq!(
+        items,
+        vec![
+            (b"a".to_vec(), 10),
+            (b"b".to_vec(), 20),
+            (b"c".to_vec(), 30)
+        ]
+    );
+    assert_eq!(stream.next(), None);
+}
+
+#[test]
+fn min_and_max_key() {
+    use crate::{Map, Set};
+
+    let map = Map::from(fst_map(vec![("abc", 1), ("abd", 2), ("b", 3), ("bzz", 4)]));
+    assert_eq!(map.min_key(), Some((b"abc".to_vec(), 1)));
+    assert_eq!(map.max_key(), Some((b"bzz".to_vec(), 4)));
+
+    let set = Set::from(fst_set(vec!["", "x"]));
+    assert_eq!(set.min_key(), Some(vec![]));
+    assert_eq!(set.max_key(), Some(b"x".to_vec()));
+    let set = Set::from(fst_set(vec![""]));
+    assert_eq!(set.min_key(), Some(vec![]));
+    assert_eq!(set.max_key(), Some(vec![]));
+    let set = Set::from(fst_set(Vec::<&str>::new()));
+    assert_eq!(set.min_key(), None);
+    assert_eq!(set.max_key(), None);
+}
+
+#[test]
+fn merge_join() {
+    use crate::stream::{merge_join, JoinType};
+    use crate::Map;
+
+    let map = Map::from(fst_map(vec![("a", 1), ("c", 3), ("d", 4)]));
+    let ext = [("b", 'b'), ("c", 'c'), ("e", 'e')];
+    let join = |join| {
+        let mut got = vec![];
+        merge_join(map.stream(), ext.iter().cloned(), join, |k, v, e| {
+            got.push((k.to_vec(), v, e))
+        })
+        .unwrap();
+        got
+    };
+    assert_eq!(
+        join(JoinType::Inner),
+        vec![(b"c".to_vec(), Some(3), Some('c'))]
+    );
+    assert_eq!(
+        join(JoinType::Left),
+        vec![
+            (b"a".to_vec(), Some(1), None),
+            (b"c".to_vec(), Some(3), Some('c')),
+            (b"d".to_vec(), Some(4), None),
+        ]
+    );
+    assert_eq!(
+        join(JoinType::Outer),
+        vec![
+            (b"a".to_vec(), Some(1), None),
+            (b"b".to_vec(), None, Some('b')),
+            (b"c".to_vec(), Some(3), Some('c')),
+            (b"d".to_vec(), Some(4), None),
+            (b"e".to_vec(), None, Some('e')),
+        ]
+    );
+
+    let mut seen = 0;
+    let err = merge_join(
+        map.stream(),
+        vec![("b", ()), ("a", ())],
+        JoinType::Outer,
+        |_, _, _| seen += 1,
+    );
+    assert!(err.is_err());
+    assert_eq!(seen, 2);
+    let err = merge_join(
+        map.stream(),
+        vec![("c", ()), ("c", ())],
+        JoinType::Inner,
+        |_, _, _| {},
+    );
+    assert!(err.is_err());
+}
+
+#[test]
+fn partition_points() {
+    use crate::{IntoStreamer, Map, Set};
+
+    let keys: Vec<String> = (0..100).map(|i| format!("{:03}", i)).collect();
+    let items: Vec<(&str, u64)> = keys.iter().map(|k| (&**k, 0)).collect();
+    let map = Map::from(fst_map(items));
+    let points = map.partition_points(4);
+    assert_eq!(
+        points,
+        vec![b"025".to_vec(), b"050".to_vec(), b"075".to_vec()]
+    );
+    let mut stream = map.range().ge(&points[0]).lt(&points[1]).into_stream();
+    let mut n = 0;
+    while stream.next().is_some() {
+        n += 1;
+    }
+    assert_eq!(n, 25);
+    assert_eq!(
+        map.partition_points(3),
+        vec![b"033".to_vec(), b"066".to_vec()]
+    );
+    assert!(map.partition_points(1).is_empty());
+    assert!(map.partition_points(0).is_empty());
+
+    let set = Set::from(fst_set(vec!["a", "b", "c"]));
+    assert_eq!(set.partition_points(2), vec![b"b".to_vec()]);
+    assert_eq!(set.partition_points(3), vec![b"b".to_vec(), b"c".to_vec()]);
+    assert_eq!(set.partition_points(8), vec![b"b".to_vec(), b"c".to_vec()]);
+    let set = Set::from(fst_set(Vec::<&str>::new()));
+    assert!(set.partition_points(4).is_empty());
+}
+
+#[test]
+fn stream_prefix() {
+    use crate::{Map, Set};
+
+    let map = Map::from(fst_map(vec![
+        ("a", 1),
+        ("ab", 2),
+        ("abc", 3),
+        ("abd", 4),
+        ("b", 5),
+    ]));
+    let mut stream = map.stream_prefix("ab");
+    let mut kvs = vec![];
+    while let Some((k, v)) = stream.next() {
+        kvs.push((k.to_vec(), v));
+    }
+    assert_eq!(
+        kvs,
+        vec![
+            (b"ab".to_vec(), 2),
+            (b"abc".to_vec(), 3),
+            (b"abd".to_vec(), 4)
+        ]
+    );
+    assert_eq!(map.stream_prefix("").into_byte_keys().len(), 5);
+    assert!(map.stream_prefix("c").into_byte_keys().is_empty());
+
+    let set = Set::from(fst_set(vec![&b"\xFF"[..], b"\xFF\xFF", b"\xFF\xFFa"]));
+    assert_eq!(
+        set.stream_prefix(b"\xFF\xFF").into_bytes(),
+        vec![b"\xFF\xFF".to_vec(), b"\xFF\xFFa".to_vec()]
+    );
+}
+
+#[test]
+fn range_bounds() {
+    use crate::{IntoStreamer, Map, Set};
+    use std::ops::Bound;
+
+    let map = Map::from(fst_map(vec![("a", 1), ("b", 2), ("m", 3), ("z", 4)]));
+    let keys = |b: crate::map::StreamBuilder<'_>| b.into_stream().into_byte_keys();
+    assert_eq!(
+        keys(map.range_bounds("a".."m")),
+        vec![b"a".to_vec(), b"b".to_vec()]
+    );
+    assert_eq!(
+        keys(map.range_bounds(b"b"..=b"m")),
+        vec![b"b".to_vec(), b"m".to_vec()]
+    );
+    assert_eq!(
+        keys(map.range_bounds("m"..)),
+        vec![b"m".to_vec(), b"z".to_vec()]
+    );
+    assert_eq!(keys(map.range_bounds(.."b")), vec![b"a".to_vec()]);
+    assert_eq!(keys(map.range_bounds(..)).len(), 4);
+    let excluded = (Bound::Excluded("a"), Bound::Excluded("z"));
+    assert_eq!(
+        keys(map.range_bounds(excluded)),
+        vec![b"b".to_vec(), b"m".to_vec()]
+    );
+    assert_eq!(
+        keys(map.range().ge("z").range_bounds("b".."c")),
+        vec![b"b".to_vec()]
+    );
+
+    let set = Set::from(fst_set(vec!["a", "b", "c"]));
+    assert_eq!(
+        set.range_bounds("b"..).into_stream().into_bytes(),
+        vec![b"b".to_vec(), b"c".to_vec()]
+    );
+}
+
+#[test]
+fn corpus_generate() {
+    use crate::corpus::generate;
+    use std::collections::HashSet;
+
+    let corpus = generate(7);
+    assert_eq!(corpus, generate(7));
+    assert_ne!(corpus, generate(8));
+    let names: HashSet<_> = corpus.iter().map(|e| e.name).collect();
+    assert_eq!(names.len(), corpus.len());
+    for entry in &corpus {
+        let fst = tokio_test::block_on(Fst::new(entry.bytes.clone())).unwrap();
+        let mut got = vec![];
+        let mut stream = fst.stream();
+        while let Some((k, v)) = stream.next() {
+            got.push((k.to_vec(), v.value()));
+        }
+        assert_eq!(got, entry.keys, "{}", entry.name);
+    }
+
+    // The last key of the far address entries shares the node after its
+    // first byte with the first key, which was compiled long before.
+    for &(name, min) in &[("far_address_2", 1 << 8), ("far_address_3", 1 << 16)] {
+        let entry = corpus.iter().find(|e| e.name == name).unwrap();
+        let fst = tokio_test::block_on(Fst::new(entry.bytes.clone())).unwrap();
+        let next = |b| {
+            let root = fst.root();
+            root.transition(root.find_input(b).unwrap())
+        };
+        let (first, last) = (next(b'a'), next(b'c'));
+        assert_eq!(first.addr, last.addr);
+        assert!(last.addr + min < fst.root().addr(), "{}", name);
+    }
+}
+
+#[test]
+fn stream_next_back() {
+    use crate::automaton::Subsequence;
+    use crate::{IntoStreamer, Map, Set};
+
+    let map = Map::from(fst_map(vec![
+        ("", 0),
+        ("a", 1),
+        ("ab", 2),
+        ("b", 3),
+        ("c", 4),
+    ]));
+    let mut stream = map.stream();
+    assert_eq!(
+        stream.next_back().map(|(k, v)| (k.to_vec(), v)),
+        Some((b"c".to_vec(), 4))
+    );
+    assert_eq!(
+        stream.next().map(|(k, v)| (k.to_vec(), v)),
+        Some((vec![], 0))
+    );
+    assert_eq!(
+        stream.next_back().map(|(k, v)| (k.to_vec(), v)),
+        Some((b"b".to_vec(), 3))
+    );
+    assert_eq!(
+        stream.next().map(|(k, v)| (k.to_vec(), v)),
+        Some((b"a".to_vec(), 1))
+    );
+    assert_eq!(
+        stream.next_back().map(|(k, v)| (k.to_vec(), v)),
+        Some((b"ab".to_vec(), 2))
+    );
+    assert_eq!(stream.next_back().map(|(k, v)| (k.to_vec(), v)), None);
+    assert_eq!(stream.next().map(|(k, v)| (k.to_vec(), v)), None);
+
+    // Every way of interleaving the two ends yields every key once.
+    let set = Set::from(fst_set(vec!["", "a", "aa", "ab", "b", "ba", "c"]));
+    let all = set.stream().into_bytes();
+    for pattern in 0..1u32 << all.len() {
+        for backward in [false, true] {
+            let range = || {
+                let range = set.range().gt("a");
+                if backward {
+                    range.backward()
+                } else {
+                    range
+                }
+            };
+            let expected = range().into_stream().into_bytes();
+            let mut stream = range().into_stream();
+            let (mut front, mut back) = (vec![], vec![]);
+            for i in 0..all.len() + 1 {
+                let key = if pattern & (1 << i) == 0 {
+                    stream.next().map(|k| front.push(k.to_vec()))
+                } else {
+                    stream.next_back().map(|k| back.push(k.to_vec()))
+                };
+                if key.is_none() {
+                    break;
+                }
+            }
+            assert!(stream.next().is_none());
+            assert!(stream.next_back().is_none());
+            back.reverse();
+            front.extend(back);
+            assert_eq!(front, expected);
+        }
+    }
+
+    let mut stream = set.search(Subsequence::new("b")).into_stream();
+    assert_eq!(stream.next_back().map(|k| k.to_vec()), Some(b"ba".to_vec()));
+    assert_eq!(stream.next_back().map(|k| k.to_vec()), Some(b"b".to_vec()));
+    assert_eq!(stream.next().map(|k| k.to_vec()), Some(b"ab".to_vec()));
+    assert!(stream.next().is_none());
+}
+
+#[test]
+fn builder_checkpoint_rollback() {
+    use crate::{Map, MapBuilder};
+
+    let build = |bad: bool| {
+        let mut b = MapBuilder::new_dictionary_encoded(vec![]).unwrap();
+        b.with_counts(true);
+        b.insert("apple", 1).unwrap();
+        b.insert("banana", 2).unwrap();
+        let checkpoint = b.checkpoint();
+        if bad {
+            b.insert("bandana", 7).unwrap();
+            b.insert("cherry", 8).unwrap();
+            b.insert("date", 9).unwrap();
+            b.rollback(checkpoint).unwrap();
+        }
+        b.insert("banner", 3).unwrap();
+        b.insert("cherry", 1).unwrap();
+        b.into_inner().unwrap()
+    };
+    let bytes = build(true);
+    assert_eq!(bytes, build(false));
+    let map = Map::from(tokio_test::block_on(Fst::new(bytes)).unwrap());
+    assert_eq!(
+        map.stream().into_str_vec().unwrap(),
+        vec![
+            ("apple".to_string(), 1),
+            ("banana".to_string(), 2),
+            ("banner".to_string(), 3),
+            ("cherry".to_string(), 1),
+        ]
+    );
+    assert_eq!(map.as_fst().distinct_values(), Some(&[0, 1, 2, 3][..]));
+    assert_eq!(map.get_index("cherry"), Some(3));
+
+    // Rolling back from a diagnostic builder restores its report, and
+    // buffered writers are flushed before they are truncated.
+    let mut b = MapBuilder::new(std::io::BufWriter::new(vec![])).unwrap();
+    b.record_invalid_keys(10);
+    b.insert("b", 1).unwrap();
+    let checkpoint = b.checkpoint();
+    b.insert("a", 2).unwrap();
+    b.insert("c", 3).unwrap();
+    assert_eq!(b.key_report().unwrap().total(), 1);
+    b.rollback(checkpoint).unwrap();
+    assert!(b.key_report().unwrap().is_empty());
+    b.insert("d", 4).unwrap();
+    let bytes = b.into_inner().unwrap().into_inner().unwrap();
+    let map = Map::from(tokio_test::block_on(Fst::new(bytes)).unwrap());
+    assert_eq!(
+        map.stream().into_byte_keys(),
+        vec![b"b".to_vec(), b"d".to_vec()]
+    );
+}
+
+#[test]
+#[should_panic(expected = "checkpoint does not belong")]
+fn builder_rollback_stale_checkpoint() {
+    let mut b = Builder::memory();
+    b.insert("a", 1).unwrap();
+    let early = b.checkpoint();
+    b.insert("b", 2).unwrap();
+    b.insert("c", 3).unwrap();
+    let late = b.checkpoint();
+    b.rollback(early).unwrap();
+    let _ = b.rollback(late);
+}
+
+#[test]
+fn builder_rollback_nested_checkpoints() {
+    let mut b = Builder::memory();
+    b.insert("a", 1).unwrap();
+    let early = b.checkpoint();
+    b.insert("b", 2).unwrap();
+    let middle = b.checkpoint();
+    b.insert("c", 3).unwrap();
+    b.rollback(middle).unwrap();
+    let late = b.checkpoint();
+    b.insert("d", 4).unwrap();
+    b.rollback(late).unwrap();
+    b.rollback(early).unwrap();
+    b.insert("e", 5).unwrap();
+    let fst = tokio_test::block_on(Fst::new(b.into_inner().unwrap())).unwrap();
+    assert_eq!(
+        fst.stream().into_byte_keys(),
+        vec![b"a".to_vec(), b"e".to_vec()]
+    );
+}
+
+#[test]
+fn builder_rollback_drained_writer() {
+    use crate::raw::Truncate;
+
+    let mut wtr = vec![1, 2, 3];
+    assert!(wtr.truncate_end(4).is_err());
+    assert_eq!(wtr, vec![1, 2, 3]);
+    wtr.truncate_end(3).unwrap();
+    assert!(wtr.is_empty());
+
+    let mut b = Builder::memory();
+    let checkpoint = b.checkpoint();
+    for k in &["a", "ab", "b", "bc", "c"] {
+        b.add(k).unwrap();
+    }
+    b.get_mut().clear();
+    assert!(b.rollback(checkpoint).is_err());
+}
+
+#[test]
+fn heap_usage() {
+    use crate::automaton::{Levenshtein, TableDfa};
+    use crate::{IntoStreamer, Map, MapBuilder};
+
+    let keys: Vec<String> = (0..100).map(|i| format!("key{:03}", i)).collect();
+    let build = |counts: bool| {
+        let mut b = MapBuilder::memory();
+        b.with_counts(counts);
+        let empty = b.heap_usage();
+        // Most of a builder is its registry, which is allocated up front.
+        assert!(empty > 10_000 * 2 * std::mem::size_of::<crate::raw::CompiledAddr>());
+        for (i, k) in keys.iter().enumerate() {
+            b.insert(k, i as u64).unwrap();
+        }
+        assert!(b.heap_usage() > empty);
+        Map::from(tokio_test::block_on(Fst::new(b.into_inner().unwrap())).unwrap())
+    };
+    let plain = build(false);
+    assert_eq!(plain.heap_usage(), 0);
+    // Subtree counts are read in place.
+    let counted = build(true);
+    assert!(counted.as_fst().has_subtree_counts());
+    assert_eq!(counted.heap_usage(), 0);
+
+    let mut stream = plain.stream();
+    let before = stream.heap_usage();
+    stream.next_back().unwrap();
+    assert!(stream.heap_usage() > before);
+    let long = Map::from(fst_map(vec![("x".repeat(1000), 1)]));
+    assert!(long.stream().heap_usage() >= 1000);
+
+    let re = Regex::new("key0[0-9]+").unwrap();
+    assert!(re.heap_usage() > Regex::new("k").unwrap().heap_usage());
+    assert!(plain.search(re).into_stream().heap_usage() > 0);
+    assert_eq!(Levenshtein::new("abcd", 1).heap_usage(), 16);
+    assert_eq!(
+        TableDfa::new(vec![[None; 256]; 2], vec![1]).heap_usage(),
+        2 * 2048 + 3 * 2
+    );
+}
+
+#[test]
+fn reverse_streams() {
+    use crate::automaton::Subsequence;
+    use crate::{IntoStreamer, Map, Set};
+
+    let map = Map::from(fst_map(vec![
+        ("", 0),
+        ("a", 1),
+        ("ab", 2),
+        ("b", 3),
+        ("ba", 4),
+    ]));
+    assert_eq!(
+        map.stream_rev().into_str_vec().unwrap(),
+        vec![
+            ("ba".to_string(), 4),
+            ("b".to_string(), 3),
+            ("ab".to_string(), 2),
+            ("a".to_string(), 1),
+            ("".to_string(), 0),
+        ]
+    );
+    let mut keys = map.keys_rev();
+    let mut got = vec![];
+    while let Some(k) = keys.next() {
+        got.push(k.to_vec());
+    }
+    assert_eq!(
+        got,
+        vec![
+            b"ba".to_vec(),
+            b"b".to_vec(),
+            b"ab".to_vec(),
+            b"a".to_vec(),
+            vec![]
+        ]
+    );
+    let mut values = map.values_rev();
+    let mut got = vec![];
+    while let Some(v) = values.next() {
+        got.push(v);
+    }
+    assert_eq!(got, vec![4, 3, 2, 1, 0]);
+
+    let mut values = map.search_rev(Subsequence::new("b")).values();
+    let mut got = vec![];
+    while let Some(v) = values.next() {
+        got.push(v);
+    }
+    assert_eq!(got, vec![4, 3, 2]);
+    let mut keys = map.search_rev(Subsequence::new("b")).lt("b").keys();
+    assert_eq!(keys.next().map(|k| k.to_vec()), Some(b"ab".to_vec()));
+    assert!(keys.next().is_none());
+    let mut keys = map.search(Subsequence::new("b")).keys();
+    assert_eq!(keys.next().map(|k| k.to_vec()), Some(b"ab".to_vec()));
+
+    let set = Set::from(fst_set(vec!["x", "xy", "y"]));
+    assert_eq!(
+        set.stream_rev().into_bytes(),
+        vec![b"y".to_vec(), b"xy".to_vec(), b"x".to_vec()]
+    );
+    assert_eq!(
+        set.search_rev(Subsequence::new("x"))
+            .into_stream()
+            .into_bytes(),
+        vec![b"xy".to_vec(), b"x".to_vec()]
+    );
+}
+
+/// A `FakeArr` whose reads fail while `fail` is set.
+#[derive(Debug)]
+struct Flaky {
+    bytes: Vec<u8>,
+    fail: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl FakeArr for Flaky {
+    fn len(&self) -> u64 {
+        self.bytes.len() as u64
+    }
+
+    fn read_into(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        if self.fail.load(std::sync::atomic::Ordering::SeqCst) {
+            return Err(std::io::Error::other("flaky"));
+        }
+        let start = offset as usize;
+        buf.copy_from_slice(&self.bytes[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn as_dyn(&self) -> &dyn FakeArr {
+        self
+    }
+}
+
+#[test]
+fn try_streamer_reports_read_errors() {
+    use crate::stream::TryStreamer;
+    use crate::Map;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let bytes = fst_map(vec![("a", 1), ("b", 2), ("c", 3)]).to_vec();
+    let fail = Arc::new(AtomicBool::new(false));
+    let flaky = Flaky {
+        bytes,
+        fail: fail.clone(),
+    };
+    let map = Map::from(tokio_test::block_on(Fst::new(flaky)).unwrap());
+
+    let mut stream = map.stream();
+    let (k, v) = stream.try_next().unwrap().unwrap();
+    assert_eq!((k.to_vec(), v), (b"a".to_vec(), 1));
+
+    fail.store(true, Ordering::SeqCst);
+    match stream.try_next() {
+        Some(Err(Error::Io(err))) => assert_eq!(err.to_string(), "flaky"),
+        got => panic!("expected a read error, got {:?}", got.map(|r| r.is_ok())),
+    }
+
+    fail.store(false, Ordering::SeqCst);
+    let mut stream = map.stream();
+    let mut got = vec![];
+    while let Some(item) = stream.try_next() {
+        let (k, v) = item.unwrap();
+        got.push((k.to_vec(), v));
+    }
+    assert_eq!(got.len(), 3);
+}
+
+#[test]
+fn into_stream_with_budget() {
+    use crate::automaton::Subsequence;
+    use crate::raw::{Budget, Page};
+    use crate::Map;
+    use std::time::Duration;
+
+    // Short keys, so that a small budget of nodes always reaches a key.
+    let words: Vec<&str> = TEXT.lines().filter(|w| w.len() <= 8).take(2000).collect();
+    let fst = fst_set(words.clone());
+    let all = fst.stream().into_byte_keys();
+
+    // Paging with each kind of limit yields every key exactly once.
+    for &budget in &[Budget::new().items(7), Budget::new().nodes(32)] {
+        let mut got = vec![];
+        let mut next = Some(fst.range());
+        while let Some(builder) = next {
+            let Page { items, rest } = builder.into_stream_with_budget(budget);
+            assert!(!items.is_empty());
+            got.extend(items.into_iter().map(|(k, _)| k));
+            next = rest;
+        }
+        assert_eq!(got, all);
+    }
+    let mut got = vec![];
+    let mut next = Some(fst.range().backward());
+    while let Some(builder) = next {
+        let Page { items, rest } = builder.into_stream_with_budget(Budget::new().items(7));
+        got.extend(items.into_iter().map(|(k, _)| k));
+        next = rest;
+    }
+    got.reverse();
+    assert_eq!(got, all);
+
+    // A stream that ends right at the limit has no continuation.
+    let map = Map::from(fst_map(vec![("a", 1), ("b", 2), ("c", 3)]));
+    let Page { items, rest } = map.range().into_stream_with_budget(Budget::new().items(3));
+    assert_eq!(items.len(), 3);
+    assert!(rest.is_none());
+    let Page { items, rest } = map.range().into_stream_with_budget(Budget::new().items(2));
+    assert_eq!(items, vec![(b"a".to_vec(), 1), (b"b".to_vec(), 2)]);
+    let Page { items, rest } = rest.unwrap().into_stream_with_budget(Budget::new());
+    assert_eq!(items, vec![(b"c".to_vec(), 3)]);
+    assert!(rest.is_none());
+
+    // Without time, nothing is found but the stream can be resumed.
+    let set = crate::Set::from(fst_set(vec!["ab", "b", "xb"]));
+    let search = set.search(Subsequence::new("b")).gt("ab");
+    let Page { items: keys, rest } =
+        search.into_stream_with_budget(Budget::new().time(Duration::ZERO));
+    assert!(keys.is_empty());
+    let Page { items: keys, rest } = rest.unwrap().into_stream_with_budget(Budget::new());
+    assert_eq!(keys, vec![b"b".to_vec(), b"xb".to_vec()]);
+    assert!(rest.is_none());
+}
+
+#[test]
+fn try_get() {
+    use crate::{Map, Set};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let fail = Arc::new(AtomicBool::new(false));
+    let flaky = |bytes| Flaky {
+        bytes,
+        fail: fail.clone(),
+    };
+    let bytes = fst_map(vec![("a", 1), ("abc", 2), ("b", 3)]).to_vec();
+    let map = Map::from(tokio_test::block_on(Fst::new(flaky(bytes))).unwrap());
+    let bytes = fst_set(vec!["a", "b"]).to_vec();
+    let set = Set::from(tokio_test::block_on(Fst::new(flaky(bytes))).unwrap());
+
+    assert_eq!(map.try_get("abc").unwrap(), Some(2));
+    assert_eq!(map.try_get("ab").unwrap(), None);
+    assert!(map.try_contains_key("b").unwrap());
+    assert!(!set.try_contains("c").unwrap());
+
+    fail.store(true, Ordering::SeqCst);
+    assert!(matches!(map.try_get("abc"), Err(Error::Io(_))));
+    assert!(matches!(map.try_contains_key("b"), Err(Error::Io(_))));
+    assert!(matches!(set.try_contains("a"), Err(Error::Io(_))));
+
+    // The same lookups succeed once reads work again.
+    fail.store(false, Ordering::SeqCst);
+    assert_eq!(map.try_get("abc").unwrap(), Some(2));
+    assert!(set.try_contains("a").unwrap());
+}
+
+#[test]
+fn from_fn_automaton() {
+    use crate::automaton::from_fn;
+    use crate::Set;
+
+    let set = Set::from(fst_set(vec!["a", "ab", "abc", "b", "bab", "bb"]));
+
+    // Keys with exactly two `b`s.
+    let two_bs = from_fn(|| 0u8, |&n, b| n + (b == b'b') as u8, |&n| n == 2);
+    let keys = set.search(two_bs.clone()).into_stream().into_bytes();
+    assert_eq!(keys, vec![b"bab".to_vec(), b"bb".to_vec()]);
+
+    // Pruning states with too many `b`s gives the same keys.
+    let pruned = two_bs.can_match(|&n| n <= 2);
+    let keys = set.search(&pruned).into_stream().into_bytes();
+    assert_eq!(keys, vec![b"bab".to_vec(), b"bb".to_vec()]);
+
+    // States can be any `Clone` type, such as the bytes seen so far.
+    let palindrome = from_fn(
+        Vec::new,
+        |seen: &Vec<u8>, b| {
+            let mut seen = seen.clone();
+            seen.push(b);
+            seen
+        },
+        |seen| !seen.is_empty() && seen.iter().eq(seen.iter().rev()),
+    );
+    let keys = set.search(palindrome).into_stream().into_bytes();
+    assert_eq!(
+        keys,
+        vec![
+            b"a".to_vec(),
+            b"b".to_vec(),
+            b"bab".to_vec(),
+            b"bb".to_vec()
+        ]
+    );
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_search() {
+    use crate::automaton::Subsequence;
+    use crate::stream::AsyncStreamer;
+    use crate::Map;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let words: Vec<(&str, u64)> = TEXT
+        .lines()
+        .take(5000)
+        .map(|w| (w, w.len() as u64))
+        .collect();
+    let map = Map::from(fst_map(words));
+    let all = map.stream().into_byte_vec();
+
+    // Enough keys that the stream takes several pages.
+    let mut stream = map.range().into_async_stream();
+    let mut got = vec![];
+    while let Some(item) = tokio_test::block_on(stream.next()) {
+        got.push(item.unwrap());
+    }
+    assert_eq!(got, all);
+
+    let search = map.search(Subsequence::new("zz")).backward();
+    let got: Vec<_> = tokio_test::block_on(futures::StreamExt::collect(search.into_async_stream()));
+    let mut want = map
+        .search(Subsequence::new("zz"))
+        .into_stream()
+        .into_byte_vec();
+    want.reverse();
+    assert_eq!(
+        got.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
+        want
+    );
+
+    let fail = Arc::new(AtomicBool::new(false));
+    let flaky = Flaky {
+        bytes: map.as_fst().to_vec(),
+        fail: fail.clone(),
+    };
+    let map = Map::from(tokio_test::block_on(Fst::new(flaky)).unwrap());
+    assert_eq!(
+        tokio_test::block_on(map.get_async(&all[7].0)).unwrap(),
+        Some(all[7].1)
+    );
+    fail.store(true, Ordering::SeqCst);
+    assert!(tokio_test::block_on(map.get_async(&all[7].0)).is_err());
+    let mut stream = map.range().into_async_stream();
+    assert!(tokio_test::block_on(stream.next()).unwrap().is_err());
+    assert!(tokio_test::block_on(stream.next()).is_none());
+}
+
+#[test]
+fn open_picks_backend() {
+    use crate::{open, open_with, Backend, OpenPolicy};
+
+    let words: Vec<(&str, u64)> = TEXT
+        .lines()
+        .take(1000)
+        .map(|w| (w, w.len() as u64))
+        .collect();
+    let map = crate::Map::from(fst_map(words));
+    let path = std::env::temp_dir().join(format!("fst-open-{}", std::process::id()));
+    std::fs::write(&path, map.as_fst().to_vec()).unwrap();
+    let all = map.stream().into_byte_vec();
+
+    let policy = OpenPolicy::new();
+    assert_eq!(policy.backend(1 << 20), Backend::Memory);
+    assert_eq!(policy.backend((1 << 20) + 1), Backend::File);
+    // SAFETY: the policy is not used to open anything.
+    let mapped = unsafe { policy.mmap(true) };
+    let large = if cfg!(feature = "mmap") {
+        Backend::Mmap
+    } else {
+        Backend::File
+    };
+    assert_eq!(mapped.backend((1 << 20) + 1), large);
+
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+    let opened = tokio_test::block_on(open(&path)).unwrap();
+    assert_send_sync(&opened);
+    assert!(opened.as_fst().as_contiguous().is_some());
+    assert_eq!(opened.stream().into_byte_vec(), all);
+
+    // SAFETY: nothing else writes to the file while it is mapped.
+    let policy = unsafe { OpenPolicy::new().max_in_memory(0).mmap(true) };
+    let opened = tokio_test::block_on(open_with(&path, policy)).unwrap();
+    assert_eq!(
+        opened.as_fst().as_contiguous().is_some(),
+        cfg!(feature = "mmap")
+    );
+    assert_eq!(opened.stream().into_byte_vec(), all);
+    drop(opened);
+
+    // Reading on demand goes through the buffered file.
+    let policy = OpenPolicy::new().max_in_memory(0);
+    let opened = tokio_test::block_on(open_with(&path, policy)).unwrap();
+    assert!(opened.as_fst().as_contiguous().is_none());
+    assert_eq!(opened.stream().into_byte_vec(), all);
+    assert_eq!(opened.get(&all[500].0), Some(all[500].1));
+    let backward = opened.range().backward().into_stream().into_byte_vec();
+    assert_eq!(backward.len(), all.len());
+
+    std::fs::remove_file(&path).unwrap();
+    assert!(tokio_test::block_on(open(&path)).is_err());
+}
+
+#[test]
+fn dyn_map() {
+    use crate::automaton::Subsequence;
+    use crate::map::DynMap;
+    use crate::FileArr;
+    use crate::Map;
+
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+    let bytes = fst_map(vec![("a", 1), ("ab", 2), ("b", 3)]).to_vec();
+    let path = std::env::temp_dir().join(format!("fst-dyn-map-{}", std::process::id()));
+    std::fs::write(&path, &bytes).unwrap();
+    let leaked: &'static [u8] = Box::leak(bytes.clone().into_boxed_slice());
+
+    let maps: Vec<DynMap> = vec![
+        Map::from(tokio_test::block_on(Fst::new(bytes)).unwrap()).into_dyn(),
+        DynMap::new(Map::from(tokio_test::block_on(Fst::new(leaked)).unwrap())),
+        DynMap::from(Map::from(
+            tokio_test::block_on(Fst::new(FileArr::open(&path).unwrap())).unwrap(),
+        )),
+    ];
+    assert_send_sync(&maps);
+    for map in &maps {
+        assert_eq!(map.get("ab"), Some(2));
+        assert_eq!(map.len(), 3);
+        assert_eq!(
+            map.search(Subsequence::new("b"))
+                .into_stream()
+                .into_byte_vec(),
+            vec![(b"ab".to_vec(), 2), (b"b".to_vec(), 3)]
+        );
+    }
+    assert!(maps[0].as_fst().as_contiguous().is_some());
+    assert!(maps[2].as_fst().as_contiguous().is_none());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_fake_arr() {
+    use crate::{AsyncFakeArr, BlockingFakeArr, FuturesBlockOn, Map};
+    use futures::future::BoxFuture;
+    use std::io;
+    use std::task::Poll;
+
+    /// Data that takes a round trip to the executor before every read.
+    #[derive(Debug)]
+    struct Remote(Vec<u8>);
+
+    impl AsyncFakeArr for Remote {
+        fn len(&self) -> u64 {
+            self.0.len() as u64
+        }
+
+        fn read_into<'a>(
+            &'a self,
+            offset: u64,
+            buf: &'a mut [u8],
+        ) -> BoxFuture<'a, io::Result<()>> {
+            Box::pin(async move {
+                let mut yielded = false;
+                futures::future::poll_fn(|cx| {
+                    if yielded {
+                        return Poll::Ready(());
+                    }
+                    yielded = true;
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                })
+                .await;
+                let start = offset as usize;
+                let bytes = self.0.get(start..start + buf.len());
+                let bytes = bytes.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+                buf.copy_from_slice(bytes);
+                Ok(())
+            })
+        }
+    }
+
+    let bytes = fst_map(vec![("a", 1), ("ab", 2), ("b", 3)]).to_vec();
+    let remote = Remote(bytes.clone());
+    assert_eq!(
+        tokio_test::block_on(remote.read_vec(0, 4)).unwrap(),
+        bytes[..4].to_vec()
+    );
+    assert!(tokio_test::block_on(remote.read_vec(bytes.len() as u64, 1)).is_err());
+
+    let arr = BlockingFakeArr::new(remote, FuturesBlockOn);
+    let map = Map::from(tokio_test::block_on(Fst::new(arr)).unwrap());
+    assert_eq!(map.get("ab"), Some(2));
+    assert_eq!(
+        map.stream().into_byte_vec(),
+        vec![(b"a".to_vec(), 1), (b"ab".to_vec(), 2), (b"b".to_vec(), 3)]
+    );
+    assert_eq!(map.into_inner().into_inner().0, bytes);
+}
+
+#[test]
+fn top_values() {
+    use crate::automaton::Subsequence;
+    use crate::Map;
+
+    // Values repeat, so that ties must be broken by key.
+    let words: Vec<(&str, u64)> = TEXT
+        .lines()
+        .take(3000)
+        .enumerate()
+        .map(|(i, w)| (w, (i as u64 * 7919) % 101))
+        .collect();
+    let map = Map::from(fst_map(words));
+    let brute = |mut items: Vec<(Vec<u8>, u64)>, k: usize| {
+        items.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        items.truncate(k);
+        items
+    };
+
+    let all = map.stream().into_byte_vec();
+    let backward = map.range().backward().into_stream().into_byte_vec();
+    for &k in &[0, 1, 10, 100, 5000] {
+        assert_eq!(map.top_values(k), brute(all.clone(), k));
+        let top = map.range().backward().top_values(k);
+        assert_eq!(top, brute(backward.clone(), k));
+    }
+    assert_eq!(map.top_values(10), map.top_k_completions("", 10));
+
+    let range = map.range().ge("b").lt("d").into_stream().into_byte_vec();
+    assert_eq!(map.range().ge("b").lt("d").top_values(20), brute(range, 20));
+    let search = map.search(Subsequence::new("ing"));
+    let matches = search.into_stream().into_byte_vec();
+    let search = map.search(Subsequence::new("ing"));
+    assert_eq!(search.top_values(20), brute(matches, 20));
+}
+
+#[test]
+fn cached_fake_arr() {
+    use crate::{CachedFakeArr, Map};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let words: Vec<(&str, u64)> = TEXT
+        .lines()
+        .take(2000)
+        .map(|w| (w, w.len() as u64))
+        .collect();
+    let bytes = fst_map(words.clone()).as_bytes().to_vec();
+    let fail = Arc::new(AtomicBool::new(false));
+
+    // Reads that straddle blocks, and blocks that are evicted, still read
+    // the right bytes.
+    let flaky = Flaky {
+        bytes: bytes.clone(),
+        fail: fail.clone(),
+    };
+    let arr = CachedFakeArr::new(flaky).block_size(7).capacity(3);
+    for (offset, len) in [(0, 1), (5, 10), (3, 30), (bytes.len() - 9, 9), (20, 0)] {
+        let mut buf = vec![0; len];
+        arr.read_into(offset as u64, &mut buf).unwrap();
+        assert_eq!(buf, &bytes[offset..offset + len]);
+    }
+    assert!(arr.read_into(bytes.len() as u64 - 1, &mut [0; 2]).is_err());
+
+    // Once the blocks of a lookup are cached, it no longer reads the inner
+    // array.
+    let flaky = Flaky {
+        bytes,
+        fail: fail.clone(),
+    };
+    let arr = CachedFakeArr::new(flaky).block_size(64).capacity(1024);
+    let map = Map::from(tokio_test::block_on(Fst::new(arr)).unwrap());
+    let (key, value) = words[1234];
+    assert_eq!(map.get(key), Some(value));
+    fail.store(true, Ordering::SeqCst);
+    assert_eq!(map.try_get(key).unwrap(), Some(value));
+    assert!(map.try_get(words[10].0).is_err());
+
+    // Without capacity, nothing is cached.
+    fail.store(false, Ordering::SeqCst);
+    let map = Map::from(tokio_test::block_on(Fst::new(map.into_inner().capacity(0))).unwrap());
+    assert_eq!(map.get(key), Some(value));
+    fail.store(true, Ordering::SeqCst);
+    assert!(map.try_get(key).is_err());
+}
+
+#[test]
+fn explain() {
+    use crate::Map;
+
+    let words: Vec<(&str, u64)> = TEXT
+        .lines()
+        .take(1000)
+        .enumerate()
+        .map(|(i, w)| (w, i as u64 * 31))
+        .collect();
+    let map = Map::from(fst_map(words.clone()));
+    for &(key, value) in &words {
+        let trace = map.explain(key);
+        assert!(trace.matched());
+        assert_eq!(trace.value, Some(Output::new(value)));
+        let sum = trace
+            .steps
+            .iter()
+            .fold(Output::zero(), |sum, step| sum.cat(step.output));
+        assert_eq!(sum.cat(trace.final_output.unwrap()), Output::new(value));
+        assert_eq!(
+            trace.steps.first().map(|step| step.from),
+            Some(map.as_fst().root().addr())
+        );
+        for pair in trace.steps.windows(2) {
+            assert_eq!(pair[0].to, pair[1].from);
+        }
+        assert_eq!(trace.steps.last().unwrap().to, trace.node);
+    }
+
+    let fst = fst_map(vec![("ab", 1), ("abcd", 2)]);
+    let trace = fst.explain("abc");
+    assert!(trace.matched());
+    assert_eq!((trace.final_output, trace.value), (None, None));
+    assert!(
+        trace.to_string().ends_with("is not final: not found"),
+        "{}",
+        trace
+    );
+    let trace = fst.explain("abx");
+    assert!(!trace.matched());
+    assert_eq!(trace.steps.len(), 2);
+    assert_eq!(trace.value, None);
+    assert!(
+        trace.to_string().contains("has no transition for \"x\""),
+        "{}",
+        trace
+    );
+    let trace = fst.explain("abcd");
+    assert_eq!(trace.value, Some(Output::new(2)));
+    assert_eq!(trace.to_string().lines().count(), 6, "{}", trace);
+    assert!(trace.to_string().ends_with(": value 2"), "{}", trace);
+
+    let mut bld = Builder::new_dictionary_encoded(vec![]).unwrap();
+    bld.insert(b"a", 500).unwrap();
+    bld.insert(b"b", 7).unwrap();
+    let bytes = bld.into_inner().unwrap();
+    let fst = Fst::from_slice(&bytes).unwrap();
+    assert_eq!(fst.explain("a").value, fst.get("a"));
+    assert_eq!(fst.explain("b").value, Some(Output::new(7)));
+}
+
+#[test]
+fn bytes_util() {
+    use crate::bytes_util::{key_successor, prefix_upper_bound};
+    use crate::Map;
+
+    assert_eq!(key_successor(b""), b"\x00");
+    assert_eq!(key_successor(b"a\xFF"), b"a\xFF\x00");
+    assert_eq!(prefix_upper_bound(b""), None);
+    assert_eq!(prefix_upper_bound(b"\xFF"), None);
+    assert_eq!(prefix_upper_bound(b"a\xFF\xFF"), Some(b"b".to_vec()));
+    assert_eq!(prefix_upper_bound(b"\x00"), Some(b"\x01".to_vec()));
+
+    let keys: Vec<&[u8]> = vec![b"", b"a", b"a\x00", b"a\xFF", b"a\xFF\xFF", b"b", b"\xFF"];
+    let map = Map::from(fst_map(keys.iter().map(|&k| (k, k.len() as u64))));
+    let range = |ge: &[u8], lt: Option<Vec<u8>>| {
+        let builder = map.range().ge(ge);
+        let builder = match lt {
+            Some(lt) => builder.lt(lt),
+            None => builder,
+        };
+        builder.into_stream().into_byte_keys()
+    };
+    for &key in &keys {
+        let gt = map.range().gt(key).into_stream().into_byte_keys();
+        assert_eq!(range(&key_successor(key), None), gt);
+        let le = map.range().le(key).into_stream().into_byte_keys();
+        assert_eq!(range(b"", Some(key_successor(key))), le);
+
+        let with_prefix: Vec<Vec<u8>> = keys
+            .iter()
+            .filter(|k| k.starts_with(key))
+            .map(|k| k.to_vec())
+            .collect();
+        assert_eq!(
+            range(key, prefix_upper_bound(key)),
+            with_prefix,
+            "{:?}",
+            key
+        );
+    }
+}
+
+#[test]
+fn recover_truncated_footer() {
+    use crate::raw::{footer, Recovered};
+
+    let words: Vec<&str> = {
+        let mut words: Vec<&str> = TEXT.lines().take(500).collect();
+        words.sort();
+        words.dedup();
+        words
+    };
+    let expected: Vec<(String, u64)> = words
+        .iter()
+        .enumerate()
+        .map(|(i, w)| (w.to_string(), i as u64 % 7))
+        .collect();
+    let build = |dictionary: bool| {
+        let mut bfst = if dictionary {
+            Builder::new_dictionary_encoded(vec![]).unwrap()
+        } else {
+            Builder::memory()
+        };
+        for (w, v) in &expected {
+            bfst.insert(w, *v).unwrap();
+        }
+        bfst.finish_into_parts().unwrap()
+    };
+    let (body, _) = build(false);
+    let len = expected.len() as u64;
+    let root = body.len() as u64 - 1;
+    // An entry from a newer writer, with a payload longer than the rest of
+    // the footer.
+    let unknown = footer::write_footer(
+        &[
+            (9, vec![0xAB; 100]),
+            (footer::TAG_MAX_KEY_LEN, 6u64.to_le_bytes().to_vec()),
+        ],
+        len,
+        root,
+    );
+    for (body, footer) in [build(false), build(true), (body, unknown)] {
+        let mut bytes = body.clone();
+        bytes.extend_from_slice(&footer);
+        // Every cut inside the footer leaves the root intact.
+        for cut in 0..footer.len() {
+            let rec = Recovered::new(bytes[..body.len() + cut].to_vec()).unwrap();
+            assert!(!rec.is_complete());
+            assert_eq!(rec.recovered_len(), body.len() as u64, "cut {}", cut);
+            let last = rec.len() - 1;
+            assert_eq!(
+                rec.stream(last).into_str_vec().unwrap().len(),
+                expected.len()
+            );
+        }
+        assert!(Recovered::new(bytes).unwrap().is_complete());
+    }
+}
+
+#[test]
+fn corrupt_max_key_len() {
+    use crate::raw::footer;
+
+    let mut bfst = Builder::memory();
+    bfst.add("abc").unwrap();
+    bfst.add("abcdef").unwrap();
+    let (mut bytes, _) = bfst.finish_into_parts().unwrap();
+    let root = bytes.len() as u64 - 1;
+    bytes.extend_from_slice(&footer::write_footer(
+        &[(
+            footer::TAG_MAX_KEY_LEN,
+            (u64::MAX >> 8).to_le_bytes().to_vec(),
+        )],
+        2,
+        root,
+    ));
+    // Streams don't allocate room for the length the footer claims.
+    let fst = tokio_test::block_on(Fst::new(bytes)).unwrap();
+    assert_eq!(
+        fst.stream().into_byte_keys(),
+        vec![b"abc".to_vec(), b"abcdef".to_vec()]
+    );
+}
+
+#[test]
+fn map_automaton_drops_derived_bounds() {
+    use crate::automaton::Automaton;
+    use crate::regex::Regex;
+
+    let fst = fst_set(vec!["abc", "foo", "foo1", "zzz"]);
+    let complement = fst
+        .search(Regex::new("foo.*").unwrap())
+        .map_automaton(|aut| aut.complement());
+    assert_eq!(
+        complement.into_stream().into_byte_keys(),
+        vec![b"abc".to_vec(), b"zzz".to_vec()]
+    );
+
+    // Bounds set explicitly are kept.
+    let kept = fst
+        .search(Regex::new("foo.*").unwrap())
+        .lt("zzz")
+        .map_automaton(|aut| aut.complement());
+    assert_eq!(kept.into_stream().into_byte_keys(), vec![b"abc".to_vec()]);
+
+    // Bounds are derived again from the new automaton.
+    let narrowed = fst
+        .search(Regex::new("foo.*").unwrap())
+        .map_automaton(|aut| aut.intersection(Regex::new("foo1").unwrap()));
+    assert_eq!(
+        narrowed.into_stream().into_byte_keys(),
+        vec![b"foo1".to_vec()]
+    );
+}
+
+#[test]
+fn bounds_after_map_automaton() {
+    use crate::automaton::Automaton;
+    use crate::regex::Regex;
+    use std::ops::Bound as StdBound;
+
+    let fst = fst_set(vec!["abc", "foo", "foo1", "zzz"]);
+    let search = fst.search(Regex::new("foo.*").unwrap());
+    assert_eq!(
+        search.bounds(),
+        (
+            StdBound::Included(&b"foo"[..]),
+            StdBound::Excluded(&b"fop"[..])
+        )
+    );
+    let complement = search.map_automaton(|aut| aut.complement());
+    assert_eq!(
+        complement.bounds(),
+        (StdBound::Unbounded, StdBound::Unbounded)
+    );
+
+    // Only the end that was not set explicitly is dropped.
+    let kept = fst
+        .search(Regex::new("foo.*").unwrap())
+        .ge("foo")
+        .map_automaton(|aut| aut.complement());
+    assert_eq!(
+        kept.bounds(),
+        (StdBound::Included(&b"foo"[..]), StdBound::Unbounded)
+    );
+
+    let narrowed = fst
+        .search(Regex::new("foo.*").unwrap())
+        .map_automaton(|aut| aut.intersection(Regex::new("foo1").unwrap()));
+    assert_eq!(
+        narrowed.bounds(),
+        (
+            StdBound::Included(&b"foo1"[..]),
+            StdBound::Excluded(&b"foo2"[..])
+        )
+    );
+}
+
+#[test]
+fn try_streamer_reports_seek_errors() {
+    use crate::stream::TryStreamer;
+    use crate::{Map, Set};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let fail = Arc::new(AtomicBool::new(false));
+    let flaky = |bytes| Flaky {
+        bytes,
+        fail: fail.clone(),
+    };
+    let bytes = fst_map(vec![("a", 1), ("b", 2), ("c", 3)]).to_vec();
+    let map = Map::from(tokio_test::block_on(Fst::new(flaky(bytes))).unwrap());
+    let bytes = fst_set(vec!["a", "b", "c"]).to_vec();
+    let set = Set::from(tokio_test::block_on(Fst::new(flaky(bytes))).unwrap());
+
+    // Streams seek to their lower bound on the first call to `try_next`, so
+    // a read that fails while seeking is returned there.
+    let mut keys = map.range().ge("b").keys();
+    let mut values = map.range().gt("a").values();
+    let mut members = set.range().ge("b").into_stream();
+    fail.store(true, Ordering::SeqCst);
+    assert!(matches!(keys.try_next(), Some(Err(Error::Io(_)))));
+    assert!(matches!(values.try_next(), Some(Err(Error::Io(_)))));
+    assert!(matches!(members.try_next(), Some(Err(Error::Io(_)))));
+
+    fail.store(false, Ordering::SeqCst);
+    let mut stream = map.range().ge("b").keys();
+    let mut got = vec![];
+    while let Some(key) = stream.try_next() {
+        got.push(key.unwrap().to_vec());
+    }
+    assert_eq!(got, vec![b"b".to_vec(), b"c".to_vec()]);
+}
+
+#[test]
+fn try_get_reports_errors_in_the_middle_of_a_lookup() {
+    use crate::Map;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Debug)]
+    struct FailAfter {
+        bytes: Vec<u8>,
+        reads_left: AtomicUsize,
+    }
+
+    impl FakeArr for FailAfter {
+        fn len(&self) -> u64 {
+            self.bytes.len() as u64
+        }
+
+        fn read_into(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+            let left = self.reads_left.load(Ordering::SeqCst);
+            if left == 0 {
+                return Err(std::io::Error::other("no reads left"));
+            }
+            self.reads_left.store(left - 1, Ordering::SeqCst);
+            let start = offset as usize;
+            buf.copy_from_slice(&self.bytes[start..start + buf.len()]);
+            Ok(())
+        }
+
+        fn as_dyn(&self) -> &dyn FakeArr {
+            self
+        }
+    }
+
+    let mut words: Vec<&str> = TEXT.lines().collect();
+    words.sort_unstable();
+    words.dedup();
+    let pairs: Vec<(&str, u64)> = words
+        .iter()
+        .enumerate()
+        .map(|(i, &w)| (w, i as u64))
+        .collect();
+    let bytes = fst_map(pairs.clone()).to_vec();
+    let data = FailAfter {
+        bytes,
+        reads_left: AtomicUsize::new(usize::MAX),
+    };
+    let map = Map::from(tokio_test::block_on(Fst::new(data)).unwrap());
+
+    // Every lookup either fails with an error or returns the right answer,
+    // however many reads it gets through before the data stops answering.
+    for &(key, value) in pairs.iter().step_by(97) {
+        let mut failed = 0;
+        for budget in 0..64 {
+            map.as_fst()
+                .as_inner()
+                .reads_left
+                .store(budget, Ordering::SeqCst);
+            match map.try_get(key) {
+                Ok(found) => assert_eq!(found, Some(value)),
+                Err(Error::Io(_)) => failed += 1,
+                Err(err) => panic!("unexpected error: {}", err),
+            }
+            map.as_fst()
+                .as_inner()
+                .reads_left
+                .store(budget, Ordering::SeqCst);
+            match map.try_contains_key(key) {
+                Ok(found) => assert!(found),
+                Err(Error::Io(_)) => {}
+                Err(err) => panic!("unexpected error: {}", err),
+            }
+        }
+        assert!(failed > 1, "{:?} failed only {} times", key, failed);
+    }
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_search_awaits_async_reads() {
+    use crate::automaton::Subsequence;
+    use crate::{AsyncFakeArr, BlockOn, BlockingFakeArr, Map};
+    use futures::future::BoxFuture;
+    use std::future::Future;
+    use std::io;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::Poll;
+
+    /// Data that takes a round trip to the executor before every read, and
+    /// counts them.
+    #[derive(Debug)]
+    struct Remote {
+        bytes: Vec<u8>,
+        reads: AtomicUsize,
+        fail: AtomicBool,
+    }
+
+    impl AsyncFakeArr for Remote {
+        fn len(&self) -> u64 {
+            self.bytes.len() as u64
+        }
+
+        fn read_into<'a>(
+            &'a self,
+            offset: u64,
+            buf: &'a mut [u8],
+        ) -> BoxFuture<'a, io::Result<()>> {
+            Box::pin(async move {
+                let mut yielded = false;
+                futures::future::poll_fn(|cx| {
+                    if yielded {
+                        return Poll::Ready(());
+                    }
+                    yielded = true;
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                })
+                .await;
+                self.reads.fetch_add(1, Ordering::SeqCst);
+                if self.fail.load(Ordering::SeqCst) {
+                    return Err(io::Error::other("remote"));
+                }
+                let start = offset as usize;
+                buf.copy_from_slice(&self.bytes[start..start + buf.len()]);
+                Ok(())
+            })
+        }
+    }
+
+    /// Blocks only while allowed to, so that the test fails if the async
+    /// methods go through `FakeArr`.
+    #[derive(Debug)]
+    struct Guarded(Arc<AtomicBool>);
+
+    impl BlockOn for Guarded {
+        fn block_on<F: Future>(&self, future: F) -> F::Output {
+            assert!(self.0.load(Ordering::SeqCst), "an async search blocked");
+            futures::executor::block_on(future)
+        }
+    }
+
+    let words: Vec<(&str, u64)> = TEXT
+        .lines()
+        .take(5000)
+        .map(|w| (w, w.len() as u64))
+        .collect();
+    let local = Map::from(fst_map(words));
+    let remote = Remote {
+        bytes: local.as_fst().to_vec(),
+        reads: AtomicUsize::new(0),
+        fail: AtomicBool::new(false),
+    };
+    let may_block = Arc::new(AtomicBool::new(true));
+    let arr = BlockingFakeArr::new(remote, Guarded(may_block.clone()));
+    let map = Map::from(tokio_test::block_on(Fst::new(arr)).unwrap());
+    may_block.store(false, Ordering::SeqCst);
+
+    let all = local.stream().into_byte_vec();
+    for (key, value) in all.iter().step_by(101) {
+        assert_eq!(
+            tokio_test::block_on(map.get_async(key)).unwrap(),
+            Some(*value)
+        );
+    }
+    assert_eq!(tokio_test::block_on(map.get_async("zzzzz")).unwrap(), None);
+
+    // A stream fetches each block it reads once, rather than every node.
+    let remote = map.as_inner().get_ref();
+    remote.reads.store(0, Ordering::SeqCst);
+    let got: Vec<_> =
+        tokio_test::block_on(futures::StreamExt::collect(map.range().into_async_stream()));
+    assert_eq!(got.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(), all);
+    let blocks = (remote.bytes.len() + 4095) / 4096;
+    assert!(remote.reads.load(Ordering::SeqCst) <= blocks);
+
+    let search = map.search(Subsequence::new("zz")).backward();
+    let got: Vec<_> = tokio_test::block_on(futures::StreamExt::collect(search.into_async_stream()));
+    let mut want = local
+        .search(Subsequence::new("zz"))
+        .into_stream()
+        .into_byte_vec();
+    want.reverse();
+    assert_eq!(
+        got.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
+        want
+    );
+
+    remote.fail.store(true, Ordering::SeqCst);
+    assert!(tokio_test::block_on(map.get_async(&all[7].0)).is_err());
+    let mut stream = map.range().into_async_stream();
+    assert!(tokio_test::block_on(futures::StreamExt::next(&mut stream))
+        .unwrap()
+        .is_err());
+    assert!(tokio_test::block_on(futures::StreamExt::next(&mut stream)).is_none());
+}
+
+#[test]
+fn backward_values_match_forward_values() {
+    use crate::automaton::{Automaton, Subsequence};
+    use crate::Map;
+
+    let map = Map::from(fst_map(vec![("a", 1), ("ab", 0)]));
+    assert_eq!(
+        map.stream_rev().into_byte_vec(),
+        vec![(b"ab".to_vec(), 0), (b"a".to_vec(), 1)]
+    );
+
+    // Values that share no prefix with those of their neighbours, so that
+    // outputs end up on final states as well as on transitions.
+    let words: Vec<(&str, u64)> = TEXT
+        .lines()
+        .take(3000)
+        .enumerate()
+        .map(|(i, w)| (w, (i as u64 * 7919) % 1013))
+        .collect();
+    let map = Map::from(fst_map(words));
+    let reversed = |mut pairs: Vec<(Vec<u8>, u64)>| {
+        pairs.reverse();
+        pairs
+    };
+
+    let all = map.stream().into_byte_vec();
+    assert_eq!(map.stream_rev().into_byte_vec(), reversed(all.clone()));
+    let mut keys = vec![];
+    let mut stream = map.keys_rev();
+    while let Some(key) = stream.next() {
+        keys.push(key.to_vec());
+    }
+    let mut values = vec![];
+    let mut stream = map.values_rev();
+    while let Some(value) = stream.next() {
+        values.push(value);
+    }
+    let (want_keys, want_values): (Vec<_>, Vec<_>) = reversed(all).into_iter().unzip();
+    assert_eq!(keys, want_keys);
+    assert_eq!(values, want_values);
+
+    let aut = Subsequence::new("ea");
+    assert_eq!(
+        map.search_rev(&aut).into_stream().into_byte_vec(),
+        reversed(map.search(&aut).into_stream().into_byte_vec())
+    );
+    let aut = Subsequence::new("b").starts_with();
+    assert_eq!(
+        map.search_rev(&aut)
+            .ge("bar")
+            .lt("bl")
+            .into_stream()
+            .into_byte_vec(),
+        reversed(
+            map.search(&aut)
+                .ge("bar")
+                .lt("bl")
+                .into_stream()
+                .into_byte_vec()
+        )
+    );
+    assert_eq!(
+        map.range()
+            .gt("c")
+            .le("d")
+            .backward()
+            .into_stream()
+            .into_byte_vec(),
+        reversed(map.range().gt("c").le("d").into_stream().into_byte_vec())
+    );
+}
+
+#[test]
+fn next_back_values_and_conversions() {
+    use crate::Map;
+
+    let map = Map::from(fst_map(vec![("a", 1), ("ab", 0)]));
+    let mut stream = map.stream();
+    assert_eq!(
+        stream.next_back().map(|(k, v)| (k.to_vec(), v)),
+        Some((b"ab".to_vec(), 0))
+    );
+    assert_eq!(
+        stream.next_back().map(|(k, v)| (k.to_vec(), v)),
+        Some((b"a".to_vec(), 1))
+    );
+    assert!(stream.next_back().is_none());
+
+    let words: Vec<(&str, u64)> = TEXT
+        .lines()
+        .take(2000)
+        .enumerate()
+        .map(|(i, w)| (w, (i as u64 * 7919) % 1013))
+        .collect();
+    let map = Map::from(fst_map(words));
+    let all = map.stream().into_byte_vec();
+
+    // Values from the back, for both directions of the stream.
+    let mut stream = map.stream();
+    let mut back = vec![];
+    while let Some((k, v)) = stream.next_back() {
+        back.push((k.to_vec(), v));
+    }
+    back.reverse();
+    assert_eq!(back, all);
+    let mut stream = map.stream_rev();
+    let mut back = vec![];
+    while let Some((k, v)) = stream.next_back() {
+        back.push((k.to_vec(), v));
+    }
+    assert_eq!(back, all);
+
+    // Conversions stop before the keys taken from the back.
+    let take_back = |n: usize| {
+        let mut stream = map.stream();
+        for _ in 0..n {
+            stream.next_back().unwrap();
+        }
+        stream
+    };
+    let n = all.len();
+    assert_eq!(take_back(10).into_byte_vec(), all[..n - 10].to_vec());
+    let strs: Vec<(String, u64)> = all[..n - 3]
+        .iter()
+        .map(|(k, v)| (String::from_utf8(k.clone()).unwrap(), *v))
+        .collect();
+    assert_eq!(take_back(3).into_str_vec().unwrap(), strs);
+    let mut stream = take_back(5).into_str_stream();
+    let mut got = vec![];
+    while let Some(item) = stream.next() {
+        let (k, v) = item.unwrap();
+        got.push((k.to_string(), v));
+    }
+    assert_eq!(got, strs[..n - 5].to_vec());
+
+    let mut stream = take_back(7);
+    let (mut keys, mut offsets, mut values) = (vec![], vec![], vec![]);
+    while stream.fill(&mut keys, &mut offsets, &mut values, 100) > 0 {}
+    assert_eq!(values.len(), n - 7);
+    assert_eq!(
+        values,
+        all[..n - 7].iter().map(|&(_, v)| v).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        &keys[..*offsets.last().unwrap() as usize],
+        &all[..n - 7]
+            .iter()
+            .flat_map(|(k, _)| k.clone())
+            .collect::<Vec<_>>()[..]
+    );
+}
+
+#[test]
+fn value_table_needs_a_newer_version_and_valid_indices() {
+    use crate::raw::footer::{write_footer, TAG_VALUE_TABLE};
+    use crate::stream::TryStreamer;
+    use byteorder::{ByteOrder, LittleEndian};
+
+    let mut bld = Builder::new_dictionary_encoded(vec![]).unwrap();
+    bld.insert("a", 500).unwrap();
+    bld.insert("b", 7).unwrap();
+    let bytes = bld.into_inner().unwrap();
+    let fst = Fst::from_slice(&bytes).unwrap();
+    assert_eq!(LittleEndian::read_u64(&bytes[..8]), VERSION);
+    assert_eq!(fst.distinct_values(), Some(&[0, 500, 7][..]));
+
+    // Rewrites the footer of `fst` with the value table `values`.
+    let with_table = |version: u64, values: &[u64]| {
+        let root_addr = fst.root().addr();
+        let mut bytes = bytes[..root_addr as usize + 1].to_vec();
+        LittleEndian::write_u64(&mut bytes[..8], version);
+        let table = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        bytes.extend(write_footer(
+            &[(TAG_VALUE_TABLE, table)],
+            fst.len(),
+            root_addr,
+        ));
+        bytes
+    };
+    let valid = with_table(VERSION, &[0, 500, 7]);
+    assert_eq!(
+        Fst::from_slice(&valid).unwrap().get("b"),
+        Some(Output::new(7))
+    );
+    // Older versions skip unknown entries, so a table is only valid in a
+    // version that refuses to be read by them.
+    assert!(Fst::from_slice(&with_table(3, &[0, 500, 7])).is_err());
+
+    // The index of "b" is past the end of a shorter table.
+    let corrupt = with_table(VERSION, &[0, 500]);
+    let fst = Fst::from_slice(&corrupt).unwrap();
+    assert_eq!(fst.try_get("a").unwrap(), Some(Output::new(500)));
+    assert!(matches!(fst.try_get("b"), Err(Error::Io(_))));
+    let mut stream = fst.stream();
+    assert_eq!(stream.try_next().unwrap().unwrap().1, Output::new(500));
+    assert!(matches!(stream.try_next(), Some(Err(Error::Io(_)))));
+    let get = std::panic::catch_unwind(|| fst.get("b"));
+    assert!(get.is_err());
+}
@@ -0,0 +1,429 @@
+use std::cmp::{self, Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use crate::automaton::Automaton;
+use crate::bytes_util::prefix_upper_bound;
+use crate::error::Result;
+use crate::fake_arr::FakeArr;
+use crate::raw::pack::{pack_size, pack_uint_in};
+use crate::raw::{
+    u64_to_Ulen, CompiledAddr, Error, Fst, Node, Output, StreamBuilder, EMPTY_ADDRESS,
+};
+use crate::stream::{IntoStreamer, Streamer};
+
+impl<Data: FakeArr> Fst<Data> {
+    /// Returns the `k` keys starting with `prefix` that have the greatest
+    /// outputs, in descending order of output. Ties are broken by key, in
+    /// lexicographic order.
+    ///
+    /// This runs a best-first search from the node reached by `prefix`, so
+    /// only the parts of the fst that can contain one of the top `k` keys are
+    /// explored in full. The search needs the greatest output reachable from
+    /// every node it visits, which is computed on the fly and memoized for
+    /// the duration of the call.
+    ///
+    /// For fsts built with `Builder::new_dictionary_encoded`, outputs along
+    /// paths are sums of table indices and bound nothing, so this instead
+    /// streams every key that starts with `prefix` and ranks the decoded
+    /// values, just like `StreamBuilder::top_values`.
+    pub fn top_k_completions<B: AsRef<[u8]>>(&self, prefix: B, k: usize) -> Vec<(Vec<u8>, Output)> {
+        let mut memo = HashMap::new();
+        self.top_k_by(prefix.as_ref(), k, |fst, node| {
+            subtree_extremum(fst, node, SidecarKind::Max, &mut memo)
+        })
+    }
+
+    /// Returns the `k` keys with the greatest outputs, in descending order of
+    /// output. Ties are broken by key, in lexicographic order.
+    ///
+    /// Unlike `top_k_completions`, which memoizes a bound for every node it
+    /// visits, this streams every key once and only holds `k` of them. See
+    /// `StreamBuilder::top_values`, which can also restrict the keys to a
+    /// range or to the matches of an automaton.
+    pub fn top_values(&self, k: usize) -> Vec<(Vec<u8>, Output)> {
+        self.range().top_values(k)
+    }
+
+    /// Just like `top_k_completions`, except the greatest output reachable
+    /// from each node is read from a precomputed sidecar.
+    ///
+    /// This avoids visiting every node below `prefix` on the first search,
+    /// which makes repeated searches over a large fst much cheaper. Like
+    /// `top_k_completions`, this streams the keys with `prefix` instead for
+    /// dictionary encoded fsts, so the sidecar goes unused.
+    ///
+    /// # Panics
+    ///
+    /// This panics if `sidecar` does not store maximums, or if it was not
+    /// built from this fst.
+    pub fn top_k_completions_with<B: AsRef<[u8]>>(
+        &self,
+        prefix: B,
+        k: usize,
+        sidecar: &OutputSidecar,
+    ) -> Vec<(Vec<u8>, Output)> {
+        assert_eq!(
+            sidecar.kind(),
+            SidecarKind::Max,
+            "top-k search needs a sidecar of maximums"
+        );
+        self.top_k_by(prefix.as_ref(), k, |_, node| {
+            sidecar
+                .get(node.addr())
+                .expect("sidecar was not built from this fst")
+        })
+    }
+
+    /// Runs a best-first search for the top `k` completions of `prefix`.
+    ///
+    /// `max_of` must return the greatest output reachable from the given
+    /// node, counting only the outputs below it.
+    pub(crate) fn top_k_by<F>(
+        &self,
+        prefix: &[u8],
+        k: usize,
+        mut max_of: F,
+    ) -> Vec<(Vec<u8>, Output)>
+    where
+        F: FnMut(&Fst<Data>, Node<'_>) -> Output,
+    {
+        if self.meta.value_table.is_some() {
+            let mut builder = self.range().ge(prefix);
+            if let Some(end) = prefix_upper_bound(prefix) {
+                builder = builder.lt(end);
+            }
+            return builder.top_values(k);
+        }
+        let mut results = vec![];
+        if k == 0 {
+            return results;
+        }
+        let mut node = self.root();
+        let mut out = Output::zero();
+        for &b in prefix {
+            node = match node.find_input(b) {
+                None => return results,
+                Some(i) => {
+                    let t = node.transition(i);
+                    out = out.cat(t.out);
+                    self.node(t.addr)
+                }
+            }
+        }
+
+        let mut queue = BinaryHeap::new();
+        queue.push(Candidate {
+            bound: out.cat(max_of(self, node)),
+            key: prefix.to_vec(),
+            out,
+            next: Some(node.addr()),
+        });
+        while let Some(c) = queue.pop() {
+            let addr = match c.next {
+                // The bound of a complete key is exact, and every other
+                // candidate is bounded from above, so no key that is still
+                // queued can beat this one.
+                None => {
+                    results.push((c.key, c.out));
+                    if results.len() == k {
+                        break;
+                    }
+                    continue;
+                }
+                Some(addr) => addr,
+            };
+            let node = self.node(addr);
+            if node.is_final() {
+                let out = c.out.cat(node.final_output());
+                queue.push(Candidate {
+                    bound: out,
+                    key: c.key.clone(),
+                    out,
+                    next: None,
+                });
+            }
+            for t in node.transitions() {
+                let out = c.out.cat(t.out);
+                let mut key = c.key.clone();
+                key.push(t.inp);
+                queue.push(Candidate {
+                    bound: out.cat(max_of(self, self.node(t.addr))),
+                    key,
+                    out,
+                    next: Some(t.addr),
+                });
+            }
+        }
+        results
+    }
+}
+
+impl<'f, A: Automaton> StreamBuilder<'f, A> {
+    /// Returns the `k` key-value pairs with the greatest outputs among the
+    /// keys this builder streams, in descending order of output. Ties are
+    /// broken by key, in lexicographic order.
+    ///
+    /// This streams every key in the range of this builder that matches its
+    /// automaton once, keeping the best `k` in a bounded heap, so it takes
+    /// memory for `k` keys only. Keys are only copied when they make it into
+    /// the heap. Without a range or automaton, `Fst::top_k_completions` with
+    /// an empty prefix is usually faster.
+    pub fn top_values(self, k: usize) -> Vec<(Vec<u8>, Output)> {
+        if k == 0 {
+            return vec![];
+        }
+        // The greatest element of the heap is the worst one kept.
+        let mut heap: BinaryHeap<(Reverse<Output>, Vec<u8>)> = BinaryHeap::with_capacity(k + 1);
+        let mut stream = self.into_stream();
+        while let Some((key, out)) = stream.next() {
+            if heap.len() == k {
+                let (Reverse(worst), ref worst_key) = *heap.peek().unwrap();
+                if out < worst {
+                    continue;
+                }
+                let key = key.to_vec();
+                if out == worst && key >= *worst_key {
+                    continue;
+                }
+                heap.pop();
+                heap.push((Reverse(out), key));
+            } else {
+                heap.push((Reverse(out), key.to_vec()));
+            }
+        }
+        heap.into_sorted_vec()
+            .into_iter()
+            .map(|(Reverse(out), key)| (key, out))
+            .collect()
+    }
+}
+
+/// Returns the greatest or least output reachable from `node`, memoizing the
+/// result for every node visited.
+fn subtree_extremum<Data: FakeArr>(
+    fst: &Fst<Data>,
+    node: Node<'_>,
+    kind: SidecarKind,
+    memo: &mut HashMap<CompiledAddr, Output>,
+) -> Output {
+    if let Some(&ext) = memo.get(&node.addr()) {
+        return ext;
+    }
+    let mut ext = if node.is_final() {
+        Some(node.final_output())
+    } else {
+        None
+    };
+    for t in node.transitions() {
+        let out = t
+            .out
+            .cat(subtree_extremum(fst, fst.node(t.addr), kind, memo));
+        ext = Some(ext.map_or(out, |ext| kind.pick(ext, out)));
+    }
+    let ext = ext.unwrap_or_else(Output::zero);
+    memo.insert(node.addr(), ext);
+    ext
+}
+
+/// Which extremum of the reachable outputs an `OutputSidecar` stores.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SidecarKind {
+    /// The greatest output reachable from each node.
+    Max,
+    /// The least output reachable from each node.
+    Min,
+}
+
+impl SidecarKind {
+    fn pick(self, a: Output, b: Output) -> Output {
+        match self {
+            SidecarKind::Max => cmp::max(a, b),
+            SidecarKind::Min => cmp::min(a, b),
+        }
+    }
+}
+
+/// Per-node extrema of the outputs reachable in an fst, stored next to it.
+///
+/// For every node, the sidecar stores the greatest (or least) output of any
+/// key that goes through the node, counting only the outputs from the node
+/// onwards. Adding the output accumulated on the way to the node gives a
+/// bound on the outputs of every key below it. This is what best-first
+/// searches like `Fst::top_k_completions_with` need to avoid exploring the
+/// whole fst.
+///
+/// A sidecar is computed once from a finished fst with `OutputSidecar::new`
+/// and can be serialized with `to_bytes` so that it does not need to be
+/// recomputed. It is only valid for the exact fst it was built from.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OutputSidecar {
+    kind: SidecarKind,
+    addrs: Vec<CompiledAddr>,
+    values: Vec<Output>,
+}
+
+impl OutputSidecar {
+    /// Computes the sidecar of the given kind for every node in `fst`.
+    pub fn new<Data: FakeArr>(fst: &Fst<Data>, kind: SidecarKind) -> OutputSidecar {
+        let mut memo = HashMap::new();
+        subtree_extremum(fst, fst.root(), kind, &mut memo);
+        let mut entries: Vec<(CompiledAddr, Output)> = memo
+            .into_iter()
+            .filter(|&(addr, _)| addr != EMPTY_ADDRESS)
+            .collect();
+        entries.sort_unstable();
+        OutputSidecar {
+            kind,
+            addrs: entries.iter().map(|&(addr, _)| addr).collect(),
+            values: entries.iter().map(|&(_, out)| out).collect(),
+        }
+    }
+
+    /// Returns the kind of extremum stored in this sidecar.
+    pub fn kind(&self) -> SidecarKind {
+        self.kind
+    }
+
+    /// Returns the number of nodes in this sidecar.
+    pub fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    /// Returns true if this sidecar has no nodes.
+    pub fn is_empty(&self) -> bool {
+        self.addrs.is_empty()
+    }
+
+    /// Returns the extremum stored for the node at `addr`, or `None` if
+    /// there is no such node.
+    pub fn get(&self, addr: CompiledAddr) -> Option<Output> {
+        if addr == EMPTY_ADDRESS {
+            return Some(Output::zero());
+        }
+        self.addrs.binary_search(&addr).ok().map(|i| self.values[i])
+    }
+
+    /// Returns the extremum of the outputs of all keys starting with
+    /// `prefix`, or `None` if no key starts with `prefix`.
+    ///
+    /// `fst` must be the fst this sidecar was built from.
+    pub fn along_path<Data: FakeArr, B: AsRef<[u8]>>(
+        &self,
+        fst: &Fst<Data>,
+        prefix: B,
+    ) -> Option<Output> {
+        let prefix = prefix.as_ref();
+        let out = fst.output_along_path(prefix)?;
+        let mut node = fst.root();
+        for &b in prefix {
+            node = fst.node(node.transition_addr(node.find_input(b)?));
+        }
+        self.get(node.addr()).map(|ext| out.cat(ext))
+    }
+
+    /// Serializes this sidecar.
+    ///
+    /// Addresses and values are packed into the smallest number of bytes that
+    /// can hold the largest of them, so that the serialized form stays
+    /// compact.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let max_addr = self.addrs.last().cloned().unwrap_or(0);
+        let max_value = self.values.iter().map(|v| v.value()).max().unwrap_or(0);
+        let asize = pack_size(max_addr);
+        let vsize = pack_size(max_value);
+        let mut bytes = vec![];
+        bytes.push(match self.kind {
+            SidecarKind::Max => 0,
+            SidecarKind::Min => 1,
+        });
+        bytes.push(asize);
+        bytes.push(vsize);
+        bytes.write_u64::<LittleEndian>(self.len() as u64).unwrap();
+        for (&addr, value) in self.addrs.iter().zip(&self.values) {
+            pack_uint_in(&mut bytes, addr, asize).unwrap();
+            pack_uint_in(&mut bytes, value.value(), vsize).unwrap();
+        }
+        bytes
+    }
+
+    /// Deserializes a sidecar written by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<OutputSidecar> {
+        if bytes.len() < 11 {
+            return Err(Error::Format.into());
+        }
+        let kind = match bytes[0] {
+            0 => SidecarKind::Max,
+            1 => SidecarKind::Min,
+            _ => return Err(Error::Format.into()),
+        };
+        let (asize, vsize) = (bytes[1], bytes[2]);
+        if asize == 0 || asize > 8 || vsize == 0 || vsize > 8 {
+            return Err(Error::Format.into());
+        }
+        let len = LittleEndian::read_u64(&bytes[3..11]);
+        let entries = &bytes[11..];
+        let entry_size = (asize + vsize) as usize;
+        if entries.len() as u64 != len.saturating_mul(entry_size as u64) {
+            return Err(Error::Format.into());
+        }
+        let mut addrs = Vec::with_capacity(len as usize);
+        let mut values = Vec::with_capacity(len as usize);
+        for entry in entries.chunks(entry_size) {
+            let (addr, value) = entry.split_at(asize as usize);
+            addrs.push(u64_to_Ulen(LittleEndian::read_uint(addr, asize as usize)));
+            values.push(Output::new(LittleEndian::read_uint(value, vsize as usize)));
+        }
+        if addrs.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(Error::Format.into());
+        }
+        Ok(OutputSidecar {
+            kind,
+            addrs,
+            values,
+        })
+    }
+}
+
+/// A partial or complete key in the best-first search.
+struct Candidate {
+    /// An upper bound on the output of any key reachable from here.
+    bound: Output,
+    key: Vec<u8>,
+    /// The output accumulated along `key`.
+    out: Output,
+    /// The node reached by `key`, or `None` if this candidate is the
+    /// complete key itself.
+    next: Option<CompiledAddr>,
+}
+
+impl Candidate {
+    fn rank(&self) -> (Output, Reverse<&[u8]>, bool) {
+        // Complete keys come before partial keys with the same bound, since
+        // the latter can only lead to longer (and thus greater) keys.
+        (self.bound, Reverse(&self.key), self.next.is_none())
+    }
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.rank() == other.rank()
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.rank().cmp(&other.rank())
+    }
+}
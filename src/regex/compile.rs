@@ -40,6 +40,14 @@ impl Compiler {
                 }
             },
             HirKind::Class(class) => match class {
+                // Classes of ASCII bytes, which non-Unicode mode gives for
+                // things like `\w`, match the same keys as Unicode classes.
+                Class::Bytes(class_bytes) if class_bytes.is_all_ascii() => {
+                    let ranges = class_bytes
+                        .iter()
+                        .map(|r| ClassUnicodeRange::new(r.start() as char, r.end() as char));
+                    self.compile_class(&ClassUnicode::new(ranges))?
+                }
                 Class::Bytes(_) => return Err(Error::NoBytes),
                 Class::Unicode(class_unicode) => self.compile_class(class_unicode)?,
             },
@@ -238,3 +246,79 @@ impl Compiler {
         }
     }
 }
+
+/// Returns literal strings that occur in every string matched by `hir`.
+///
+/// Each literal is the longest run of literal characters that is required
+/// at its position, encoded as UTF-8. Literals inside alternations and
+/// optional repetitions are never required and are skipped.
+pub fn required_literals(hir: &Hir) -> Vec<Vec<u8>> {
+    let mut lits = vec![];
+    collect_required(hir, &mut lits);
+    let mut seen = vec![];
+    lits.retain(|lit: &Vec<u8>| {
+        if lit.is_empty() || seen.contains(lit) {
+            false
+        } else {
+            seen.push(lit.clone());
+            true
+        }
+    });
+    lits
+}
+
+fn collect_required(hir: &Hir, lits: &mut Vec<Vec<u8>>) {
+    if let Some(lit) = exact_literal(hir) {
+        lits.push(lit);
+        return;
+    }
+    match hir.kind() {
+        HirKind::Group(group) => collect_required(&group.hir, lits),
+        HirKind::Concat(hirs) => {
+            let mut run = vec![];
+            for hir in hirs {
+                match exact_literal(hir) {
+                    Some(lit) => run.extend(lit),
+                    None => {
+                        lits.push(std::mem::take(&mut run));
+                        collect_required(hir, lits);
+                    }
+                }
+            }
+            lits.push(run);
+        }
+        HirKind::Repetition(repetition) => {
+            let min = match repetition.kind {
+                RepetitionKind::ZeroOrOne | RepetitionKind::ZeroOrMore => 0,
+                RepetitionKind::OneOrMore => 1,
+                RepetitionKind::Range(RepetitionRange::Exactly(min))
+                | RepetitionKind::Range(RepetitionRange::AtLeast(min))
+                | RepetitionKind::Range(RepetitionRange::Bounded(min, _)) => min,
+            };
+            if min > 0 {
+                collect_required(&repetition.hir, lits);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Returns the string matched by `hir` if it matches exactly one string.
+fn exact_literal(hir: &Hir) -> Option<Vec<u8>> {
+    match hir.kind() {
+        HirKind::Empty => Some(vec![]),
+        HirKind::Literal(Literal::Unicode(c)) => {
+            let mut buf = [0; 4];
+            Some(c.encode_utf8(&mut buf).as_bytes().to_vec())
+        }
+        HirKind::Group(group) => exact_literal(&group.hir),
+        HirKind::Concat(hirs) => {
+            let mut lit = vec![];
+            for hir in hirs {
+                lit.extend(exact_literal(hir)?);
+            }
+            Some(lit)
+        }
+        _ => None,
+    }
+}
@@ -1,39 +1,176 @@
+use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::mem;
 
 use super::sparse::SparseSet;
 use super::Error;
 use super::Inst;
 
-const STATE_LIMIT: usize = 1_000; // currently at least 2MB >_<
+pub const STATE_LIMIT: usize = 1_000; // currently at least 2MB >_<
+
+/// States with at most this many runs of equal transitions are stored
+/// sparsely by default.
+pub const SPARSE_MAX_RANGES: usize = 8;
 
 pub struct DfaBuilder {
     dfa: Dfa,
     cache: HashMap<Vec<usize>, usize>,
+    state_limit: usize,
+    sparse_max_ranges: usize,
 }
 
 pub struct Dfa {
     insts: Vec<Inst>,
+    classes: ByteClasses,
     states: Vec<State>,
 }
 
+/// A partition of input bytes into classes that every instruction treats
+/// alike.
+///
+/// Two bytes are in the same class when no `Range` instruction contains one
+/// but not the other, so they lead every state to the same next state. Dense
+/// states then only need one entry per class instead of one per byte, which
+/// for most patterns is a small fraction of 256.
+struct ByteClasses([u8; 256]);
+
+impl ByteClasses {
+    fn new(insts: &[Inst]) -> ByteClasses {
+        // A class starts at every byte where some range starts or ends.
+        let mut boundaries = [false; 256];
+        for inst in insts {
+            if let Inst::Range(start, end) = *inst {
+                boundaries[start as usize] = true;
+                if end < 255 {
+                    boundaries[end as usize + 1] = true;
+                }
+            }
+        }
+        let mut classes = [0; 256];
+        let mut class = 0u8;
+        for b in 1..256 {
+            if boundaries[b] {
+                class += 1;
+            }
+            classes[b] = class;
+        }
+        ByteClasses(classes)
+    }
+
+    #[inline]
+    fn get(&self, byte: u8) -> usize {
+        self.0[byte as usize] as usize
+    }
+
+    fn len(&self) -> usize {
+        self.0[255] as usize + 1
+    }
+
+    /// Returns the first byte of every class, in order.
+    fn representatives(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..256)
+            .filter(move |&b| b == 0 || self.0[b] != self.0[b - 1])
+            .map(|b| b as u8)
+    }
+}
+
 struct State {
     insts: Vec<usize>,
-    next: [Option<usize>; 256],
+    next: Transitions,
     is_match: bool,
 }
 
+/// The outgoing transitions of a DFA state.
+///
+/// Dense states index a table by the class of the input byte. Sparse states keep sorted,
+/// non-overlapping runs of input bytes that lead to the same state and are
+/// searched with a binary search. A state that matches a large Unicode class
+/// usually has only a handful of runs, so storing it sparsely takes a
+/// fraction of the memory of a table.
+enum Transitions {
+    Dense(Box<[Option<usize>]>),
+    Sparse(Vec<(u8, u8, usize)>),
+}
+
+impl Transitions {
+    #[inline]
+    fn get(&self, classes: &ByteClasses, byte: u8) -> Option<usize> {
+        match *self {
+            Transitions::Dense(ref next) => next[classes.get(byte)],
+            Transitions::Sparse(ref ranges) => ranges
+                .binary_search_by(|&(start, end, _)| {
+                    if end < byte {
+                        Ordering::Less
+                    } else if start > byte {
+                        Ordering::Greater
+                    } else {
+                        Ordering::Equal
+                    }
+                })
+                .ok()
+                .map(|i| ranges[i].2),
+        }
+    }
+
+    fn set(&mut self, class: usize, si: Option<usize>) {
+        match *self {
+            Transitions::Dense(ref mut next) => next[class] = si,
+            Transitions::Sparse(_) => panic!("BUG: sparse states are immutable"),
+        }
+    }
+
+    /// Returns the runs of input bytes that lead to the same state.
+    fn ranges(&self, classes: &ByteClasses) -> Vec<(u8, u8, usize)> {
+        match *self {
+            Transitions::Sparse(ref ranges) => ranges.clone(),
+            Transitions::Dense(ref next) => {
+                let mut ranges: Vec<(u8, u8, usize)> = vec![];
+                for b in 0..256 {
+                    let (b, si) = match next[classes.get(b as u8)] {
+                        None => continue,
+                        Some(si) => (b as u8, si),
+                    };
+                    match ranges.last_mut() {
+                        Some(last) if last.2 == si && last.1 as usize + 1 == b as usize => {
+                            last.1 = b;
+                        }
+                        _ => ranges.push((b, b, si)),
+                    }
+                }
+                ranges
+            }
+        }
+    }
+}
+
 impl DfaBuilder {
     pub fn new(insts: Vec<Inst>) -> Self {
         DfaBuilder {
             dfa: Dfa {
+                classes: ByteClasses::new(&insts),
                 insts,
                 states: Vec::with_capacity(16),
             },
             cache: HashMap::with_capacity(1024),
+            state_limit: STATE_LIMIT,
+            sparse_max_ranges: SPARSE_MAX_RANGES,
         }
     }
 
+    /// Sets the maximum number of states the DFA may have.
+    pub fn state_limit(mut self, limit: usize) -> Self {
+        self.state_limit = limit;
+        self
+    }
+
+    /// Sets the maximum number of transition runs a state may have and still
+    /// be stored sparsely.
+    pub fn sparse_max_ranges(mut self, max: usize) -> Self {
+        self.sparse_max_ranges = max;
+        self
+    }
+
     pub fn build(mut self) -> Result<Dfa, Error> {
         let mut cur = SparseSet::new(self.dfa.insts.len());
         let mut next = SparseSet::new(self.dfa.insts.len());
@@ -41,20 +178,27 @@ impl DfaBuilder {
         self.dfa.add(&mut cur, 0);
         let mut states = vec![self.cached_state(&cur).unwrap()];
         let mut seen = HashSet::new();
+        let representatives: Vec<u8> = self.dfa.classes.representatives().collect();
         while let Some(s) = states.pop() {
-            for b in 0..256 {
-                let ns = self.run_state(&mut cur, &mut next, s, b as u8);
+            for (class, &b) in representatives.iter().enumerate() {
+                let ns = self.run_state(&mut cur, &mut next, s, class, b);
                 if let Some(ns) = ns {
                     if !seen.contains(&ns) {
                         seen.insert(ns);
                         states.push(ns);
                     }
                 }
-                if self.dfa.states.len() > STATE_LIMIT {
-                    return Err(Error::TooManyStates(STATE_LIMIT));
+                if self.dfa.states.len() > self.state_limit {
+                    return Err(Error::TooManyStates(self.state_limit));
                 }
             }
         }
+        for state in &mut self.dfa.states {
+            let ranges = state.next.ranges(&self.dfa.classes);
+            if ranges.len() <= self.sparse_max_ranges {
+                state.next = Transitions::Sparse(ranges);
+            }
+        }
         Ok(self.dfa)
     }
 
@@ -63,6 +207,7 @@ impl DfaBuilder {
         cur: &mut SparseSet,
         next: &mut SparseSet,
         state: usize,
+        class: usize,
         byte: u8,
     ) -> Option<usize> {
         cur.clear();
@@ -71,7 +216,7 @@ impl DfaBuilder {
         }
         self.dfa.run(cur, next, byte);
         let next_state = self.cached_state(next);
-        self.dfa.states[state].next[byte as usize] = next_state;
+        self.dfa.states[state].next.set(class, next_state);
         next_state
     }
 
@@ -102,7 +247,7 @@ impl DfaBuilder {
             Entry::Vacant(v) => {
                 self.dfa.states.push(State {
                     insts,
-                    next: [None; 256],
+                    next: Transitions::Dense(vec![None; self.dfa.classes.len()].into()),
                     is_match,
                 });
                 *v.insert(self.dfa.states.len() - 1)
@@ -117,7 +262,60 @@ impl Dfa {
     }
 
     pub fn accept(&self, si: usize, byte: u8) -> Option<usize> {
-        self.states[si].next[byte as usize]
+        self.states[si].next.get(&self.classes, byte)
+    }
+
+    /// Returns the number of states stored densely and sparsely, in that
+    /// order.
+    pub fn layout(&self) -> (usize, usize) {
+        let dense = self
+            .states
+            .iter()
+            .filter(|state| matches!(state.next, Transitions::Dense(_)))
+            .count();
+        (dense, self.states.len() - dense)
+    }
+
+    /// Returns the number of bytes held by the instructions and states of
+    /// this DFA.
+    pub fn heap_usage(&self) -> usize {
+        let states = self.states.iter().map(|s| {
+            let next = match s.next {
+                Transitions::Dense(ref next) => mem::size_of_val(&**next),
+                Transitions::Sparse(ref next) => {
+                    next.capacity() * mem::size_of::<(u8, u8, usize)>()
+                }
+            };
+            s.insts.capacity() * mem::size_of::<usize>() + next
+        });
+        self.insts.capacity() * mem::size_of::<Inst>()
+            + self.states.capacity() * mem::size_of::<State>()
+            + states.sum::<usize>()
+    }
+
+    /// Returns the number of classes that input bytes are partitioned into.
+    pub fn num_byte_classes(&self) -> usize {
+        self.classes.len()
+    }
+
+    /// Returns the bytes that every match must start with.
+    ///
+    /// This follows the start state for as long as it is not a match state
+    /// and has exactly one outgoing transition.
+    pub fn literal_prefix(&self) -> Vec<u8> {
+        let mut prefix = vec![];
+        let mut seen = HashSet::new();
+        let mut si = 0;
+        while !self.is_match(si) && seen.insert(si) {
+            match self.states[si].next.ranges(&self.classes)[..] {
+                [(start, end, next)] if start == end => {
+                    prefix.push(start);
+                    si = next;
+                }
+                _ => break,
+            }
+        }
+        prefix
     }
 
     fn add(&self, set: &mut SparseSet, ip: usize) {
@@ -164,14 +362,20 @@ impl fmt::Debug for Dfa {
         }
         writeln!(f, "------------")?;
         for (i, state) in self.states.iter().enumerate() {
+            let layout = match state.next {
+                Transitions::Dense(_) => "dense",
+                Transitions::Sparse(_) => "sparse",
+            };
             if state.is_match {
-                writeln!(f, "{:03}* {:?}", i, state.insts)?;
+                writeln!(f, "{:03}* {:?} ({})", i, state.insts, layout)?;
             } else {
-                writeln!(f, "{:03}  {:?}", i, state.insts)?;
+                writeln!(f, "{:03}  {:?} ({})", i, state.insts, layout)?;
             }
-            for j in 0..256 {
-                if let Some(si) = state.next[j] {
-                    writeln!(f, "{:03}   {:X} => {}", i, j, si)?;
+            for (start, end, si) in state.next.ranges(&self.classes) {
+                if start == end {
+                    writeln!(f, "{:03}   {:X} => {}", i, start, si)?;
+                } else {
+                    writeln!(f, "{:03}   {:X}-{:X} => {}", i, start, end, si)?;
                 }
             }
         }
@@ -37,6 +37,12 @@ pub enum Error {
     ///
     /// This restriction may be lifted in the future.
     NoBytes,
+    /// The pattern needs Unicode mode, but it was disabled by
+    /// `RegexFlags::unicode`.
+    ///
+    /// Without Unicode mode, a pattern may only match ASCII, so constructs
+    /// such as `.`, negated classes and non-ASCII literals are rejected.
+    UnicodeDisabled(regex_syntax::Error),
 }
 
 impl From<regex_syntax::Error> for Error {
@@ -77,6 +83,12 @@ impl fmt::Display for Error {
                  (hopefully temporary)."
             ),
             NoBytes => write!(f, "Byte literals are not allowed."),
+            UnicodeDisabled(ref err) => write!(
+                f,
+                "The pattern needs Unicode mode, which was disabled by \
+                 its flags: {}",
+                err
+            ),
         }
     }
 }
@@ -85,7 +97,7 @@ impl error::Error for Error {
     fn cause(&self) -> Option<&dyn error::Error> {
         use self::Error::*;
         match *self {
-            Syntax(ref err) => Some(err),
+            Syntax(ref err) | UnicodeDisabled(ref err) => Some(err),
             _ => None,
         }
     }
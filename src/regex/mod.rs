@@ -1,5 +1,6 @@
 use crate::Automaton;
 use regex_syntax;
+use regex_syntax::hir::ErrorKind;
 use std::fmt;
 use utf8_ranges;
 
@@ -54,9 +55,149 @@ pub use self::error::Error;
 /// crate will grow facilities for detecting regular expressions that will
 /// scan a large portion of a transducer and optionally disallow them.
 ///
+/// # Sharing
+///
+/// A compiled `Regex` is immutable, `Send` and `Sync`. Compiling is the
+/// expensive part, so compile a pattern once and share it, for instance in
+/// an `Arc`, between all the searches that use it, even concurrent ones.
 pub struct Regex {
     original: String,
     dfa: dfa::Dfa,
+    prefix: Vec<u8>,
+    literals: Vec<Vec<u8>>,
+}
+
+/// Flags that change how a pattern is interpreted, for `Regex::new_with_flags`
+/// and `RegexBuilder::flags`.
+///
+/// These are the same as the inline flags `i`, `s` and `u`, which can still
+/// be used in the pattern to override them for part of it.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct RegexFlags {
+    /// Letters match both their uppercase and lowercase forms. This is the
+    /// `i` flag and is disabled by default.
+    pub case_insensitive: bool,
+    /// `.` matches `\n` too. This is the `s` flag and is disabled by
+    /// default.
+    pub dot_matches_new_line: bool,
+    /// Classes such as `\w` and case folding use Unicode rules rather than
+    /// ASCII rules. This is the `u` flag and is enabled by default.
+    ///
+    /// Since keys are matched as UTF-8, a pattern compiled without Unicode
+    /// must not match anything but ASCII, so constructs such as `.` or
+    /// `[^a]` are rejected with `Error::UnicodeDisabled`.
+    pub unicode: bool,
+}
+
+impl Default for RegexFlags {
+    fn default() -> RegexFlags {
+        RegexFlags {
+            case_insensitive: false,
+            dot_matches_new_line: false,
+            unicode: true,
+        }
+    }
+}
+
+/// A builder for a `Regex` with non-default limits and tuning knobs.
+///
+/// Input bytes that the pattern never tells apart share a class, such as
+/// the ASCII digits for the pattern `[0-9]+`. Every DFA state is stored
+/// either densely, as a table indexed by the class of the input byte, or
+/// sparsely, as a sorted list of byte ranges. Dense states have the
+/// fastest lookups, while sparse states take far less memory for states with
+/// few distinct transitions, such as those matching large Unicode classes.
+/// A state is stored sparsely when its transitions form at most
+/// `sparse_max_ranges` runs of consecutive bytes leading to the same state.
+/// The chosen layout of each state is shown by the `Debug` output of the
+/// `Regex`.
+#[derive(Clone, Debug)]
+pub struct RegexBuilder {
+    pattern: String,
+    flags: RegexFlags,
+    size_limit: usize,
+    state_limit: usize,
+    sparse_max_ranges: usize,
+}
+
+impl RegexBuilder {
+    /// Create a builder for the given pattern, with default settings.
+    pub fn new(re: &str) -> RegexBuilder {
+        RegexBuilder {
+            pattern: re.to_owned(),
+            flags: RegexFlags::default(),
+            size_limit: 10 * (1 << 20),
+            state_limit: dfa::STATE_LIMIT,
+            sparse_max_ranges: dfa::SPARSE_MAX_RANGES,
+        }
+    }
+
+    /// Sets the flags the pattern is interpreted with.
+    pub fn flags(mut self, flags: RegexFlags) -> RegexBuilder {
+        self.flags = flags;
+        self
+    }
+
+    /// Sets the approximate size limit, in bytes, of the compiled program.
+    ///
+    /// The default is 10MB.
+    pub fn size_limit(mut self, bytes: usize) -> RegexBuilder {
+        self.size_limit = bytes;
+        self
+    }
+
+    /// Sets the maximum number of DFA states.
+    ///
+    /// The default is 1,000.
+    pub fn state_limit(mut self, states: usize) -> RegexBuilder {
+        self.state_limit = states;
+        self
+    }
+
+    /// Sets the maximum number of transition ranges a DFA state may have and
+    /// still be stored sparsely.
+    ///
+    /// Use `0` to store every state densely and `256` to store every state
+    /// sparsely. The default is 8.
+    pub fn sparse_max_ranges(mut self, ranges: usize) -> RegexBuilder {
+        self.sparse_max_ranges = ranges;
+        self
+    }
+
+    /// Compile the regular expression.
+    pub fn build(&self) -> Result<Regex, Error> {
+        let hir = regex_syntax::ParserBuilder::new()
+            .case_insensitive(self.flags.case_insensitive)
+            .dot_matches_new_line(self.flags.dot_matches_new_line)
+            .unicode(self.flags.unicode)
+            .build()
+            .parse(&self.pattern)
+            .map_err(|err| match err {
+                regex_syntax::Error::Translate(ref e)
+                    if !self.flags.unicode
+                        && matches!(
+                            e.kind(),
+                            ErrorKind::InvalidUtf8 | ErrorKind::UnicodeNotAllowed
+                        ) =>
+                {
+                    Error::UnicodeDisabled(err)
+                }
+                err => Error::Syntax(err),
+            })?;
+        let insts = self::compile::Compiler::new(self.size_limit).compile(&hir)?;
+        let literals = self::compile::required_literals(&hir);
+        let dfa = self::dfa::DfaBuilder::new(insts)
+            .state_limit(self.state_limit)
+            .sparse_max_ranges(self.sparse_max_ranges)
+            .build()?;
+        let prefix = dfa.literal_prefix();
+        Ok(Regex {
+            original: self.pattern.clone(),
+            dfa,
+            prefix,
+            literals,
+        })
+    }
 }
 
 #[derive(Eq, PartialEq)]
@@ -79,17 +220,48 @@ impl Regex {
     /// used with the `search` method of any finite state transducer.
     #[inline]
     pub fn new(re: &str) -> Result<Regex, Error> {
-        Regex::with_size_limit(10 * (1 << 20), re)
+        RegexBuilder::new(re).build()
     }
 
-    fn with_size_limit(size: usize, re: &str) -> Result<Regex, Error> {
-        let hir = regex_syntax::Parser::new().parse(re)?;
-        let insts = self::compile::Compiler::new(size).compile(&hir)?;
-        let dfa = self::dfa::DfaBuilder::new(insts).build()?;
-        Ok(Regex {
-            original: re.to_owned(),
-            dfa,
-        })
+    /// Create a new regular expression query, interpreting the pattern with
+    /// the given flags.
+    ///
+    /// This avoids splicing inline flags such as `(?i)` into patterns built
+    /// programmatically. Errors are reported just like `new`.
+    #[allow(clippy::result_large_err)]
+    pub fn new_with_flags(re: &str, flags: RegexFlags) -> Result<Regex, Error> {
+        RegexBuilder::new(re).flags(flags).build()
+    }
+
+    /// Returns the literal prefix that every key matched by this regex
+    /// starts with.
+    ///
+    /// For example, the prefix of `foo[0-9]+` is `foo`. The prefix is empty
+    /// if matches may start with more than one byte.
+    pub fn literal_prefix(&self) -> &[u8] {
+        &self.prefix
+    }
+
+    /// Returns literal strings that every key matched by this regex
+    /// contains.
+    ///
+    /// For example, every match of `foo[0-9]+bar` contains both `foo` and
+    /// `bar`. A key that lacks any of these cannot match, so callers can use
+    /// them to rule out keys with a cheaper check before running the regex.
+    /// The list is empty if no literal is required.
+    pub fn required_literals(&self) -> &[Vec<u8>] {
+        &self.literals
+    }
+
+    /// Returns the number of bytes this regex holds on the heap, most of
+    /// which is its compiled DFA.
+    pub fn heap_usage(&self) -> usize {
+        let literals = self.literals.iter().map(|l| l.capacity()).sum::<usize>();
+        self.original.capacity()
+            + self.prefix.capacity()
+            + self.literals.capacity() * std::mem::size_of::<Vec<u8>>()
+            + literals
+            + self.dfa.heap_usage()
     }
 }
 
@@ -115,11 +287,24 @@ impl Automaton for Regex {
     fn accept(&self, state: &Option<usize>, byte: u8) -> Option<usize> {
         state.and_then(|state| self.dfa.accept(state, byte))
     }
+
+    #[inline]
+    fn literal_prefix(&self) -> &[u8] {
+        &self.prefix
+    }
 }
 
 impl fmt::Debug for Regex {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "Regex({:?})", self.original)?;
+        let (dense, sparse) = self.dfa.layout();
+        writeln!(
+            f,
+            "{} dense states, {} sparse states, {} byte classes",
+            dense,
+            sparse,
+            self.dfa.num_byte_classes()
+        )?;
         self.dfa.fmt(f)
     }
 }
@@ -0,0 +1,104 @@
+/*!
+Owned snapshots of search results.
+
+Streams borrow their keys from the fst and hand them out one at a time,
+which suits scans but not handlers that return a page of results, such as
+a web endpoint answering with JSON. `SearchResults::collect` copies the
+first matches of a stream, along with the automaton state each key ended
+in, into an owned container that records whether more matches were left
+behind. With the `serde` feature, that container serializes as is.
+*/
+use crate::automaton::Automaton;
+use crate::fake_arr::FakeArr;
+use crate::map::StreamWithStateBuilder;
+use crate::stream::{IntoStreamer, Streamer};
+
+/// The first matches of a search, along with whether there were more.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SearchResults<S = ()> {
+    /// The matches, in the order the stream yielded them.
+    pub hits: Vec<Hit<S>>,
+    /// True if the stream had more matches than the limit.
+    pub truncated: bool,
+}
+
+/// A single match of a search.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Hit<S = ()> {
+    /// The key that matched.
+    pub key: Vec<u8>,
+    /// The value of the key.
+    pub value: u64,
+    /// The state of the automaton once it read the key, or a summary of it.
+    pub state: S,
+}
+
+impl<S> SearchResults<S> {
+    /// Collects at most `limit` matches of `stream`, along with the
+    /// automaton state each key ended in.
+    ///
+    /// One more match is read to tell whether the results were truncated.
+    pub fn collect<A>(stream: StreamWithStateBuilder<'_, A>, limit: usize) -> SearchResults<S>
+    where
+        A: Automaton<State = S>,
+        S: Clone,
+    {
+        let mut stream = stream.into_stream();
+        let mut hits = vec![];
+        let mut truncated = false;
+        while let Some((key, value, state)) = stream.next() {
+            if hits.len() == limit {
+                truncated = true;
+                break;
+            }
+            hits.push(Hit {
+                key: key.to_vec(),
+                value,
+                state,
+            });
+        }
+        SearchResults { hits, truncated }
+    }
+
+    /// Replaces the state of every match with `f(state)`.
+    ///
+    /// This turns automaton states, which are often internal to the
+    /// automaton, into whatever summary a response should carry, such as
+    /// an edit distance.
+    pub fn map_states<U, F>(self, mut f: F) -> SearchResults<U>
+    where
+        F: FnMut(S) -> U,
+    {
+        SearchResults {
+            hits: self
+                .hits
+                .into_iter()
+                .map(|hit| Hit {
+                    key: hit.key,
+                    value: hit.value,
+                    state: f(hit.state),
+                })
+                .collect(),
+            truncated: self.truncated,
+        }
+    }
+
+    /// Returns the number of matches collected.
+    pub fn len(&self) -> usize {
+        self.hits.len()
+    }
+
+    /// Returns true if and only if no match was collected.
+    pub fn is_empty(&self) -> bool {
+        self.hits.is_empty()
+    }
+}
+
+impl<S> Hit<S> {
+    /// Returns the key as a string, if it is valid UTF-8.
+    pub fn key_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.key).ok()
+    }
+}
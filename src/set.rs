@@ -0,0 +1,724 @@
+use std::fmt;
+use std::io;
+use std::iter::FromIterator;
+
+use crate::raw;
+use crate::stream::{IntoStreamer, Streamer, TryStreamer};
+use crate::{
+    automaton::{AlwaysMatch, Automaton},
+    fake_arr::{FakeArr, FakeArrRef, Ulen},
+};
+use crate::{IoContext, Result};
+
+/// Set is a lexicographically ordered set of byte strings.
+///
+/// A `Set` is constructed with the `SetBuilder` type. It is backed by the
+/// same finite state transducer as `Map`, with every output set to `0`, so
+/// an fst built by a `MapBuilder` can be read as a set of its keys.
+///
+/// Sets support membership tests, range queries, automata based searches and
+/// set operations on their keys. Like maps, they can never be modified once
+/// constructed and must be built from lexicographically ordered byte
+/// sequences. Unlike maps, the same key may be inserted more than once in a
+/// row.
+pub struct Set<Data: FakeArr>(raw::Fst<Data>);
+
+impl Set<Vec<u8>> {
+    /// Reads a set from `rdr` until EOF and opens it.
+    ///
+    /// `size_hint` is the expected size of the set in bytes. Use `0` if the
+    /// size is not known. See `Map::from_reader`.
+    pub async fn from_reader<R: io::Read>(mut rdr: R, size_hint: usize) -> Result<Set<Vec<u8>>> {
+        let mut data = Vec::with_capacity(size_hint);
+        rdr.read_to_end(&mut data)
+            .map_err(|err| IoContext::new("reading set", err))?;
+        Ok(Set(raw::Fst::new(data).await?))
+    }
+}
+
+impl<Data: FakeArr> Set<Data> {
+    /// Tests the membership of a single key.
+    pub fn contains<K: AsRef<[u8]>>(&self, key: K) -> bool {
+        self.0.contains_key(key)
+    }
+
+    /// Just like `contains`, except a read that fails returns an error
+    /// instead of panicking. See `raw::Fst::try_get`.
+    pub fn try_contains<K: AsRef<[u8]>>(&self, key: K) -> Result<bool> {
+        self.0.try_contains_key(key)
+    }
+
+    /// Returns true if and only if some key in this set starts with
+    /// `prefix`, without streaming. See `raw::Fst::contains_prefix`.
+    pub fn contains_prefix<K: AsRef<[u8]>>(&self, prefix: K) -> bool {
+        self.0.contains_prefix(prefix)
+    }
+
+    /// Returns the greatest key that is less than or equal to `key`. See
+    /// `raw::Fst::get_floor`.
+    pub fn get_floor<K: AsRef<[u8]>>(&self, key: K) -> Option<Vec<u8>> {
+        self.0.get_floor(key).map(|(key, _)| key)
+    }
+
+    /// Returns the smallest key that is greater than or equal to `key`. See
+    /// `raw::Fst::get_ceiling`.
+    pub fn get_ceiling<K: AsRef<[u8]>>(&self, key: K) -> Option<Vec<u8>> {
+        self.0.get_ceiling(key).map(|(key, _)| key)
+    }
+
+    /// Returns the smallest key in this set, or `None` if it is empty,
+    /// without building a stream.
+    pub fn min_key(&self) -> Option<Vec<u8>> {
+        self.0.min_key().map(|(key, _)| key)
+    }
+
+    /// Returns the greatest key in this set, or `None` if it is empty,
+    /// without building a stream.
+    pub fn max_key(&self) -> Option<Vec<u8>> {
+        self.0.max_key().map(|(key, _)| key)
+    }
+
+    /// Returns the ordinal of a key, which is its zero-based position in
+    /// lexicographic order, or `None` if it is not in this set. See
+    /// `raw::Fst::get_index`.
+    pub fn get_index<K: AsRef<[u8]>>(&self, key: K) -> Option<u64> {
+        self.0.get_index(key)
+    }
+
+    /// Returns the key whose ordinal is `index`, or `None` if `index` is
+    /// not less than the length of this set. See `raw::Fst::get_key`.
+    pub fn get_key(&self, index: u64) -> Option<Vec<u8>> {
+        self.0.get_key(index).map(|(key, _)| key)
+    }
+
+    /// Returns up to `n - 1` keys that split this set into `n` ranges with
+    /// nearly the same number of keys each, so that each range can be
+    /// streamed by a separate worker. See `raw::Fst::partition_points`.
+    pub fn partition_points(&self, n: usize) -> Vec<Vec<u8>> {
+        self.0.partition_points(n)
+    }
+
+    /// Tests the membership of many keys at once. See
+    /// `raw::Fst::contains_all`.
+    pub fn contains_all<K: AsRef<[u8]>>(&self, keys: &[K]) -> Vec<bool> {
+        self.0.contains_all(keys)
+    }
+
+    /// Return a lexicographically ordered stream of all keys in this set.
+    ///
+    /// While this is a stream, it does require heap space proportional to the
+    /// longest key in the set.
+    #[inline]
+    pub fn stream(&self) -> Stream<'_> {
+        Stream(self.0.stream())
+    }
+
+    /// Return a stream of all keys in this set, in reverse lexicographic
+    /// order. See `raw::Fst::stream_rev`.
+    pub fn stream_rev(&self) -> Stream<'_> {
+        Stream(self.0.stream_rev())
+    }
+
+    /// Returns a lexicographically ordered stream of the keys in this set
+    /// that start with `prefix`. See `raw::Fst::stream_prefix`.
+    pub fn stream_prefix<K: AsRef<[u8]>>(&self, prefix: K) -> Stream<'_> {
+        Stream(self.0.stream_prefix(prefix))
+    }
+
+    /// Return a builder for range queries.
+    ///
+    /// A range query returns a subset of keys in this set in a range given in
+    /// lexicographic order.
+    #[inline]
+    pub fn range(&self) -> StreamBuilder<'_> {
+        StreamBuilder(self.0.range())
+    }
+
+    /// Return a builder for the keys of this set in `range`, which is any
+    /// standard range of byte strings, such as `"a".."m"`. See
+    /// `raw::StreamBuilder::range_bounds`.
+    pub fn range_bounds<R: raw::KeyRange>(&self, range: R) -> StreamBuilder<'_> {
+        StreamBuilder(self.0.range_bounds(range))
+    }
+
+    /// Executes an automaton on the keys of this set.
+    ///
+    /// Note that this returns a `StreamBuilder`, which can be used to
+    /// add a range query to the search (see the `range` method).
+    pub fn search<A: Automaton>(&self, aut: A) -> StreamBuilder<'_, A> {
+        StreamBuilder(self.0.search(aut))
+    }
+
+    /// Executes an automaton on the keys of this set, streaming the matches
+    /// in reverse lexicographic order. This is a shorthand for
+    /// `search(aut).backward()`.
+    pub fn search_rev<A: Automaton>(&self, aut: A) -> StreamBuilder<'_, A> {
+        self.search(aut).backward()
+    }
+
+    /// Returns the number of elements in this set.
+    #[inline]
+    pub fn len(&self) -> Ulen {
+        self.0.len()
+    }
+
+    /// Returns true if and only if this set is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Creates a new set operation with this set added to it.
+    ///
+    /// The `OpBuilder` type can be used to add additional set streams
+    /// and perform set operations like union, intersection, difference and
+    /// symmetric difference.
+    #[inline]
+    pub fn op(&self) -> OpBuilder<'_> {
+        OpBuilder::new().add(self)
+    }
+
+    /// Returns true if and only if the `self` set is disjoint with the set
+    /// `stream`.
+    ///
+    /// `stream` must be a lexicographically ordered sequence of byte strings.
+    pub fn is_disjoint<'f, I, S>(&self, stream: I) -> bool
+    where
+        I: for<'a> IntoStreamer<'a, Into = S, Item = FakeArrRef<'a>>,
+        S: 'f + for<'a> Streamer<'a, Item = FakeArrRef<'a>>,
+    {
+        self.op().add(stream).intersection().next().is_none()
+    }
+
+    /// Returns true if and only if the `self` set is a subset of `stream`.
+    ///
+    /// `stream` must be a lexicographically ordered sequence of byte strings.
+    pub fn is_subset<'f, I, S>(&self, stream: I) -> bool
+    where
+        I: for<'a> IntoStreamer<'a, Into = S, Item = FakeArrRef<'a>>,
+        S: 'f + for<'a> Streamer<'a, Item = FakeArrRef<'a>>,
+    {
+        let mut count = 0;
+        let mut is = self.op().add(stream).intersection();
+        while is.next().is_some() {
+            count += 1;
+        }
+        count == self.len()
+    }
+
+    /// Returns true if and only if the `self` set is a superset of `stream`.
+    ///
+    /// `stream` must be a lexicographically ordered sequence of byte strings.
+    pub fn is_superset<'f, I, S>(&self, stream: I) -> bool
+    where
+        I: for<'a> IntoStreamer<'a, Into = S, Item = FakeArrRef<'a>>,
+        S: 'f + for<'a> Streamer<'a, Item = FakeArrRef<'a>>,
+    {
+        let mut unexpected = OpBuilder::new().add(stream).add(self).difference();
+        unexpected.next().is_none()
+    }
+
+    /// Returns a reference to the underlying raw finite state transducer.
+    #[inline]
+    pub fn as_fst(&self) -> &raw::Fst<Data> {
+        &self.0
+    }
+
+    /// Returns the number of bytes this set holds on the heap, not counting
+    /// its data. See `raw::Fst::heap_usage`.
+    pub fn heap_usage(&self) -> usize {
+        self.0.heap_usage()
+    }
+
+    /// Consumes this set and returns the underlying raw finite state
+    /// transducer.
+    #[inline]
+    pub fn into_fst(self) -> raw::Fst<Data> {
+        self.0
+    }
+}
+
+impl<Data: FakeArr> fmt::Debug for Set<Data> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Set([")?;
+        let mut stream = self.stream();
+        let mut first = true;
+        while let Some(k) = stream.next() {
+            if !first {
+                write!(f, ", ")?;
+            }
+            first = false;
+            write!(f, "{}", String::from_utf8_lossy(&k.actually_read_it()))?;
+        }
+        write!(f, "])")
+    }
+}
+
+/// Returns the underlying finite state transducer.
+impl<Data: FakeArr> AsRef<raw::Fst<Data>> for Set<Data> {
+    #[inline]
+    fn as_ref(&self) -> &raw::Fst<Data> {
+        &self.0
+    }
+}
+
+impl<'s, 'a, Data: FakeArr> IntoStreamer<'a> for &'s Set<Data> {
+    type Item = FakeArrRef<'a>;
+    type Into = Stream<'s>;
+
+    #[inline]
+    fn into_stream(self) -> Self::Into {
+        Stream(self.0.stream())
+    }
+}
+
+// Construct a set from an Fst object.
+impl<Data: FakeArr> From<raw::Fst<Data>> for Set<Data> {
+    #[inline]
+    fn from(fst: raw::Fst<Data>) -> Self {
+        Set(fst)
+    }
+}
+
+/// A builder for creating a set.
+///
+/// This is not your average everyday builder. It has two important qualities
+/// that make it a bit unique from what you might expect:
+///
+/// 1. All keys must be added in lexicographic order. Adding a key out of order
+///    will result in an error. Adding the same key twice in a row is allowed.
+/// 2. The representation of a set is streamed to *any* `io::Write` as it is
+///    built. For an in memory representation, this can be a `Vec<u8>`.
+pub struct SetBuilder<W>(raw::Builder<W>);
+
+impl SetBuilder<Vec<u8>> {
+    /// Create a builder that builds a set in memory.
+    #[inline]
+    pub fn memory() -> Self {
+        SetBuilder(raw::Builder::memory())
+    }
+}
+
+impl<W: io::Write> SetBuilder<W> {
+    /// Create a builder that builds a set by writing it to `wtr` in a
+    /// streaming fashion.
+    pub fn new(wtr: W) -> Result<SetBuilder<W>> {
+        raw::Builder::new_type(wtr, 0).map(SetBuilder)
+    }
+
+    /// Stores the number of keys below every node, which speeds up
+    /// `Set::get_index`, `Set::get_key` and `StreamBuilder::count`. See
+    /// `raw::Builder::with_counts`.
+    pub fn with_counts(&mut self, enabled: bool) {
+        self.0.with_counts(enabled)
+    }
+
+    /// Insert a new key into the set.
+    ///
+    /// If a key is inserted that is less than any previous key added, then
+    /// an error is returned. Similarly, if there was a problem writing to
+    /// the underlying writer, an error is returned.
+    pub fn insert<K: AsRef<[u8]>>(&mut self, key: K) -> Result<()> {
+        self.0.add(key)
+    }
+
+    /// Calls insert on each item in the iterator.
+    ///
+    /// If an error occurred while adding an element, processing is stopped
+    /// and the error is returned.
+    pub fn extend_iter<K, I>(&mut self, iter: I) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        I: IntoIterator<Item = K>,
+    {
+        for key in iter {
+            self.0.add(key)?;
+        }
+        Ok(())
+    }
+
+    /// Calls insert on each item in the stream.
+    ///
+    /// Note that unlike `extend_iter`, this is not generic on the items in
+    /// the stream.
+    pub fn extend_stream<'f, I, S>(&mut self, stream: I) -> Result<()>
+    where
+        I: for<'a> IntoStreamer<'a, Into = S, Item = FakeArrRef<'a>>,
+        S: 'f + for<'a> Streamer<'a, Item = FakeArrRef<'a>>,
+    {
+        let mut stream = stream.into_stream();
+        while let Some(key) = stream.next() {
+            self.0.add(key.actually_read_it())?;
+        }
+        Ok(())
+    }
+
+    /// Finishes the construction of the set and flushes the underlying
+    /// writer. After completion, the data written to `W` may be read using
+    /// one of `Set`'s constructor methods.
+    pub fn finish(self) -> Result<()> {
+        self.0.finish()
+    }
+
+    /// Just like `finish`, except it returns the underlying writer after
+    /// flushing it.
+    pub fn into_inner(self) -> Result<W> {
+        self.0.into_inner()
+    }
+
+    /// Gets a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        self.0.get_ref()
+    }
+
+    /// Returns the number of bytes written to the underlying writer
+    pub fn bytes_written(&self) -> u64 {
+        self.0.bytes_written()
+    }
+
+    /// Returns the number of bytes this builder holds on the heap, not
+    /// counting the writer. See `raw::Builder::heap_usage`.
+    pub fn heap_usage(&self) -> usize {
+        self.0.heap_usage()
+    }
+
+    /// Returns a checkpoint that `rollback` can return this builder to. See
+    /// `raw::Builder::checkpoint`.
+    pub fn checkpoint(&mut self) -> raw::Checkpoint {
+        self.0.checkpoint()
+    }
+}
+
+impl<W: raw::Truncate> SetBuilder<W> {
+    /// Returns this builder to `checkpoint`, undoing every key inserted
+    /// after it, and truncating the writer accordingly. See
+    /// `raw::Builder::rollback`.
+    pub fn rollback(&mut self, checkpoint: raw::Checkpoint) -> Result<()> {
+        self.0.rollback(checkpoint)
+    }
+}
+
+/// A lexicographically ordered stream of keys from a set.
+///
+/// The `A` type parameter corresponds to an optional automaton to filter
+/// the stream. By default, no filtering is done.
+///
+/// The `'s` lifetime parameter refers to the lifetime of the underlying set.
+pub struct Stream<'s, A = AlwaysMatch>(raw::Stream<'s, A>)
+where
+    A: Automaton;
+
+impl<'a, 's, A: Automaton> Streamer<'a> for Stream<'s, A> {
+    type Item = FakeArrRef<'a>;
+
+    fn next(&'a mut self) -> Option<Self::Item> {
+        self.0.next().map(|(key, _)| key)
+    }
+}
+
+impl<'a, 's, A: Automaton> TryStreamer<'a> for Stream<'s, A> {
+    type Item = FakeArrRef<'a>;
+
+    fn try_next(&'a mut self) -> Option<Result<Self::Item>> {
+        Some(self.0.try_next()?.map(|(key, _)| key))
+    }
+}
+
+impl<'s, A: Automaton + Clone> Stream<'s, A> {
+    /// Returns the next key from the other end of this stream. See
+    /// `raw::Stream::next_back`.
+    pub fn next_back(&mut self) -> Option<FakeArrRef<'_>> {
+        self.0.next_back().map(|(key, _)| key)
+    }
+}
+
+impl<'s, A: Automaton> Stream<'s, A> {
+    /// Returns the number of bytes this stream holds on the heap. See
+    /// `raw::Stream::heap_usage`.
+    pub fn heap_usage(&self) -> usize {
+        self.0.heap_usage()
+    }
+
+    /// Convert this stream into a vector of byte strings.
+    ///
+    /// Note that this creates a new allocation for every key in the stream.
+    pub fn into_bytes(self) -> Vec<Vec<u8>> {
+        self.0.into_byte_keys()
+    }
+
+    /// Convert this stream into a vector of Unicode strings.
+    ///
+    /// If any key is not valid UTF-8, then iteration on the stream is stopped
+    /// and a UTF-8 decoding error is returned.
+    ///
+    /// Note that this creates a new allocation for every key in the stream.
+    pub fn into_strs(self) -> Result<Vec<String>> {
+        self.0.into_str_keys()
+    }
+}
+
+/// A builder for constructing range queries on streams.
+///
+/// Once all bounds are set, one should call `into_stream` to get a
+/// `Stream`.
+///
+/// Bounds are not additive. That is, if `ge` is called twice on the same
+/// builder, then the second setting wins.
+///
+/// The `A` type parameter corresponds to an optional automaton to filter
+/// the stream. By default, no filtering is done.
+///
+/// The `'s` lifetime parameter refers to the lifetime of the underlying set.
+pub struct StreamBuilder<'s, A = AlwaysMatch>(raw::StreamBuilder<'s, A>);
+
+impl<'s, A: Automaton> StreamBuilder<'s, A> {
+    /// Specify a greater-than-or-equal-to bound.
+    pub fn ge<T: AsRef<[u8]>>(self, bound: T) -> Self {
+        StreamBuilder(self.0.ge(bound))
+    }
+
+    /// Specify a greater-than bound.
+    pub fn gt<T: AsRef<[u8]>>(self, bound: T) -> Self {
+        StreamBuilder(self.0.gt(bound))
+    }
+
+    /// Specify a less-than-or-equal-to bound.
+    pub fn le<T: AsRef<[u8]>>(self, bound: T) -> Self {
+        StreamBuilder(self.0.le(bound))
+    }
+
+    /// Specify a less-than bound.
+    pub fn lt<T: AsRef<[u8]>>(self, bound: T) -> Self {
+        StreamBuilder(self.0.lt(bound))
+    }
+
+    /// Specify both bounds from a standard range. See
+    /// `raw::StreamBuilder::range_bounds`.
+    pub fn range_bounds<R: raw::KeyRange>(self, range: R) -> Self {
+        StreamBuilder(self.0.range_bounds(range))
+    }
+
+    /// Streams the keys in descending order.
+    pub fn backward(self) -> Self {
+        StreamBuilder(self.0.backward())
+    }
+
+    /// Returns the number of keys this builder would stream, without
+    /// streaming them. See `raw::StreamBuilder::count`.
+    pub fn count(self) -> u64 {
+        self.0.count()
+    }
+
+    /// Streams the matching keys until `budget` runs out, and returns them
+    /// with a builder that resumes after the last one when there may be
+    /// more. See `raw::StreamBuilder::into_stream_with_budget`.
+    pub fn into_stream_with_budget(
+        self,
+        budget: raw::Budget,
+    ) -> raw::Page<Vec<u8>, StreamBuilder<'s, A>> {
+        let page = self.0.into_stream_with_budget(budget);
+        raw::Page {
+            items: page.items.into_iter().map(|(k, _)| k).collect(),
+            rest: page.rest.map(StreamBuilder),
+        }
+    }
+}
+
+impl<'s, 'a, A: Automaton> IntoStreamer<'a> for StreamBuilder<'s, A> {
+    type Item = FakeArrRef<'a>;
+    type Into = Stream<'s, A>;
+
+    fn into_stream(self) -> Self::Into {
+        Stream(self.0.into_stream())
+    }
+}
+
+/// A builder for collecting set streams on which to perform set operations.
+///
+/// Set operations include intersection, union, difference and symmetric
+/// difference. The result of each set operation is itself a stream that emits
+/// keys in lexicographic order.
+///
+/// All set operations work efficiently on an arbitrary number of
+/// streams with memory proportional to the number of streams.
+///
+/// The `'s` lifetime parameter refers to the lifetime of the underlying set.
+pub struct OpBuilder<'s>(raw::OpBuilder<'s>);
+
+impl<'s> OpBuilder<'s> {
+    /// Create a new set operation builder.
+    #[inline]
+    pub fn new() -> Self {
+        OpBuilder(raw::OpBuilder::default())
+    }
+
+    /// Add a stream to this set operation.
+    ///
+    /// This is useful for a chaining style pattern, e.g.,
+    /// `builder.add(stream1).add(stream2).union()`.
+    ///
+    /// The stream must emit a lexicographically ordered sequence of byte
+    /// strings.
+    pub fn add<I, S>(mut self, streamable: I) -> Self
+    where
+        I: for<'a> IntoStreamer<'a, Into = S, Item = FakeArrRef<'a>>,
+        S: 's + for<'a> Streamer<'a, Item = FakeArrRef<'a>>,
+    {
+        self.push(streamable);
+        self
+    }
+
+    /// Add a stream to this set operation.
+    ///
+    /// The stream must emit a lexicographically ordered sequence of byte
+    /// strings.
+    pub fn push<I, S>(&mut self, streamable: I)
+    where
+        I: for<'a> IntoStreamer<'a, Into = S, Item = FakeArrRef<'a>>,
+        S: 's + for<'a> Streamer<'a, Item = FakeArrRef<'a>>,
+    {
+        self.0.push(StreamZeroOutput(streamable.into_stream()));
+    }
+
+    /// Performs a union operation on all streams that have been added.
+    #[inline]
+    pub fn union(self) -> Union<'s> {
+        Union(self.0.union())
+    }
+
+    /// Performs an intersection operation on all streams that have been added.
+    #[inline]
+    pub fn intersection(self) -> Intersection<'s> {
+        Intersection(self.0.intersection())
+    }
+
+    /// Performs a difference operation with respect to the first stream added.
+    /// That is, this returns a stream of all elements in the first stream
+    /// that don't exist in any other stream that has been added.
+    #[inline]
+    pub fn difference(self) -> Difference<'s> {
+        Difference(self.0.difference())
+    }
+
+    /// Performs a symmetric difference operation on all of the streams that
+    /// have been added.
+    ///
+    /// When there are only two streams, then the keys returned correspond to
+    /// keys that are in either stream but *not* in both streams.
+    ///
+    /// More generally, for any number of streams, keys that occur in an odd
+    /// number of streams are returned.
+    #[inline]
+    pub fn symmetric_difference(self) -> SymmetricDifference<'s> {
+        SymmetricDifference(self.0.symmetric_difference())
+    }
+}
+
+impl<'f, I, S> Extend<I> for OpBuilder<'f>
+where
+    I: for<'a> IntoStreamer<'a, Into = S, Item = FakeArrRef<'a>>,
+    S: 'f + for<'a> Streamer<'a, Item = FakeArrRef<'a>>,
+{
+    fn extend<T>(&mut self, it: T)
+    where
+        T: IntoIterator<Item = I>,
+    {
+        for stream in it {
+            self.push(stream);
+        }
+    }
+}
+
+impl<'f, I, S> FromIterator<I> for OpBuilder<'f>
+where
+    I: for<'a> IntoStreamer<'a, Into = S, Item = FakeArrRef<'a>>,
+    S: 'f + for<'a> Streamer<'a, Item = FakeArrRef<'a>>,
+{
+    fn from_iter<T>(it: T) -> Self
+    where
+        T: IntoIterator<Item = I>,
+    {
+        let mut op = OpBuilder::new();
+        op.extend(it);
+        op
+    }
+}
+
+/// A stream of set union over multiple streams in lexicographic order.
+///
+/// The `'s` lifetime parameter refers to the lifetime of the underlying set.
+pub struct Union<'s>(raw::Union<'s>);
+
+impl<'a, 's> Streamer<'a> for Union<'s> {
+    type Item = FakeArrRef<'a>;
+
+    #[inline]
+    fn next(&'a mut self) -> Option<Self::Item> {
+        self.0.next().map(|(key, _)| key)
+    }
+}
+
+/// A stream of set intersection over multiple streams in lexicographic order.
+///
+/// The `'s` lifetime parameter refers to the lifetime of the underlying set.
+pub struct Intersection<'s>(raw::Intersection<'s>);
+
+impl<'a, 's> Streamer<'a> for Intersection<'s> {
+    type Item = FakeArrRef<'a>;
+
+    #[inline]
+    fn next(&'a mut self) -> Option<Self::Item> {
+        self.0.next().map(|(key, _)| key)
+    }
+}
+
+/// A stream of set difference over multiple streams in lexicographic order.
+///
+/// The difference operation is taken with respect to the first stream and the
+/// rest of the streams. i.e., All elements in the first stream that do not
+/// appear in any other streams.
+///
+/// The `'s` lifetime parameter refers to the lifetime of the underlying set.
+pub struct Difference<'s>(raw::Difference<'s>);
+
+impl<'a, 's> Streamer<'a> for Difference<'s> {
+    type Item = &'a [u8];
+
+    #[inline]
+    fn next(&'a mut self) -> Option<Self::Item> {
+        self.0.next().map(|(key, _)| key)
+    }
+}
+
+/// A stream of set symmetric difference over multiple streams in
+/// lexicographic order.
+///
+/// The `'s` lifetime parameter refers to the lifetime of the underlying set.
+pub struct SymmetricDifference<'s>(raw::SymmetricDifference<'s>);
+
+impl<'a, 's> Streamer<'a> for SymmetricDifference<'s> {
+    type Item = &'a [u8];
+
+    #[inline]
+    fn next(&'a mut self) -> Option<Self::Item> {
+        self.0.next().map(|(key, _)| key)
+    }
+}
+
+/// A specialized stream for mapping set streams (`&[u8]`) to streams used by
+/// raw fsts (`(&[u8], Output)`).
+///
+/// If this were iterators, we could use `iter::Map`, but doing this on streams
+/// requires HKT, so we need to write out the monomorphization ourselves.
+struct StreamZeroOutput<S>(S);
+
+impl<'a, S> Streamer<'a> for StreamZeroOutput<S>
+where
+    S: Streamer<'a, Item = FakeArrRef<'a>>,
+{
+    type Item = (FakeArrRef<'a>, raw::Output);
+
+    fn next(&'a mut self) -> Option<Self::Item> {
+        self.0.next().map(|key| (key, raw::Output::zero()))
+    }
+}
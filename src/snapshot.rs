@@ -0,0 +1,109 @@
+/*!
+Consistent snapshots of layers that a writer swaps while readers stream.
+
+Engines that keep an index as several maps, like the layers of a
+`map::MultiMap` or the base and deltas of a `delta::LayeredMap`, replace
+that set of layers as they merge and flush. `Snapshots` holds the current
+set behind an `Arc`. Readers take a `Snapshot`, which keeps its layers alive
+and unchanged for as long as it is held, while a writer installs a new set
+with `Snapshots::install`. Old layers are dropped once the last snapshot of
+them goes away.
+
+The current set is swapped atomically, so readers never take a lock. Taking
+a snapshot loads the set and clones an `Arc`, which never waits on other
+readers, on a writer that is building or installing a new layer, or on
+streams held by other readers.
+*/
+use std::ops::Deref;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+
+use crate::map::{Map, MultiMap};
+use crate::FakeArr;
+
+/// A set of layers that readers can take snapshots of.
+#[derive(Debug)]
+pub struct Snapshots<T> {
+    current: ArcSwap<Snapshot<T>>,
+}
+
+impl<T> Snapshots<T> {
+    /// Starts at epoch `0` with the given layers.
+    pub fn new(layers: T) -> Snapshots<T> {
+        Snapshots {
+            current: ArcSwap::from_pointee(Snapshot {
+                epoch: 0,
+                layers: Arc::new(layers),
+            }),
+        }
+    }
+
+    /// Returns the layers installed last.
+    pub fn snapshot(&self) -> Snapshot<T> {
+        Snapshot::clone(&self.current.load())
+    }
+
+    /// Returns the epoch of the layers installed last.
+    pub fn epoch(&self) -> u64 {
+        self.current.load().epoch
+    }
+
+    /// Replaces the layers and returns the epoch of the new ones.
+    ///
+    /// Snapshots taken before this call keep reading the layers they were
+    /// taken from. Concurrent installs each get their own epoch, in the
+    /// order in which their layers become current.
+    pub fn install(&self, layers: T) -> u64 {
+        let layers = Arc::new(layers);
+        let prev = self.current.rcu(|current| Snapshot {
+            epoch: current.epoch + 1,
+            layers: layers.clone(),
+        });
+        prev.epoch + 1
+    }
+}
+
+/// The layers installed in `Snapshots` at some epoch.
+///
+/// Streams borrowing from a snapshot see exactly these layers, whatever is
+/// installed in the meantime.
+#[derive(Debug)]
+pub struct Snapshot<T> {
+    epoch: u64,
+    layers: Arc<T>,
+}
+
+impl<T> Snapshot<T> {
+    /// Returns the epoch at which these layers were installed.
+    ///
+    /// Epochs start at `0` and increase by one with every install.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+}
+
+impl<T> Clone for Snapshot<T> {
+    fn clone(&self) -> Snapshot<T> {
+        Snapshot {
+            epoch: self.epoch,
+            layers: self.layers.clone(),
+        }
+    }
+}
+
+impl<T> Deref for Snapshot<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.layers
+    }
+}
+
+impl<Data: FakeArr> Snapshot<Vec<Arc<Map<Data>>>> {
+    /// Returns a `MultiMap` over the maps of this snapshot, from oldest to
+    /// newest.
+    pub fn multi_map(&self) -> MultiMap<'_, Data> {
+        MultiMap::new(self.layers.iter().map(|map| &**map))
+    }
+}
@@ -1,3 +1,26 @@
+/*!
+Streams and adapters for composing them.
+
+`Streamer` and `IntoStreamer` are also exported at the root of the crate.
+The adapters in this module, `map_items`, `filter` and `take_while`, work
+like their namesakes on `Iterator`, for any stream. `merge_join` walks a
+stream of a map alongside a sorted iterator from elsewhere. `TryStreamer`
+reports failed reads of a `FakeArr` as errors instead of panics, and
+`AsyncStreamer` streams owned items in async code.
+*/
+use std::borrow::Cow;
+use std::cmp::Ordering;
+#[cfg(feature = "async")]
+use std::future::Future;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+#[cfg(feature = "async")]
+use std::task::{Context, Poll};
+
+use crate::error::Result;
+use crate::fake_arr::{FakeArr, FakeArrRef};
+use crate::raw::Error;
+
 /// Streamer describes a "streaming iterator."
 ///
 /// It provides a mechanism for writing code that is generic over streams
@@ -128,3 +151,271 @@ impl<'a, S: Streamer<'a>> IntoStreamer<'a> for S {
         self
     }
 }
+
+/// TryStreamer is a stream whose reads can fail.
+///
+/// `Streamer::next` has no way to report that a `FakeArr` backed by disk or
+/// network IO failed to read, so such failures panic. `try_next` returns the
+/// IO error instead.
+///
+/// This is implemented by the streams that read an fst directly: `raw::Stream`
+/// and `raw::StreamWithState`, and the streams of keys and values of `Map`
+/// and `Set` built on them. After an error, the position of the stream is
+/// unspecified and it should be dropped.
+pub trait TryStreamer<'a> {
+    /// The type of the item emitted by this stream.
+    type Item: 'a;
+
+    /// Emits the next element in this stream, `None` to indicate the stream
+    /// has been exhausted, or the error that stopped it from being read.
+    fn try_next(&'a mut self) -> Option<Result<Self::Item>>;
+}
+
+/// AsyncStreamer is a stream for async code, whose items are owned.
+///
+/// Items cannot borrow from the stream as they do with `Streamer`, since a
+/// task may be suspended between two of them. `next` returns a future of
+/// the next item, like `StreamExt::next` in the `futures` crate.
+///
+/// This is only available with the `async` feature.
+#[cfg(feature = "async")]
+pub trait AsyncStreamer {
+    /// The type of the item emitted by this stream.
+    type Item;
+
+    /// Attempts to emit the next element in this stream, or `None` if the
+    /// stream has been exhausted. If no element is ready, the task of `cx`
+    /// is woken once one may be.
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Self::Item>>;
+
+    /// Returns a future of the next element in this stream.
+    fn next(&mut self) -> Next<'_, Self>
+    where
+        Self: Sized,
+    {
+        Next(self)
+    }
+}
+
+/// The future returned by `AsyncStreamer::next`.
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct Next<'s, S>(&'s mut S);
+
+#[cfg(feature = "async")]
+impl<S: AsyncStreamer> Future for Next<'_, S> {
+    type Output = Option<S::Item>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        self.0.poll_next(cx)
+    }
+}
+
+/// Returns a stream that emits `f(item)` for every item of `stream`.
+///
+/// Since `f` is called with items that borrow from `stream`, it usually
+/// returns owned values, such as a copy of a key or just a value.
+pub fn map_items<S, F>(stream: S, f: F) -> MapItems<S, F> {
+    MapItems { stream, f }
+}
+
+/// Returns a stream that emits the items of `stream` for which `pred`
+/// returns true.
+pub fn filter<S, F>(stream: S, pred: F) -> Filter<S, F> {
+    Filter { stream, pred }
+}
+
+/// Returns a stream that emits the items of `stream` until the first one
+/// for which `pred` returns false, and then ends.
+pub fn take_while<S, F>(stream: S, pred: F) -> TakeWhile<S, F> {
+    TakeWhile {
+        stream,
+        pred,
+        done: false,
+    }
+}
+
+/// A stream that transforms the items of another stream. See `map_items`.
+#[derive(Clone, Debug)]
+pub struct MapItems<S, F> {
+    stream: S,
+    f: F,
+}
+
+impl<'a, S, F, T> Streamer<'a> for MapItems<S, F>
+where
+    S: Streamer<'a>,
+    F: FnMut(S::Item) -> T,
+    T: 'a,
+{
+    type Item = T;
+
+    fn next(&'a mut self) -> Option<T> {
+        self.stream.next().map(&mut self.f)
+    }
+}
+
+/// A stream that skips the items of another stream that don't satisfy a
+/// predicate. See `filter`.
+#[derive(Clone, Debug)]
+pub struct Filter<S, F> {
+    stream: S,
+    pred: F,
+}
+
+// The predicate must accept items of any lifetime, so that it cannot keep
+// the items it is given. Otherwise it could hold on to a skipped item,
+// whose key the stream then overwrites.
+impl<'a, S, F> Streamer<'a> for Filter<S, F>
+where
+    S: for<'x> Streamer<'x>,
+    F: for<'x> FnMut(&<S as Streamer<'x>>::Item) -> bool,
+{
+    type Item = <S as Streamer<'a>>::Item;
+
+    fn next(&'a mut self) -> Option<Self::Item> {
+        let stream: *mut S = &mut self.stream;
+        loop {
+            // SAFETY: Every item borrows `self.stream` for `'a`, but an item
+            // is only returned if it is the last one taken, and items that
+            // are skipped are dropped before the next one is taken, since
+            // the predicate is higher-ranked and cannot keep them. So the
+            // borrows never overlap, even though the borrow checker can't
+            // tell that a borrow returned from one iteration of a loop ends
+            // when the loop goes on instead.
+            let item = unsafe { &mut *stream }.next()?;
+            if (self.pred)(&item) {
+                return Some(item);
+            }
+        }
+    }
+}
+
+/// A stream that ends at the first item of another stream that doesn't
+/// satisfy a predicate. See `take_while`.
+#[derive(Clone, Debug)]
+pub struct TakeWhile<S, F> {
+    stream: S,
+    pred: F,
+    done: bool,
+}
+
+impl<'a, S, F> Streamer<'a> for TakeWhile<S, F>
+where
+    S: Streamer<'a>,
+    F: FnMut(&S::Item) -> bool,
+{
+    type Item = S::Item;
+
+    fn next(&'a mut self) -> Option<S::Item> {
+        if self.done {
+            return None;
+        }
+        let item = self.stream.next()?;
+        if (self.pred)(&item) {
+            Some(item)
+        } else {
+            self.done = true;
+            None
+        }
+    }
+}
+
+/// Which keys `merge_join` reports.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum JoinType {
+    /// Only keys in both the stream and the iterator.
+    Inner,
+    /// Every key of the stream, whether or not it is in the iterator.
+    Left,
+    /// Every key of either the stream or the iterator.
+    Outer,
+}
+
+/// Walks a stream of keys and values, such as a stream of a map, alongside
+/// an iterator of keys and values in lexicographic order, and calls `f` with
+/// every key that `join` selects.
+///
+/// `f` is called in lexicographic order of the keys, with the key, its
+/// value in the stream and its value in the iterator. Keys are matched
+/// without buffering either side, so this reconciles a map with an external
+/// sorted list, such as the terms of another index, in a single pass.
+///
+/// The keys of `sorted` must be strictly increasing. If they are not, then
+/// an error is returned once the key out of order is reached, after `f` was
+/// called for the keys before it.
+pub fn merge_join<'f, I, S, J, K, V, F>(
+    stream: I,
+    sorted: J,
+    join: JoinType,
+    mut f: F,
+) -> Result<()>
+where
+    I: for<'a> IntoStreamer<'a, Into = S, Item = (FakeArrRef<'a>, u64)>,
+    S: 'f + for<'a> Streamer<'a, Item = (FakeArrRef<'a>, u64)>,
+    J: IntoIterator<Item = (K, V)>,
+    K: AsRef<[u8]>,
+    F: FnMut(&[u8], Option<u64>, Option<V>),
+{
+    let mut stream = stream.into_stream();
+    let mut sorted = sorted.into_iter();
+    let mut previous: Option<Vec<u8>> = None;
+    // Takes the next item of `sorted`, checking that it is in order.
+    let mut next_sorted = |previous: &mut Option<Vec<u8>>| -> Result<Option<(K, V)>> {
+        let (key, val) = match sorted.next() {
+            None => return Ok(None),
+            Some(item) => item,
+        };
+        if let Some(ref previous) = *previous {
+            if key.as_ref() <= &previous[..] {
+                let got = key.as_ref().to_vec();
+                return Err(if got == *previous {
+                    Error::DuplicateKey { got }
+                } else {
+                    Error::OutOfOrder {
+                        previous: previous.clone(),
+                        got,
+                    }
+                }
+                .into());
+            }
+        }
+        *previous = Some(key.as_ref().to_vec());
+        Ok(Some((key, val)))
+    };
+    let mut pending = next_sorted(&mut previous)?;
+    while let Some((k, val)) = stream.next() {
+        let k = match k.as_contiguous() {
+            Some(k) => Cow::Borrowed(k),
+            None => Cow::Owned(k.to_vec()),
+        };
+        let mut matched = false;
+        while let Some((ref key, _)) = pending {
+            let ord = key.as_ref().cmp(&k[..]);
+            if ord == Ordering::Greater {
+                break;
+            }
+            let (key, ext) = pending.take().unwrap();
+            if ord == Ordering::Equal {
+                f(&k, Some(val), Some(ext));
+                matched = true;
+            } else if join == JoinType::Outer {
+                f(key.as_ref(), None, Some(ext));
+            }
+            pending = next_sorted(&mut previous)?;
+            if matched {
+                break;
+            }
+        }
+        if !matched && join != JoinType::Inner {
+            f(&k, Some(val), None);
+        }
+    }
+    while let Some((key, ext)) = pending {
+        if join == JoinType::Outer {
+            f(key.as_ref(), None, Some(ext));
+        }
+        pending = next_sorted(&mut previous)?;
+    }
+    Ok(())
+}